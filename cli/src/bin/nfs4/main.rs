@@ -0,0 +1,642 @@
+// Copyright 2023 Remi Bernotavicius
+
+use chrono::{offset::TimeZone as _, Local};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use nfs4::{AttrRequest, FileAttribute, FileAttributes};
+use nfs4_client::Result;
+use nfs4_client::{WalkFilter, WalkOptions, WalkOrder};
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+mod shell;
+
+fn file_attrs(s: &str) -> std::result::Result<FileAttributes, String> {
+    let mut attrs = FileAttributes::default();
+
+    for e in s.split(',') {
+        let i = e.find('=').ok_or(String::from("Missing `=`"))?;
+        let key = &e[..i];
+        let value = &e[(i + 1)..];
+        attrs.insert(match key {
+            "size" => FileAttribute::Size(value.parse::<u64>().map_err(|e| e.to_string())?),
+            "owner" => FileAttribute::Owner(value.into()),
+            "owner_group" => FileAttribute::OwnerGroup(value.into()),
+            other => return Err(format!("unsupported attribute `{other}`")),
+        });
+    }
+
+    Ok(attrs)
+}
+
+#[derive(Subcommand)]
+enum Command {
+    GetAttr {
+        path: PathBuf,
+    },
+    SetAttr {
+        path: PathBuf,
+        #[arg(value_parser = file_attrs)]
+        attrs: FileAttributes,
+    },
+    ReadDir {
+        path: PathBuf,
+    },
+    Remove {
+        path: PathBuf,
+    },
+    Download {
+        remote: PathBuf,
+        local: PathBuf,
+    },
+    Upload {
+        local: PathBuf,
+        remote: PathBuf,
+    },
+    GetFacl {
+        path: PathBuf,
+    },
+    SetFacl {
+        path: PathBuf,
+        acl: nfs4::Acl,
+    },
+    Chown {
+        path: PathBuf,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// Make a hard link to `target`, or a symbolic link with `-s`
+    Ln {
+        #[arg(short, long)]
+        symbolic: bool,
+        target: PathBuf,
+        link: PathBuf,
+    },
+    #[command(name = "readlink")]
+    ReadLink {
+        path: PathBuf,
+    },
+    /// Make a block or character device, FIFO or socket
+    Mknod {
+        path: PathBuf,
+        #[arg(value_enum)]
+        node_type: NodeType,
+        /// Needed for block and character devices
+        #[arg(required_if_eq_any([("node_type", "b"), ("node_type", "c")]))]
+        major: Option<u32>,
+        #[arg(required_if_eq_any([("node_type", "b"), ("node_type", "c")]))]
+        minor: Option<u32>,
+    },
+    /// List everything below a directory, or just what matches
+    Find {
+        path: PathBuf,
+        /// Only names matching this shell pattern
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long, value_enum)]
+        r#type: Option<EntryType>,
+        /// Only files at least this many bytes
+        #[arg(long)]
+        min_size: Option<u64>,
+        /// Only files at most this many bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// Only files modified in the last this many seconds
+        #[arg(long)]
+        modified_within: Option<u64>,
+        /// Only files modified more than this many seconds ago
+        #[arg(long)]
+        modified_before: Option<u64>,
+        #[arg(long)]
+        max_depth: Option<usize>,
+        #[arg(long)]
+        breadth_first: bool,
+        /// How many directories to list at once
+        #[arg(long, default_value_t = 4)]
+        parallel: usize,
+    },
+    /// Show how much space each directory below `path` uses
+    Du {
+        path: PathBuf,
+        /// Only show the total for `path`
+        #[arg(short, long)]
+        summarize: bool,
+        /// Only show directories this far below `path`
+        #[arg(short = 'd', long)]
+        max_depth: Option<usize>,
+        /// Don't count directories on other filesystems
+        #[arg(short = 'x', long)]
+        one_file_system: bool,
+        /// Show sizes in bytes rather than KiB
+        #[arg(short, long)]
+        bytes: bool,
+    },
+    /// Make one directory tree look like another, like `rsync -a`. Whichever of `source` and
+    /// `destination` starts with `:` is on the server, the other is local
+    Sync {
+        source: String,
+        destination: String,
+        /// Compare the contents of files the same size, rather than modification times
+        #[arg(short, long)]
+        checksum: bool,
+        /// Remove anything in the destination which isn't in the source
+        #[arg(long)]
+        delete: bool,
+        /// Show what would be done without doing it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Also copy owners and groups
+        #[arg(short, long)]
+        owner: bool,
+        /// How many files to copy at once
+        #[arg(long, default_value_t = 4)]
+        parallel: usize,
+    },
+    /// Run commands one after another in one session, with tab completion of remote paths
+    Shell,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EntryType {
+    #[value(name = "f")]
+    Regular,
+    #[value(name = "d")]
+    Directory,
+    #[value(name = "l")]
+    Link,
+    #[value(name = "b")]
+    Block,
+    #[value(name = "c")]
+    Character,
+    #[value(name = "p")]
+    Fifo,
+    #[value(name = "s")]
+    Socket,
+}
+
+impl From<EntryType> for nfs4::FileType {
+    fn from(t: EntryType) -> Self {
+        match t {
+            EntryType::Regular => Self::Regular,
+            EntryType::Directory => Self::Directory,
+            EntryType::Link => Self::Link,
+            EntryType::Block => Self::Block,
+            EntryType::Character => Self::Character,
+            EntryType::Fifo => Self::Fifo,
+            EntryType::Socket => Self::Socket,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NodeType {
+    #[value(name = "b")]
+    Block,
+    #[value(name = "c")]
+    Character,
+    #[value(name = "p")]
+    Fifo,
+    #[value(name = "s")]
+    Socket,
+}
+
+#[derive(Parser)]
+struct Options {
+    host: String,
+    #[clap(default_value_t = nfs4_client::NFS_PORT)]
+    port: u16,
+    /// Map owners as `name@DOMAIN` using the local passwd and group files
+    #[clap(long)]
+    id_domain: Option<String>,
+    /// Open this many connections to the server and spread requests across them
+    #[clap(long, default_value_t = 1)]
+    nconnect: usize,
+    /// Cache attributes and looked up names, with the usual acregmin/acdirmin timeouts
+    #[clap(long)]
+    cache: bool,
+    /// Cache file data, checking it is still current each time a file is opened
+    #[clap(long)]
+    data_cache: bool,
+    /// Also keep cached file data in this directory, so later runs can use it
+    #[clap(long, requires = "data_cache")]
+    data_cache_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+fn print_listing(mut entries: nfs4_client::ReadDir<'_, TcpStream>) -> Result<()> {
+    while let Some(e) = entries.next() {
+        let e = e?;
+        let attrs = nfs4::Attributes::from(e.attrs);
+        let id_mapper = entries.client().id_mapper();
+        let owner = attrs.owner.as_deref().map(|o| id_mapper.display_owner(o));
+        let name = &e.name;
+        let mode = attrs.mode.unwrap_or(nfs4::Mode(0));
+        let num_links = attrs.num_links.unwrap_or_default();
+        let owner = owner.unwrap_or_default();
+        let size = attrs.size.unwrap_or_default();
+
+        let modify_str = attrs
+            .time_modify
+            .and_then(|t| t.to_date_time())
+            .and_then(|t| Local.from_local_datetime(&t).single())
+            .map(|t| t.to_rfc2822())
+            .unwrap_or_default();
+
+        println!("{mode:?} {num_links:3} {owner:5} {size:10} {modify_str:31} {name}");
+    }
+    Ok(())
+}
+
+struct Cli {
+    client: nfs4_client::Client<TcpStream>,
+}
+
+impl Cli {
+    fn get_attr(&mut self, path: PathBuf) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        let reply = self.client.get_attr(handle)?;
+        println!("{reply:#?}");
+        Ok(())
+    }
+
+    fn read_dir(&mut self, path: PathBuf) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        let attr_request = AttrRequest::new()
+            .mode()
+            .num_links()
+            .owner()
+            .size()
+            .time_modify();
+        print_listing(self.client.read_dir_iter(handle, attr_request.into()))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> Result<()> {
+        let (parent_dir, name) = (path.parent().unwrap(), path.file_name().unwrap());
+        let parent = self.client.look_up(parent_dir)?;
+        self.client.remove(parent, name.to_str().unwrap())?;
+        Ok(())
+    }
+
+    fn download(&mut self, remote: PathBuf, local: PathBuf) -> Result<()> {
+        let local_file = if local.to_string_lossy().ends_with('/') {
+            local.join(remote.file_name().unwrap())
+        } else {
+            local
+        };
+
+        let handle = self.client.look_up(&remote)?;
+        let remote_attrs = self
+            .client
+            .get_attributes(handle.clone(), AttrRequest::new().size())?;
+        let size = remote_attrs.size.unwrap_or_default();
+
+        let progress = ProgressBar::new(size).with_style(
+            ProgressStyle::with_template("{wide_bar} {percent}% {binary_bytes_per_sec}").unwrap(),
+        );
+        let file = std::fs::File::create(local_file)?;
+        self.client.read_all(handle, progress.wrap_write(file))?;
+        Ok(())
+    }
+
+    fn set_attr(&mut self, path: PathBuf, attrs: FileAttributes) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        self.client.set_attr(handle, attrs)?;
+        Ok(())
+    }
+
+    fn get_facl(&mut self, path: PathBuf) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        let acl = self.client.get_acl(handle)?;
+        println!("# file: {}", path.display());
+        print!("{acl}");
+        Ok(())
+    }
+
+    fn set_facl(&mut self, path: PathBuf, acl: nfs4::Acl) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        self.client.set_acl(handle, acl)?;
+        Ok(())
+    }
+
+    fn chown(&mut self, path: PathBuf, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        self.client.set_owner(handle, uid, gid)?;
+        Ok(())
+    }
+
+    fn ln(&mut self, symbolic: bool, target: PathBuf, link: PathBuf) -> Result<()> {
+        let (parent_dir, name) = (link.parent().unwrap(), link.file_name().unwrap());
+        let parent = self.client.look_up(parent_dir)?;
+        let name = name.to_str().unwrap();
+        if symbolic {
+            let target = target.to_str().unwrap();
+            self.client
+                .create_symlink(parent, name, target, FileAttributes::default())?;
+        } else {
+            let handle = self.client.look_up(&target)?;
+            self.client.hard_link(handle, parent, name)?;
+        }
+        Ok(())
+    }
+
+    fn read_link(&mut self, path: PathBuf) -> Result<()> {
+        let handle = self
+            .client
+            .look_up_with(&path, nfs4_client::LookUpOptions::no_follow())?;
+        println!("{}", self.client.read_link(handle)?);
+        Ok(())
+    }
+
+    fn mknod(
+        &mut self,
+        path: PathBuf,
+        node_type: NodeType,
+        major: Option<u32>,
+        minor: Option<u32>,
+    ) -> Result<()> {
+        let (parent_dir, name) = (path.parent().unwrap(), path.file_name().unwrap());
+        let parent = self.client.look_up(parent_dir)?;
+        // Clap only leaves these out for FIFOs and sockets, which don't use them.
+        let device = nfs4::DeviceData {
+            major: major.unwrap_or_default(),
+            minor: minor.unwrap_or_default(),
+        };
+        let special_file = match node_type {
+            NodeType::Block => nfs4_client::SpecialFile::Block(device),
+            NodeType::Character => nfs4_client::SpecialFile::Character(device),
+            NodeType::Fifo => nfs4_client::SpecialFile::Fifo,
+            NodeType::Socket => nfs4_client::SpecialFile::Socket,
+        };
+        self.client.mknod(
+            parent,
+            name.to_str().unwrap(),
+            special_file,
+            FileAttributes::default(),
+        )?;
+        Ok(())
+    }
+
+    fn find(&mut self, path: PathBuf, options: WalkOptions) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        for entry in self.client.walk(handle, options)? {
+            match entry {
+                Ok(entry) => println!("{}", path.join(entry.path).display()),
+                Err(error) => eprintln!("{error:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    fn du(
+        &mut self,
+        path: PathBuf,
+        summarize: bool,
+        max_depth: Option<usize>,
+        one_file_system: bool,
+        bytes: bool,
+    ) -> Result<()> {
+        let handle = self.client.look_up(&path)?;
+        let options = WalkOptions {
+            attr_request: AttrRequest::new().size().space_used().num_links().into(),
+            same_file_system: one_file_system,
+            ..Default::default()
+        };
+        let mut directories = vec![(PathBuf::new(), 0)];
+        let mut totals = HashMap::new();
+        let mut seen = HashSet::new();
+        for entry in self.client.walk(handle, options)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    eprintln!("{error:?}");
+                    continue;
+                }
+            };
+            let attrs = &entry.attrs;
+            if attrs.is_dir() {
+                directories.push((entry.path.clone(), entry.depth));
+            } else if attrs.num_links.unwrap_or(1) > 1 && !seen.insert((attrs.fs_id, attrs.file_id))
+            {
+                continue;
+            }
+            let used = attrs.space_used.or(attrs.size).unwrap_or_default();
+            for dir in entry.path.ancestors() {
+                *totals.entry(dir.to_owned()).or_insert(0) += used;
+            }
+        }
+
+        let max_depth = if summarize { Some(0) } else { max_depth };
+        for (dir, depth) in directories.into_iter().rev() {
+            if max_depth.is_some_and(|max| depth > max) {
+                continue;
+            }
+            let total = totals.get(&dir).copied().unwrap_or_default();
+            let total = if bytes { total } else { total.div_ceil(1024) };
+            println!("{total}\t{}", path.join(dir).display());
+        }
+        Ok(())
+    }
+
+    /// Looks up the directory, creating it if it doesn't exist.
+    fn look_up_or_create_dir(&mut self, path: &Path) -> Result<nfs4::FileHandle> {
+        match self.client.look_up(path) {
+            Err(nfs4_client::Error::Path(e))
+                if matches!(
+                    e.error,
+                    nfs4_client::Error::Protocol(nfs4::StatusError::NoEnt)
+                ) =>
+            {
+                let (parent_dir, name) = (path.parent().unwrap(), path.file_name().unwrap());
+                let parent = self.client.look_up(parent_dir)?;
+                self.client.create_directory(
+                    parent,
+                    name.to_str().unwrap(),
+                    FileAttributes::default(),
+                )
+            }
+            res => res,
+        }
+    }
+
+    fn sync(
+        &mut self,
+        source: String,
+        destination: String,
+        options: nfs4_client::SyncOptions,
+    ) -> Result<()> {
+        let actions = match (source.strip_prefix(':'), destination.strip_prefix(':')) {
+            (None, Some(remote)) => {
+                let remote = if options.dry_run {
+                    self.client.look_up(remote)?
+                } else {
+                    self.look_up_or_create_dir(Path::new(remote))?
+                };
+                self.client.sync_to_remote(source, remote, &options)?
+            }
+            (Some(remote), None) => {
+                let remote = self.client.look_up(remote)?;
+                self.client
+                    .sync_from_remote(remote, destination, &options)?
+            }
+            _ => {
+                eprintln!("exactly one of source and destination must start with `:`");
+                std::process::exit(2);
+            }
+        };
+        for action in actions {
+            match action {
+                nfs4_client::SyncAction::Remove(path) => println!("deleting {}", path.display()),
+                nfs4_client::SyncAction::CreateDirectory(path) => println!("{}/", path.display()),
+                nfs4_client::SyncAction::CreateSymlink(path, target) => {
+                    println!("{} -> {target}", path.display())
+                }
+                nfs4_client::SyncAction::Copy(path) => println!("{}", path.display()),
+                nfs4_client::SyncAction::SetAttributes(path) => {
+                    println!("{} (attributes)", path.display())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn upload(&mut self, local: PathBuf, remote: PathBuf) -> Result<()> {
+        let (parent_dir, name) = if remote.to_string_lossy().ends_with('/') {
+            (remote.as_ref(), local.file_name().unwrap())
+        } else {
+            (remote.parent().unwrap(), remote.file_name().unwrap())
+        };
+
+        let parent = self.client.look_up(parent_dir)?;
+        let handle = self.client.create_file(parent, name.to_str().unwrap())?;
+
+        let file = std::fs::File::open(local)?;
+        let progress = ProgressBar::new(file.metadata()?.len()).with_style(
+            ProgressStyle::with_template("{wide_bar} {percent}% {binary_bytes_per_sec}").unwrap(),
+        );
+        self.client.write_all(handle, progress.wrap_read(file))?;
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    let opts = Options::parse();
+
+    let transport = TcpStream::connect((opts.host, opts.port))?;
+    transport.set_nodelay(true)?;
+    let addr = transport.peer_addr()?;
+    let mut client = nfs4_client::Client::new(transport)?;
+    for _ in 1..opts.nconnect {
+        let transport = TcpStream::connect(addr)?;
+        transport.set_nodelay(true)?;
+        let trunking = client.add_connection(transport)?;
+        if trunking != nfs4_client::Trunking::Session {
+            eprintln!("server doesn't allow session trunking ({trunking:?})");
+            break;
+        }
+    }
+    if opts.cache {
+        client.enable_cache(Default::default());
+    }
+    if opts.data_cache {
+        client.enable_data_cache(nfs4_client::DataCacheConfig {
+            directory: opts.data_cache_dir,
+            ..Default::default()
+        })?;
+    }
+    if let Some(domain) = opts.id_domain {
+        let passwd = nfs4_client::PasswdIdMapper::from_system()?;
+        client.set_id_mapper(nfs4_client::DomainIdMapper::new(domain, passwd));
+    }
+
+    let mut cli = Cli { client };
+    match opts.command {
+        Command::GetAttr { path } => cli.get_attr(path)?,
+        Command::ReadDir { path } => cli.read_dir(path)?,
+        Command::Remove { path } => cli.remove(path)?,
+        Command::Download { remote, local } => cli.download(remote, local)?,
+        Command::SetAttr { path, attrs } => cli.set_attr(path, attrs)?,
+        Command::Upload { local, remote } => cli.upload(local, remote)?,
+        Command::GetFacl { path } => cli.get_facl(path)?,
+        Command::SetFacl { path, acl } => cli.set_facl(path, acl)?,
+        Command::Chown { path, uid, gid } => cli.chown(path, uid, gid)?,
+        Command::Ln {
+            symbolic,
+            target,
+            link,
+        } => cli.ln(symbolic, target, link)?,
+        Command::ReadLink { path } => cli.read_link(path)?,
+        Command::Mknod {
+            path,
+            node_type,
+            major,
+            minor,
+        } => cli.mknod(path, node_type, major, minor)?,
+        Command::Find {
+            path,
+            name,
+            r#type,
+            min_size,
+            max_size,
+            modified_within,
+            modified_before,
+            max_depth,
+            breadth_first,
+            parallel,
+        } => {
+            let ago = |secs| SystemTime::now() - Duration::from_secs(secs);
+            let filter = WalkFilter {
+                name,
+                file_type: r#type.map(Into::into),
+                min_size,
+                max_size,
+                modified_after: modified_within.map(ago),
+                modified_before: modified_before.map(ago),
+            };
+            let order = if breadth_first {
+                WalkOrder::BreadthFirst
+            } else {
+                WalkOrder::DepthFirst
+            };
+            let options = WalkOptions {
+                order,
+                filter,
+                max_depth,
+                parallel,
+                ..Default::default()
+            };
+            cli.find(path, options)?
+        }
+        Command::Du {
+            path,
+            summarize,
+            max_depth,
+            one_file_system,
+            bytes,
+        } => cli.du(path, summarize, max_depth, one_file_system, bytes)?,
+        Command::Sync {
+            source,
+            destination,
+            checksum,
+            delete,
+            dry_run,
+            owner,
+            parallel,
+        } => {
+            let options = nfs4_client::SyncOptions {
+                checksum,
+                delete,
+                preserve_owner: owner,
+                dry_run,
+                parallel,
+                ..Default::default()
+            };
+            cli.sync(source, destination, options)?
+        }
+        Command::Shell => shell::run(cli)?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,589 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! `nfs4 shell`, which runs commands one after another over one session, like `ftp` or
+//! `smbclient`. Remote paths are completed with tab from directory listings kept for the session,
+//! and `*`, `?` and `[...]` in the last component of a remote path are matched against them.
+
+use super::Cli;
+use nfs4::{AttrRequest, Attributes, FileAttribute, FileAttributes, Mode, StatusError};
+use nfs4_client::{glob_matches, Error};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+const COMMANDS: &[(&str, &str)] = &[
+    ("cd", "[DIR]"),
+    ("chmod", "MODE PATH..."),
+    ("chown", "[UID][:GID] PATH..."),
+    ("exit", ""),
+    ("get", "REMOTE [LOCAL]"),
+    ("help", ""),
+    ("ls", "[-l] [PATH...]"),
+    ("mkdir", "DIR..."),
+    ("mv", "FROM... TO"),
+    ("put", "LOCAL [REMOTE]"),
+    ("pwd", ""),
+    ("quit", ""),
+    ("rm", "PATH..."),
+    ("stat", "PATH..."),
+];
+
+enum ShellError {
+    Usage(String),
+    Client(Error),
+}
+
+impl From<Error> for ShellError {
+    fn from(error: Error) -> Self {
+        Self::Client(error)
+    }
+}
+
+impl From<io::Error> for ShellError {
+    fn from(error: io::Error) -> Self {
+        Self::Client(error.into())
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage(message) => write!(f, "{message}"),
+            Self::Client(Error::Io(error)) => write!(f, "{error}"),
+            Self::Client(Error::Path(error)) => {
+                write!(f, "{}: {:?}", error.path.display(), error.error)
+            }
+            Self::Client(error) => write!(f, "{error:?}"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, ShellError>;
+
+fn usage(command: &str) -> ShellError {
+    let args = COMMANDS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map_or("", |(_, args)| args);
+    ShellError::Usage(format!("usage: {command} {args}"))
+}
+
+fn is_not_found(error: &Error) -> bool {
+    match error {
+        Error::Protocol(StatusError::NoEnt) => true,
+        Error::Path(error) => is_not_found(&error.error),
+        _ => false,
+    }
+}
+
+/// A word of a command line. Globs in words with any quoting aren't expanded.
+#[derive(Default)]
+struct Word {
+    text: String,
+    quoted: bool,
+}
+
+/// Splits a line into words at whitespace, with quotes and `\` working more or less like in `sh`.
+fn split_words(line: &str) -> Result<Vec<Word>> {
+    let mut words = vec![];
+    let mut word: Option<Word> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(Word::default).quoted = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| ShellError::Usage("trailing `\\`".into()))?;
+                let word = word.get_or_insert_with(Word::default);
+                word.text.push(c);
+                word.quoted = true;
+            }
+            (_, c) => word.get_or_insert_with(Word::default).text.push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(ShellError::Usage("unterminated quote".into()));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Joins `path` onto `cwd`, dealing with `.` and `..` without asking the server.
+fn resolve(cwd: &Path, path: &str) -> PathBuf {
+    let mut resolved = PathBuf::from("/");
+    for component in cwd.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    resolved
+}
+
+fn split_path(path: &Path) -> Result<(&Path, &str)> {
+    match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(ShellError::Usage(format!(
+            "`{}` isn't in a directory",
+            path.display()
+        ))),
+    }
+}
+
+struct Session {
+    cli: Cli,
+    cwd: PathBuf,
+    /// The names in directories listed so far, and whether each is a directory.
+    listings: HashMap<PathBuf, Vec<(String, bool)>>,
+}
+
+impl Session {
+    fn listing(&mut self, dir: &Path) -> Result<&[(String, bool)]> {
+        if !self.listings.contains_key(dir) {
+            let client = &mut self.cli.client;
+            let handle = client.look_up(dir)?;
+            let entries = client.read_dir(handle, AttrRequest::new().type_().into())?;
+            let mut names: Vec<_> = entries
+                .into_iter()
+                .map(|e| {
+                    let is_dir = Attributes::from(e.attrs).is_dir();
+                    (e.name, is_dir)
+                })
+                .collect();
+            names.sort();
+            self.listings.insert(dir.to_owned(), names);
+        }
+        Ok(&self.listings[dir])
+    }
+
+    /// Forgets the listings `path` is in or of, after it changed.
+    fn changed(&mut self, path: &Path) {
+        self.listings.remove(path);
+        if let Some(parent) = path.parent() {
+            self.listings.remove(parent);
+        }
+    }
+
+    /// Whether there is a directory at `path`, rather than something else or nothing.
+    fn is_dir(&mut self, path: &Path) -> Result<bool> {
+        let client = &mut self.cli.client;
+        match client.look_up(path) {
+            Ok(handle) => Ok(client
+                .get_attributes(handle, AttrRequest::new().type_())?
+                .is_dir()),
+            Err(error) if is_not_found(&error) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Resolves words naming remote paths, replacing any with a glob in the last component by
+    /// the paths it matches. Globs which match nothing are left as they are.
+    fn expand(&mut self, words: &[Word]) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for word in words {
+            let path = resolve(&self.cwd, &word.text);
+            let Ok((dir, pattern)) = split_path(&path) else {
+                paths.push(path);
+                continue;
+            };
+            if word.quoted || !pattern.contains(['*', '?', '[']) {
+                paths.push(path);
+                continue;
+            }
+            let hidden = pattern.starts_with('.');
+            let matches: Vec<_> = self
+                .listing(dir)?
+                .iter()
+                .filter(|(name, _)| {
+                    (hidden || !name.starts_with('.')) && glob_matches(pattern, name)
+                })
+                .map(|(name, _)| dir.join(name))
+                .collect();
+            if matches.is_empty() {
+                paths.push(path);
+            } else {
+                paths.extend(matches);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Runs one command line, returning whether to carry on.
+    fn run(&mut self, command: &str, args: &[Word]) -> Result<bool> {
+        match command {
+            "cd" => self.cd(args)?,
+            "chmod" => self.chmod(args)?,
+            "chown" => self.chown(args)?,
+            "exit" | "quit" => return Ok(false),
+            "get" => self.get(args)?,
+            "help" => {
+                for (name, args) in COMMANDS {
+                    println!("{name} {args}");
+                }
+            }
+            "ls" => self.ls(args)?,
+            "mkdir" => self.mkdir(args)?,
+            "mv" => self.mv(args)?,
+            "put" => self.put(args)?,
+            "pwd" => println!("{}", self.cwd.display()),
+            "rm" => self.rm(args)?,
+            "stat" => {
+                for path in self.expand(args)? {
+                    self.cli.get_attr(path)?;
+                }
+            }
+            other => {
+                return Err(ShellError::Usage(format!(
+                    "unknown command `{other}`, try `help`"
+                )))
+            }
+        }
+        Ok(true)
+    }
+
+    fn cd(&mut self, args: &[Word]) -> Result<()> {
+        let dir = match self.expand(args)?.as_slice() {
+            [] => PathBuf::from("/"),
+            [dir] => dir.clone(),
+            _ => return Err(usage("cd")),
+        };
+        let client = &mut self.cli.client;
+        let handle = client.look_up(&dir)?;
+        if !client
+            .get_attributes(handle, AttrRequest::new().type_())?
+            .is_dir()
+        {
+            return Err(Error::Protocol(StatusError::NotDir).into());
+        }
+        self.cwd = dir;
+        Ok(())
+    }
+
+    fn ls(&mut self, args: &[Word]) -> Result<()> {
+        let (long, args) = match args {
+            [first, rest @ ..] if first.text == "-l" && !first.quoted => (true, rest),
+            args => (false, args),
+        };
+        let mut paths = self.expand(args)?;
+        if paths.is_empty() {
+            paths.push(self.cwd.clone());
+        }
+        let many = paths.len() > 1;
+        for path in paths {
+            if !self.is_dir(&path)? {
+                self.cli.client.look_up(&path)?;
+                println!(
+                    "{}",
+                    path.strip_prefix(&self.cwd).unwrap_or(&path).display()
+                );
+                continue;
+            }
+            if many {
+                println!("{}:", path.display());
+            }
+            // Listing a directory is how to see what changed in it since it was last listed.
+            self.listings.remove(&path);
+            if long {
+                self.cli.read_dir(path)?;
+                continue;
+            }
+            for (name, is_dir) in self.listing(&path)? {
+                println!("{name}{}", if *is_dir { "/" } else { "" });
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, args: &[Word]) -> Result<()> {
+        let (remotes, local) = match args {
+            [remote] => (
+                self.expand(std::slice::from_ref(remote))?,
+                PathBuf::from("."),
+            ),
+            [remote, local] => (
+                self.expand(std::slice::from_ref(remote))?,
+                PathBuf::from(&local.text),
+            ),
+            _ => return Err(usage("get")),
+        };
+        let into_dir = local.is_dir();
+        if remotes.len() > 1 && !into_dir {
+            return Err(ShellError::Usage(format!(
+                "`{}` has to be a directory to get several files into",
+                local.display()
+            )));
+        }
+        for remote in remotes {
+            let (_, name) = split_path(&remote)?;
+            let local = if into_dir {
+                local.join(name)
+            } else {
+                local.clone()
+            };
+            self.cli.download(remote, local)?;
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, args: &[Word]) -> Result<()> {
+        let (local, remote) = match args {
+            [local] => (PathBuf::from(&local.text), self.cwd.clone()),
+            [local, remote] => (PathBuf::from(&local.text), resolve(&self.cwd, &remote.text)),
+            _ => return Err(usage("put")),
+        };
+        let remote = if self.is_dir(&remote)? {
+            let name = local.file_name().ok_or_else(|| usage("put"))?;
+            remote.join(name)
+        } else {
+            remote
+        };
+        split_path(&remote)?;
+        self.cli.upload(local, remote.clone())?;
+        self.changed(&remote);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, args: &[Word]) -> Result<()> {
+        if args.is_empty() {
+            return Err(usage("mkdir"));
+        }
+        for word in args {
+            let path = resolve(&self.cwd, &word.text);
+            let (parent, name) = split_path(&path)?;
+            let parent = self.cli.client.look_up(parent)?;
+            self.cli
+                .client
+                .create_directory(parent, name, FileAttributes::default())?;
+            self.changed(&path);
+        }
+        Ok(())
+    }
+
+    fn rm(&mut self, args: &[Word]) -> Result<()> {
+        if args.is_empty() {
+            return Err(usage("rm"));
+        }
+        for path in self.expand(args)? {
+            let (parent, name) = split_path(&path)?;
+            let parent = self.cli.client.look_up(parent)?;
+            self.cli.client.remove(parent, name)?;
+            self.changed(&path);
+        }
+        Ok(())
+    }
+
+    fn mv(&mut self, args: &[Word]) -> Result<()> {
+        let [from @ .., to] = args else {
+            return Err(usage("mv"));
+        };
+        let from = self.expand(from)?;
+        let to = resolve(&self.cwd, &to.text);
+        let into_dir = self.is_dir(&to)?;
+        if from.is_empty() || (from.len() > 1 && !into_dir) {
+            return Err(usage("mv"));
+        }
+        for from in from {
+            let (from_dir, from_name) = split_path(&from)?;
+            let to = if into_dir {
+                to.join(from_name)
+            } else {
+                to.clone()
+            };
+            let (to_dir, to_name) = split_path(&to)?;
+            let client = &mut self.cli.client;
+            let from_handle = client.look_up(from_dir)?;
+            let to_handle = client.look_up(to_dir)?;
+            client.rename(from_handle, to_handle, from_name, to_name)?;
+            self.changed(&from);
+            self.changed(&to);
+        }
+        Ok(())
+    }
+
+    fn chmod(&mut self, args: &[Word]) -> Result<()> {
+        let [mode, paths @ ..] = args else {
+            return Err(usage("chmod"));
+        };
+        let mode = u32::from_str_radix(&mode.text, 8)
+            .map_err(|_| ShellError::Usage(format!("`{}` isn't an octal mode", mode.text)))?;
+        if paths.is_empty() {
+            return Err(usage("chmod"));
+        }
+        for path in self.expand(paths)? {
+            let handle = self.cli.client.look_up(&path)?;
+            let mut attrs = FileAttributes::default();
+            attrs.insert(FileAttribute::Mode(Mode(mode)));
+            self.cli.client.set_attr(handle, attrs)?;
+        }
+        Ok(())
+    }
+
+    fn chown(&mut self, args: &[Word]) -> Result<()> {
+        let [owner, paths @ ..] = args else {
+            return Err(usage("chown"));
+        };
+        let (uid, gid) = owner.text.split_once(':').unwrap_or((&owner.text, ""));
+        let id = |id: &str| {
+            (!id.is_empty())
+                .then(|| id.parse::<u32>())
+                .transpose()
+                .map_err(|_| ShellError::Usage(format!("`{id}` isn't a numeric id")))
+        };
+        let (uid, gid) = (id(uid)?, id(gid)?);
+        if paths.is_empty() || (uid.is_none() && gid.is_none()) {
+            return Err(usage("chown"));
+        }
+        for path in self.expand(paths)? {
+            self.cli.chown(path, uid, gid)?;
+        }
+        Ok(())
+    }
+}
+
+struct ShellHelper {
+    session: Rc<RefCell<Session>>,
+    files: FilenameCompleter,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before_cursor = &line[..pos];
+        let start = before_cursor
+            .rfind(char::is_whitespace)
+            .map_or(0, |i| i + 1);
+        let word = &before_cursor[start..];
+        let earlier: Vec<_> = before_cursor[..start].split_whitespace().collect();
+        match earlier.as_slice() {
+            [] => {
+                let commands = COMMANDS
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(word))
+                    .map(|(name, _)| Pair {
+                        display: name.to_string(),
+                        replacement: format!("{name} "),
+                    })
+                    .collect();
+                return Ok((start, commands));
+            }
+            // The local side of transfers.
+            ["put"] | ["get", _] => return self.files.complete(line, pos, ctx),
+            _ => {}
+        }
+
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+        let mut session = self.session.borrow_mut();
+        let resolved = resolve(&session.cwd, dir);
+        let Ok(listing) = session.listing(&resolved) else {
+            return Ok((start, vec![]));
+        };
+        let candidates = listing
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, is_dir)| {
+                let slash = if *is_dir { "/" } else { "" };
+                Pair {
+                    display: format!("{name}{slash}"),
+                    replacement: format!("{dir}{name}{slash}"),
+                }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn readline_error(error: ReadlineError) -> Error {
+    match error {
+        ReadlineError::Io(error) => error.into(),
+        error => io::Error::other(error).into(),
+    }
+}
+
+/// Reads and runs commands until `exit` or the end of the input. History is kept in
+/// `~/.nfs4_history`.
+pub(crate) fn run(cli: Cli) -> nfs4_client::Result<()> {
+    let session = Rc::new(RefCell::new(Session {
+        cli,
+        cwd: PathBuf::from("/"),
+        listings: HashMap::new(),
+    }));
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper {
+        session: session.clone(),
+        files: FilenameCompleter::new(),
+    }));
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".nfs4_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let prompt = format!("nfs4:{}> ", session.borrow().cwd.display());
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(readline_error(error)),
+        };
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(error) => {
+                eprintln!("{error}");
+                continue;
+            }
+        };
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        match session.borrow_mut().run(&command.text, args) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(error) => eprintln!("{}: {error}", command.text),
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
@@ -0,0 +1,663 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Protocol types for NFSv3 (RFC 1813). The MOUNT protocol used to get the root file handle is in
+//! the `mount` module, locking is in the `nlm` and `nsm` modules.
+
+use bitflags::bitflags;
+use bitflags_serde_shim::impl_serde_for_bitflags;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{
+    de::Deserializer,
+    ser::{SerializeStruct as _, Serializer},
+    Deserialize, Serialize,
+};
+use xdr_extras::{DeserializeWithDiscriminant, SerializeWithDiscriminant};
+
+pub mod mount;
+pub mod nlm;
+pub mod nsm;
+
+pub const NFS_PROGRAM: u32 = 100003;
+pub const NFS_VERSION: u32 = 3;
+pub const NFS_PORT: u16 = 2049;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum Procedure {
+    Null = 0,
+    GetAttr = 1,
+    SetAttr = 2,
+    LookUp = 3,
+    Access = 4,
+    ReadLink = 5,
+    Read = 6,
+    Write = 7,
+    Create = 8,
+    MkDir = 9,
+    Symlink = 10,
+    MkNod = 11,
+    Remove = 12,
+    RmDir = 13,
+    Rename = 14,
+    Link = 15,
+    ReadDir = 16,
+    ReadDirPlus = 17,
+    FsStat = 18,
+    FsInfo = 19,
+    PathConf = 20,
+    Commit = 21,
+}
+
+#[derive(
+    SerializeWithDiscriminant,
+    DeserializeWithDiscriminant,
+    PartialEq,
+    Eq,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+)]
+#[repr(u32)]
+pub enum StatusError {
+    Perm = 1,
+    NoEnt = 2,
+    Io = 5,
+    NxIo = 6,
+    Access = 13,
+    Exist = 17,
+    XDev = 18,
+    NoDev = 19,
+    NotDir = 20,
+    IsDir = 21,
+    Inval = 22,
+    FBig = 27,
+    NoSpc = 28,
+    RoFs = 30,
+    MLink = 31,
+    NameTooLong = 63,
+    NotEmpty = 66,
+    DQuot = 69,
+    Stale = 70,
+    Remote = 71,
+    BadHandle = 10001,
+    NotSync = 10002,
+    BadCookie = 10003,
+    NotSupported = 10004,
+    TooSmall = 10005,
+    ServerFault = 10006,
+    BadType = 10007,
+    JukeBox = 10008,
+}
+
+/// The result of an NFSv3 procedure. Unlike NFSv4, failures carry a body too, usually attributes
+/// so the client can keep its cache up to date.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum StatusResult<T, F = ()> {
+    Ok(T),
+    Err(StatusError, F),
+}
+
+impl<T, F> Serialize for StatusResult<T, F>
+where
+    T: Serialize,
+    F: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("StatusResult", 2)?;
+        match self {
+            Self::Ok(v) => {
+                state.serialize_field("discriminant", &0u32)?;
+                state.serialize_field("ok", v)?;
+            }
+            Self::Err(e, v) => {
+                state.serialize_field("discriminant", e)?;
+                state.serialize_field("fail", v)?;
+            }
+        }
+        state.end()
+    }
+}
+
+impl<'de, T, F> Deserialize<'de> for StatusResult<T, F>
+where
+    T: Deserialize<'de>,
+    F: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor<T, F>(std::marker::PhantomData<(T, F)>);
+
+        impl<'de, T, F> serde::de::Visitor<'de> for Visitor<T, F>
+        where
+            T: Deserialize<'de>,
+            F: Deserialize<'de>,
+        {
+            type Value = StatusResult<T, F>;
+
+            fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("StatusResult")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let disc: u32 = seq
+                    .next_element()?
+                    .ok_or(serde::de::Error::custom("expected discriminant"))?;
+                if disc == 0 {
+                    Ok(StatusResult::Ok(
+                        seq.next_element()?
+                            .ok_or(serde::de::Error::custom("expected value"))?,
+                    ))
+                } else {
+                    let err_id: StatusError = disc.try_into().map_err(|_| {
+                        serde::de::Error::custom(format!(
+                            "unexpected value {disc:?} for StatusError"
+                        ))
+                    })?;
+                    Ok(StatusResult::Err(
+                        err_id,
+                        seq.next_element()?
+                            .ok_or(serde::de::Error::custom("expected value"))?,
+                    ))
+                }
+            }
+        }
+
+        deserializer.deserialize_struct(
+            "StatusResult",
+            &["disc", "value"],
+            Visitor(std::marker::PhantomData),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FileHandle(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Verifier(pub u64);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct Time {
+    pub seconds: u32,
+    pub nseconds: u32,
+}
+
+#[derive(
+    SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Copy, Clone, Debug,
+)]
+#[repr(u32)]
+pub enum FileType {
+    Regular = 1,
+    Directory = 2,
+    Block = 3,
+    Character = 4,
+    Symlink = 5,
+    Socket = 6,
+    Fifo = 7,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct SpecData {
+    pub major: u32,
+    pub minor: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FileAttributes {
+    pub type_: FileType,
+    pub mode: u32,
+    pub num_links: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub used: u64,
+    pub raw_device: SpecData,
+    pub fsid: u64,
+    pub file_id: u64,
+    pub access_time: Time,
+    pub modify_time: Time,
+    pub change_time: Time,
+}
+
+pub type PostOpAttributes = Option<FileAttributes>;
+
+/// The subset of attributes used for weak cache consistency checks.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct WccAttributes {
+    pub size: u64,
+    pub modify_time: Time,
+    pub change_time: Time,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct WccData {
+    pub before: Option<WccAttributes>,
+    pub after: PostOpAttributes,
+}
+
+#[derive(
+    SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Clone, Debug, Default,
+)]
+#[repr(u32)]
+pub enum SetTime {
+    #[default]
+    DontChange = 0,
+    ServerTime = 1,
+    ClientTime(Time) = 2,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct SetAttributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub access_time: SetTime,
+    pub modify_time: SetTime,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DirOpArgs {
+    pub dir: FileHandle,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct GetAttrArgs {
+    pub object: FileHandle,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct GetAttrRes {
+    pub object_attributes: FileAttributes,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct SetAttrArgs {
+    pub object: FileHandle,
+    pub new_attributes: SetAttributes,
+    /// Only apply the change if the object's ctime still matches
+    pub guard: Option<Time>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct SetAttrRes {
+    pub object_wcc: WccData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LookUpRes {
+    pub object: FileHandle,
+    pub object_attributes: PostOpAttributes,
+    pub dir_attributes: PostOpAttributes,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct LookUpFail {
+    pub dir_attributes: PostOpAttributes,
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct Access: u32 {
+        const READ      = 0x00000001;
+        const LOOKUP    = 0x00000002;
+        const MODIFY    = 0x00000004;
+        const EXTEND    = 0x00000008;
+        const DELETE    = 0x00000010;
+        const EXECUTE   = 0x00000020;
+    }
+}
+
+impl_serde_for_bitflags!(Access);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct AccessArgs {
+    pub object: FileHandle,
+    pub access: Access,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct AccessRes {
+    pub object_attributes: PostOpAttributes,
+    pub access: Access,
+}
+
+/// The failure body of procedures that only return the object's attributes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct AttributesFail {
+    pub object_attributes: PostOpAttributes,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadLinkArgs {
+    pub symlink: FileHandle,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadLinkRes {
+    pub symlink_attributes: PostOpAttributes,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadArgs {
+    pub file: FileHandle,
+    pub offset: u64,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadRes {
+    pub file_attributes: PostOpAttributes,
+    pub count: u32,
+    pub eof: bool,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(
+    SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Copy, Clone, Debug,
+)]
+#[repr(u32)]
+pub enum StableHow {
+    Unstable = 0,
+    DataSync = 1,
+    FileSync = 2,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct WriteArgs {
+    pub file: FileHandle,
+    pub offset: u64,
+    pub count: u32,
+    pub stable: StableHow,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct WriteRes {
+    pub file_wcc: WccData,
+    pub count: u32,
+    pub committed: StableHow,
+    pub verifier: Verifier,
+}
+
+/// The failure body of procedures that modify a single object or directory.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct WccFail {
+    pub wcc: WccData,
+}
+
+#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Clone, Debug)]
+#[repr(u32)]
+pub enum CreateHow {
+    Unchecked(SetAttributes) = 0,
+    Guarded(SetAttributes) = 1,
+    Exclusive(Verifier) = 2,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CreateArgs {
+    pub where_: DirOpArgs,
+    pub how: CreateHow,
+}
+
+/// The result of CREATE, MKDIR, SYMLINK and MKNOD.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CreateRes {
+    pub object: Option<FileHandle>,
+    pub object_attributes: PostOpAttributes,
+    pub dir_wcc: WccData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MkDirArgs {
+    pub where_: DirOpArgs,
+    pub attributes: SetAttributes,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct SymlinkData {
+    pub attributes: SetAttributes,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct SymlinkArgs {
+    pub where_: DirOpArgs,
+    pub symlink: SymlinkData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DeviceData {
+    pub attributes: SetAttributes,
+    pub spec: SpecData,
+}
+
+#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Clone, Debug)]
+#[repr(u32)]
+pub enum MkNodData {
+    Regular = 1,
+    Directory = 2,
+    Block(DeviceData) = 3,
+    Character(DeviceData) = 4,
+    Symlink = 5,
+    Socket(SetAttributes) = 6,
+    Fifo(SetAttributes) = 7,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MkNodArgs {
+    pub where_: DirOpArgs,
+    pub what: MkNodData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct RemoveRes {
+    pub dir_wcc: WccData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct RenameArgs {
+    pub from: DirOpArgs,
+    pub to: DirOpArgs,
+}
+
+/// Both the success and failure body of RENAME.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct RenameRes {
+    pub from_dir_wcc: WccData,
+    pub to_dir_wcc: WccData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LinkArgs {
+    pub file: FileHandle,
+    pub link: DirOpArgs,
+}
+
+/// Both the success and failure body of LINK.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct LinkRes {
+    pub file_attributes: PostOpAttributes,
+    pub link_dir_wcc: WccData,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, PartialOrd, Ord)]
+pub struct Cookie(pub u64);
+
+impl Cookie {
+    pub const fn initial() -> Self {
+        Self(0)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadDirArgs {
+    pub dir: FileHandle,
+    pub cookie: Cookie,
+    pub cookie_verifier: Verifier,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DirectoryEntry {
+    pub file_id: u64,
+    pub name: String,
+    pub cookie: Cookie,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DirectoryList {
+    #[serde(with = "xdr_extras::list")]
+    pub entries: Vec<DirectoryEntry>,
+    pub eof: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadDirRes {
+    pub dir_attributes: PostOpAttributes,
+    pub cookie_verifier: Verifier,
+    pub reply: DirectoryList,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadDirPlusArgs {
+    pub dir: FileHandle,
+    pub cookie: Cookie,
+    pub cookie_verifier: Verifier,
+    /// Maximum number of bytes of directory information (just names and file ids)
+    pub dir_count: u32,
+    /// Maximum size of the whole reply
+    pub max_count: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DirectoryEntryPlus {
+    pub file_id: u64,
+    pub name: String,
+    pub cookie: Cookie,
+    pub attributes: PostOpAttributes,
+    pub handle: Option<FileHandle>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DirectoryListPlus {
+    #[serde(with = "xdr_extras::list")]
+    pub entries: Vec<DirectoryEntryPlus>,
+    pub eof: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ReadDirPlusRes {
+    pub dir_attributes: PostOpAttributes,
+    pub cookie_verifier: Verifier,
+    pub reply: DirectoryListPlus,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FsStatArgs {
+    pub fs_root: FileHandle,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FsStatRes {
+    pub object_attributes: PostOpAttributes,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub available_bytes: u64,
+    pub total_files: u64,
+    pub free_files: u64,
+    pub available_files: u64,
+    /// Number of seconds for which the file system is not expected to change
+    pub invariant_seconds: u32,
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct FsProperties: u32 {
+        const LINK          = 0x00000001;
+        const SYMLINK       = 0x00000002;
+        const HOMOGENEOUS   = 0x00000008;
+        const CAN_SET_TIME  = 0x00000010;
+    }
+}
+
+impl_serde_for_bitflags!(FsProperties);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FsInfoArgs {
+    pub fs_root: FileHandle,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FsInfoRes {
+    pub object_attributes: PostOpAttributes,
+    pub read_max: u32,
+    pub read_preferred: u32,
+    pub read_multiple: u32,
+    pub write_max: u32,
+    pub write_preferred: u32,
+    pub write_multiple: u32,
+    pub dir_preferred: u32,
+    pub max_file_size: u64,
+    pub time_delta: Time,
+    pub properties: FsProperties,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct PathConfArgs {
+    pub object: FileHandle,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct PathConfRes {
+    pub object_attributes: PostOpAttributes,
+    pub link_max: u32,
+    pub name_max: u32,
+    pub no_trunc: bool,
+    pub chown_restricted: bool,
+    pub case_insensitive: bool,
+    pub case_preserving: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CommitArgs {
+    pub file: FileHandle,
+    pub offset: u64,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CommitRes {
+    pub file_wcc: WccData,
+    pub verifier: Verifier,
+}
+
+pub type GetAttrResult = StatusResult<GetAttrRes>;
+pub type SetAttrResult = StatusResult<SetAttrRes, WccFail>;
+pub type LookUpResult = StatusResult<LookUpRes, LookUpFail>;
+pub type AccessResult = StatusResult<AccessRes, AttributesFail>;
+pub type ReadLinkResult = StatusResult<ReadLinkRes, AttributesFail>;
+pub type ReadResult = StatusResult<ReadRes, AttributesFail>;
+pub type WriteResult = StatusResult<WriteRes, WccFail>;
+pub type CreateResult = StatusResult<CreateRes, WccFail>;
+pub type RemoveResult = StatusResult<RemoveRes, WccFail>;
+pub type RenameResult = StatusResult<RenameRes, RenameRes>;
+pub type LinkResult = StatusResult<LinkRes, LinkRes>;
+pub type ReadDirResult = StatusResult<ReadDirRes, AttributesFail>;
+pub type ReadDirPlusResult = StatusResult<ReadDirPlusRes, AttributesFail>;
+pub type FsStatResult = StatusResult<FsStatRes, AttributesFail>;
+pub type FsInfoResult = StatusResult<FsInfoRes, AttributesFail>;
+pub type PathConfResult = StatusResult<PathConfRes, AttributesFail>;
+pub type CommitResult = StatusResult<CommitRes, WccFail>;
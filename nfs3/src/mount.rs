@@ -0,0 +1,67 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! The MOUNT v3 protocol (RFC 1813 Appendix I), used to get the root file handle of an export.
+
+use super::{FileHandle, StatusResult};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
+
+pub const MOUNT_PROGRAM: u32 = 100005;
+pub const MOUNT_VERSION: u32 = 3;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum Procedure {
+    Null = 0,
+    Mount = 1,
+    Dump = 2,
+    Unmount = 3,
+    UnmountAll = 4,
+    Export = 5,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MountArgs {
+    pub dir_path: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MountRes {
+    pub handle: FileHandle,
+    /// The `AuthFlavor`s the server accepts for this export. Kept as numbers since they include
+    /// flavors like Kerberos pseudo-flavors `AuthFlavor` doesn't know about.
+    pub auth_flavors: Vec<u32>,
+}
+
+/// The MOUNT status codes are a subset of the NFSv3 ones.
+pub type MountResult = StatusResult<MountRes>;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MountEntry {
+    pub host_name: String,
+    pub directory: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DumpRes {
+    #[serde(with = "xdr_extras::list")]
+    pub mounts: Vec<MountEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Group {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ExportEntry {
+    pub dir_path: String,
+    #[serde(with = "xdr_extras::list")]
+    pub groups: Vec<Group>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ExportRes {
+    #[serde(with = "xdr_extras::list")]
+    pub exports: Vec<ExportEntry>,
+}
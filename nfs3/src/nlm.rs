@@ -0,0 +1,158 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! The Network Lock Manager protocol version 4 (RFC 1813 chapter 6), which provides byte-range
+//! locks for NFSv3.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
+use xdr_extras::{DeserializeWithDiscriminant, SerializeWithDiscriminant};
+
+pub const NLM_PROGRAM: u32 = 100021;
+pub const NLM_VERSION: u32 = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum Procedure {
+    Null = 0,
+    Test = 1,
+    Lock = 2,
+    Cancel = 3,
+    Unlock = 4,
+    Granted = 5,
+    TestMsg = 6,
+    LockMsg = 7,
+    CancelMsg = 8,
+    UnlockMsg = 9,
+    GrantedMsg = 10,
+    TestRes = 11,
+    LockRes = 12,
+    CancelRes = 13,
+    UnlockRes = 14,
+    GrantedRes = 15,
+    /// Not part of the RFC. Linux has statd call this on the lock manager when a monitored host
+    /// reboots, with `nsm::Status` as the arguments.
+    SmNotify = 16,
+    Share = 20,
+    Unshare = 21,
+    NmLock = 22,
+    FreeAll = 23,
+}
+
+#[derive(
+    SerializeWithDiscriminant,
+    DeserializeWithDiscriminant,
+    PartialEq,
+    Eq,
+    Copy,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+)]
+#[repr(u32)]
+pub enum Status {
+    Granted = 0,
+    Denied = 1,
+    DeniedNoLocks = 2,
+    Blocked = 3,
+    DeniedGracePeriod = 4,
+    Deadlock = 5,
+    ReadOnlyFs = 6,
+    StaleFileHandle = 7,
+    FileTooBig = 8,
+    Failed = 9,
+}
+
+/// Opaque data, used for cookies, file handles and lock owners.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug, Default)]
+pub struct NetObj(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+/// Who holds a lock which conflicts with the one asked about in TEST.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Holder {
+    pub exclusive: bool,
+    pub svid: i32,
+    pub owner: NetObj,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A byte-range lock. A length of 0 means to the end of the file. Locks are owned by the
+/// combination of `caller_name`, `owner` and `svid` (normally the process id).
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Lock {
+    pub caller_name: String,
+    pub handle: NetObj,
+    pub owner: NetObj,
+    pub svid: i32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Res {
+    pub cookie: NetObj,
+    pub status: Status,
+}
+
+#[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Clone, Debug)]
+#[repr(u32)]
+pub enum TestReply {
+    Granted = 0,
+    Denied(Holder) = 1,
+    DeniedNoLocks = 2,
+    Blocked = 3,
+    DeniedGracePeriod = 4,
+    Deadlock = 5,
+    ReadOnlyFs = 6,
+    StaleFileHandle = 7,
+    FileTooBig = 8,
+    Failed = 9,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct TestRes {
+    pub cookie: NetObj,
+    pub reply: TestReply,
+}
+
+/// Arguments to TEST, and to GRANTED which the server calls back on the client.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct TestArgs {
+    pub cookie: NetObj,
+    pub exclusive: bool,
+    pub lock: Lock,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LockArgs {
+    pub cookie: NetObj,
+    /// Whether the server should queue the request and call GRANTED once the lock is available.
+    pub block: bool,
+    pub exclusive: bool,
+    pub lock: Lock,
+    /// Set when re-establishing locks after the server rebooted, during its grace period.
+    pub reclaim: bool,
+    /// The client's NSM state number.
+    pub state: i32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CancelArgs {
+    pub cookie: NetObj,
+    pub block: bool,
+    pub exclusive: bool,
+    pub lock: Lock,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UnlockArgs {
+    pub cookie: NetObj,
+    pub lock: Lock,
+}
+
+/// Arguments to FREE_ALL, releasing every lock held by a host which rebooted.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct NotifyArgs {
+    pub name: String,
+    pub state: i32,
+}
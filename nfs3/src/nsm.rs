@@ -0,0 +1,96 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! The Network Status Monitor protocol (statd), which lets lock managers find out when a peer
+//! reboots and its locks need to be reclaimed or released.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
+use serde_xdr::opaque_data::fixed_length;
+use xdr_extras::{DeserializeWithDiscriminant, SerializeWithDiscriminant};
+
+pub const NSM_PROGRAM: u32 = 100024;
+pub const NSM_VERSION: u32 = 1;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum Procedure {
+    Null = 0,
+    Stat = 1,
+    Monitor = 2,
+    Unmonitor = 3,
+    UnmonitorAll = 4,
+    SimulateCrash = 5,
+    Notify = 6,
+}
+
+#[derive(
+    SerializeWithDiscriminant,
+    DeserializeWithDiscriminant,
+    PartialEq,
+    Eq,
+    Copy,
+    Clone,
+    Debug,
+    TryFromPrimitive,
+)]
+#[repr(u32)]
+pub enum StatStatus {
+    Success = 0,
+    Failure = 1,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Name {
+    pub mon_name: String,
+}
+
+/// The state number is odd while the host is up and goes up by one on each reboot and shutdown.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StatRes {
+    pub status: StatStatus,
+    pub state: i32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct State {
+    pub state: i32,
+}
+
+/// The RPC procedure statd calls on the local host when a monitored host reboots.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MyId {
+    pub my_name: String,
+    pub my_program: u32,
+    pub my_version: u32,
+    pub my_procedure: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MonId {
+    pub mon_name: String,
+    pub my_id: MyId,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Mon {
+    pub mon_id: MonId,
+    /// Handed back as is in the `Status` callback.
+    #[serde(with = "fixed_length")]
+    pub private: [u8; 16],
+}
+
+/// Arguments to NOTIFY, sent to the statd of each peer after a reboot.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StatChange {
+    pub mon_name: String,
+    pub state: i32,
+}
+
+/// What statd sends to the procedure in `MyId` when a monitored host reboots.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Status {
+    pub mon_name: String,
+    pub state: i32,
+    #[serde(with = "fixed_length")]
+    pub private: [u8; 16],
+}
@@ -0,0 +1,180 @@
+// copyright 2023 Remi Bernotavicius
+
+use nfs3::mount::{ExportEntry, ExportRes, Group, MountRes, MountResult};
+use nfs3::*;
+use nfs3::{nlm, nsm};
+
+fn round_trip<T>(value: &T, expected: &[u8])
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    let actual = serde_xdr::to_bytes(value).unwrap();
+    assert_eq!(actual, expected);
+    let decoded: T = serde_xdr::from_bytes(expected).unwrap();
+    assert_eq!(&decoded, value);
+}
+
+#[test]
+fn status_result_ok() {
+    let res: CommitResult = StatusResult::Ok(CommitRes {
+        file_wcc: WccData::default(),
+        verifier: Verifier(0x0102030405060708),
+    });
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 0, // NFS3_OK
+            0, 0, 0, 0, // no before attributes
+            0, 0, 0, 0, // no after attributes
+            1, 2, 3, 4, 5, 6, 7, 8, // verifier
+        ],
+    );
+}
+
+#[test]
+fn status_result_err_has_fail_body() {
+    let res: LookUpResult = StatusResult::Err(StatusError::NoEnt, LookUpFail::default());
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 2, // NFS3ERR_NOENT
+            0, 0, 0, 0, // no directory attributes
+        ],
+    );
+
+    let res: MountResult = StatusResult::Err(StatusError::Access, ());
+    round_trip(&res, &[0, 0, 0, 13]);
+}
+
+#[test]
+fn mount_res() {
+    let res: MountResult = StatusResult::Ok(MountRes {
+        handle: FileHandle(vec![0xaa, 0xbb]),
+        auth_flavors: vec![1, 390003],
+    });
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 0, // MNT3_OK
+            0, 0, 0, 2, 0xaa, 0xbb, 0, 0, // handle
+            0, 0, 0, 2, 0, 0, 0, 1, 0, 5, 0xf3, 0x73, // auth flavors
+        ],
+    );
+}
+
+#[test]
+fn export_list() {
+    let res = ExportRes {
+        exports: vec![ExportEntry {
+            dir_path: "/a".into(),
+            groups: vec![Group { name: "*".into() }],
+        }],
+    };
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 1, // value follows
+            0, 0, 0, 2, b'/', b'a', 0, 0, // dir_path
+            0, 0, 0, 1, // value follows
+            0, 0, 0, 1, b'*', 0, 0, 0, // group
+            0, 0, 0, 0, // end of groups
+            0, 0, 0, 0, // end of exports
+        ],
+    );
+}
+
+#[test]
+fn read_dir_plus_list() {
+    let res: ReadDirPlusResult = StatusResult::Ok(ReadDirPlusRes {
+        dir_attributes: None,
+        cookie_verifier: Verifier(9),
+        reply: DirectoryListPlus {
+            entries: vec![DirectoryEntryPlus {
+                file_id: 5,
+                name: "f".into(),
+                cookie: Cookie(3),
+                attributes: None,
+                handle: Some(FileHandle(vec![1, 2, 3, 4])),
+            }],
+            eof: true,
+        },
+    });
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 0, // NFS3_OK
+            0, 0, 0, 0, // no directory attributes
+            0, 0, 0, 0, 0, 0, 0, 9, // cookie verifier
+            0, 0, 0, 1, // value follows
+            0, 0, 0, 0, 0, 0, 0, 5, // file id
+            0, 0, 0, 1, b'f', 0, 0, 0, // name
+            0, 0, 0, 0, 0, 0, 0, 3, // cookie
+            0, 0, 0, 0, // no attributes
+            0, 0, 0, 1, 0, 0, 0, 4, 1, 2, 3, 4, // handle
+            0, 0, 0, 0, // end of entries
+            0, 0, 0, 1, // eof
+        ],
+    );
+}
+
+#[test]
+fn create_how() {
+    round_trip(
+        &CreateHow::Exclusive(Verifier(1)),
+        &[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1],
+    );
+    round_trip(
+        &CreateHow::Guarded(SetAttributes {
+            mode: Some(0o644),
+            access_time: SetTime::ServerTime,
+            ..Default::default()
+        }),
+        &[
+            0, 0, 0, 1, // GUARDED
+            0, 0, 0, 1, 0, 0, 0x01, 0xa4, // mode
+            0, 0, 0, 0, // uid
+            0, 0, 0, 0, // gid
+            0, 0, 0, 0, // size
+            0, 0, 0, 1, // atime
+            0, 0, 0, 0, // mtime
+        ],
+    );
+}
+
+#[test]
+fn nlm_test_res_denied() {
+    let res = nlm::TestRes {
+        cookie: nlm::NetObj(vec![1]),
+        reply: nlm::TestReply::Denied(nlm::Holder {
+            exclusive: true,
+            svid: 42,
+            owner: nlm::NetObj(b"ab".to_vec()),
+            offset: 0,
+            length: 10,
+        }),
+    };
+    round_trip(
+        &res,
+        &[
+            0, 0, 0, 1, 1, 0, 0, 0, // cookie
+            0, 0, 0, 1, // DENIED
+            0, 0, 0, 1, // exclusive
+            0, 0, 0, 42, // svid
+            0, 0, 0, 2, b'a', b'b', 0, 0, // owner
+            0, 0, 0, 0, 0, 0, 0, 0, // offset
+            0, 0, 0, 0, 0, 0, 0, 10, // length
+        ],
+    );
+}
+
+#[test]
+fn nsm_status_has_fixed_private_data() {
+    let status = nsm::Status {
+        mon_name: "h".into(),
+        state: 3,
+        private: [9; 16],
+    };
+    let mut expected = vec![0, 0, 0, 1, b'h', 0, 0, 0, 0, 0, 0, 3];
+    expected.extend([9; 16]);
+    round_trip(&status, &expected);
+}
@@ -0,0 +1,423 @@
+// Copyright 2023 Remi Bernotavicius
+
+use derive_more::From;
+use nfs3::mount::{self, ExportEntry, MountArgs, MountRes, MOUNT_PROGRAM, MOUNT_VERSION};
+use nfs3::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Component, Path};
+use sun_rpc_client::{PortMapperClient, RpcClient, Transport};
+
+pub use lock::{CallbackEvent, CallbackListener, LockClient, LockOutcome, StatusMonitorClient};
+
+mod lock;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, From)]
+pub enum Error {
+    SunRpc(sun_rpc_client::Error),
+    Protocol(StatusError),
+    Lock(nlm::Status),
+    Io(io::Error),
+    /// The portmapper doesn't know about the given program
+    #[from(ignore)]
+    NotRegistered(u32),
+    /// The portmapper wouldn't register the given program, usually because something else already
+    /// has
+    #[from(ignore)]
+    RegisterFailed(u32),
+    /// statd couldn't monitor the given host
+    #[from(ignore)]
+    MonitorFailed(String),
+}
+
+fn into_result<T, F>(res: StatusResult<T, F>) -> Result<T> {
+    match res {
+        StatusResult::Ok(v) => Ok(v),
+        StatusResult::Err(e, _) => Err(e.into()),
+    }
+}
+
+/// Talks the MOUNT protocol, used to get the root file handle of an export.
+pub struct MountClient<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+}
+
+impl<TransportT: Transport> MountClient<TransportT> {
+    pub fn new(transport: TransportT) -> Self {
+        Self {
+            rpc_client: RpcClient::new(transport, MOUNT_PROGRAM).with_version(MOUNT_VERSION),
+        }
+    }
+
+    fn call<Args: Serialize, Res: DeserializeOwned + std::fmt::Debug>(
+        &mut self,
+        procedure: mount::Procedure,
+        args: Args,
+    ) -> Result<Res> {
+        self.rpc_client.send_request(procedure.into(), args)?;
+        Ok(self.rpc_client.receive_reply()?)
+    }
+
+    pub fn mount(&mut self, dir_path: &str) -> Result<MountRes> {
+        into_result::<_, ()>(self.call(
+            mount::Procedure::Mount,
+            MountArgs {
+                dir_path: dir_path.into(),
+            },
+        )?)
+    }
+
+    pub fn unmount(&mut self, dir_path: &str) -> Result<()> {
+        self.call(
+            mount::Procedure::Unmount,
+            MountArgs {
+                dir_path: dir_path.into(),
+            },
+        )
+    }
+
+    pub fn exports(&mut self) -> Result<Vec<ExportEntry>> {
+        Ok(self
+            .call::<_, mount::ExportRes>(mount::Procedure::Export, ())?
+            .exports)
+    }
+}
+
+pub struct Client<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+    root: FileHandle,
+    max_read: u32,
+    max_write: u32,
+}
+
+impl Client<TcpStream> {
+    /// Asks the portmapper at the given address (normally port 111 of the server) where MOUNT and
+    /// NFS are, mounts the export and connects to it.
+    pub fn connect(port_mapper: SocketAddr, export: &str) -> Result<Self> {
+        let mut port_mapper_client = PortMapperClient::new(TcpStream::connect(port_mapper)?);
+        let mut get_port = |program, version| {
+            port_mapper_client
+                .get_port(program, version)?
+                .map(|port| SocketAddr::new(port_mapper.ip(), port))
+                .ok_or(Error::NotRegistered(program))
+        };
+        let mount_addr = get_port(MOUNT_PROGRAM, MOUNT_VERSION)?;
+        let nfs_addr = get_port(NFS_PROGRAM, NFS_VERSION)?;
+
+        let root = MountClient::new(TcpStream::connect(mount_addr)?)
+            .mount(export)?
+            .handle;
+        Self::new(TcpStream::connect(nfs_addr)?, root)
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Connects to an NFSv3 server given the root file handle from MOUNT.
+    pub fn new(transport: TransportT, root: FileHandle) -> Result<Self> {
+        Self::with_rpc_client(
+            RpcClient::new(transport, NFS_PROGRAM).with_version(NFS_VERSION),
+            root,
+        )
+    }
+
+    /// Like `new`, but with an `RpcClient` that may have been set up with other credentials.
+    pub fn with_rpc_client(rpc_client: RpcClient<TransportT>, root: FileHandle) -> Result<Self> {
+        let mut client = Self {
+            rpc_client,
+            root,
+            max_read: 0,
+            max_write: 0,
+        };
+        let fs_info = client.fs_info(client.root.clone())?;
+        client.max_read = fs_info.read_max;
+        client.max_write = fs_info.write_max;
+        Ok(client)
+    }
+
+    fn call<Args: Serialize, Res: DeserializeOwned + std::fmt::Debug>(
+        &mut self,
+        procedure: Procedure,
+        args: Args,
+    ) -> Result<Res> {
+        self.rpc_client.send_request(procedure.into(), args)?;
+        Ok(self.rpc_client.receive_reply()?)
+    }
+
+    pub fn root(&self) -> &FileHandle {
+        &self.root
+    }
+
+    pub fn fs_info(&mut self, handle: FileHandle) -> Result<FsInfoRes> {
+        into_result::<_, AttributesFail>(
+            self.call(Procedure::FsInfo, FsInfoArgs { fs_root: handle })?,
+        )
+    }
+
+    pub fn get_attr(&mut self, handle: FileHandle) -> Result<FileAttributes> {
+        Ok(into_result::<GetAttrRes, ()>(
+            self.call(Procedure::GetAttr, GetAttrArgs { object: handle })?,
+        )?
+        .object_attributes)
+    }
+
+    pub fn set_attr(&mut self, handle: FileHandle, attrs: SetAttributes) -> Result<()> {
+        into_result::<SetAttrRes, WccFail>(self.call(
+            Procedure::SetAttr,
+            SetAttrArgs {
+                object: handle,
+                new_attributes: attrs,
+                guard: None,
+            },
+        )?)?;
+        Ok(())
+    }
+
+    /// Looks up a single name in the given directory.
+    pub fn look_up_in(&mut self, dir: FileHandle, name: &str) -> Result<LookUpRes> {
+        into_result::<_, LookUpFail>(self.call(
+            Procedure::LookUp,
+            DirOpArgs {
+                dir,
+                name: name.into(),
+            },
+        )?)
+    }
+
+    /// Looks up a path relative to the root of the export, one component at a time. `..` is
+    /// looked up on the server, except at the root of the export, which it doesn't go above.
+    pub fn look_up(&mut self, path: impl AsRef<Path>) -> Result<FileHandle> {
+        let mut handle = self.root.clone();
+        let mut depth = 0_usize;
+        for component in path.as_ref().components() {
+            let name = match component {
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => continue,
+                Component::ParentDir if depth == 0 => continue,
+                Component::ParentDir => {
+                    depth -= 1;
+                    ".."
+                }
+                Component::Normal(name) => {
+                    depth += 1;
+                    name.to_str().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidFilename, "not UTF-8")
+                    })?
+                }
+            };
+            handle = self.look_up_in(handle, name)?.object;
+        }
+        Ok(handle)
+    }
+
+    pub fn read(&mut self, handle: FileHandle, offset: u64, count: u32) -> Result<ReadRes> {
+        into_result::<_, AttributesFail>(self.call(
+            Procedure::Read,
+            ReadArgs {
+                file: handle,
+                offset,
+                count,
+            },
+        )?)
+    }
+
+    pub fn read_all(&mut self, handle: FileHandle, mut sink: impl io::Write) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let read_res = self.read(handle.clone(), offset, self.max_read)?;
+            offset += read_res.data.len() as u64;
+            sink.write_all(&read_res.data)?;
+            if read_res.eof {
+                break;
+            }
+            if read_res.data.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, handle: FileHandle, offset: u64, data: Vec<u8>) -> Result<WriteRes> {
+        self.write_with_stability(handle, offset, StableHow::FileSync, data)
+    }
+
+    /// Like `write`, but data written with `StableHow::Unstable` needs a `commit` to be durable.
+    pub fn write_with_stability(
+        &mut self,
+        handle: FileHandle,
+        offset: u64,
+        stable: StableHow,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        into_result::<_, WccFail>(self.call(
+            Procedure::Write,
+            WriteArgs {
+                file: handle,
+                offset,
+                count: data.len() as u32,
+                stable,
+                data,
+            },
+        )?)
+    }
+
+    pub fn write_all(&mut self, handle: FileHandle, mut source: impl io::Read) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let mut buf = vec![0; self.max_write as usize];
+            let amount_read = source.read(&mut buf[..])?;
+            if amount_read == 0 {
+                break;
+            }
+
+            buf.truncate(amount_read);
+
+            while !buf.is_empty() {
+                let write_res = self.write(handle.clone(), offset, buf.clone())?;
+                let written = (write_res.count as usize).min(buf.len());
+                if written == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                buf.drain(..written);
+                offset += written as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit(&mut self, handle: FileHandle) -> Result<CommitRes> {
+        into_result::<_, WccFail>(self.call(
+            Procedure::Commit,
+            CommitArgs {
+                file: handle,
+                offset: 0,
+                count: 0,
+            },
+        )?)
+    }
+
+    /// Lists a directory with READDIRPLUS, so each entry comes with its attributes and handle.
+    pub fn read_dir(&mut self, handle: FileHandle) -> Result<Vec<DirectoryEntryPlus>> {
+        let mut entries = vec![];
+        let mut cookie = Cookie::initial();
+        let mut cookie_verifier = Verifier(0);
+        loop {
+            let res = into_result::<ReadDirPlusRes, AttributesFail>(self.call(
+                Procedure::ReadDirPlus,
+                ReadDirPlusArgs {
+                    dir: handle.clone(),
+                    cookie,
+                    cookie_verifier: cookie_verifier.clone(),
+                    dir_count: 8192,
+                    max_count: self.max_read.min(32768),
+                },
+            )?)?;
+
+            let Some(last) = res.reply.entries.last() else {
+                if res.reply.eof {
+                    break Ok(entries);
+                }
+                // Not even one entry fit in the reply.
+                break Err(Error::Protocol(StatusError::TooSmall));
+            };
+            cookie = last.cookie;
+            entries.extend(res.reply.entries);
+            if res.reply.eof {
+                break Ok(entries);
+            }
+            cookie_verifier = res.cookie_verifier;
+        }
+    }
+
+    fn created_handle(
+        &mut self,
+        dir: FileHandle,
+        name: &str,
+        res: CreateRes,
+    ) -> Result<FileHandle> {
+        match res.object {
+            Some(handle) => Ok(handle),
+            // The server is allowed to leave out the handle, it can be looked up instead.
+            None => self.look_up_in(dir, name).map(|r| r.object),
+        }
+    }
+
+    pub fn create_file(&mut self, parent: FileHandle, name: &str) -> Result<FileHandle> {
+        let res = into_result::<_, WccFail>(self.call(
+            Procedure::Create,
+            CreateArgs {
+                where_: DirOpArgs {
+                    dir: parent.clone(),
+                    name: name.into(),
+                },
+                how: CreateHow::Unchecked(Default::default()),
+            },
+        )?)?;
+        self.created_handle(parent, name, res)
+    }
+
+    pub fn create_directory(
+        &mut self,
+        parent_dir: FileHandle,
+        name: &str,
+        attrs: SetAttributes,
+    ) -> Result<FileHandle> {
+        let res = into_result::<_, WccFail>(self.call(
+            Procedure::MkDir,
+            MkDirArgs {
+                where_: DirOpArgs {
+                    dir: parent_dir.clone(),
+                    name: name.into(),
+                },
+                attributes: attrs,
+            },
+        )?)?;
+        self.created_handle(parent_dir, name, res)
+    }
+
+    /// Removes a non-directory entry.
+    pub fn remove(&mut self, handle: FileHandle, entry_name: &str) -> Result<()> {
+        into_result::<RemoveRes, WccFail>(self.call(
+            Procedure::Remove,
+            DirOpArgs {
+                dir: handle,
+                name: entry_name.into(),
+            },
+        )?)?;
+        Ok(())
+    }
+
+    pub fn remove_directory(&mut self, handle: FileHandle, entry_name: &str) -> Result<()> {
+        into_result::<RemoveRes, WccFail>(self.call(
+            Procedure::RmDir,
+            DirOpArgs {
+                dir: handle,
+                name: entry_name.into(),
+            },
+        )?)?;
+        Ok(())
+    }
+
+    pub fn rename(
+        &mut self,
+        src_dir: FileHandle,
+        target_dir: FileHandle,
+        src_entry: &str,
+        target_entry: &str,
+    ) -> Result<RenameRes> {
+        into_result::<_, RenameRes>(self.call(
+            Procedure::Rename,
+            RenameArgs {
+                from: DirOpArgs {
+                    dir: src_dir,
+                    name: src_entry.into(),
+                },
+                to: DirOpArgs {
+                    dir: target_dir,
+                    name: target_entry.into(),
+                },
+            },
+        )?)
+    }
+}
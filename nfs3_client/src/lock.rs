@@ -0,0 +1,550 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Byte-range locking through the Network Lock Manager, and finding out about server reboots
+//! through the Network Status Monitor.
+
+use super::{Error, Result};
+use nfs3::nlm::{self, NetObj, NLM_PROGRAM, NLM_VERSION};
+use nfs3::nsm::{self, NSM_PROGRAM, NSM_VERSION};
+use nfs3::FileHandle;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use sun_rpc::{AcceptedReplyBody, Message, MessageBody};
+use sun_rpc_client::{
+    encode_reply, read_record, PortMapperClient, RpcClient, Transport, PORT_MAPPER_PORT,
+};
+
+fn look_up_port(port_mapper: SocketAddr, program: u32, version: u32) -> Result<SocketAddr> {
+    PortMapperClient::new(TcpStream::connect(port_mapper)?)
+        .get_port(program, version)?
+        .map(|port| SocketAddr::new(port_mapper.ip(), port))
+        .ok_or(Error::NotRegistered(program))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LockOutcome {
+    Granted,
+    /// The server queued the request and will call GRANTED or GRANTED_MSG once the lock is
+    /// available.
+    Blocked,
+}
+
+#[derive(Clone, Debug)]
+struct HeldLock {
+    lock: nlm::Lock,
+    exclusive: bool,
+}
+
+/// A client of the server's lock manager. Locks are owned by the `caller_name` and the process
+/// id.
+pub struct LockClient<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+    caller_name: String,
+    svid: i32,
+    state: i32,
+    next_cookie: u64,
+    held: Vec<HeldLock>,
+    pending: Vec<HeldLock>,
+}
+
+impl LockClient<TcpStream> {
+    /// Finds the lock manager with the portmapper at the given address and connects to it.
+    pub fn connect(port_mapper: SocketAddr, caller_name: &str) -> Result<Self> {
+        let addr = look_up_port(port_mapper, NLM_PROGRAM, NLM_VERSION)?;
+        Ok(Self::new(TcpStream::connect(addr)?, caller_name))
+    }
+}
+
+impl<TransportT: Transport> LockClient<TransportT> {
+    pub fn new(transport: TransportT, caller_name: &str) -> Self {
+        Self {
+            rpc_client: RpcClient::new(transport, NLM_PROGRAM).with_version(NLM_VERSION),
+            caller_name: caller_name.into(),
+            svid: std::process::id() as i32,
+            state: 0,
+            next_cookie: 1,
+            held: vec![],
+            pending: vec![],
+        }
+    }
+
+    /// Sets the local NSM state number, sent along with lock requests.
+    pub fn set_state(&mut self, state: i32) {
+        self.state = state;
+    }
+
+    fn call<Args: Serialize, Res: DeserializeOwned + std::fmt::Debug>(
+        &mut self,
+        procedure: nlm::Procedure,
+        args: Args,
+    ) -> Result<Res> {
+        self.rpc_client.send_request(procedure.into(), args)?;
+        Ok(self.rpc_client.receive_reply()?)
+    }
+
+    fn cookie(&mut self) -> NetObj {
+        let cookie = NetObj(self.next_cookie.to_be_bytes().to_vec());
+        self.next_cookie += 1;
+        cookie
+    }
+
+    fn lock_for(&self, handle: &FileHandle, offset: u64, length: u64) -> nlm::Lock {
+        nlm::Lock {
+            caller_name: self.caller_name.clone(),
+            handle: NetObj(handle.0.clone()),
+            owner: NetObj(format!("{}@{}", self.svid, self.caller_name).into_bytes()),
+            svid: self.svid,
+            offset,
+            length,
+        }
+    }
+
+    /// Returns who holds a lock conflicting with the given one, if anyone does. A length of 0
+    /// means to the end of the file.
+    pub fn test(
+        &mut self,
+        handle: &FileHandle,
+        offset: u64,
+        length: u64,
+        exclusive: bool,
+    ) -> Result<Option<nlm::Holder>> {
+        let args = nlm::TestArgs {
+            cookie: self.cookie(),
+            exclusive,
+            lock: self.lock_for(handle, offset, length),
+        };
+        let res: nlm::TestRes = self.call(nlm::Procedure::Test, args)?;
+        match res.reply {
+            nlm::TestReply::Granted => Ok(None),
+            nlm::TestReply::Denied(holder) => Ok(Some(holder)),
+            nlm::TestReply::DeniedNoLocks => Err(nlm::Status::DeniedNoLocks.into()),
+            nlm::TestReply::Blocked => Err(nlm::Status::Blocked.into()),
+            nlm::TestReply::DeniedGracePeriod => Err(nlm::Status::DeniedGracePeriod.into()),
+            nlm::TestReply::Deadlock => Err(nlm::Status::Deadlock.into()),
+            nlm::TestReply::ReadOnlyFs => Err(nlm::Status::ReadOnlyFs.into()),
+            nlm::TestReply::StaleFileHandle => Err(nlm::Status::StaleFileHandle.into()),
+            nlm::TestReply::FileTooBig => Err(nlm::Status::FileTooBig.into()),
+            nlm::TestReply::Failed => Err(nlm::Status::Failed.into()),
+        }
+    }
+
+    fn send_lock(&mut self, held: &HeldLock, block: bool, reclaim: bool) -> Result<nlm::Status> {
+        let args = nlm::LockArgs {
+            cookie: self.cookie(),
+            block,
+            exclusive: held.exclusive,
+            lock: held.lock.clone(),
+            reclaim,
+            state: self.state,
+        };
+        let res: nlm::Res = self.call(nlm::Procedure::Lock, args)?;
+        Ok(res.status)
+    }
+
+    /// Takes a lock. With `block`, a conflicting lock makes the server queue the request instead
+    /// of denying it, and `wait_granted` finds out when it is granted.
+    pub fn lock(
+        &mut self,
+        handle: &FileHandle,
+        offset: u64,
+        length: u64,
+        exclusive: bool,
+        block: bool,
+    ) -> Result<LockOutcome> {
+        let held = HeldLock {
+            lock: self.lock_for(handle, offset, length),
+            exclusive,
+        };
+        match self.send_lock(&held, block, false)? {
+            nlm::Status::Granted => {
+                self.held.push(held);
+                Ok(LockOutcome::Granted)
+            }
+            nlm::Status::Blocked => {
+                self.pending.push(held);
+                Ok(LockOutcome::Blocked)
+            }
+            status => Err(status.into()),
+        }
+    }
+
+    /// Waits for the server to grant one of the blocked lock requests, returning false if it
+    /// didn't within the timeout.
+    pub fn wait_granted(&mut self, listener: &CallbackListener, timeout: Duration) -> bool {
+        let pending = &self.pending;
+        let granted = listener.wait_for(timeout, |event| match event {
+            CallbackEvent::Granted(lock) => pending.iter().any(|p| p.lock == *lock),
+            _ => false,
+        });
+        let Some(CallbackEvent::Granted(lock)) = granted else {
+            return false;
+        };
+        let index = self.pending.iter().position(|p| p.lock == lock).unwrap();
+        self.held.push(self.pending.remove(index));
+        true
+    }
+
+    /// Gives up on a blocked lock request.
+    pub fn cancel(
+        &mut self,
+        handle: &FileHandle,
+        offset: u64,
+        length: u64,
+        exclusive: bool,
+    ) -> Result<()> {
+        let lock = self.lock_for(handle, offset, length);
+        self.pending.retain(|p| p.lock != lock);
+        let args = nlm::CancelArgs {
+            cookie: self.cookie(),
+            block: true,
+            exclusive,
+            lock,
+        };
+        let res: nlm::Res = self.call(nlm::Procedure::Cancel, args)?;
+        match res.status {
+            nlm::Status::Granted => Ok(()),
+            status => Err(status.into()),
+        }
+    }
+
+    pub fn unlock(&mut self, handle: &FileHandle, offset: u64, length: u64) -> Result<()> {
+        let lock = self.lock_for(handle, offset, length);
+        self.held.retain(|h| h.lock != lock);
+        let args = nlm::UnlockArgs {
+            cookie: self.cookie(),
+            lock,
+        };
+        let res: nlm::Res = self.call(nlm::Procedure::Unlock, args)?;
+        match res.status {
+            nlm::Status::Granted => Ok(()),
+            status => Err(status.into()),
+        }
+    }
+
+    /// Takes all the held locks again after the server rebooted. This has to happen during the
+    /// server's grace period, before other clients can take conflicting locks.
+    pub fn reclaim(&mut self) -> Result<()> {
+        for held in self.held.clone() {
+            match self.send_lock(&held, false, true)? {
+                nlm::Status::Granted => {}
+                status => return Err(status.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Something the server told the `CallbackListener`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallbackEvent {
+    /// A blocked lock request was granted.
+    Granted(nlm::Lock),
+    /// statd reported that a monitored host rebooted, so locks held there need reclaiming.
+    Rebooted(nsm::Status),
+}
+
+type Events = Arc<(Mutex<VecDeque<CallbackEvent>>, Condvar)>;
+
+/// Where to find the lock manager of the server to send GRANTED_RES to, if not on the portmapper
+/// of the host which called.
+type ServerPortMapper = Arc<Mutex<Option<SocketAddr>>>;
+
+/// Serves the lock manager procedures servers call back on clients: GRANTED and GRANTED_MSG, and
+/// the reboot notification from statd.
+///
+/// Servers find it by asking this host's portmapper, so it is registered there as the lock
+/// manager for as long as it lives.
+pub struct CallbackListener {
+    addr: SocketAddr,
+    port_mapper: SocketAddr,
+    server_port_mapper: ServerPortMapper,
+    events: Events,
+}
+
+fn call_args<T: DeserializeOwned>(record: &[u8]) -> Option<T> {
+    match serde_xdr::from_bytes::<_, Message<T>>(record) {
+        Ok(Message {
+            body: MessageBody::Call(call),
+            ..
+        }) => Some(call.call_args),
+        _ => None,
+    }
+}
+
+fn push_event(events: &Events, event: CallbackEvent) {
+    let (lock, condvar) = &**events;
+    lock.lock().unwrap().push_back(event);
+    condvar.notify_all();
+}
+
+/// Handles a call, returning the reply, and setting `granted_res` to the results to send back to
+/// the caller's lock manager for a GRANTED_MSG.
+fn handle_callback(
+    events: &Events,
+    record: &[u8],
+    granted_res: &mut Option<nlm::Res>,
+) -> Option<Vec<u8>> {
+    let Ok(Message {
+        xid,
+        body: MessageBody::Call(call),
+    }) = serde_xdr::from_bytes::<_, Message<()>>(record)
+    else {
+        return None;
+    };
+    if (call.program, call.version) != (NLM_PROGRAM, NLM_VERSION) {
+        return encode_reply(xid, AcceptedReplyBody::<()>::ProgramUnavailable).ok();
+    }
+    let reply = match nlm::Procedure::try_from(call.procedure) {
+        Ok(nlm::Procedure::Null) => encode_reply(xid, AcceptedReplyBody::Success(())),
+        Ok(nlm::Procedure::Granted) => match call_args::<nlm::TestArgs>(record) {
+            Some(nlm::TestArgs { cookie, lock, .. }) => {
+                push_event(events, CallbackEvent::Granted(lock));
+                let res = nlm::Res {
+                    cookie,
+                    status: nlm::Status::Granted,
+                };
+                encode_reply(xid, AcceptedReplyBody::Success(res))
+            }
+            None => encode_reply(xid, AcceptedReplyBody::<()>::GarbageArguments),
+        },
+        // The asynchronous version Linux uses. The results go in a GRANTED_RES call of our own.
+        Ok(nlm::Procedure::GrantedMsg) => match call_args::<nlm::TestArgs>(record) {
+            Some(nlm::TestArgs { cookie, lock, .. }) => {
+                push_event(events, CallbackEvent::Granted(lock));
+                *granted_res = Some(nlm::Res {
+                    cookie,
+                    status: nlm::Status::Granted,
+                });
+                encode_reply(xid, AcceptedReplyBody::Success(()))
+            }
+            None => encode_reply(xid, AcceptedReplyBody::<()>::GarbageArguments),
+        },
+        Ok(nlm::Procedure::SmNotify) => match call_args::<nsm::Status>(record) {
+            Some(status) => {
+                push_event(events, CallbackEvent::Rebooted(status));
+                encode_reply(xid, AcceptedReplyBody::Success(()))
+            }
+            None => encode_reply(xid, AcceptedReplyBody::<()>::GarbageArguments),
+        },
+        _ => encode_reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+    };
+    reply.ok()
+}
+
+fn send_granted_res(port_mapper: SocketAddr, res: nlm::Res) -> Result<()> {
+    let addr = look_up_port(port_mapper, NLM_PROGRAM, NLM_VERSION)?;
+    let mut client =
+        RpcClient::new(TcpStream::connect(addr)?, NLM_PROGRAM).with_version(NLM_VERSION);
+    client.send_request(nlm::Procedure::GrantedRes.into(), res)?;
+    client.receive_reply::<()>()?;
+    Ok(())
+}
+
+fn serve_callbacks(
+    events: Events,
+    server_port_mapper: ServerPortMapper,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    while let Some(record) = read_record(&mut stream)? {
+        let mut granted_res = None;
+        if let Some(reply) = handle_callback(&events, &record, &mut granted_res) {
+            io::Write::write_all(&mut stream, &reply)?;
+        }
+        if let Some(res) = granted_res {
+            let port_mapper = server_port_mapper
+                .lock()
+                .unwrap()
+                .unwrap_or(SocketAddr::new(peer.ip(), PORT_MAPPER_PORT));
+            // The lock is ours either way, the server just doesn't hear that it arrived.
+            let _ = send_granted_res(port_mapper, res);
+        }
+    }
+    Ok(())
+}
+
+impl CallbackListener {
+    /// Starts listening on the given address, serving connections on background threads, and
+    /// registers it with the portmapper at `port_mapper`, which should be this host's.
+    pub fn bind(addr: impl ToSocketAddrs, port_mapper: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let events: Events = Default::default();
+
+        let registered = PortMapperClient::new(TcpStream::connect(port_mapper)?).set(
+            NLM_PROGRAM,
+            NLM_VERSION,
+            addr.port(),
+        )?;
+        if !registered {
+            return Err(Error::RegisterFailed(NLM_PROGRAM));
+        }
+
+        let server_port_mapper: ServerPortMapper = Default::default();
+        let accept_events = events.clone();
+        let accept_server_port_mapper = server_port_mapper.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let events = accept_events.clone();
+                let server_port_mapper = accept_server_port_mapper.clone();
+                std::thread::spawn(move || serve_callbacks(events, server_port_mapper, stream));
+            }
+        });
+        Ok(Self {
+            addr,
+            port_mapper,
+            server_port_mapper,
+            events,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Sends the GRANTED_RES for a GRANTED_MSG to the lock manager registered with the given
+    /// portmapper, rather than the one on the standard port of the host which called.
+    pub fn set_server_port_mapper(&self, port_mapper: SocketAddr) {
+        *self.server_port_mapper.lock().unwrap() = Some(port_mapper);
+    }
+
+    /// What to pass to `StatusMonitorClient::monitor` to have reboot notifications come here.
+    pub fn my_id(&self, my_name: &str) -> nsm::MyId {
+        nsm::MyId {
+            my_name: my_name.into(),
+            my_program: NLM_PROGRAM,
+            my_version: NLM_VERSION,
+            my_procedure: nlm::Procedure::SmNotify.into(),
+        }
+    }
+
+    /// Removes and returns the first event matching the predicate, waiting up to the timeout for
+    /// one to arrive.
+    fn wait_for(
+        &self,
+        timeout: Duration,
+        mut predicate: impl FnMut(&CallbackEvent) -> bool,
+    ) -> Option<CallbackEvent> {
+        let deadline = Instant::now() + timeout;
+        let (lock, condvar) = &*self.events;
+        let mut events = lock.lock().unwrap();
+        loop {
+            if let Some(index) = events.iter().position(&mut predicate) {
+                return events.remove(index);
+            }
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            events = condvar.wait_timeout(events, remaining).unwrap().0;
+        }
+    }
+
+    /// The next event, waiting up to the timeout for one to arrive.
+    pub fn next_event(&self, timeout: Duration) -> Option<CallbackEvent> {
+        self.wait_for(timeout, |_| true)
+    }
+}
+
+impl Drop for CallbackListener {
+    /// Unregisters from the portmapper. Connections keep being served, but servers won't find
+    /// this listener anymore.
+    fn drop(&mut self) {
+        if let Ok(stream) = TcpStream::connect(self.port_mapper) {
+            let _ = PortMapperClient::new(stream).unset(NLM_PROGRAM, NLM_VERSION);
+        }
+    }
+}
+
+/// A client of statd, the Network Status Monitor.
+pub struct StatusMonitorClient<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+}
+
+impl StatusMonitorClient<TcpStream> {
+    /// Finds statd with the portmapper at the given address and connects to it.
+    pub fn connect(port_mapper: SocketAddr) -> Result<Self> {
+        let addr = look_up_port(port_mapper, NSM_PROGRAM, NSM_VERSION)?;
+        Ok(Self::new(TcpStream::connect(addr)?))
+    }
+}
+
+impl<TransportT: Transport> StatusMonitorClient<TransportT> {
+    pub fn new(transport: TransportT) -> Self {
+        Self {
+            rpc_client: RpcClient::new(transport, NSM_PROGRAM).with_version(NSM_VERSION),
+        }
+    }
+
+    fn call<Args: Serialize, Res: DeserializeOwned + std::fmt::Debug>(
+        &mut self,
+        procedure: nsm::Procedure,
+        args: Args,
+    ) -> Result<Res> {
+        self.rpc_client.send_request(procedure.into(), args)?;
+        Ok(self.rpc_client.receive_reply()?)
+    }
+
+    fn check(res: nsm::StatRes, mon_name: &str) -> Result<i32> {
+        match res.status {
+            nsm::StatStatus::Success => Ok(res.state),
+            nsm::StatStatus::Failure => Err(Error::MonitorFailed(mon_name.into())),
+        }
+    }
+
+    /// The current state number of statd's host.
+    pub fn stat(&mut self, mon_name: &str) -> Result<i32> {
+        let res = self.call(
+            nsm::Procedure::Stat,
+            nsm::Name {
+                mon_name: mon_name.into(),
+            },
+        )?;
+        Self::check(res, mon_name)
+    }
+
+    /// Asks statd to call the procedure in `my_id` when `mon_name` reboots. Returns the local
+    /// state number.
+    pub fn monitor(&mut self, mon_name: &str, my_id: nsm::MyId, private: [u8; 16]) -> Result<i32> {
+        let res = self.call(
+            nsm::Procedure::Monitor,
+            nsm::Mon {
+                mon_id: nsm::MonId {
+                    mon_name: mon_name.into(),
+                    my_id,
+                },
+                private,
+            },
+        )?;
+        Self::check(res, mon_name)
+    }
+
+    pub fn unmonitor(&mut self, mon_name: &str, my_id: nsm::MyId) -> Result<i32> {
+        let res: nsm::State = self.call(
+            nsm::Procedure::Unmonitor,
+            nsm::MonId {
+                mon_name: mon_name.into(),
+                my_id,
+            },
+        )?;
+        Ok(res.state)
+    }
+
+    pub fn unmonitor_all(&mut self, my_id: nsm::MyId) -> Result<i32> {
+        let res: nsm::State = self.call(nsm::Procedure::UnmonitorAll, my_id)?;
+        Ok(res.state)
+    }
+
+    /// Tells a peer's statd that this host rebooted and now has the given state number.
+    pub fn notify(&mut self, mon_name: &str, state: i32) -> Result<()> {
+        self.call(
+            nsm::Procedure::Notify,
+            nsm::StatChange {
+                mon_name: mon_name.into(),
+                state,
+            },
+        )
+    }
+}
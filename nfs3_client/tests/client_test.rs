@@ -0,0 +1,120 @@
+// Copyright Remi Bernotavicius
+
+use nfs3::{FileType, StableHow, StatusError};
+use nfs3_client::{Client, Error, MountClient};
+use nfs4_test_server::TestServer;
+
+#[test]
+fn mount_and_look_up() {
+    let server = TestServer::new();
+    server.create_dir("/a_dir");
+    server.write_file("/a_dir/a_file", b"hello");
+
+    let mut client = Client::connect(server.addr(), "/").unwrap();
+    let handle = client.look_up("/a_dir/a_file").unwrap();
+    let attrs = client.get_attr(handle).unwrap();
+    assert_eq!(attrs.type_, FileType::Regular);
+    assert_eq!(attrs.size, 5);
+
+    assert!(matches!(
+        client.look_up("/a_dir/missing"),
+        Err(Error::Protocol(StatusError::NoEnt))
+    ));
+
+    // `..` goes back up rather than being skipped, but not above the root.
+    server.create_dir("/b_dir");
+    server.write_file("/b_dir/a_file", b"other file");
+    let other = client.look_up("/a_dir/../b_dir/./a_file").unwrap();
+    assert_eq!(client.get_attr(other).unwrap().size, 10);
+    assert!(matches!(
+        client.look_up("/b_dir/../a_file"),
+        Err(Error::Protocol(StatusError::NoEnt))
+    ));
+    let root = client.look_up("/").unwrap();
+    assert_eq!(client.look_up("/../..").unwrap(), root);
+
+    let mut client = Client::connect(server.addr(), "/a_dir").unwrap();
+    let handle = client.look_up("a_file").unwrap();
+    let mut data = vec![];
+    client.read_all(handle, &mut data).unwrap();
+    assert_eq!(data, b"hello");
+}
+
+#[test]
+fn mount_missing_export() {
+    let server = TestServer::new();
+    let mut mount = MountClient::new(server.connect());
+    assert!(matches!(
+        mount.mount("/nope"),
+        Err(Error::Protocol(StatusError::NoEnt))
+    ));
+    let exports = mount.exports().unwrap();
+    assert_eq!(exports[0].dir_path, "/");
+}
+
+#[test]
+fn write_read_rename_remove() {
+    let server = TestServer::new();
+    let mut client = Client::connect(server.addr(), "/").unwrap();
+    let root = client.root().clone();
+
+    let dir = client
+        .create_directory(root.clone(), "dir", Default::default())
+        .unwrap();
+    let file = client.create_file(dir.clone(), "file").unwrap();
+
+    let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    client.write_all(file.clone(), &data[..]).unwrap();
+    assert_eq!(server.file_contents("/dir/file").unwrap(), data);
+
+    let res = client
+        .write_with_stability(file.clone(), 0, StableHow::Unstable, b"xy".to_vec())
+        .unwrap();
+    let commit = client.commit(file.clone()).unwrap();
+    assert_eq!(res.verifier, commit.verifier);
+
+    let mut read_back = vec![];
+    client.read_all(file, &mut read_back).unwrap();
+    assert_eq!(&read_back[..2], b"xy");
+    assert_eq!(read_back.len(), data.len());
+
+    client
+        .rename(dir.clone(), root.clone(), "file", "moved")
+        .unwrap();
+    assert!(server.file_contents("/dir/file").is_none());
+    assert!(server.file_contents("/moved").is_some());
+
+    assert!(matches!(
+        client.remove(root.clone(), "dir"),
+        Err(Error::Protocol(StatusError::IsDir))
+    ));
+    client.remove(root.clone(), "moved").unwrap();
+    client.remove_directory(root.clone(), "dir").unwrap();
+    assert!(client.read_dir(root).unwrap().is_empty());
+}
+
+#[test]
+fn read_dir_plus_continues_with_cookie() {
+    let server = TestServer::new();
+    server.create_dir("/big");
+    let mut expected: Vec<String> = (0..500).map(|i| format!("file_{i:04}")).collect();
+    for name in &expected {
+        server.write_file(&format!("/big/{name}"), name.as_bytes());
+    }
+    expected.sort();
+
+    let mut client = Client::connect(server.addr(), "/").unwrap();
+    let dir = client.look_up("/big").unwrap();
+    let entries = client.read_dir(dir).unwrap();
+
+    let names: Vec<String> = entries.iter().map(|e| e.name.clone()).collect();
+    assert_eq!(names, expected);
+
+    let entry = &entries[0];
+    assert_eq!(entry.attributes.as_ref().unwrap().size, 9);
+    let mut data = vec![];
+    client
+        .read_all(entry.handle.clone().unwrap(), &mut data)
+        .unwrap();
+    assert_eq!(data, b"file_0000");
+}
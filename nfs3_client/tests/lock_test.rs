@@ -0,0 +1,168 @@
+// Copyright Remi Bernotavicius
+
+use nfs3::nlm::Status;
+use nfs3::FileHandle;
+use nfs3_client::{
+    CallbackEvent, CallbackListener, Client, Error, LockClient, LockOutcome, StatusMonitorClient,
+};
+use nfs4_test_server::TestServer;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn server_with_file() -> (TestServer, FileHandle) {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"some data");
+    let handle = Client::connect(server.addr(), "/")
+        .unwrap()
+        .look_up("/a_file")
+        .unwrap();
+    (server, handle)
+}
+
+#[test]
+fn lock_test_unlock() {
+    let (server, handle) = server_with_file();
+    let mut a = LockClient::connect(server.addr(), "host-a").unwrap();
+    let mut b = LockClient::connect(server.addr(), "host-b").unwrap();
+
+    assert_eq!(
+        a.lock(&handle, 0, 100, true, false).unwrap(),
+        LockOutcome::Granted
+    );
+
+    let holder = b.test(&handle, 50, 10, false).unwrap().unwrap();
+    assert!(holder.exclusive);
+    assert_eq!((holder.offset, holder.length), (0, 100));
+    assert_eq!(b.test(&handle, 100, 0, true).unwrap(), None);
+
+    assert!(matches!(
+        b.lock(&handle, 50, 10, false, false),
+        Err(Error::Lock(Status::Denied))
+    ));
+    assert_eq!(
+        b.lock(&handle, 100, 0, true, false).unwrap(),
+        LockOutcome::Granted
+    );
+
+    a.unlock(&handle, 0, 100).unwrap();
+    assert_eq!(
+        b.lock(&handle, 50, 10, false, false).unwrap(),
+        LockOutcome::Granted
+    );
+}
+
+#[test]
+fn blocked_lock_granted_by_callback() {
+    let (server, handle) = server_with_file();
+    let listener = CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+
+    let mut a = LockClient::connect(server.addr(), "host-a").unwrap();
+    let mut b = LockClient::connect(server.addr(), "host-b").unwrap();
+
+    a.lock(&handle, 0, 0, true, false).unwrap();
+    assert_eq!(
+        b.lock(&handle, 0, 0, true, true).unwrap(),
+        LockOutcome::Blocked
+    );
+    assert!(!b.wait_granted(&listener, Duration::from_millis(100)));
+
+    a.unlock(&handle, 0, 0).unwrap();
+    assert!(b.wait_granted(&listener, TIMEOUT));
+    assert!(a.test(&handle, 0, 1, false).unwrap().is_some());
+}
+
+#[test]
+fn blocked_lock_granted_by_msg_callback() {
+    let (server, handle) = server_with_file();
+    server.set_granted_msg(true);
+    let listener = CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+    listener.set_server_port_mapper(server.addr());
+
+    let mut a = LockClient::connect(server.addr(), "host-a").unwrap();
+    let mut b = LockClient::connect(server.addr(), "host-b").unwrap();
+
+    a.lock(&handle, 0, 0, true, false).unwrap();
+    assert_eq!(
+        b.lock(&handle, 0, 0, true, true).unwrap(),
+        LockOutcome::Blocked
+    );
+    a.unlock(&handle, 0, 0).unwrap();
+    assert!(b.wait_granted(&listener, TIMEOUT));
+
+    // The results come back in a GRANTED_RES call after the reply.
+    let deadline = Instant::now() + TIMEOUT;
+    while server.granted_results().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let results = server.granted_results();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].status, Status::Granted);
+}
+
+#[test]
+fn cancel_blocked_lock() {
+    let (server, handle) = server_with_file();
+    let listener = CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+
+    let mut a = LockClient::connect(server.addr(), "host-a").unwrap();
+    let mut b = LockClient::connect(server.addr(), "host-b").unwrap();
+
+    a.lock(&handle, 0, 0, true, false).unwrap();
+    b.lock(&handle, 0, 0, true, true).unwrap();
+    b.cancel(&handle, 0, 0, true).unwrap();
+
+    a.unlock(&handle, 0, 0).unwrap();
+    assert_eq!(listener.next_event(Duration::from_millis(200)), None);
+    assert_eq!(a.test(&handle, 0, 1, true).unwrap(), None);
+}
+
+#[test]
+fn reclaim_after_reboot() {
+    let (server, handle) = server_with_file();
+    let listener = CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+
+    let mut monitor = StatusMonitorClient::connect(server.addr()).unwrap();
+    let state = monitor
+        .monitor("server", listener.my_id("host-a"), [7; 16])
+        .unwrap();
+
+    let mut a = LockClient::connect(server.addr(), "host-a").unwrap();
+    let mut b = LockClient::connect(server.addr(), "host-b").unwrap();
+    a.set_state(state);
+    a.lock(&handle, 0, 10, true, false).unwrap();
+
+    server.reboot_lock_manager();
+    let Some(CallbackEvent::Rebooted(status)) = listener.next_event(TIMEOUT) else {
+        panic!("no reboot notification");
+    };
+    assert_eq!(status.mon_name, "server");
+    assert_eq!(status.state, state + 2);
+    assert_eq!(status.private, [7; 16]);
+
+    assert!(matches!(
+        b.lock(&handle, 0, 10, true, false),
+        Err(Error::Lock(Status::DeniedGracePeriod))
+    ));
+    a.reclaim().unwrap();
+    server.end_grace_period();
+
+    assert!(matches!(
+        b.lock(&handle, 0, 10, true, false),
+        Err(Error::Lock(Status::Denied))
+    ));
+    assert_eq!(monitor.stat("server").unwrap(), state + 2);
+}
+
+#[test]
+fn callback_listener_registration() {
+    let server = TestServer::new();
+    let listener = CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+    assert!(matches!(
+        CallbackListener::bind("127.0.0.1:0", server.addr()),
+        Err(Error::RegisterFailed(_))
+    ));
+
+    drop(listener);
+    CallbackListener::bind("127.0.0.1:0", server.addr()).unwrap();
+}
@@ -0,0 +1,508 @@
+// Copyright 2023 Remi Bernotavicius
+
+use crate::{Ace, AceFlags, AceMask, AceType, Acl, Identity, Mode};
+use std::fmt;
+use std::str::FromStr;
+
+impl Identity {
+    pub const OWNER: &'static str = "OWNER@";
+    pub const GROUP: &'static str = "GROUP@";
+    pub const EVERYONE: &'static str = "EVERYONE@";
+
+    pub fn owner() -> Self {
+        Self(Self::OWNER.into())
+    }
+
+    pub fn group() -> Self {
+        Self(Self::GROUP.into())
+    }
+
+    pub fn everyone() -> Self {
+        Self(Self::EVERYONE.into())
+    }
+}
+
+impl From<&str> for Identity {
+    fn from(s: &str) -> Self {
+        Self(s.into())
+    }
+}
+
+impl From<String> for Identity {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+const ACE_TYPE_CHARS: [(AceType, char); 4] = [
+    (AceType::AccessAllowed, 'A'),
+    (AceType::AccessDenied, 'D'),
+    (AceType::SystemAudit, 'U'),
+    (AceType::SystemAlarm, 'L'),
+];
+
+const ACE_FLAG_CHARS: [(AceFlags, char); 8] = [
+    (AceFlags::FILE_INHERIT_ACE, 'f'),
+    (AceFlags::DIRECTORY_INHERIT_ACE, 'd'),
+    (AceFlags::NO_PROPAGATE_INHERIT_ACE, 'n'),
+    (AceFlags::INHERIT_ONLY_ACE, 'i'),
+    (AceFlags::SUCCESSFUL_ACCESS_ACE_FLAG, 'S'),
+    (AceFlags::FAILED_ACCESS_ACE_FLAG, 'F'),
+    (AceFlags::IDENTIFIER_GROUP, 'g'),
+    (AceFlags::INHERITED_ACE, 'I'),
+];
+
+const ACE_MASK_CHARS: [(AceMask, char); 14] = [
+    (AceMask::READ_DATA, 'r'),
+    (AceMask::WRITE_DATA, 'w'),
+    (AceMask::APPEND_DATA, 'a'),
+    (AceMask::EXECUTE, 'x'),
+    (AceMask::DELETE, 'd'),
+    (AceMask::DELETE_CHILD, 'D'),
+    (AceMask::READ_ATTRIBUTES, 't'),
+    (AceMask::WRITE_ATTRIBUTES, 'T'),
+    (AceMask::READ_NAMED_ATTRS, 'n'),
+    (AceMask::WRITE_NAMED_ATTRS, 'N'),
+    (AceMask::READ_ACL, 'c'),
+    (AceMask::WRITE_ACL, 'C'),
+    (AceMask::WRITE_OWNER, 'o'),
+    (AceMask::SYNCHRONIZE, 'y'),
+];
+
+impl AceMask {
+    /// The `R` alias accepted by `nfs4_setfacl`
+    pub const GENERIC_READ: Self = Self::READ_DATA
+        .union(Self::READ_NAMED_ATTRS)
+        .union(Self::READ_ATTRIBUTES)
+        .union(Self::READ_ACL)
+        .union(Self::SYNCHRONIZE);
+
+    /// The `W` alias accepted by `nfs4_setfacl`
+    pub const GENERIC_WRITE: Self = Self::WRITE_DATA
+        .union(Self::APPEND_DATA)
+        .union(Self::WRITE_ATTRIBUTES)
+        .union(Self::WRITE_NAMED_ATTRS)
+        .union(Self::READ_ACL)
+        .union(Self::WRITE_ACL)
+        .union(Self::SYNCHRONIZE);
+
+    /// The `X` alias accepted by `nfs4_setfacl`
+    pub const GENERIC_EXECUTE: Self = Self::EXECUTE
+        .union(Self::READ_ATTRIBUTES)
+        .union(Self::READ_ACL)
+        .union(Self::SYNCHRONIZE);
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AclParseError {
+    MissingField(&'static str),
+    TrailingField(String),
+    UnknownType(String),
+    UnknownFlag(char),
+    UnknownPermission(char),
+    EmptyPrincipal,
+}
+
+impl fmt::Display for AclParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingField(field) => write!(f, "missing ACE field `{field}`"),
+            Self::TrailingField(field) => write!(f, "unexpected trailing ACE field `{field}`"),
+            Self::UnknownType(t) => write!(f, "unknown ACE type `{t}`"),
+            Self::UnknownFlag(c) => write!(f, "unknown ACE flag `{c}`"),
+            Self::UnknownPermission(c) => write!(f, "unknown ACE permission `{c}`"),
+            Self::EmptyPrincipal => write!(f, "ACE principal is empty"),
+        }
+    }
+}
+
+impl std::error::Error for AclParseError {}
+
+impl Ace {
+    pub fn new(type_: AceType, who: impl Into<Identity>, access_mask: AceMask) -> Self {
+        Self {
+            type_,
+            flags: AceFlags::empty(),
+            access_mask,
+            who: who.into(),
+        }
+    }
+
+    pub fn allow(who: impl Into<Identity>, access_mask: AceMask) -> Self {
+        Self::new(AceType::AccessAllowed, who, access_mask)
+    }
+
+    pub fn deny(who: impl Into<Identity>, access_mask: AceMask) -> Self {
+        Self::new(AceType::AccessDenied, who, access_mask)
+    }
+
+    pub fn with_flags(mut self, flags: AceFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    pub fn is_inherited(&self) -> bool {
+        self.flags.contains(AceFlags::INHERITED_ACE)
+    }
+
+    pub fn is_inherit_only(&self) -> bool {
+        self.flags.contains(AceFlags::INHERIT_ONLY_ACE)
+    }
+
+    fn applies_to(&self, who: &str) -> bool {
+        !self.is_inherit_only() && (self.who.0 == who || self.who.0 == Identity::EVERYONE)
+    }
+
+    /// Sort key for canonical ordering: explicit entries before inherited ones, and within each
+    /// of those, deny before allow before audit and alarm entries.
+    fn canonical_rank(&self) -> (bool, u8) {
+        let type_rank = match self.type_ {
+            AceType::AccessDenied => 0,
+            AceType::AccessAllowed => 1,
+            AceType::SystemAudit | AceType::SystemAlarm => 2,
+        };
+        (self.is_inherited(), type_rank)
+    }
+}
+
+impl fmt::Display for Ace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, type_char) = ACE_TYPE_CHARS
+            .iter()
+            .find(|(t, _)| *t == self.type_)
+            .unwrap();
+        write!(f, "{type_char}:")?;
+        for (flag, c) in ACE_FLAG_CHARS {
+            if self.flags.contains(flag) {
+                write!(f, "{c}")?;
+            }
+        }
+        write!(f, ":{}:", self.who)?;
+        for (mask, c) in ACE_MASK_CHARS {
+            if self.access_mask.contains(mask) {
+                write!(f, "{c}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Ace {
+    type Err = AclParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.trim().splitn(4, ':');
+        let type_str = fields.next().ok_or(AclParseError::MissingField("type"))?;
+        let flags_str = fields.next().ok_or(AclParseError::MissingField("flags"))?;
+        let who = fields
+            .next()
+            .ok_or(AclParseError::MissingField("principal"))?;
+        let mask_str = fields
+            .next()
+            .ok_or(AclParseError::MissingField("permissions"))?;
+        if let Some(i) = mask_str.find(':') {
+            return Err(AclParseError::TrailingField(mask_str[i + 1..].into()));
+        }
+
+        let mut type_chars = type_str.chars();
+        let type_ = match (type_chars.next(), type_chars.next()) {
+            (Some(c), None) => ACE_TYPE_CHARS
+                .iter()
+                .find(|(_, tc)| *tc == c)
+                .map(|(t, _)| *t),
+            _ => None,
+        }
+        .ok_or_else(|| AclParseError::UnknownType(type_str.into()))?;
+
+        let mut flags = AceFlags::empty();
+        for c in flags_str.chars() {
+            let (flag, _) = ACE_FLAG_CHARS
+                .iter()
+                .find(|(_, fc)| *fc == c)
+                .ok_or(AclParseError::UnknownFlag(c))?;
+            flags |= *flag;
+        }
+
+        if who.is_empty() {
+            return Err(AclParseError::EmptyPrincipal);
+        }
+
+        let mut access_mask = AceMask::empty();
+        for c in mask_str.chars() {
+            access_mask |= match c {
+                'R' => AceMask::GENERIC_READ,
+                'W' => AceMask::GENERIC_WRITE,
+                'X' => AceMask::GENERIC_EXECUTE,
+                c => {
+                    ACE_MASK_CHARS
+                        .iter()
+                        .find(|(_, mc)| *mc == c)
+                        .ok_or(AclParseError::UnknownPermission(c))?
+                        .0
+                }
+            };
+        }
+
+        Ok(Self {
+            type_,
+            flags,
+            access_mask,
+            who: who.into(),
+        })
+    }
+}
+
+/// Formats one ACE per line, the same as `nfs4_getfacl`.
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ace in &self.aces {
+            writeln!(f, "{ace}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the output of `nfs4_getfacl` or the input of `nfs4_setfacl -S`. Empty lines and lines
+/// starting with `#` are ignored. ACEs may also be separated by commas.
+impl FromStr for Acl {
+    type Err = AclParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let aces = s
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .flat_map(|l| l.split(','))
+            .filter(|a| !a.trim().is_empty())
+            .map(Ace::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { aces })
+    }
+}
+
+fn mode_bits_to_mask(bits: u32, is_dir: bool) -> AceMask {
+    let mut mask = AceMask::empty();
+    if bits & 0o4 != 0 {
+        mask |= AceMask::READ_DATA;
+    }
+    if bits & 0o2 != 0 {
+        mask |= AceMask::WRITE_DATA | AceMask::APPEND_DATA;
+        if is_dir {
+            mask |= AceMask::DELETE_CHILD;
+        }
+    }
+    if bits & 0o1 != 0 {
+        mask |= AceMask::EXECUTE;
+    }
+    mask
+}
+
+fn mask_to_mode_bits(mask: AceMask) -> u32 {
+    let mut bits = 0;
+    if mask.contains(AceMask::READ_DATA) {
+        bits |= 0o4;
+    }
+    if mask.contains(AceMask::WRITE_DATA) {
+        bits |= 0o2;
+    }
+    if mask.contains(AceMask::EXECUTE) {
+        bits |= 0o1;
+    }
+    bits
+}
+
+impl Acl {
+    /// Builds the ACL equivalent to the given permission bits, using the `OWNER@`, `GROUP@` and
+    /// `EVERYONE@` special identities. Deny entries are only added where a more general class
+    /// has permissions that a more specific one lacks.
+    pub fn from_mode(mode: Mode, is_dir: bool) -> Self {
+        let rwx = AceMask::READ_DATA
+            | AceMask::WRITE_DATA
+            | AceMask::APPEND_DATA
+            | AceMask::EXECUTE
+            | if is_dir {
+                AceMask::DELETE_CHILD
+            } else {
+                AceMask::empty()
+            };
+        let always = AceMask::READ_ATTRIBUTES | AceMask::READ_ACL | AceMask::SYNCHRONIZE;
+        let owner_only = AceMask::WRITE_ATTRIBUTES | AceMask::WRITE_ACL | AceMask::WRITE_OWNER;
+
+        let owner = mode_bits_to_mask(mode.0 >> 6 & 0o7, is_dir);
+        let group = mode_bits_to_mask(mode.0 >> 3 & 0o7, is_dir);
+        let other = mode_bits_to_mask(mode.0 & 0o7, is_dir);
+
+        let mut builder = AclBuilder::new();
+
+        let owner_deny = (group | other).difference(owner) & rwx;
+        if !owner_deny.is_empty() {
+            builder = builder.deny(Identity::owner(), owner_deny);
+        }
+        builder = builder.allow(Identity::owner(), owner | always | owner_only);
+
+        let group_deny = other.difference(group) & rwx;
+        if !group_deny.is_empty() {
+            builder = builder.ace(
+                Ace::deny(Identity::group(), group_deny).with_flags(AceFlags::IDENTIFIER_GROUP),
+            );
+        }
+        builder = builder.ace(
+            Ace::allow(Identity::group(), group | always).with_flags(AceFlags::IDENTIFIER_GROUP),
+        );
+        builder.allow(Identity::everyone(), other | always).build()
+    }
+
+    /// Computes the permission bits that best approximate this ACL. Only the `OWNER@`, `GROUP@`
+    /// and `EVERYONE@` entries are considered, named principals have no mode equivalent.
+    pub fn to_mode(&self) -> Mode {
+        let class_bits = |who: &str| {
+            let mut allowed = AceMask::empty();
+            let mut denied = AceMask::empty();
+            for ace in self.aces.iter().filter(|a| a.applies_to(who)) {
+                let undecided = ace.access_mask.difference(allowed | denied);
+                match ace.type_ {
+                    AceType::AccessAllowed => allowed |= undecided,
+                    AceType::AccessDenied => denied |= undecided,
+                    AceType::SystemAudit | AceType::SystemAlarm => {}
+                }
+            }
+            mask_to_mode_bits(allowed)
+        };
+
+        Mode(
+            class_bits(Identity::OWNER) << 6
+                | class_bits(Identity::GROUP) << 3
+                | class_bits(Identity::EVERYONE),
+        )
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        self.aces
+            .windows(2)
+            .all(|w| w[0].canonical_rank() <= w[1].canonical_rank())
+    }
+
+    /// Stable-sorts the entries into canonical order: explicit entries before inherited ones, and
+    /// deny entries before allow entries within each group.
+    pub fn canonicalize(&mut self) {
+        self.aces.sort_by_key(Ace::canonical_rank);
+    }
+
+    /// Returns the ACL a new file or directory created in a directory with this ACL would get.
+    pub fn inherited(&self, is_dir: bool) -> Self {
+        let inherit_flags = AceFlags::FILE_INHERIT_ACE
+            | AceFlags::DIRECTORY_INHERIT_ACE
+            | AceFlags::NO_PROPAGATE_INHERIT_ACE
+            | AceFlags::INHERIT_ONLY_ACE;
+
+        let aces = self
+            .aces
+            .iter()
+            .filter_map(|ace| {
+                let mut ace = ace.clone();
+                if !is_dir {
+                    if !ace.flags.contains(AceFlags::FILE_INHERIT_ACE) {
+                        return None;
+                    }
+                    ace.flags.remove(inherit_flags);
+                } else if ace.flags.contains(AceFlags::DIRECTORY_INHERIT_ACE) {
+                    if ace.flags.contains(AceFlags::NO_PROPAGATE_INHERIT_ACE) {
+                        ace.flags.remove(inherit_flags);
+                    } else {
+                        ace.flags.remove(AceFlags::INHERIT_ONLY_ACE);
+                    }
+                } else if ace.flags.contains(AceFlags::FILE_INHERIT_ACE)
+                    && !ace.flags.contains(AceFlags::NO_PROPAGATE_INHERIT_ACE)
+                {
+                    ace.flags.insert(AceFlags::INHERIT_ONLY_ACE);
+                } else {
+                    return None;
+                }
+                ace.flags.insert(AceFlags::INHERITED_ACE);
+                Some(ace)
+            })
+            .collect();
+        Self { aces }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct AclBuilder {
+    aces: Vec<Ace>,
+}
+
+impl From<Acl> for AclBuilder {
+    fn from(acl: Acl) -> Self {
+        Self { aces: acl.aces }
+    }
+}
+
+impl AclBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ace(mut self, ace: Ace) -> Self {
+        self.aces.push(ace);
+        self
+    }
+
+    pub fn allow(self, who: impl Into<Identity>, access_mask: AceMask) -> Self {
+        self.ace(Ace::allow(who, access_mask))
+    }
+
+    pub fn deny(self, who: impl Into<Identity>, access_mask: AceMask) -> Self {
+        self.ace(Ace::deny(who, access_mask))
+    }
+
+    /// Inserts the entry at the given position, clamping to the end of the list.
+    pub fn insert(mut self, index: usize, ace: Ace) -> Self {
+        let index = index.min(self.aces.len());
+        self.aces.insert(index, ace);
+        self
+    }
+
+    /// Removes the entry at the given position, if there is one.
+    pub fn remove(mut self, index: usize) -> Self {
+        if index < self.aces.len() {
+            self.aces.remove(index);
+        }
+        self
+    }
+
+    /// Removes every entry for the given principal.
+    pub fn remove_who(mut self, who: &str) -> Self {
+        self.aces.retain(|a| a.who.0 != who);
+        self
+    }
+
+    pub fn retain(mut self, f: impl FnMut(&Ace) -> bool) -> Self {
+        self.aces.retain(f);
+        self
+    }
+
+    /// Moves the entry at `from` so it ends up at position `to`.
+    pub fn move_ace(mut self, from: usize, to: usize) -> Self {
+        if from < self.aces.len() {
+            let ace = self.aces.remove(from);
+            let to = to.min(self.aces.len());
+            self.aces.insert(to, ace);
+        }
+        self
+    }
+
+    pub fn canonicalize(mut self) -> Self {
+        self.aces.sort_by_key(Ace::canonical_rank);
+        self
+    }
+
+    pub fn build(self) -> Acl {
+        Acl { aces: self.aces }
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright 2023 Remi Bernotavicius
+
+use crate::*;
+use paste::paste;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+macro_rules! attributes_struct {
+    ($($field:ident: $variant:ident($type:ty),)*) => { paste! {
+        /// A typed view of `FileAttributes` with one field per attribute.
+        #[derive(PartialEq, Eq, Clone, Debug, Default)]
+        pub struct Attributes {
+            $(pub $field: Option<$type>,)*
+        }
+
+        impl From<FileAttributes> for Attributes {
+            fn from(attrs: FileAttributes) -> Self {
+                let mut ret = Self::default();
+                for attr in attrs {
+                    match attr {
+                        $(FileAttribute::$variant(v) => ret.$field = Some(v),)*
+                    }
+                }
+                ret
+            }
+        }
+
+        impl From<Attributes> for FileAttributes {
+            fn from(attrs: Attributes) -> Self {
+                let mut ret = Self::default();
+                $(
+                    if let Some(v) = attrs.$field {
+                        ret.insert(FileAttribute::$variant(v));
+                    }
+                )*
+                ret
+            }
+        }
+
+        impl Attributes {
+            /// The set of attributes which have a value.
+            pub fn ids(&self) -> EnumSet<FileAttributeId> {
+                let mut ret = EnumSet::default();
+                $(
+                    if self.$field.is_some() {
+                        ret.insert(FileAttributeId::$variant);
+                    }
+                )*
+                ret
+            }
+
+            $(
+                pub fn [<with_ $field>](mut self, value: $type) -> Self {
+                    self.$field = Some(value);
+                    self
+                }
+            )*
+        }
+
+        /// Builds the set of attributes to ask for in a GETATTR or READDIR.
+        #[derive(PartialEq, Eq, Clone, Debug, Default)]
+        pub struct AttrRequest(EnumSet<FileAttributeId>);
+
+        impl AttrRequest {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            $(
+                pub fn $field(mut self) -> Self {
+                    self.0.insert(FileAttributeId::$variant);
+                    self
+                }
+            )*
+        }
+    }}
+}
+
+attributes_struct! {
+    supported_attrs: SupportedAttrs(EnumSet<FileAttributeId>),
+    type_: Type(FileType),
+    fh_expire_type: FhExpireType(u32),
+    change: Change(Change),
+    size: Size(u64),
+    link_support: LinkSupport(bool),
+    symlink_support: SymlinkSupport(bool),
+    named_attr: NamedAttr(bool),
+    fs_id: FsId(FsId),
+    unique_handles: UniqueHandles(bool),
+    lease_time: LeaseTime(Lease),
+    read_dir_attr_error: ReadDirAttrError(StatusResult<()>),
+    acl: Acl(Acl),
+    acl_support: AclSupport(u32),
+    archive: Archive(bool),
+    can_set_time: CanSetTime(bool),
+    case_insensitive: CaseInsensitive(bool),
+    case_preserving: CasePreserving(bool),
+    chown_restricted: ChownRestricted(bool),
+    file_handle: FileHandle(FileHandle),
+    file_id: FileId(FileId),
+    files_avail: FilesAvail(u64),
+    files_free: FilesFree(u64),
+    files_total: FilesTotal(u64),
+    fs_locations: FsLocations(FsLocations),
+    homogeneous: Homogeneous(bool),
+    max_file_size: MaxFileSize(u64),
+    max_link: MaxLink(u32),
+    max_name: MaxName(u32),
+    max_read: MaxRead(u64),
+    max_write: MaxWrite(u64),
+    mime_type: MimeType(String),
+    mode: Mode(Mode),
+    no_trunc: NoTrunc(bool),
+    num_links: NumLinks(u32),
+    owner: Owner(String),
+    owner_group: OwnerGroup(String),
+    quota_avail_hard: QuotaAvailHard(u64),
+    quota_avail_soft: QuotaAvailSoft(u64),
+    quota_used: QuotaUsed(u64),
+    raw_dev: RawDev(DeviceData),
+    space_avail: SpaceAvail(u64),
+    space_free: SpaceFree(u64),
+    space_total: SpaceTotal(u64),
+    space_used: SpaceUsed(u64),
+    system: System(bool),
+    time_access: TimeAccess(Time),
+    time_access_set: TimeAccessSet(SetTime),
+    time_backup: TimeBackup(Time),
+    time_create: TimeCreate(Time),
+    time_delta: TimeDelta(Time),
+    time_metadata: TimeMetadata(Time),
+    time_modify: TimeModify(Time),
+    time_modify_set: TimeModifySet(SetTime),
+    mounted_on_fileid: MountedOnFileid(FileId),
+    dir_notif_delay: DirNotifDelay(Time),
+    dirent_notif_delay: DirentNotifDelay(Time),
+    dacl: Dacl(AclWithFlags),
+    sacl: Sacl(AclWithFlags),
+    change_policy: ChangePolicy(ChangePolicy),
+    fs_status: FsStatus(FsStatus),
+    fs_layout_type: FsLayoutType(Vec<LayoutType>),
+    layout_hint: LayoutHint(LayoutHint),
+    layout_type: LayoutType(Vec<LayoutType>),
+    layout_blksize: LayoutBlksize(u32),
+    layout_alignment: LayoutAlignment(u32),
+    fs_locations_info: FsLocationsInfo(FsLocationsInfo),
+    mds_threshold: MdsThreshold(MdsThreshold),
+    retention_get: RetentionGet(RetentionGet),
+    retention_set: RetentionSet(RetentionSet),
+    retentevt_get: RetentevtGet(RetentionGet),
+    retentevt_set: RetentevtSet(RetentionSet),
+    retention_hold: RetentionHold(u64),
+    mode_set_masked: ModeSetMasked(ModeMasked),
+    supported_attrs_exclusive_create: SupportedAttrsExclusiveCreate(EnumSet<FileAttributeId>),
+    fs_charset_cap: FsCharsetCap(u32),
+}
+
+impl From<AttrRequest> for EnumSet<FileAttributeId> {
+    fn from(req: AttrRequest) -> Self {
+        req.0
+    }
+}
+
+impl AttrRequest {
+    /// The attributes needed to fill in the fields `std::fs::Metadata` has.
+    pub fn metadata() -> Self {
+        Self::new()
+            .type_()
+            .size()
+            .mode()
+            .num_links()
+            .owner()
+            .owner_group()
+            .time_access()
+            .time_modify()
+            .time_metadata()
+            .time_create()
+    }
+}
+
+impl Time {
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        let nanos = Duration::from_nanos(self.nseconds.into());
+        if self.seconds >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(self.seconds as u64) + nanos)
+        } else {
+            UNIX_EPOCH
+                .checked_sub(Duration::from_secs(self.seconds.unsigned_abs()))?
+                .checked_add(nanos)
+        }
+    }
+}
+
+impl From<SystemTime> for Time {
+    fn from(t: SystemTime) -> Self {
+        match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => Self {
+                seconds: d.as_secs() as i64,
+                nseconds: d.subsec_nanos(),
+            },
+            Err(e) => {
+                let d = e.duration();
+                if d.subsec_nanos() == 0 {
+                    Self {
+                        seconds: -(d.as_secs() as i64),
+                        nseconds: 0,
+                    }
+                } else {
+                    Self {
+                        seconds: -(d.as_secs() as i64) - 1,
+                        nseconds: 1_000_000_000 - d.subsec_nanos(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        *self == Self::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        *self == Self::Regular
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        *self == Self::Link
+    }
+}
+
+impl From<std::fs::FileType> for FileType {
+    fn from(t: std::fs::FileType) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt as _;
+            if t.is_block_device() {
+                return Self::Block;
+            } else if t.is_char_device() {
+                return Self::Character;
+            } else if t.is_socket() {
+                return Self::Socket;
+            } else if t.is_fifo() {
+                return Self::Fifo;
+            }
+        }
+        if t.is_dir() {
+            Self::Directory
+        } else if t.is_symlink() {
+            Self::Link
+        } else {
+            Self::Regular
+        }
+    }
+}
+
+impl Attributes {
+    pub fn is_dir(&self) -> bool {
+        self.type_.as_ref().is_some_and(FileType::is_dir)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.type_.as_ref().is_some_and(FileType::is_file)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.type_.as_ref().is_some_and(FileType::is_symlink)
+    }
+
+    #[cfg(unix)]
+    pub fn permissions(&self) -> Option<std::fs::Permissions> {
+        use std::os::unix::fs::PermissionsExt as _;
+        Some(std::fs::Permissions::from_mode(self.mode?.0))
+    }
+
+    pub fn accessed(&self) -> Option<SystemTime> {
+        self.time_access?.to_system_time()
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.time_modify?.to_system_time()
+    }
+
+    pub fn created(&self) -> Option<SystemTime> {
+        self.time_create?.to_system_time()
+    }
+
+    pub fn with_access_time(self, t: SystemTime) -> Self {
+        self.with_time_access_set(SetTime::SetToClientTime(t.into()))
+    }
+
+    pub fn with_modify_time(self, t: SystemTime) -> Self {
+        self.with_time_modify_set(SetTime::SetToClientTime(t.into()))
+    }
+
+    /// Keeps only the attributes that SETATTR accepts, turning the access and modify times into
+    /// their settable counterparts.
+    pub fn into_settable(self) -> Self {
+        Self {
+            size: self.size,
+            acl: self.acl,
+            archive: self.archive,
+            mime_type: self.mime_type,
+            mode: self.mode,
+            owner: self.owner,
+            owner_group: self.owner_group,
+            system: self.system,
+            time_access_set: self
+                .time_access_set
+                .or(self.time_access.map(SetTime::SetToClientTime)),
+            time_backup: self.time_backup,
+            time_create: self.time_create,
+            time_modify_set: self
+                .time_modify_set
+                .or(self.time_modify.map(SetTime::SetToClientTime)),
+            dacl: self.dacl,
+            sacl: self.sacl,
+            retention_set: self.retention_set,
+            retentevt_set: self.retentevt_set,
+            retention_hold: self.retention_hold,
+            mode_set_masked: self.mode_set_masked,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<&std::fs::Metadata> for Attributes {
+    fn from(m: &std::fs::Metadata) -> Self {
+        let mut attrs = Self {
+            type_: Some(m.file_type().into()),
+            size: Some(m.len()),
+            time_access: m.accessed().ok().map(Into::into),
+            time_modify: m.modified().ok().map(Into::into),
+            time_create: m.created().ok().map(Into::into),
+            ..Self::default()
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt as _;
+            attrs.mode = Some(Mode(m.mode() & 0o7777));
+            attrs.num_links = m.nlink().try_into().ok();
+            attrs.time_metadata = Some(Time {
+                seconds: m.ctime(),
+                nseconds: m.ctime_nsec() as u32,
+            });
+        }
+        attrs
+    }
+}
@@ -55,6 +55,15 @@ impl<K, V> Default for EnumMap<K, V> {
     }
 }
 
+impl<K, V> IntoIterator for EnumMap<K, V> {
+    type Item = V;
+    type IntoIter = std::collections::btree_map::IntoValues<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_values()
+    }
+}
+
 impl<K, V> FromIterator<V> for EnumMap<K, V>
 where
     K: Ord,
@@ -118,6 +127,10 @@ impl<K> EnumSet<K>
 where
     K: Ord + Copy,
 {
+    pub fn insert(&mut self, key: K) -> bool {
+        self.0.insert(key)
+    }
+
     pub fn remove(&mut self, key: K) -> bool {
         self.0.remove(&key)
     }
@@ -13,11 +13,17 @@ use serde::{
 };
 use serde_xdr::opaque_data::fixed_length;
 use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use sun_rpc::{AuthFlavor, AuthSysParameters};
 use xdr_extras::{DeserializeWithDiscriminant, SerializeWithDiscriminant};
 
+mod acl;
+mod attributes;
 mod enum_map;
 
+pub use acl::{AclBuilder, AclParseError};
+pub use attributes::{AttrRequest, Attributes};
+
 pub type FileAttributes = EnumMap<FileAttributeId, FileAttribute>;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -132,7 +138,7 @@ pub enum FileType {
 #[repr(u32)]
 pub enum CreateType {
     Directory = 2,
-    Block = 3,
+    Block(DeviceData) = 3,
     Character(DeviceData) = 4,
     Link(String) = 5,
     Socket = 6,
@@ -232,13 +238,13 @@ pub enum FileAttributeId {
     FsCharsetCap = 76,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub struct FsId {
     pub major: u64,
     pub minor: u64,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
 pub struct FileHandle(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -326,12 +332,13 @@ impl_serde_for_bitflags!(AclFlags);
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct AclWithFlags {
     pub flags: AclFlags,
+    pub aces: Vec<Ace>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct ChangePolicy(u32);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
 pub struct Time {
     pub seconds: i64,
     pub nseconds: u32,
@@ -352,6 +359,7 @@ pub enum LayoutType {
     NfsV41Files = 1,
     Osd2Objects = 2,
     BlockVolume = 3,
+    FlexFiles = 4,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -478,10 +486,10 @@ pub enum StatusError {
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
-pub struct Component(String);
+pub struct Component(pub String);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
-pub struct PathName(Vec<Component>);
+pub struct PathName(pub Vec<Component>);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct FsLocation {
@@ -512,6 +520,55 @@ pub struct FsLocationsServer {
     pub server: String,
 }
 
+bitflags! {
+    /// The flags in the `GFlags` byte of `FsLocationsServer::info`.
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct FsLocationsServerFlags: u8 {
+        const WRITABLE = 0x01;
+        /// The entry is for the server the request was sent to.
+        const CUR_REQ = 0x02;
+        const ABSENT = 0x04;
+        const GOING = 0x08;
+        const SPLIT = 0x10;
+    }
+}
+
+/// What each byte of `FsLocationsServer::info` holds.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum FsLocationsInfoByte {
+    GFlags = 0,
+    TFlags = 1,
+    ClSimul = 2,
+    ClHandle = 3,
+    ClFileId = 4,
+    ClWriteVer = 5,
+    ClChange = 6,
+    ClReadDir = 7,
+    ReadRank = 8,
+    WriteRank = 9,
+    ReadOrder = 10,
+    WriteOrder = 11,
+}
+
+impl FsLocationsServer {
+    /// A byte of `info`, which is 0 if the server sent fewer.
+    pub fn info_byte(&self, byte: FsLocationsInfoByte) -> u8 {
+        self.info.get(byte as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_info_byte(&mut self, byte: FsLocationsInfoByte, value: u8) {
+        let index = byte as usize;
+        if self.info.len() <= index {
+            self.info.resize(index + 1, 0);
+        }
+        self.info[index] = value;
+    }
+
+    pub fn flags(&self) -> FsLocationsServerFlags {
+        FsLocationsServerFlags::from_bits_truncate(self.info_byte(FsLocationsInfoByte::GFlags))
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct FsLocationsItem {
     pub entries: Vec<FsLocationsServer>,
@@ -803,7 +860,7 @@ pub struct Lease(pub u32);
 #[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
 pub struct Change(pub u64);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub struct FileId(pub u64);
 
 #[derive(
@@ -1484,16 +1541,12 @@ pub struct GetDirDelegationArgs {
 #[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
 pub struct DeviceId(#[serde(with = "fixed_length")] pub [u8; 16]);
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
-pub struct Util(pub u32);
-
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct GetDeviceInfoArgs {
     pub device_id: DeviceId,
-    pub util: Util,
-    pub first_stripe_index: u32,
-    pub pattern_offset: u64,
-    pub fh_list: Vec<FileHandle>,
+    pub layout_type: LayoutType,
+    pub max_count: u32,
+    pub notify_types: EnumSet<NotifyDeviceIdType>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -1575,6 +1628,38 @@ pub struct LayoutReturnArgs {
     pub layout_return: LayoutReturn,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct DeviceError {
+    pub device_id: DeviceId,
+    pub status: StatusResult<()>,
+    pub opnum: OperationId,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LayoutErrorArgs {
+    pub offset: u64,
+    pub length: u64,
+    pub state_id: StateId,
+    pub errors: Vec<DeviceError>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub struct IoInfo {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LayoutStatsArgs {
+    pub offset: u64,
+    pub length: u64,
+    pub state_id: StateId,
+    pub read: IoInfo,
+    pub write: IoInfo,
+    pub device_id: DeviceId,
+    pub layout_update: LayoutUpdate,
+}
+
 #[derive(SerializeWithDiscriminant, DeserializeWithDiscriminant, PartialEq, Eq, Clone, Debug)]
 #[repr(u32)]
 pub enum SecInfoStyle {
@@ -1706,6 +1791,8 @@ pub enum OperationId {
     WantDelegation = 56,
     DestroyClientId = 57,
     ReclaimComplete = 58,
+    LayoutError = 64,
+    LayoutStats = 65,
 }
 
 #[derive(
@@ -1764,6 +1851,8 @@ pub enum ArgOp {
     WantDelegation(WantDelegationArgs) = OperationId::WantDelegation as u32,
     DestroyClientId(DestroyClientIdArgs) = OperationId::DestroyClientId as u32,
     ReclaimComplete(ReclaimCompleteArgs) = OperationId::ReclaimComplete as u32,
+    LayoutError(LayoutErrorArgs) = OperationId::LayoutError as u32,
+    LayoutStats(LayoutStatsArgs) = OperationId::LayoutStats as u32,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -1797,7 +1886,7 @@ pub struct CommitRes {
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
-pub struct ChangeId(u64);
+pub struct ChangeId(pub u64);
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct ChangeInfo {
@@ -2097,6 +2186,247 @@ pub struct LayoutGetRes {
     pub layout: Vec<Layout>,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Util(pub u32);
+
+impl Util {
+    pub const DENSE: u32 = 0x00000001;
+    pub const COMMIT_THRU_MDS: u32 = 0x00000002;
+    pub const STRIPE_UNIT_SIZE_MASK: u32 = 0xFFFFFFC0;
+
+    pub fn stripe_unit(&self) -> u32 {
+        self.0 & Self::STRIPE_UNIT_SIZE_MASK
+    }
+
+    pub fn is_dense(&self) -> bool {
+        self.0 & Self::DENSE != 0
+    }
+
+    pub fn commit_through_mds(&self) -> bool {
+        self.0 & Self::COMMIT_THRU_MDS != 0
+    }
+}
+
+/// The body of a `LayoutContent` with type `NfsV41Files`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FilesLayout {
+    pub device_id: DeviceId,
+    pub util: Util,
+    pub first_stripe_index: u32,
+    pub pattern_offset: u64,
+    pub fh_list: Vec<FileHandle>,
+}
+
+/// Where a file offset lands when striped with a `FilesLayout`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct StripeLocation {
+    /// Which stripe the offset lands in. For a `FilesLayout` this indexes `stripe_indices` (and
+    /// `fh_list` when it has more than one handle), for a `FlexFilesLayout` the mirror's data
+    /// servers.
+    pub stripe_index: usize,
+    /// The offset to use for I/O on the data server
+    pub offset: u64,
+    /// How many bytes are left in this stripe unit
+    pub remaining: u64,
+}
+
+impl FilesLayout {
+    /// Maps a file offset to a data server as described in RFC 5661 section 13.4. Returns `None`
+    /// for offsets before `pattern_offset` or when the layout has no stripe unit.
+    pub fn locate(&self, stripe_count: usize, offset: u64) -> Option<StripeLocation> {
+        let unit = self.util.stripe_unit() as u64;
+        if unit == 0 || stripe_count == 0 {
+            return None;
+        }
+        let relative = offset.checked_sub(self.pattern_offset)?;
+        let stripe_number = relative / unit;
+        let stripe_index =
+            ((stripe_number + self.first_stripe_index as u64) % stripe_count as u64) as usize;
+
+        let offset = if self.util.is_dense() {
+            (stripe_number / stripe_count as u64) * unit + relative % unit
+        } else {
+            offset
+        };
+        Some(StripeLocation {
+            stripe_index,
+            offset,
+            remaining: unit - relative % unit,
+        })
+    }
+
+    pub fn file_handle(&self, stripe_index: usize) -> Option<&FileHandle> {
+        if self.fh_list.len() == 1 {
+            self.fh_list.first()
+        } else {
+            self.fh_list.get(stripe_index)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct NetAddr {
+    pub net_id: String,
+    pub addr: String,
+}
+
+impl NetAddr {
+    /// Parses the universal address format, where the port follows the host as two more
+    /// dot-separated octets.
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        let mut parts = self.addr.rsplitn(3, '.');
+        let low: u8 = parts.next()?.parse().ok()?;
+        let high: u8 = parts.next()?.parse().ok()?;
+        let ip: IpAddr = parts.next()?.parse().ok()?;
+        Some(SocketAddr::new(ip, u16::from_be_bytes([high, low])))
+    }
+}
+
+impl From<SocketAddr> for NetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let [high, low] = addr.port().to_be_bytes();
+        Self {
+            net_id: if addr.is_ipv4() { "tcp" } else { "tcp6" }.into(),
+            addr: format!("{}.{high}.{low}", addr.ip()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct MultipathList(pub Vec<NetAddr>);
+
+/// The body of a `DeviceAddr` with type `NfsV41Files`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FilesLayoutDsAddr {
+    pub stripe_indices: Vec<u32>,
+    pub multipath_ds_list: Vec<MultipathList>,
+}
+
+bitflags! {
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub struct FlexFilesFlags: u32 {
+        const NO_LAYOUTCOMMIT   = 0x00000001;
+        const NO_IO_THRU_MDS    = 0x00000002;
+        const NO_READ_IO        = 0x00000004;
+        const WRITE_ONE_MIRROR  = 0x00000008;
+    }
+}
+
+impl_serde_for_bitflags!(FlexFilesFlags);
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesDeviceVersion {
+    pub version: u32,
+    pub minor_version: u32,
+    pub rsize: u32,
+    pub wsize: u32,
+    pub tightly_coupled: bool,
+}
+
+/// The body of a `DeviceAddr` with type `FlexFiles`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesDeviceAddr {
+    pub netaddrs: MultipathList,
+    pub versions: Vec<FlexFilesDeviceVersion>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesDataServer {
+    pub device_id: DeviceId,
+    pub efficiency: u32,
+    pub state_id: StateId,
+    pub fh_versions: Vec<FileHandle>,
+    pub user: String,
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesMirror {
+    pub data_servers: Vec<FlexFilesDataServer>,
+}
+
+/// The body of a `LayoutContent` with type `FlexFiles`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesLayout {
+    pub stripe_unit: u64,
+    pub mirrors: Vec<FlexFilesMirror>,
+    pub flags: FlexFilesFlags,
+    pub stats_collect_hint: u32,
+}
+
+impl FlexFilesLayout {
+    /// Maps a file offset to a data server within a mirror. Mirrors with more than one data
+    /// server are striped densely.
+    pub fn locate(&self, mirror: usize, offset: u64) -> Option<StripeLocation> {
+        let count = self.mirrors.get(mirror)?.data_servers.len() as u64;
+        if count == 0 {
+            return None;
+        }
+        if count == 1 || self.stripe_unit == 0 {
+            return Some(StripeLocation {
+                stripe_index: 0,
+                offset,
+                remaining: u64::MAX - offset,
+            });
+        }
+        let unit = self.stripe_unit;
+        let stripe_number = offset / unit;
+        Some(StripeLocation {
+            stripe_index: (stripe_number % count) as usize,
+            offset: (stripe_number / count) * unit + offset % unit,
+            remaining: unit - offset % unit,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesIoError {
+    pub offset: u64,
+    pub length: u64,
+    pub state_id: StateId,
+    pub errors: Vec<DeviceError>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct FlexFilesIoLatency {
+    pub ops_requested: u64,
+    pub bytes_requested: u64,
+    pub ops_completed: u64,
+    pub bytes_completed: u64,
+    pub bytes_not_delivered: u64,
+    pub total_busy_time: Time,
+    pub aggregate_completion_time: Time,
+}
+
+/// The body of a `LayoutUpdate` with type `FlexFiles`, sent with LAYOUTSTATS
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesLayoutUpdate {
+    pub addr: NetAddr,
+    pub file_handle: FileHandle,
+    pub read: FlexFilesIoLatency,
+    pub write: FlexFilesIoLatency,
+    pub duration: Time,
+    pub local: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FlexFilesIoStats {
+    pub offset: u64,
+    pub length: u64,
+    pub state_id: StateId,
+    pub read: IoInfo,
+    pub write: IoInfo,
+    pub device_id: DeviceId,
+    pub layout_update: FlexFilesLayoutUpdate,
+}
+
+/// The body of a `LayoutReturnFile` with type `FlexFiles`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct FlexFilesLayoutReturn {
+    pub io_errors: Vec<FlexFilesIoError>,
+    pub io_stats: Vec<FlexFilesIoStats>,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct LayoutReturnRes {
     pub state_id: Option<StateId>,
@@ -2205,4 +2535,6 @@ pub enum ResOp {
     WantDelegation(StatusResult<WantDelegationRes>) = OperationId::WantDelegation as u32,
     DestroyClientId(StatusResult<()>) = OperationId::DestroyClientId as u32,
     ReclaimComplete(StatusResult<()>) = OperationId::ReclaimComplete as u32,
+    LayoutError(StatusResult<()>) = OperationId::LayoutError as u32,
+    LayoutStats(StatusResult<()>) = OperationId::LayoutStats as u32,
 }
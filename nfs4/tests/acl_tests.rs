@@ -0,0 +1,197 @@
+// copyright 2023 Remi Bernotavicius
+
+use nfs4::{
+    Ace, AceFlags, AceMask, AceType, Acl, AclBuilder, AclFlags, AclParseError, AclWithFlags,
+    Identity, Mode,
+};
+
+#[test]
+fn ace_text_round_trip() {
+    let text = "A:fdg:staff@example.com:rwaxtTcCy";
+    let ace: Ace = text.parse().unwrap();
+    assert_eq!(
+        ace,
+        Ace {
+            type_: AceType::AccessAllowed,
+            flags: AceFlags::FILE_INHERIT_ACE
+                | AceFlags::DIRECTORY_INHERIT_ACE
+                | AceFlags::IDENTIFIER_GROUP,
+            access_mask: AceMask::READ_DATA
+                | AceMask::WRITE_DATA
+                | AceMask::APPEND_DATA
+                | AceMask::EXECUTE
+                | AceMask::READ_ATTRIBUTES
+                | AceMask::WRITE_ATTRIBUTES
+                | AceMask::READ_ACL
+                | AceMask::WRITE_ACL
+                | AceMask::SYNCHRONIZE,
+            who: Identity("staff@example.com".into()),
+        }
+    );
+    assert_eq!(ace.to_string(), text);
+}
+
+#[test]
+fn ace_generic_aliases() {
+    let ace: Ace = "D::EVERYONE@:RX".parse().unwrap();
+    assert_eq!(ace.type_, AceType::AccessDenied);
+    assert_eq!(
+        ace.access_mask,
+        AceMask::GENERIC_READ | AceMask::GENERIC_EXECUTE
+    );
+    assert_eq!(ace.to_string(), "D::EVERYONE@:rxtncy");
+}
+
+#[test]
+fn ace_parse_errors() {
+    assert_eq!(
+        "A::OWNER@".parse::<Ace>(),
+        Err(AclParseError::MissingField("permissions"))
+    );
+    assert_eq!(
+        "Q::OWNER@:r".parse::<Ace>(),
+        Err(AclParseError::UnknownType("Q".into()))
+    );
+    assert_eq!(
+        "A:z:OWNER@:r".parse::<Ace>(),
+        Err(AclParseError::UnknownFlag('z'))
+    );
+    assert_eq!(
+        "A::OWNER@:rq".parse::<Ace>(),
+        Err(AclParseError::UnknownPermission('q'))
+    );
+    assert_eq!("A:::r".parse::<Ace>(), Err(AclParseError::EmptyPrincipal));
+}
+
+#[test]
+fn acl_parses_getfacl_output() {
+    let text = "\
+# file: /files/a_file
+A::OWNER@:rwatTcCy
+A:g:GROUP@:rtcy
+
+A::EVERYONE@:rtcy
+";
+    let acl: Acl = text.parse().unwrap();
+    assert_eq!(acl.aces.len(), 3);
+    assert_eq!(
+        acl.to_string(),
+        "A::OWNER@:rwatTcCy\nA:g:GROUP@:rtcy\nA::EVERYONE@:rtcy\n"
+    );
+
+    let comma_separated: Acl = "A::OWNER@:rwatTcCy,A:g:GROUP@:rtcy,A::EVERYONE@:rtcy"
+        .parse()
+        .unwrap();
+    assert_eq!(comma_separated, acl);
+}
+
+#[test]
+fn mode_to_acl_and_back() {
+    for mode in [0o755, 0o644, 0o600, 0o000, 0o777, 0o057, 0o704, 0o640] {
+        for is_dir in [false, true] {
+            let acl = Acl::from_mode(Mode(mode), is_dir);
+            assert_eq!(acl.to_mode(), Mode(mode), "mode = {mode:o}, acl =\n{acl}");
+        }
+    }
+}
+
+#[test]
+fn mode_to_acl_denies_owner() {
+    let acl = Acl::from_mode(Mode(0o077), false);
+    assert_eq!(
+        acl.aces[0],
+        Ace::deny(
+            Identity::owner(),
+            AceMask::READ_DATA | AceMask::WRITE_DATA | AceMask::APPEND_DATA | AceMask::EXECUTE
+        )
+    );
+}
+
+#[test]
+fn builder_edits() {
+    let acl = AclBuilder::from(Acl::from_mode(Mode(0o644), false))
+        .allow("alice@example.com", AceMask::READ_DATA)
+        .deny("bob@example.com", AceMask::WRITE_DATA)
+        .move_ace(4, 0)
+        .remove_who(Identity::EVERYONE)
+        .build();
+
+    let who: Vec<_> = acl.aces.iter().map(|a| a.who.0.as_str()).collect();
+    assert_eq!(
+        who,
+        ["bob@example.com", "OWNER@", "GROUP@", "alice@example.com"]
+    );
+}
+
+#[test]
+fn canonical_ordering() {
+    let mut acl = AclBuilder::new()
+        .ace(Ace::allow("a", AceMask::READ_DATA).with_flags(AceFlags::INHERITED_ACE))
+        .allow("b", AceMask::READ_DATA)
+        .ace(Ace::deny("c", AceMask::WRITE_DATA).with_flags(AceFlags::INHERITED_ACE))
+        .deny("d", AceMask::WRITE_DATA)
+        .allow("e", AceMask::EXECUTE)
+        .build();
+    assert!(!acl.is_canonical());
+
+    acl.canonicalize();
+    assert!(acl.is_canonical());
+
+    let who: Vec<_> = acl.aces.iter().map(|a| a.who.0.as_str()).collect();
+    assert_eq!(who, ["d", "b", "e", "c", "a"]);
+}
+
+#[test]
+fn inheritance() {
+    let parent = AclBuilder::new()
+        .ace(Ace::allow("files", AceMask::READ_DATA).with_flags(AceFlags::FILE_INHERIT_ACE))
+        .ace(
+            Ace::allow("dirs", AceMask::EXECUTE)
+                .with_flags(AceFlags::DIRECTORY_INHERIT_ACE | AceFlags::INHERIT_ONLY_ACE),
+        )
+        .ace(
+            Ace::allow("once", AceMask::WRITE_DATA)
+                .with_flags(AceFlags::DIRECTORY_INHERIT_ACE | AceFlags::NO_PROPAGATE_INHERIT_ACE),
+        )
+        .allow("none", AceMask::DELETE)
+        .build();
+
+    let file = parent.inherited(false);
+    assert_eq!(
+        file.aces,
+        [Ace::allow("files", AceMask::READ_DATA).with_flags(AceFlags::INHERITED_ACE)]
+    );
+
+    let dir = parent.inherited(true);
+    assert_eq!(
+        dir.aces,
+        [
+            Ace::allow("files", AceMask::READ_DATA).with_flags(
+                AceFlags::FILE_INHERIT_ACE | AceFlags::INHERIT_ONLY_ACE | AceFlags::INHERITED_ACE
+            ),
+            Ace::allow("dirs", AceMask::EXECUTE)
+                .with_flags(AceFlags::DIRECTORY_INHERIT_ACE | AceFlags::INHERITED_ACE),
+            Ace::allow("once", AceMask::WRITE_DATA).with_flags(AceFlags::INHERITED_ACE),
+        ]
+    );
+}
+
+#[test]
+fn acl_with_flags_serialization_round_trip() {
+    let expected_acl = AclWithFlags {
+        flags: AclFlags::AUTO_INHERIT,
+        aces: vec![Ace::allow("OWNER@", AceMask::READ_DATA)],
+    };
+
+    let expected = [
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x4f, 0x57, 0x4e, 0x45, 0x52, 0x40,
+        0x00, 0x00,
+    ];
+
+    let actual = serde_xdr::to_bytes(&expected_acl).unwrap();
+    assert_eq!(&expected[..], &actual[..]);
+
+    let actual_acl: AclWithFlags = serde_xdr::from_bytes(&expected[..]).unwrap();
+    assert_eq!(expected_acl, actual_acl);
+}
@@ -0,0 +1,125 @@
+// copyright 2023 Remi Bernotavicius
+
+use nfs4::{
+    AttrRequest, Attributes, EnumSet, FileAttribute, FileAttributeId, FileAttributes, FileType,
+    Mode, SetTime, Time,
+};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn file_attributes_round_trip() {
+    let file_attrs: FileAttributes = [
+        FileAttribute::Type(FileType::Regular),
+        FileAttribute::Size(4096),
+        FileAttribute::Mode(Mode(0o644)),
+        FileAttribute::Owner("1000".into()),
+        FileAttribute::TimeModify(Time {
+            seconds: 1685326502,
+            nseconds: 281570962,
+        }),
+    ]
+    .into_iter()
+    .collect();
+
+    let attrs = Attributes::from(file_attrs.clone());
+    assert_eq!(attrs.type_, Some(FileType::Regular));
+    assert_eq!(attrs.size, Some(4096));
+    assert_eq!(attrs.mode, Some(Mode(0o644)));
+    assert_eq!(attrs.owner.as_deref(), Some("1000"));
+    assert_eq!(attrs.num_links, None);
+    assert!(attrs.is_file());
+    assert!(!attrs.is_dir());
+
+    let ids: EnumSet<FileAttributeId> = [
+        FileAttributeId::Type,
+        FileAttributeId::Size,
+        FileAttributeId::Mode,
+        FileAttributeId::Owner,
+        FileAttributeId::TimeModify,
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(attrs.ids(), ids);
+
+    assert_eq!(FileAttributes::from(attrs), file_attrs);
+}
+
+#[test]
+fn attr_request_builder() {
+    let request: EnumSet<FileAttributeId> = AttrRequest::new().size().type_().mode().into();
+    let expected: EnumSet<FileAttributeId> = [
+        FileAttributeId::Type,
+        FileAttributeId::Size,
+        FileAttributeId::Mode,
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(request, expected);
+}
+
+#[test]
+fn time_system_time_conversion() {
+    let t = Time {
+        seconds: 1685326502,
+        nseconds: 281570962,
+    };
+    let st = t.to_system_time().unwrap();
+    assert_eq!(
+        st,
+        UNIX_EPOCH + Duration::from_secs(1685326502) + Duration::from_nanos(281570962)
+    );
+    assert_eq!(Time::from(st), t);
+
+    let before_epoch = UNIX_EPOCH - Duration::from_millis(1500);
+    let t = Time::from(before_epoch);
+    assert_eq!(
+        t,
+        Time {
+            seconds: -2,
+            nseconds: 500_000_000
+        }
+    );
+    assert_eq!(t.to_system_time(), Some(before_epoch));
+}
+
+#[test]
+fn settable_attributes() {
+    let modify = Time {
+        seconds: 100,
+        nseconds: 0,
+    };
+    let attrs = Attributes::default()
+        .with_size(10)
+        .with_num_links(3)
+        .with_time_modify(modify)
+        .with_mode(Mode(0o600))
+        .into_settable();
+    assert_eq!(
+        attrs,
+        Attributes::default()
+            .with_size(10)
+            .with_mode(Mode(0o600))
+            .with_time_modify_set(SetTime::SetToClientTime(modify))
+    );
+}
+
+#[test]
+fn from_metadata() {
+    let path = std::env::temp_dir().join(format!("nfs4_attributes_test_{}", std::process::id()));
+    std::fs::write(&path, b"hello").unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let attrs = Attributes::from(&metadata);
+    assert!(attrs.is_file());
+    assert_eq!(attrs.size, Some(5));
+    assert_eq!(attrs.modified(), Some(metadata.modified().unwrap()));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        assert_eq!(
+            attrs.permissions().unwrap().mode(),
+            metadata.permissions().mode() & 0o7777
+        );
+    }
+}
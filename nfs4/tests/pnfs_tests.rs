@@ -0,0 +1,224 @@
+// copyright 2023 Remi Bernotavicius
+
+use nfs4::{
+    DeviceError, DeviceId, FileHandle, FilesLayout, FilesLayoutDsAddr, FlexFilesDataServer,
+    FlexFilesFlags, FlexFilesIoError, FlexFilesLayout, FlexFilesLayoutReturn, FlexFilesMirror,
+    MultipathList, NetAddr, OperationId, StateId, StatusError, StatusResult, StripeLocation, Util,
+};
+use std::net::SocketAddr;
+
+fn layout(util: u32, first_stripe_index: u32, pattern_offset: u64) -> FilesLayout {
+    FilesLayout {
+        device_id: DeviceId([7; 16]),
+        util: Util(util),
+        first_stripe_index,
+        pattern_offset,
+        fh_list: vec![FileHandle(vec![1, 2, 3])],
+    }
+}
+
+#[test]
+fn util_bits() {
+    let util = Util(0x10000 | Util::DENSE | Util::COMMIT_THRU_MDS);
+    assert_eq!(util.stripe_unit(), 0x10000);
+    assert!(util.is_dense());
+    assert!(util.commit_through_mds());
+    assert!(!Util(0x10000).is_dense());
+}
+
+#[test]
+fn locate_sparse() {
+    let layout = layout(4096, 0, 0);
+    assert_eq!(
+        layout.locate(3, 0),
+        Some(StripeLocation {
+            stripe_index: 0,
+            offset: 0,
+            remaining: 4096
+        })
+    );
+    assert_eq!(
+        layout.locate(3, 4096 * 4 + 10),
+        Some(StripeLocation {
+            stripe_index: 1,
+            offset: 4096 * 4 + 10,
+            remaining: 4086
+        })
+    );
+}
+
+#[test]
+fn locate_dense() {
+    let layout = layout(4096 | Util::DENSE, 0, 0);
+    let location = layout.locate(3, 4096 * 4 + 10).unwrap();
+    assert_eq!(location.stripe_index, 1);
+    assert_eq!(location.offset, 4096 + 10);
+
+    let location = layout.locate(3, 4096 * 2).unwrap();
+    assert_eq!(location.stripe_index, 2);
+    assert_eq!(location.offset, 0);
+}
+
+#[test]
+fn locate_first_stripe_index_and_pattern_offset() {
+    let layout = layout(4096 | Util::DENSE, 2, 1000);
+    assert_eq!(layout.locate(3, 999), None);
+
+    let location = layout.locate(3, 1000).unwrap();
+    assert_eq!(location.stripe_index, 2);
+    assert_eq!(location.offset, 0);
+
+    let location = layout.locate(3, 1000 + 4096).unwrap();
+    assert_eq!(location.stripe_index, 0);
+    assert_eq!(location.offset, 0);
+}
+
+#[test]
+fn file_handle_per_stripe() {
+    let mut layout = layout(4096, 0, 0);
+    assert_eq!(layout.file_handle(2), Some(&FileHandle(vec![1, 2, 3])));
+
+    layout.fh_list = vec![FileHandle(vec![1]), FileHandle(vec![2])];
+    assert_eq!(layout.file_handle(1), Some(&FileHandle(vec![2])));
+    assert_eq!(layout.file_handle(2), None);
+}
+
+#[test]
+fn net_addr_universal_format() {
+    let addr: SocketAddr = "192.168.1.20:2049".parse().unwrap();
+    let net_addr = NetAddr::from(addr);
+    assert_eq!(
+        net_addr,
+        NetAddr {
+            net_id: "tcp".into(),
+            addr: "192.168.1.20.8.1".into()
+        }
+    );
+    assert_eq!(net_addr.to_socket_addr(), Some(addr));
+
+    let addr: SocketAddr = "[fe80::1]:20049".parse().unwrap();
+    assert_eq!(NetAddr::from(addr).to_socket_addr(), Some(addr));
+
+    let bad = NetAddr {
+        net_id: "tcp".into(),
+        addr: "not-an-address".into(),
+    };
+    assert_eq!(bad.to_socket_addr(), None);
+}
+
+#[test]
+fn files_layout_serialization_round_trip() {
+    let layout = layout(0x10000 | Util::DENSE, 1, 0);
+    let expected = [
+        0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07,
+        0x07, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x02, 0x03, 0x00,
+    ];
+
+    let actual = serde_xdr::to_bytes(&layout).unwrap();
+    assert_eq!(&expected[..], &actual[..]);
+
+    let actual_layout: FilesLayout = serde_xdr::from_bytes(&expected[..]).unwrap();
+    assert_eq!(layout, actual_layout);
+}
+
+#[test]
+fn device_addr_serialization_round_trip() {
+    let addr = FilesLayoutDsAddr {
+        stripe_indices: vec![1, 0],
+        multipath_ds_list: vec![
+            MultipathList(vec!["10.0.0.1:2049".parse::<SocketAddr>().unwrap().into()]),
+            MultipathList(vec![]),
+        ],
+    };
+    let serialized = serde_xdr::to_bytes(&addr).unwrap();
+    let actual: FilesLayoutDsAddr = serde_xdr::from_bytes(&serialized[..]).unwrap();
+    assert_eq!(addr, actual);
+}
+
+fn flex_files_layout(data_servers_per_mirror: &[usize]) -> FlexFilesLayout {
+    FlexFilesLayout {
+        stripe_unit: 4096,
+        mirrors: data_servers_per_mirror
+            .iter()
+            .map(|count| FlexFilesMirror {
+                data_servers: (0..*count)
+                    .map(|i| FlexFilesDataServer {
+                        device_id: DeviceId([i as u8; 16]),
+                        efficiency: 1,
+                        state_id: StateId::anonymous(),
+                        fh_versions: vec![FileHandle(vec![i as u8])],
+                        user: "0".into(),
+                        group: "0".into(),
+                    })
+                    .collect(),
+            })
+            .collect(),
+        flags: FlexFilesFlags::NO_LAYOUTCOMMIT,
+        stats_collect_hint: 0,
+    }
+}
+
+#[test]
+fn flex_files_locate() {
+    let layout = flex_files_layout(&[1, 3, 0]);
+
+    // A single data server mirror isn't striped.
+    assert_eq!(
+        layout.locate(0, 10000),
+        Some(StripeLocation {
+            stripe_index: 0,
+            offset: 10000,
+            remaining: u64::MAX - 10000
+        })
+    );
+
+    assert_eq!(
+        layout.locate(1, 4096 * 4 + 10),
+        Some(StripeLocation {
+            stripe_index: 1,
+            offset: 4096 + 10,
+            remaining: 4096 - 10
+        })
+    );
+    assert_eq!(layout.locate(2, 0), None);
+    assert_eq!(layout.locate(3, 0), None);
+}
+
+#[test]
+fn flex_files_layout_serialization_round_trip() {
+    let layout = flex_files_layout(&[1, 2]);
+    let serialized = serde_xdr::to_bytes(&layout).unwrap();
+    let actual: FlexFilesLayout = serde_xdr::from_bytes(&serialized[..]).unwrap();
+    assert_eq!(layout, actual);
+}
+
+#[test]
+fn flex_files_layout_return_serialization() {
+    let layout_return = FlexFilesLayoutReturn {
+        io_errors: vec![FlexFilesIoError {
+            offset: 1,
+            length: 2,
+            state_id: StateId::anonymous(),
+            errors: vec![DeviceError {
+                device_id: DeviceId([3; 16]),
+                status: StatusResult::Err(StatusError::NxIo),
+                opnum: OperationId::Read,
+            }],
+        }],
+        io_stats: vec![],
+    };
+    let expected = [
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x03, 0x03, 0x03,
+        0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x00, 0x00, 0x00, 0x06,
+        0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    let actual = serde_xdr::to_bytes(&layout_return).unwrap();
+    assert_eq!(&expected[..], &actual[..]);
+
+    let actual_return: FlexFilesLayoutReturn = serde_xdr::from_bytes(&expected[..]).unwrap();
+    assert_eq!(layout_return, actual_return);
+}
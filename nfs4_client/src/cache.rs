@@ -0,0 +1,339 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! An optional cache of attributes and looked up names, so walking a tree doesn't pay a round
+//! trip for every component of every path. Attributes are trusted for a while, like the `ac*`
+//! mount options, and the names in a directory for as long as its `Change` attribute is the one
+//! they were looked up under. Changes made through this client move the cached `Change` along
+//! with the `ChangeInfo` the server returns, so they don't throw the directory away.
+
+use super::{Client, Result};
+use nfs4::{
+    Change, ChangeInfo, EnumSet, FileAttribute, FileAttributeId, FileAttributes, FileHandle,
+    FileType, ToId as _,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use sun_rpc_client::Transport;
+
+/// How long cached attributes are used before asking the server again. Each time they come back
+/// with the same `Change` the time doubles, from the minimum up to the maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub acregmin: Duration,
+    pub acregmax: Duration,
+    pub acdirmin: Duration,
+    pub acdirmax: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            acregmin: Duration::from_secs(3),
+            acregmax: Duration::from_secs(60),
+            acdirmin: Duration::from_secs(30),
+            acdirmax: Duration::from_secs(60),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The same timeout for everything, without growing.
+    pub fn fixed(timeout: Duration) -> Self {
+        Self {
+            acregmin: timeout,
+            acregmax: timeout,
+            acdirmin: timeout,
+            acdirmax: timeout,
+        }
+    }
+}
+
+/// What a name in a directory refers to.
+pub(crate) type Entry = (FileHandle, Option<FileType>);
+
+struct CachedAttributes {
+    attrs: FileAttributes,
+    fetched: Instant,
+    timeout: Duration,
+}
+
+struct CachedDirectory {
+    /// The directory's `Change` when the entries were looked up.
+    change: Change,
+    entries: HashMap<String, Entry>,
+}
+
+pub(crate) fn change(attrs: &FileAttributes) -> Option<Change> {
+    attrs.get_as(FileAttributeId::Change).copied()
+}
+
+pub(crate) fn file_type(attrs: &FileAttributes) -> Option<FileType> {
+    attrs.get_as(FileAttributeId::Type).cloned()
+}
+
+fn change_id(change: &ChangeInfo) -> (Change, Change) {
+    (Change(change.before.0), Change(change.after.0))
+}
+
+pub(crate) struct MetadataCache {
+    config: CacheConfig,
+    attributes: HashMap<FileHandle, CachedAttributes>,
+    directories: HashMap<FileHandle, CachedDirectory>,
+    root: Option<FileHandle>,
+}
+
+impl MetadataCache {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            attributes: HashMap::new(),
+            directories: HashMap::new(),
+            root: None,
+        }
+    }
+
+    fn fresh_attributes(&self, handle: &FileHandle) -> Option<&FileAttributes> {
+        let cached = self.attributes.get(handle)?;
+        (cached.fetched.elapsed() < cached.timeout).then_some(&cached.attrs)
+    }
+
+    /// The requested attributes, if all of them are cached and haven't timed out.
+    pub(crate) fn get_attributes(
+        &self,
+        handle: &FileHandle,
+        request: &EnumSet<FileAttributeId>,
+    ) -> Option<FileAttributes> {
+        let attrs = self.fresh_attributes(handle)?;
+        request
+            .clone()
+            .into_iter()
+            .map(|id| attrs.get(id).cloned())
+            .collect()
+    }
+
+    /// Records attributes just fetched from the server. If the `Change` is the same as before,
+    /// the ones cached earlier which weren't fetched this time are kept.
+    pub(crate) fn insert_attributes(&mut self, handle: FileHandle, mut attrs: FileAttributes) {
+        let (min, max) = match file_type(&attrs) {
+            Some(FileType::Directory) => (self.config.acdirmin, self.config.acdirmax),
+            _ => (self.config.acregmin, self.config.acregmax),
+        };
+        let timeout = match self.attributes.remove(&handle) {
+            Some(old) if change(&attrs).is_some() && change(&old.attrs) == change(&attrs) => {
+                for attr in old.attrs {
+                    if attrs.get(attr.to_id()).is_none() {
+                        attrs.insert(attr);
+                    }
+                }
+                (old.timeout * 2).clamp(min, max)
+            }
+            _ => min,
+        };
+        self.attributes.insert(
+            handle,
+            CachedAttributes {
+                attrs,
+                fetched: Instant::now(),
+                timeout,
+            },
+        );
+    }
+
+    /// What `name` in `dir` refers to, if the directory's attributes are fresh and it hasn't
+    /// changed since the name was looked up.
+    pub(crate) fn look_up(&self, dir: &FileHandle, name: &str) -> Option<Entry> {
+        let dir_change = change(self.fresh_attributes(dir)?)?;
+        let cached = self.directories.get(dir)?;
+        (cached.change == dir_change)
+            .then(|| cached.entries.get(name).cloned())
+            .flatten()
+    }
+
+    /// Records a name looked up in `dir` while it had the given `Change`.
+    pub(crate) fn insert_entry(
+        &mut self,
+        dir: FileHandle,
+        dir_change: Change,
+        name: String,
+        entry: Entry,
+    ) {
+        let cached = self
+            .directories
+            .entry(dir)
+            .or_insert_with(|| CachedDirectory {
+                change: dir_change,
+                entries: HashMap::new(),
+            });
+        if cached.change != dir_change {
+            cached.change = dir_change;
+            cached.entries.clear();
+        }
+        cached.entries.insert(name, entry);
+    }
+
+    /// Applies a change this client made to `dir`. If nothing else changed the directory in
+    /// between, its cached `Change` moves along and `edit` updates the names. Otherwise all that
+    /// is cached about the directory is dropped.
+    pub(crate) fn update_directory(
+        &mut self,
+        dir: &FileHandle,
+        change_info: &ChangeInfo,
+        edit: impl FnOnce(&mut HashMap<String, Entry>),
+    ) {
+        let (before, after) = change_id(change_info);
+        let attrs_current = self
+            .attributes
+            .get(dir)
+            .is_some_and(|c| change(&c.attrs) == Some(before));
+        let entries_current = self
+            .directories
+            .get(dir)
+            .is_some_and(|c| c.change == before);
+        if !change_info.atomic || !attrs_current || !entries_current {
+            self.invalidate(dir);
+            return;
+        }
+
+        // Only the type and `Change` are known now, the rest has to be fetched again.
+        let cached = self.attributes.get_mut(dir).unwrap();
+        let mut attrs = FileAttributes::default();
+        if let Some(file_type) = file_type(&cached.attrs) {
+            attrs.insert(FileAttribute::Type(file_type));
+        }
+        attrs.insert(FileAttribute::Change(after));
+        cached.attrs = attrs;
+
+        let cached = self.directories.get_mut(dir).unwrap();
+        cached.change = after;
+        edit(&mut cached.entries);
+    }
+
+    pub(crate) fn invalidate_attributes(&mut self, handle: &FileHandle) {
+        self.attributes.remove(handle);
+    }
+
+    pub(crate) fn invalidate(&mut self, handle: &FileHandle) {
+        self.attributes.remove(handle);
+        self.directories.remove(handle);
+    }
+
+    pub(crate) fn root(&self) -> Option<FileHandle> {
+        self.root.clone()
+    }
+
+    pub(crate) fn set_root(&mut self, root: FileHandle) {
+        self.root = Some(root);
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Starts caching attributes and looked up names. Anything already cached is dropped.
+    pub fn enable_cache(&mut self, config: CacheConfig) {
+        self.cache = Some(MetadataCache::new(config));
+    }
+
+    pub fn disable_cache(&mut self) {
+        self.cache = None;
+    }
+
+    /// Drops the cached attributes of the file and, for a directory, the names looked up in it.
+    pub fn invalidate(&mut self, handle: FileHandle) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate(&handle);
+        }
+    }
+
+    /// Drops everything cached.
+    pub fn invalidate_all(&mut self) {
+        if let Some(cache) = &mut self.cache {
+            *cache = MetadataCache::new(cache.config);
+        }
+    }
+
+    /// Fetches attributes unless they are all cached. `Type` and `Change` are always asked for
+    /// too, since the cache needs them.
+    pub(crate) fn fetch_attributes(
+        &mut self,
+        handle: FileHandle,
+        mut request: EnumSet<FileAttributeId>,
+    ) -> Result<FileAttributes> {
+        let Some(cache) = &self.cache else {
+            return self.fetch_attributes_uncached(handle, request);
+        };
+        if let Some(attrs) = cache.get_attributes(&handle, &request) {
+            return Ok(attrs);
+        }
+        let wanted = request.clone();
+        for id in [FileAttributeId::Type, FileAttributeId::Change] {
+            if self.supported_attrs.contains(id) {
+                request.insert(id);
+            }
+        }
+        let attrs = self.fetch_attributes_uncached(handle.clone(), request)?;
+        let cache = self.cache.as_mut().unwrap();
+        cache.insert_attributes(handle.clone(), attrs);
+        Ok(cache.get_attributes(&handle, &wanted).unwrap_or_default())
+    }
+
+    pub(crate) fn invalidate_attributes(&mut self, handle: &FileHandle) {
+        if let Some(cache) = &mut self.cache {
+            cache.invalidate_attributes(handle);
+        }
+    }
+
+    /// Records that `name` was added to `dir`, given the `ChangeInfo` from doing it.
+    pub(crate) fn entry_added(
+        &mut self,
+        dir: &FileHandle,
+        name: &str,
+        entry: Option<Entry>,
+        change_info: &ChangeInfo,
+    ) {
+        if let Some(cache) = &mut self.cache {
+            cache.update_directory(dir, change_info, |entries| {
+                entries.remove(name);
+                if let Some(entry) = entry {
+                    entries.insert(name.into(), entry);
+                }
+            });
+        }
+    }
+
+    /// Records that `name` was removed from `dir`, given the `ChangeInfo` from doing it. The
+    /// link count of what it referred to changed, so its attributes are dropped.
+    pub(crate) fn entry_removed(&mut self, dir: &FileHandle, name: &str, change_info: &ChangeInfo) {
+        if let Some(cache) = &mut self.cache {
+            let mut removed = None;
+            cache.update_directory(dir, change_info, |entries| removed = entries.remove(name));
+            if let Some((handle, _)) = removed {
+                cache.invalidate_attributes(&handle);
+            }
+        }
+    }
+
+    /// Records a rename, given the `ChangeInfo` for both directories.
+    pub(crate) fn entry_renamed(
+        &mut self,
+        (src_dir, src_name): (&FileHandle, &str),
+        (target_dir, target_name): (&FileHandle, &str),
+        (src_change, target_change): (&ChangeInfo, &ChangeInfo),
+    ) {
+        let Some(cache) = &mut self.cache else {
+            return;
+        };
+        if src_dir == target_dir {
+            cache.update_directory(src_dir, src_change, |entries| {
+                entries.remove(target_name);
+                if let Some(entry) = entries.remove(src_name) {
+                    entries.insert(target_name.into(), entry);
+                }
+            });
+            return;
+        }
+        let mut moved = None;
+        cache.update_directory(src_dir, src_change, |entries| {
+            moved = entries.remove(src_name)
+        });
+        self.entry_added(target_dir, target_name, moved, target_change);
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2023 Remi Bernotavicius
+
+use super::{process_compound, Client, CompoundRequest, Error, Result, ReturnSecond};
+use nfs4::{ArgOp, ResOp, SequenceArgs, StatusError, StatusResult};
+use std::collections::VecDeque;
+use sun_rpc_client::Transport;
+
+/// Why a compound sent with `Client::compound` stopped early.
+#[derive(Debug)]
+pub struct CompoundError {
+    /// The position of the failed op in the request, not counting the SEQUENCE the client adds.
+    pub index: usize,
+    pub status: StatusError,
+    /// The results of the ops up to and including the failed one.
+    pub results: Vec<ResOp>,
+}
+
+/// Any op, with its result left undecoded. A `Vec<ArgOp>` sends a sequence of ops only known at
+/// run time.
+impl CompoundRequest for ArgOp {
+    type Response = ResOp;
+    type Geometry = ();
+
+    fn into_arg_array(self) -> (Vec<ArgOp>, Self::Geometry) {
+        (vec![self], ())
+    }
+
+    fn process_reply(res_array: &mut VecDeque<ResOp>, _geometry: ()) -> Result<Self::Response> {
+        res_array
+            .pop_front()
+            .ok_or(Error::CompoundResponseMismatch("too few replies".into()))
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Sends the given ops as one compound after a SEQUENCE, returning the result of each.
+    ///
+    /// `args` is an op's arguments (`PutFhArgs`, `GetFh`, `VerifyArgs`, ...), a tuple of them, a
+    /// `Vec` of one kind of them or an `ArgOp`, and the response has the same shape. The server
+    /// stops at the first op which fails, which is reported as `Error::Compound` with its index.
+    pub fn compound<Args>(&mut self, args: Args) -> Result<Args::Response>
+    where
+        Args: CompoundRequest,
+    {
+        let sequence = self.sequence_args(0, 0);
+        let connection = self.next_connection();
+        let raw_client = self.connection(connection);
+        let (_, geometry) = raw_client.send_compound(ReturnSecond(sequence, args))?;
+        let (_, mut reply) = raw_client.receive_compound()?;
+
+        if let StatusResult::Err(status) = &reply.status {
+            // Only report ops after the SEQUENCE, its errors are about the session.
+            if reply.res_array.len() > 1 {
+                let results = reply.res_array.split_off(1);
+                return Err(Error::Compound(Box::new(CompoundError {
+                    index: results.len() - 1,
+                    status: status.clone(),
+                    results,
+                })));
+            }
+        }
+        process_compound::<ReturnSecond<SequenceArgs, Args>>(reply, geometry)
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Operations which only go ahead if a file's attributes are as expected, checked with VERIFY or
+//! NVERIFY in the same compound so nothing can change in between.
+
+use super::{Client, CompoundRequest, Error, Result, ReturnSecond};
+use nfs4::{
+    ArgOp, Attributes, Change, FileHandle, LookUpArgs, NVerifyArgs, PutFhArgs, RemoveArgs, ResOp,
+    StableHow, StateId, StatusError, VerifyArgs, WriteArgs, WriteRes,
+};
+use std::collections::VecDeque;
+use sun_rpc_client::Transport;
+
+/// What a file's attributes have to look like for a conditional operation to happen. Attributes
+/// which are `None` aren't checked. Failing the check is `Error::PreconditionFailed`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// All of the attributes have the given values (VERIFY).
+    Matches(Attributes),
+    /// At least one of the attributes has a different value (NVERIFY).
+    Differs(Attributes),
+}
+
+impl Precondition {
+    /// The file hasn't been changed since it had the given `Change` attribute.
+    pub fn unchanged(change: Change) -> Self {
+        Self::Matches(Attributes::default().with_change(change))
+    }
+}
+
+impl CompoundRequest for Precondition {
+    type Response = ();
+    type Geometry = bool;
+
+    fn into_arg_array(self) -> (Vec<ArgOp>, Self::Geometry) {
+        match self {
+            Self::Matches(attrs) => (
+                VerifyArgs {
+                    object_attributes: attrs.into(),
+                }
+                .into_arg_array()
+                .0,
+                true,
+            ),
+            Self::Differs(attrs) => (
+                NVerifyArgs {
+                    object_attributes: attrs.into(),
+                }
+                .into_arg_array()
+                .0,
+                false,
+            ),
+        }
+    }
+
+    fn process_reply(res_array: &mut VecDeque<ResOp>, matches: bool) -> Result<()> {
+        if matches {
+            VerifyArgs::process_reply(res_array, ())
+        } else {
+            NVerifyArgs::process_reply(res_array, ())
+        }
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    fn do_conditional<Args>(&mut self, args: Args) -> Result<Args::Response>
+    where
+        Args: CompoundRequest,
+    {
+        match self.do_compound(args) {
+            Err(Error::Protocol(StatusError::NotSame | StatusError::Same)) => {
+                Err(Error::PreconditionFailed)
+            }
+            res => res,
+        }
+    }
+
+    /// Like `write`, but only if the file's attributes meet the precondition.
+    pub fn write_if(
+        &mut self,
+        handle: FileHandle,
+        precondition: Precondition,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        self.invalidate_attributes(&handle);
+        self.invalidate_data_range(&handle, offset, data.len() as u64);
+        self.do_conditional(ReturnSecond(
+            (PutFhArgs { object: handle }, precondition),
+            WriteArgs {
+                state_id: StateId::anonymous(),
+                offset,
+                stable: StableHow::FileSync,
+                data,
+            },
+        ))
+    }
+
+    /// Like `write`, but only if the file's `Change` attribute is still `expected_change`.
+    pub fn write_if_unchanged(
+        &mut self,
+        handle: FileHandle,
+        expected_change: Change,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        self.write_if(
+            handle,
+            Precondition::unchanged(expected_change),
+            offset,
+            data,
+        )
+    }
+
+    /// Removes the entry with the given name from the directory, but only if the attributes of
+    /// what it refers to meet the precondition.
+    pub fn remove_if_attrs_match(
+        &mut self,
+        dir: FileHandle,
+        entry_name: &str,
+        precondition: Precondition,
+    ) -> Result<()> {
+        let res = self.do_conditional(ReturnSecond(
+            (
+                PutFhArgs {
+                    object: dir.clone(),
+                },
+                LookUpArgs {
+                    object_name: entry_name.into(),
+                },
+                precondition,
+                PutFhArgs {
+                    object: dir.clone(),
+                },
+            ),
+            RemoveArgs {
+                target: entry_name.into(),
+            },
+        ))?;
+        self.entry_removed(&dir, entry_name, &res.change_info);
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! An optional cache of file data in fixed size blocks, with close-to-open consistency: the
+//! cached data for a file is checked against its `Change` attribute each time it is opened, and
+//! after it is closed having been written to. In between, reads trust what is cached. Blocks can
+//! also be kept in files on local disk, so they are still there for the next client.
+
+use super::{Client, Error, Result, ReturnSecond};
+use nfs4::{Change, FileAttributeId, FileHandle, PutFhArgs, ReadArgs, ReadRes, StateId};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use sun_rpc_client::Transport;
+
+/// How `Client::enable_data_cache` caches file data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataCacheConfig {
+    /// Data is fetched and cached in blocks of this many bytes.
+    pub block_size: u32,
+    /// How much data to keep in memory before dropping the least recently used blocks.
+    pub max_memory: usize,
+    /// Also keep blocks in files under this directory, like FS-Cache does.
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for DataCacheConfig {
+    fn default() -> Self {
+        Self {
+            block_size: 128 * 1024,
+            max_memory: 64 * 1024 * 1024,
+            directory: None,
+        }
+    }
+}
+
+struct Block {
+    data: Vec<u8>,
+    /// The file ends in this block.
+    eof: bool,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CachedFile {
+    /// The `Change` attribute the blocks belong to.
+    change: Option<Change>,
+    blocks: BTreeMap<u64, Block>,
+}
+
+pub(crate) struct DataCache {
+    config: DataCacheConfig,
+    files: HashMap<FileHandle, CachedFile>,
+    /// Which block was used at each tick, oldest first.
+    lru: BTreeMap<u64, (FileHandle, u64)>,
+    tick: u64,
+    memory: usize,
+}
+
+fn hex(handle: &FileHandle) -> String {
+    handle.0.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl DataCache {
+    fn new(config: DataCacheConfig) -> Result<Self> {
+        if let Some(directory) = &config.directory {
+            fs::create_dir_all(directory)?;
+        }
+        Ok(Self {
+            config,
+            files: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            memory: 0,
+        })
+    }
+
+    fn block_size(&self) -> u64 {
+        u64::from(self.config.block_size.max(1))
+    }
+
+    fn file_directory(&self, handle: &FileHandle) -> Option<PathBuf> {
+        Some(self.config.directory.as_ref()?.join(hex(handle)))
+    }
+
+    fn block_path(&self, handle: &FileHandle, index: u64, eof: bool) -> Option<PathBuf> {
+        let name = if eof {
+            format!("{index}.eof")
+        } else {
+            index.to_string()
+        };
+        Some(self.file_directory(handle)?.join(name))
+    }
+
+    fn is_validated(&self, handle: &FileHandle) -> bool {
+        self.files.get(handle).is_some_and(|f| f.change.is_some())
+    }
+
+    /// Keeps the cached data for the file if it belongs to the given `Change`, otherwise drops
+    /// it. The data kept on disk is checked too, along with the block size it was cached with.
+    fn revalidate(&mut self, handle: &FileHandle, change: Change) {
+        if self.files.get(handle).and_then(|f| f.change) == Some(change) {
+            return;
+        }
+        let version = format!("{} {}", change.0, self.block_size());
+        let on_disk = self
+            .file_directory(handle)
+            .and_then(|dir| fs::read_to_string(dir.join("change")).ok());
+        if on_disk.as_deref() != Some(&version) {
+            self.invalidate(handle);
+        } else {
+            self.drop_from_memory(handle);
+        }
+        if let Some(dir) = self.file_directory(handle) {
+            // The cache on disk is best effort, failing to write it only means fetching again.
+            let _ = fs::create_dir_all(&dir).and_then(|()| fs::write(dir.join("change"), version));
+        }
+        self.files.entry(handle.clone()).or_default().change = Some(change);
+    }
+
+    /// Moves the cached data along to a new `Change`, after writes from this client which already
+    /// dropped the blocks they overlapped.
+    fn set_change(&mut self, handle: &FileHandle, change: Change) {
+        let Some(file) = self.files.get_mut(handle) else {
+            return;
+        };
+        file.change = Some(change);
+        if let Some(dir) = self.file_directory(handle) {
+            let version = format!("{} {}", change.0, self.block_size());
+            let _ = fs::write(dir.join("change"), version);
+        }
+    }
+
+    fn drop_from_memory(&mut self, handle: &FileHandle) {
+        if let Some(file) = self.files.remove(handle) {
+            for block in file.blocks.values() {
+                self.lru.remove(&block.last_used);
+                self.memory -= block.data.len();
+            }
+        }
+    }
+
+    fn invalidate(&mut self, handle: &FileHandle) {
+        self.drop_from_memory(handle);
+        if let Some(dir) = self.file_directory(handle) {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    /// Drops the blocks overlapping `offset..end`, and the one the file used to end in if it is
+    /// before `end`.
+    fn invalidate_range(&mut self, handle: &FileHandle, offset: u64, end: u64) {
+        let block_size = self.block_size();
+        let first = offset / block_size;
+        let last = end.div_ceil(block_size);
+        if let Some(file) = self.files.get_mut(handle) {
+            let stale: Vec<_> = file
+                .blocks
+                .iter()
+                .filter(|(i, b)| (first..last).contains(*i) || (b.eof && **i < last))
+                .map(|(i, _)| *i)
+                .collect();
+            for index in stale {
+                let block = file.blocks.remove(&index).unwrap();
+                self.lru.remove(&block.last_used);
+                self.memory -= block.data.len();
+            }
+        }
+
+        let Some(dir) = self.file_directory(handle) else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let (index, eof) = match name.strip_suffix(".eof") {
+                Some(index) => (index, true),
+                None => (name, false),
+            };
+            let Ok(index) = index.parse::<u64>() else {
+                continue;
+            };
+            if (first..last).contains(&index) || (eof && index < last) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    fn next_tick(&mut self, handle: &FileHandle, index: u64) -> u64 {
+        self.tick += 1;
+        self.lru.insert(self.tick, (handle.clone(), index));
+        self.tick
+    }
+
+    /// The data in the block and whether the file ends in it.
+    fn get(&mut self, handle: &FileHandle, index: u64) -> Option<(Vec<u8>, bool)> {
+        let file = self.files.get(handle)?;
+        if let Some(block) = file.blocks.get(&index) {
+            let (data, eof, last_used) = (block.data.clone(), block.eof, block.last_used);
+            self.lru.remove(&last_used);
+            let tick = self.next_tick(handle, index);
+            let block = self.files.get_mut(handle)?.blocks.get_mut(&index)?;
+            block.last_used = tick;
+            return Some((data, eof));
+        }
+        let (data, eof) = [false, true].into_iter().find_map(|eof| {
+            let data = fs::read(self.block_path(handle, index, eof)?).ok()?;
+            Some((data, eof))
+        })?;
+        self.insert_in_memory(handle, index, data.clone(), eof);
+        Some((data, eof))
+    }
+
+    fn insert(&mut self, handle: &FileHandle, index: u64, data: Vec<u8>, eof: bool) {
+        if let Some(path) = self.block_path(handle, index, eof) {
+            let temp = path.with_extension("tmp");
+            let _ = fs::write(&temp, &data).and_then(|()| fs::rename(&temp, &path));
+        }
+        self.insert_in_memory(handle, index, data, eof);
+    }
+
+    fn insert_in_memory(&mut self, handle: &FileHandle, index: u64, data: Vec<u8>, eof: bool) {
+        let Some(file) = self.files.get(handle) else {
+            return;
+        };
+        if let Some(old) = file.blocks.get(&index) {
+            let last_used = old.last_used;
+            self.lru.remove(&last_used);
+        }
+        let len = data.len();
+        let tick = self.next_tick(handle, index);
+        let file = self.files.get_mut(handle).unwrap();
+        let block = Block {
+            data,
+            eof,
+            last_used: tick,
+        };
+        if let Some(old) = file.blocks.insert(index, block) {
+            self.memory -= old.data.len();
+        }
+        self.memory += len;
+
+        while self.memory > self.config.max_memory {
+            let Some((_, (handle, index))) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(block) = self
+                .files
+                .get_mut(&handle)
+                .and_then(|f| f.blocks.remove(&index))
+            {
+                self.memory -= block.data.len();
+            }
+        }
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Starts caching file data. Reads from a file are served from the cache until it is opened
+    /// again and found to have changed.
+    pub fn enable_data_cache(&mut self, config: DataCacheConfig) -> Result<()> {
+        self.data_cache = Some(DataCache::new(config)?);
+        Ok(())
+    }
+
+    pub fn disable_data_cache(&mut self) {
+        self.data_cache = None;
+    }
+
+    /// Drops the cached data for the file, including any on disk.
+    pub fn invalidate_data(&mut self, handle: FileHandle) {
+        if let Some(cache) = &mut self.data_cache {
+            cache.invalidate(&handle);
+        }
+    }
+
+    fn fetch_change(&mut self, handle: &FileHandle) -> Result<Change> {
+        self.fetch_attributes_uncached(
+            handle.clone(),
+            [FileAttributeId::Change].into_iter().collect(),
+        )?
+        .remove_as(FileAttributeId::Change)
+        .ok_or(Error::MissingAttribute(FileAttributeId::Change))
+    }
+
+    /// Checks the cached data for the file against its `Change` attribute. Without `force`, only
+    /// files which haven't been checked yet are.
+    pub(crate) fn revalidate_data(&mut self, handle: &FileHandle, force: bool) -> Result<()> {
+        match &self.data_cache {
+            Some(cache) if force || !cache.is_validated(handle) => {}
+            _ => return Ok(()),
+        }
+        let change = self.fetch_change(handle)?;
+        if let Some(cache) = &mut self.data_cache {
+            cache.revalidate(handle, change);
+        }
+        Ok(())
+    }
+
+    /// Called when a file this client wrote to is closed. The writes dropped the blocks they
+    /// overlapped, so the rest is still good for the `Change` the file has now.
+    pub(crate) fn data_written(&mut self, handle: &FileHandle) -> Result<()> {
+        if self.data_cache.is_none() {
+            return Ok(());
+        }
+        let change = self.fetch_change(handle)?;
+        if let Some(cache) = &mut self.data_cache {
+            cache.set_change(handle, change);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn invalidate_data_range(&mut self, handle: &FileHandle, offset: u64, len: u64) {
+        if let Some(cache) = &mut self.data_cache {
+            cache.invalidate_range(handle, offset, offset.saturating_add(len));
+        }
+    }
+
+    /// Reads a block from the server, asking again when the server returns less than asked for.
+    fn fetch_block(
+        &mut self,
+        handle: &FileHandle,
+        state_id: StateId,
+        index: u64,
+        block_size: u64,
+    ) -> Result<(Vec<u8>, bool)> {
+        let mut data = vec![];
+        loop {
+            let res = self.do_compound(ReturnSecond(
+                PutFhArgs {
+                    object: handle.clone(),
+                },
+                ReadArgs {
+                    state_id,
+                    offset: index * block_size + data.len() as u64,
+                    count: (block_size - data.len() as u64) as u32,
+                },
+            ))?;
+            let eof = res.eof || res.data.is_empty();
+            data.extend(res.data);
+            if eof || data.len() as u64 >= block_size {
+                return Ok((data, eof));
+            }
+        }
+    }
+
+    /// Like a READ, but with the data coming from the cache where it can.
+    pub(crate) fn read_cached(
+        &mut self,
+        handle: &FileHandle,
+        state_id: StateId,
+        offset: u64,
+        count: u32,
+    ) -> Result<ReadRes> {
+        self.revalidate_data(handle, false)?;
+        let block_size = self.data_cache.as_ref().unwrap().block_size();
+        let mut data = vec![];
+        let mut position = offset;
+        let mut eof = false;
+        while data.len() < count as usize && !eof {
+            let index = position / block_size;
+            let cache = self.data_cache.as_mut().unwrap();
+            let (block, block_eof) = match cache.get(handle, index) {
+                Some(block) => block,
+                None => {
+                    let (block, block_eof) =
+                        self.fetch_block(handle, state_id, index, block_size)?;
+                    let cache = self.data_cache.as_mut().unwrap();
+                    cache.insert(handle, index, block.clone(), block_eof);
+                    (block, block_eof)
+                }
+            };
+            let start = (position - index * block_size) as usize;
+            let end = block.len().min(start + count as usize - data.len());
+            if start < end {
+                data.extend_from_slice(&block[start..end]);
+                position = offset + data.len() as u64;
+            }
+            eof = block_eof && end >= block.len();
+            if start >= end && !eof {
+                // Only a block the file ends in can be short.
+                break;
+            }
+        }
+        Ok(ReadRes { eof, data })
+    }
+
+    /// `read_all` through the cache, a block at a time. Reading the whole file counts as opening
+    /// it, so the cached data is checked first.
+    pub(crate) fn read_all_cached(
+        &mut self,
+        handle: FileHandle,
+        mut sink: impl io::Write,
+    ) -> Result<()> {
+        self.revalidate_data(&handle, true)?;
+        let block_size = self.data_cache.as_ref().unwrap().config.block_size.max(1);
+        let mut offset = 0;
+        loop {
+            let res = self.read_cached(&handle, StateId::anonymous(), offset, block_size)?;
+            sink.write_all(&res.data)?;
+            offset += res.data.len() as u64;
+            if res.eof || res.data.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2023 Remi Bernotavicius
+
+use super::unstable::{UncommittedWrites, MAX_UNCOMMITTED};
+use super::{Client, Result, ReturnSecond};
+use nfs4::{
+    AttrRequest, FileHandle, OpenArgs, OpenClaim, OpenFlag, PutFhArgs, SequenceId, ShareAccess,
+    ShareDeny, StateId, StateOwner,
+};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use sun_rpc_client::Transport;
+
+/// An open file with a cursor, usable anywhere a `std::io` reader or writer is.
+///
+/// Reads fetch `max_read` bytes at a time, and writes are held back until there are `max_write`
+/// bytes and then sent UNSTABLE. `flush` and `sync_all` send what is buffered and do a COMMIT,
+/// writing the data again if the server rebooted and lost it. Dropping the file does the same as
+/// `close` but ignores errors.
+///
+/// With the data cache enabled, opening a file checks its cached data against the `Change`
+/// attribute, and closing it after writing keeps the cached data which wasn't written over.
+pub struct RemoteFile<'a, TransportT: Transport> {
+    client: &'a mut Client<TransportT>,
+    handle: FileHandle,
+    state_id: StateId,
+    position: u64,
+    read_buf: Vec<u8>,
+    read_buf_offset: u64,
+    write_buf: Vec<u8>,
+    write_buf_offset: u64,
+    uncommitted: UncommittedWrites,
+    written: bool,
+    closed: bool,
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Opens the file with the given handle, with the cursor at the start.
+    pub fn open_file(
+        &mut self,
+        handle: FileHandle,
+        share_access: ShareAccess,
+    ) -> Result<RemoteFile<'_, TransportT>> {
+        let state_id = self.open_state(handle.clone(), share_access)?;
+        self.revalidate_data(&handle, true)?;
+        Ok(RemoteFile {
+            client: self,
+            handle,
+            state_id,
+            position: 0,
+            read_buf: vec![],
+            read_buf_offset: 0,
+            write_buf: vec![],
+            write_buf_offset: 0,
+            uncommitted: UncommittedWrites::default(),
+            written: false,
+            closed: false,
+        })
+    }
+
+    /// Opens the file with the given handle, returning the open's state id.
+    pub(crate) fn open_state(
+        &mut self,
+        handle: FileHandle,
+        share_access: ShareAccess,
+    ) -> Result<StateId> {
+        let open_res = self.do_compound(ReturnSecond(
+            PutFhArgs {
+                object: handle.clone(),
+            },
+            OpenArgs {
+                sequence_id: SequenceId(0),
+                share_access,
+                share_deny: ShareDeny::NONE,
+                owner: StateOwner {
+                    client_id: self.client_id,
+                    opaque: self.client_owner.owner_id.clone(),
+                },
+                open_how: OpenFlag::OpenNoCreate,
+                claim: OpenClaim::Fh,
+            },
+        ))?;
+        Ok(open_res.state_id)
+    }
+}
+
+impl<TransportT: Transport> RemoteFile<'_, TransportT> {
+    pub fn handle(&self) -> &FileHandle {
+        &self.handle
+    }
+
+    fn max_read(&self) -> usize {
+        self.client.max_read as usize
+    }
+
+    fn max_write(&self) -> usize {
+        self.client.max_write as usize
+    }
+
+    /// Where the cursor is in the read buffer, if it is in there.
+    fn read_buf_position(&self) -> Option<usize> {
+        let start = self.position.checked_sub(self.read_buf_offset)?;
+        (start < self.read_buf.len() as u64).then_some(start as usize)
+    }
+
+    fn read_at_position(&mut self, count: usize) -> io::Result<Vec<u8>> {
+        self.flush_writes()?;
+        let res = self.client.read_with_state(
+            self.handle.clone(),
+            self.state_id,
+            self.position,
+            count.try_into().unwrap_or(u32::MAX),
+        )?;
+        Ok(res.data)
+    }
+
+    /// Sends the buffered writes to the server, without committing them. With `full_only`, a
+    /// final chunk smaller than `max_write` stays buffered.
+    fn send_writes(&mut self, full_only: bool) -> io::Result<()> {
+        let max_write = self.max_write().max(1);
+        let len = if full_only {
+            self.write_buf.len() / max_write * max_write
+        } else {
+            self.write_buf.len()
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        let data: Vec<u8> = self.write_buf.drain(..len).collect();
+        let offset = self.write_buf_offset;
+        self.write_buf_offset += len as u64;
+        self.written = true;
+        self.client.write_unstable(
+            &self.handle,
+            self.state_id,
+            offset,
+            &data,
+            &mut self.uncommitted,
+        )?;
+        if self.uncommitted.len() >= MAX_UNCOMMITTED {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn flush_writes(&mut self) -> io::Result<()> {
+        self.send_writes(false)
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        self.client
+            .commit_writes(&self.handle, self.state_id, &mut self.uncommitted)?;
+        Ok(())
+    }
+
+    /// Sends any buffered writes and commits everything written to stable storage.
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        self.flush_writes()?;
+        self.commit()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.sync_all()?;
+        self.client.close(self.handle.clone(), self.state_id)?;
+        if self.written {
+            self.client.data_written(&self.handle)?;
+        }
+        Ok(())
+    }
+
+    /// Writes out and commits what is buffered, then closes the file.
+    pub fn close(mut self) -> io::Result<()> {
+        self.finish()
+    }
+}
+
+impl<TransportT: Transport> Drop for RemoteFile<'_, TransportT> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl<TransportT: Transport> Read for RemoteFile<'_, TransportT> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_buf_position().is_none() && buf.len() >= self.max_read() {
+            let data = self.read_at_position(buf.len())?;
+            buf[..data.len()].copy_from_slice(&data);
+            self.position += data.len() as u64;
+            return Ok(data.len());
+        }
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<TransportT: Transport> BufRead for RemoteFile<'_, TransportT> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let start = match self.read_buf_position() {
+            Some(start) => start,
+            None => {
+                self.read_buf = self.read_at_position(self.max_read())?;
+                self.read_buf_offset = self.position;
+                0
+            }
+        };
+        Ok(&self.read_buf[start..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt as u64;
+    }
+}
+
+impl<TransportT: Transport> Write for RemoteFile<'_, TransportT> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_buf_offset + self.write_buf.len() as u64 != self.position {
+            self.flush_writes()?;
+        }
+        if self.write_buf.is_empty() {
+            self.write_buf_offset = self.position;
+        }
+        self.read_buf.clear();
+        self.write_buf.extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        self.send_writes(true)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl<TransportT: Transport> Seek for RemoteFile<'_, TransportT> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.position, offset),
+            SeekFrom::End(offset) => {
+                self.flush_writes()?;
+                let attrs = self
+                    .client
+                    .get_attributes(self.handle.clone(), AttrRequest::new().size())?;
+                (attrs.size.unwrap_or(0), offset)
+            }
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
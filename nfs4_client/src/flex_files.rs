@@ -0,0 +1,491 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! I/O through a `FlexFiles` layout (RFC 8435). Each mirror holds a full copy of the file, reads
+//! go to one mirror and writes go to all of them.
+
+use super::nfs3_data_server::Nfs3DataServer;
+use super::pnfs::{connect_multipath, decode_body, fill_hole, Connector};
+use super::*;
+use std::time::{Duration, Instant};
+
+enum DataServerClient<TransportT> {
+    V4(Box<Client<TransportT>>),
+    V3(Nfs3DataServer<TransportT>),
+}
+
+impl<TransportT: Transport> DataServerClient<TransportT> {
+    fn read(
+        &mut self,
+        handle: FileHandle,
+        state_id: StateId,
+        offset: u64,
+        count: u32,
+    ) -> Result<ReadRes> {
+        match self {
+            Self::V4(c) => c.read_with_state(handle, state_id, offset, count),
+            Self::V3(c) => c.read(handle, offset, count),
+        }
+    }
+
+    fn write(
+        &mut self,
+        handle: FileHandle,
+        state_id: StateId,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        match self {
+            Self::V4(c) => c.write_with_state(handle, state_id, offset, StableHow::Unstable, data),
+            Self::V3(c) => c.write(handle, offset, StableHow::Unstable, data),
+        }
+    }
+
+    fn commit(&mut self, handle: FileHandle) -> Result<CommitRes> {
+        match self {
+            Self::V4(c) => c.commit(handle),
+            Self::V3(c) => c.commit(handle),
+        }
+    }
+}
+
+fn duration_to_time(duration: Duration) -> Time {
+    Time {
+        seconds: duration.as_secs() as i64,
+        nseconds: duration.subsec_nanos(),
+    }
+}
+
+fn add_duration(time: &mut Time, duration: Duration) {
+    let total = Duration::new(time.seconds as u64, time.nseconds) + duration;
+    *time = duration_to_time(total);
+}
+
+#[derive(Default)]
+struct IoCounters {
+    info: IoInfo,
+    latency: FlexFilesIoLatency,
+}
+
+impl IoCounters {
+    fn record(&mut self, requested: u64, completed: Option<u64>, elapsed: Duration) {
+        self.latency.ops_requested += 1;
+        self.latency.bytes_requested += requested;
+        add_duration(&mut self.latency.total_busy_time, elapsed);
+        match completed {
+            Some(bytes) => {
+                self.info.count += 1;
+                self.info.bytes += bytes;
+                self.latency.ops_completed += 1;
+                self.latency.bytes_completed += bytes;
+                add_duration(&mut self.latency.aggregate_completion_time, elapsed);
+            }
+            None => self.latency.bytes_not_delivered += requested,
+        }
+    }
+}
+
+struct MirrorDataServer<TransportT> {
+    device_id: DeviceId,
+    addr: Option<NetAddr>,
+    handle: FileHandle,
+    state_id: StateId,
+    efficiency: u32,
+    rsize: u32,
+    wsize: u32,
+    /// `None` if connecting to the data server failed
+    client: Option<DataServerClient<TransportT>>,
+    read: IoCounters,
+    write: IoCounters,
+    uncommitted: bool,
+}
+
+impl<TransportT: Transport> MirrorDataServer<TransportT> {
+    fn read(&mut self, offset: u64, count: u32) -> Result<ReadRes> {
+        let client = self.client.as_mut().ok_or(StatusError::NxIo)?;
+        let start = Instant::now();
+        let res = client.read(self.handle.clone(), self.state_id, offset, count);
+        let completed = res.as_ref().ok().map(|r| r.data.len() as u64);
+        self.read.record(count as u64, completed, start.elapsed());
+        res
+    }
+
+    fn write(&mut self, offset: u64, data: Vec<u8>) -> Result<WriteRes> {
+        let client = self.client.as_mut().ok_or(StatusError::NxIo)?;
+        let requested = data.len() as u64;
+        let start = Instant::now();
+        let res = client.write(self.handle.clone(), self.state_id, offset, data);
+        let completed = res.as_ref().ok().map(|r| r.count as u64);
+        self.write.record(requested, completed, start.elapsed());
+        if let Ok(res) = &res {
+            self.uncommitted |= res.committed == StableHow::Unstable;
+        }
+        res
+    }
+}
+
+fn error_status(error: &Error) -> StatusError {
+    match error {
+        Error::Protocol(status) => status.clone(),
+        _ => StatusError::NxIo,
+    }
+}
+
+pub(crate) struct FlexFilesIo<TransportT> {
+    layout: FlexFilesLayout,
+    layout_state_id: StateId,
+    mirrors: Vec<Vec<MirrorDataServer<TransportT>>>,
+    opened: Instant,
+    errors: Vec<FlexFilesIoError>,
+    reported_errors: usize,
+}
+
+impl<TransportT: Transport> FlexFilesIo<TransportT> {
+    pub(crate) fn flags(&self) -> FlexFilesFlags {
+        self.layout.flags
+    }
+
+    fn record_error(
+        &mut self,
+        offset: u64,
+        length: u64,
+        device_id: DeviceId,
+        error: &Error,
+        opnum: OperationId,
+    ) {
+        self.errors.push(FlexFilesIoError {
+            offset,
+            length,
+            state_id: self.layout_state_id,
+            errors: vec![DeviceError {
+                device_id,
+                status: StatusResult::Err(error_status(error)),
+                opnum,
+            }],
+        });
+    }
+
+    /// Errors recorded since the last call, to be sent with LAYOUTERROR.
+    pub(crate) fn take_new_errors(&mut self) -> Vec<FlexFilesIoError> {
+        let errors = self.errors[self.reported_errors..].to_vec();
+        self.reported_errors = self.errors.len();
+        errors
+    }
+
+    /// Reads from the most efficient mirror that works. Returns `None` if the read should go
+    /// through the metadata server instead.
+    pub(crate) fn read(&mut self, offset: u64, count: u32, size: u64) -> Result<Option<ReadRes>> {
+        if self.layout.flags.contains(FlexFilesFlags::NO_READ_IO) {
+            return Ok(None);
+        }
+        let mut candidates: Vec<_> = (0..self.mirrors.len())
+            .filter_map(|m| Some((m, self.layout.locate(m, offset)?)))
+            .collect();
+        candidates
+            .sort_by_key(|(m, l)| std::cmp::Reverse(self.mirrors[*m][l.stripe_index].efficiency));
+
+        let mut last_error = None;
+        for (mirror, location) in candidates {
+            let data_server = &mut self.mirrors[mirror][location.stripe_index];
+            let count = count
+                .min(data_server.rsize)
+                .min(location.remaining.try_into().unwrap_or(u32::MAX));
+            match data_server.read(location.offset, count) {
+                Ok(res) => return Ok(Some(fill_hole(res, offset, count, size))),
+                Err(error) => {
+                    let device_id = data_server.device_id;
+                    self.record_error(offset, count as u64, device_id, &error, OperationId::Read);
+                    last_error = Some(error);
+                }
+            }
+        }
+        match last_error {
+            Some(error) if self.layout.flags.contains(FlexFilesFlags::NO_IO_THRU_MDS) => Err(error),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes to every mirror (or just one with `WRITE_ONE_MIRROR`). Returns `None` if the write
+    /// should go through the metadata server instead.
+    pub(crate) fn write(&mut self, offset: u64, data: &[u8]) -> Result<Option<WriteRes>> {
+        let mut targets: Vec<_> = (0..self.mirrors.len())
+            .filter_map(|m| Some((m, self.layout.locate(m, offset)?)))
+            .collect();
+        if self.layout.flags.contains(FlexFilesFlags::WRITE_ONE_MIRROR) {
+            let first_connected = targets
+                .iter()
+                .position(|(m, l)| self.mirrors[*m][l.stripe_index].client.is_some())
+                .unwrap_or(0);
+            targets = targets.into_iter().skip(first_connected).take(1).collect();
+        }
+        if targets.is_empty() {
+            return Ok(None);
+        }
+
+        let count = targets
+            .iter()
+            .map(|(m, l)| (self.mirrors[*m][l.stripe_index].wsize as u64).min(l.remaining) as usize)
+            .fold(data.len(), usize::min);
+
+        let mut result: Option<WriteRes> = None;
+        let mut last_error = None;
+        for (mirror, location) in targets {
+            let data_server = &mut self.mirrors[mirror][location.stripe_index];
+            match data_server.write(location.offset, data[..count].to_vec()) {
+                Ok(res) => {
+                    result = Some(match result {
+                        Some(r) => WriteRes {
+                            count: r.count.min(res.count),
+                            committed: if r.committed == StableHow::Unstable {
+                                r.committed
+                            } else {
+                                res.committed
+                            },
+                            write_veritifer: r.write_veritifer,
+                        },
+                        None => res,
+                    });
+                }
+                Err(error) => {
+                    let device_id = data_server.device_id;
+                    self.record_error(offset, count as u64, device_id, &error, OperationId::Write);
+                    last_error = Some(error);
+                }
+            }
+        }
+        match last_error {
+            Some(error) if self.layout.flags.contains(FlexFilesFlags::NO_IO_THRU_MDS) => Err(error),
+            Some(_) => Ok(None),
+            None => Ok(result),
+        }
+    }
+
+    /// Commits unstable writes on every data server that has them.
+    pub(crate) fn commit(&mut self) -> Result<()> {
+        let mut failed = None;
+        for mirror in 0..self.mirrors.len() {
+            for index in 0..self.mirrors[mirror].len() {
+                let data_server = &mut self.mirrors[mirror][index];
+                if !data_server.uncommitted {
+                    continue;
+                }
+                let client = data_server.client.as_mut().ok_or(StatusError::NxIo)?;
+                match client.commit(data_server.handle.clone()) {
+                    Ok(_) => data_server.uncommitted = false,
+                    Err(error) => {
+                        let device_id = data_server.device_id;
+                        self.record_error(0, u64::MAX, device_id, &error, OperationId::Commit);
+                        failed = Some(error);
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Statistics for every data server that saw any I/O.
+    pub(crate) fn io_stats(&self, offset: u64, length: u64) -> Vec<FlexFilesIoStats> {
+        let duration = duration_to_time(self.opened.elapsed());
+        self.mirrors
+            .iter()
+            .flatten()
+            .filter(|ds| ds.read.latency.ops_requested > 0 || ds.write.latency.ops_requested > 0)
+            .map(|ds| FlexFilesIoStats {
+                offset,
+                length,
+                state_id: self.layout_state_id,
+                read: ds.read.info,
+                write: ds.write.info,
+                device_id: ds.device_id,
+                layout_update: FlexFilesLayoutUpdate {
+                    addr: ds.addr.clone().unwrap_or(NetAddr {
+                        net_id: String::new(),
+                        addr: String::new(),
+                    }),
+                    file_handle: ds.handle.clone(),
+                    read: ds.read.latency.clone(),
+                    write: ds.write.latency.clone(),
+                    duration,
+                    local: false,
+                },
+            })
+            .collect()
+    }
+
+    /// The body for LAYOUTRETURN.
+    pub(crate) fn return_body(&self, offset: u64, length: u64) -> Vec<u8> {
+        serde_xdr::to_bytes(&FlexFilesLayoutReturn {
+            io_errors: self.errors.clone(),
+            io_stats: self.io_stats(offset, length),
+        })
+        .unwrap()
+    }
+}
+
+/// Picks the first device version this client can speak.
+fn usable_version(versions: &[FlexFilesDeviceVersion]) -> Option<usize> {
+    versions
+        .iter()
+        .position(|v| (v.version == 4 && v.minor_version >= 1) || v.version == 3)
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Connects to the data servers of all the mirrors. Data servers that can't be reached are
+    /// remembered and reported as errors once I/O is attempted on them.
+    pub(crate) fn flex_files_io<ConnectorT: Connector>(
+        &mut self,
+        layout: FlexFilesLayout,
+        layout_state_id: StateId,
+        connector: &mut ConnectorT,
+    ) -> Result<FlexFilesIo<ConnectorT::Transport>> {
+        if layout.mirrors.iter().all(|m| m.data_servers.is_empty()) {
+            return Err(StatusError::BadLayout.into());
+        }
+
+        let max_count = self.session.fore_channel_attrs.max_response_size;
+        let mut devices: Vec<(DeviceId, FlexFilesDeviceAddr)> = vec![];
+        let mut mirrors = vec![];
+        for mirror in &layout.mirrors {
+            let mut data_servers = vec![];
+            for ds in &mirror.data_servers {
+                let device = match devices.iter().find(|(id, _)| *id == ds.device_id) {
+                    Some((_, device)) => device.clone(),
+                    None => {
+                        let device_info = self.do_compound(GetDeviceInfoArgs {
+                            device_id: ds.device_id,
+                            layout_type: LayoutType::FlexFiles,
+                            max_count,
+                            notify_types: Default::default(),
+                        })?;
+                        let device: FlexFilesDeviceAddr =
+                            decode_body(&device_info.device_addr.body)?;
+                        devices.push((ds.device_id, device.clone()));
+                        device
+                    }
+                };
+
+                let version_index = usable_version(&device.versions);
+                let handle = version_index
+                    .and_then(|i| ds.fh_versions.get(i))
+                    .or(ds.fh_versions.first())
+                    .cloned()
+                    .ok_or(StatusError::BadLayout)?;
+
+                let mut addr = None;
+                let mut rsize = self.max_read.try_into().unwrap_or(u32::MAX);
+                let mut wsize = self.max_write.try_into().unwrap_or(u32::MAX);
+                let mut client = None;
+                if let Some(version) = version_index.map(|i| &device.versions[i]) {
+                    rsize = rsize.min(version.rsize);
+                    wsize = wsize.min(version.wsize);
+                    if let Ok((net_addr, transport)) =
+                        connect_multipath(connector, &device.netaddrs)
+                    {
+                        addr = Some(net_addr);
+                        client = if version.version == 3 {
+                            let uid = self.id_mapper.decode_uid(&ds.user);
+                            let gid = self.id_mapper.decode_gid(&ds.group);
+                            Some(DataServerClient::V3(Nfs3DataServer::new(
+                                transport, uid, gid,
+                            )))
+                        } else {
+                            Client::new_session(
+                                transport,
+                                ExchangeIdFlags::USE_PNFS_DS,
+                                version.minor_version,
+                            )
+                            .ok()
+                            .map(|c| DataServerClient::V4(Box::new(c)))
+                        };
+                    }
+                }
+
+                data_servers.push(MirrorDataServer {
+                    device_id: ds.device_id,
+                    addr,
+                    handle,
+                    state_id: ds.state_id,
+                    efficiency: ds.efficiency,
+                    rsize,
+                    wsize,
+                    client,
+                    read: IoCounters::default(),
+                    write: IoCounters::default(),
+                    uncommitted: false,
+                });
+            }
+            mirrors.push(data_servers);
+        }
+
+        Ok(FlexFilesIo {
+            layout,
+            layout_state_id,
+            mirrors,
+            opened: Instant::now(),
+            errors: vec![],
+            reported_errors: 0,
+        })
+    }
+
+    /// Sends any newly recorded data server errors with LAYOUTERROR. It only exists in NFSv4.2,
+    /// with older versions the errors are only reported at LAYOUTRETURN.
+    pub(crate) fn report_flex_files_errors<DsTransportT: Transport>(
+        &mut self,
+        handle: &FileHandle,
+        io: &mut FlexFilesIo<DsTransportT>,
+    ) -> Result<()> {
+        let errors = io.take_new_errors();
+        if self.minor_version() < 2 {
+            return Ok(());
+        }
+        for error in errors {
+            self.do_compound(ReturnSecond(
+                PutFhArgs {
+                    object: handle.clone(),
+                },
+                LayoutErrorArgs {
+                    offset: error.offset,
+                    length: error.length,
+                    state_id: error.state_id,
+                    errors: error.errors,
+                },
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Sends the I/O statistics with LAYOUTSTATS, it only exists in NFSv4.2.
+    pub(crate) fn send_flex_files_stats<DsTransportT: Transport>(
+        &mut self,
+        handle: &FileHandle,
+        io: &FlexFilesIo<DsTransportT>,
+        offset: u64,
+        length: u64,
+    ) -> Result<()> {
+        if self.minor_version() < 2 {
+            return Ok(());
+        }
+        for stats in io.io_stats(offset, length) {
+            self.do_compound(ReturnSecond(
+                PutFhArgs {
+                    object: handle.clone(),
+                },
+                LayoutStatsArgs {
+                    offset: stats.offset,
+                    length: stats.length,
+                    state_id: stats.state_id,
+                    read: stats.read,
+                    write: stats.write,
+                    device_id: stats.device_id,
+                    layout_update: LayoutUpdate {
+                        type_: LayoutType::FlexFiles,
+                        body: serde_xdr::to_bytes(&stats.layout_update).unwrap(),
+                    },
+                },
+            ))?;
+        }
+        Ok(())
+    }
+}
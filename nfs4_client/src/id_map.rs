@@ -0,0 +1,268 @@
+// Copyright 2023 Remi Bernotavicius
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+pub const NOBODY_ID: u32 = 65534;
+
+/// Translates between the `owner` and `owner_group` strings used on the wire and local numeric
+/// ids.
+///
+/// `Client` uses it for the methods which deal in ids, like `owner_uid` and `set_owner`, and in
+/// `RemoteFs`. Methods which take or return `FileAttributes` or `Attributes` leave `Owner` and
+/// `OwnerGroup` as the raw strings.
+pub trait IdMapper: Send + Sync {
+    fn owner_to_uid(&self, owner: &str) -> Option<u32>;
+    fn group_to_gid(&self, group: &str) -> Option<u32>;
+    fn uid_to_owner(&self, uid: u32) -> Option<String>;
+    fn gid_to_group(&self, gid: u32) -> Option<String>;
+
+    /// Like `owner_to_uid`, but unknown owners map to `nobody` the same as the kernel client.
+    fn decode_uid(&self, owner: &str) -> u32 {
+        self.owner_to_uid(owner).unwrap_or(NOBODY_ID)
+    }
+
+    fn decode_gid(&self, group: &str) -> u32 {
+        self.group_to_gid(group).unwrap_or(NOBODY_ID)
+    }
+
+    /// What to show people for an owner: the uid it maps to, or the string as it is if it doesn't
+    /// map to anything, rather than `nobody`.
+    fn display_owner(&self, owner: &str) -> String {
+        self.owner_to_uid(owner)
+            .map(|uid| uid.to_string())
+            .unwrap_or_else(|| owner.to_owned())
+    }
+
+    /// Like `uid_to_owner`, but falls back to sending the id as a decimal string.
+    fn encode_uid(&self, uid: u32) -> String {
+        self.uid_to_owner(uid).unwrap_or_else(|| uid.to_string())
+    }
+
+    fn encode_gid(&self, gid: u32) -> String {
+        self.gid_to_group(gid).unwrap_or_else(|| gid.to_string())
+    }
+}
+
+/// Sends and expects ids as decimal strings, what Linux servers do when id mapping is disabled.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct NumericIdMapper;
+
+impl IdMapper for NumericIdMapper {
+    fn owner_to_uid(&self, owner: &str) -> Option<u32> {
+        owner.parse().ok()
+    }
+
+    fn group_to_gid(&self, group: &str) -> Option<u32> {
+        group.parse().ok()
+    }
+
+    fn uid_to_owner(&self, uid: u32) -> Option<String> {
+        Some(uid.to_string())
+    }
+
+    fn gid_to_group(&self, gid: u32) -> Option<String> {
+        Some(gid.to_string())
+    }
+}
+
+/// A fixed set of mappings, mostly useful for tests.
+#[derive(Clone, Default, Debug)]
+pub struct StaticIdMapper {
+    users: BTreeMap<String, u32>,
+    groups: BTreeMap<String, u32>,
+}
+
+impl StaticIdMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn user(mut self, name: impl Into<String>, uid: u32) -> Self {
+        self.users.insert(name.into(), uid);
+        self
+    }
+
+    pub fn group(mut self, name: impl Into<String>, gid: u32) -> Self {
+        self.groups.insert(name.into(), gid);
+        self
+    }
+}
+
+fn find_name(map: &BTreeMap<String, u32>, id: u32) -> Option<String> {
+    map.iter().find(|(_, v)| **v == id).map(|(k, _)| k.clone())
+}
+
+impl IdMapper for StaticIdMapper {
+    fn owner_to_uid(&self, owner: &str) -> Option<u32> {
+        self.users.get(owner).copied()
+    }
+
+    fn group_to_gid(&self, group: &str) -> Option<u32> {
+        self.groups.get(group).copied()
+    }
+
+    fn uid_to_owner(&self, uid: u32) -> Option<String> {
+        find_name(&self.users, uid)
+    }
+
+    fn gid_to_group(&self, gid: u32) -> Option<String> {
+        find_name(&self.groups, gid)
+    }
+}
+
+/// Maps names using the contents of `/etc/passwd` and `/etc/group`. Purely numeric names are
+/// accepted as ids.
+#[derive(Clone, Default, Debug)]
+pub struct PasswdIdMapper {
+    inner: StaticIdMapper,
+}
+
+fn parse_id_file(contents: &str) -> BTreeMap<String, u32> {
+    contents
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let mut fields = l.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_owned(), id))
+        })
+        .collect()
+}
+
+impl PasswdIdMapper {
+    pub fn parse(passwd: &str, group: &str) -> Self {
+        Self {
+            inner: StaticIdMapper {
+                users: parse_id_file(passwd),
+                groups: parse_id_file(group),
+            },
+        }
+    }
+
+    pub fn from_files(passwd: impl AsRef<Path>, group: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::parse(
+            &std::fs::read_to_string(passwd)?,
+            &std::fs::read_to_string(group)?,
+        ))
+    }
+
+    pub fn from_system() -> io::Result<Self> {
+        Self::from_files("/etc/passwd", "/etc/group")
+    }
+}
+
+impl IdMapper for PasswdIdMapper {
+    fn owner_to_uid(&self, owner: &str) -> Option<u32> {
+        self.inner
+            .owner_to_uid(owner)
+            .or_else(|| NumericIdMapper.owner_to_uid(owner))
+    }
+
+    fn group_to_gid(&self, group: &str) -> Option<u32> {
+        self.inner
+            .group_to_gid(group)
+            .or_else(|| NumericIdMapper.group_to_gid(group))
+    }
+
+    fn uid_to_owner(&self, uid: u32) -> Option<String> {
+        self.inner.uid_to_owner(uid)
+    }
+
+    fn gid_to_group(&self, gid: u32) -> Option<String> {
+        self.inner.gid_to_group(gid)
+    }
+}
+
+/// Adds and strips an `@domain` suffix around another mapper, like the `Domain` setting in
+/// `idmapd.conf`. Names from any other domain are unknown.
+#[derive(Clone, Debug)]
+pub struct DomainIdMapper<InnerT> {
+    domain: String,
+    local_domains: Vec<String>,
+    inner: InnerT,
+}
+
+impl<InnerT: IdMapper> DomainIdMapper<InnerT> {
+    pub fn new(domain: impl Into<String>, inner: InnerT) -> Self {
+        Self {
+            domain: domain.into(),
+            local_domains: vec![],
+            inner,
+        }
+    }
+
+    /// Also accept names from this domain, like `Local-Realms` in `idmapd.conf`.
+    pub fn with_local_domain(mut self, domain: impl Into<String>) -> Self {
+        self.local_domains.push(domain.into());
+        self
+    }
+
+    /// Reads the `Domain` and `Local-Realms` settings from the `[General]` section of an
+    /// `idmapd.conf` file. Returns `None` if there is no `Domain` setting.
+    pub fn from_idmapd_conf(conf: &str, inner: InnerT) -> Option<Self> {
+        let mut section = "";
+        let mut domain = None;
+        let mut local_domains = vec![];
+        for line in conf.lines().map(str::trim) {
+            if line.starts_with('#') || line.starts_with(';') || line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim();
+                continue;
+            }
+            if !section.eq_ignore_ascii_case("General") {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key.eq_ignore_ascii_case("Domain") {
+                domain = Some(value.to_owned());
+            } else if key.eq_ignore_ascii_case("Local-Realms") {
+                local_domains.extend(value.split(',').map(|d| d.trim().to_owned()));
+            }
+        }
+        Some(Self {
+            domain: domain?,
+            local_domains,
+            inner,
+        })
+    }
+
+    fn strip_domain<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match name.rsplit_once('@') {
+            Some((name, domain)) => (domain.eq_ignore_ascii_case(&self.domain)
+                || self
+                    .local_domains
+                    .iter()
+                    .any(|d| domain.eq_ignore_ascii_case(d)))
+            .then_some(name),
+            None => Some(name),
+        }
+    }
+}
+
+impl<InnerT: IdMapper> IdMapper for DomainIdMapper<InnerT> {
+    fn owner_to_uid(&self, owner: &str) -> Option<u32> {
+        self.inner.owner_to_uid(self.strip_domain(owner)?)
+    }
+
+    fn group_to_gid(&self, group: &str) -> Option<u32> {
+        self.inner.group_to_gid(self.strip_domain(group)?)
+    }
+
+    fn uid_to_owner(&self, uid: u32) -> Option<String> {
+        let name = self.inner.uid_to_owner(uid)?;
+        Some(format!("{name}@{}", self.domain))
+    }
+
+    fn gid_to_group(&self, gid: u32) -> Option<String> {
+        let name = self.inner.gid_to_group(gid)?;
+        Some(format!("{name}@{}", self.domain))
+    }
+}
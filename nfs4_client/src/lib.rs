@@ -1,13 +1,56 @@
 // Copyright 2023 Remi Bernotavicius
 
+use cache::MetadataCache;
+use data_cache::DataCache;
 use derive_more::From;
 use nfs4::*;
 use paste::paste;
 use rand::Rng as _;
 use std::collections::VecDeque;
 use std::io;
-use std::path::{Component, Path};
+use sun_rpc::Xid;
 use sun_rpc_client::{RpcClient, Transport};
+use transfer::InOrder;
+
+pub use cache::CacheConfig;
+pub use compound::CompoundError;
+pub use conditional::Precondition;
+pub use data_cache::DataCacheConfig;
+pub use file::RemoteFile;
+pub use id_map::{
+    DomainIdMapper, IdMapper, NumericIdMapper, PasswdIdMapper, StaticIdMapper, NOBODY_ID,
+};
+pub use namespace::{Located, MountCrossing, Namespace, Referral};
+pub use path_walk::{LookUpOptions, PathError};
+pub use pipeline::PipelineConfig;
+pub use pnfs::{Connector, PnfsFile, TcpConnector};
+pub use read_dir::ReadDir;
+pub use remote_fs::{DirEntry, MemoryFs, Metadata, OpenOptions, RemoteFs, SetAttributes};
+pub use replica::{ReplicaLocation, Replicated, ReplicatedFile};
+pub use sync::{SyncAction, SyncOptions};
+pub use tree_walk::{glob_matches, Walk, WalkEntry, WalkFilter, WalkOptions, WalkOrder};
+pub use trunking::Trunking;
+
+mod cache;
+mod compound;
+mod conditional;
+mod data_cache;
+mod file;
+mod flex_files;
+mod id_map;
+mod namespace;
+mod nfs3_data_server;
+mod path_walk;
+mod pipeline;
+mod pnfs;
+mod read_dir;
+mod remote_fs;
+mod replica;
+mod sync;
+mod transfer;
+mod tree_walk;
+mod trunking;
+mod unstable;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -46,8 +89,27 @@ pub enum Error {
     Protocol(StatusError),
     Lock(LockStatusError),
     Io(std::io::Error),
+    Deserialization(serde_xdr::CompatDeserializationError),
     #[from(ignore)]
     CompoundResponseMismatch(String),
+    #[from(ignore)]
+    MissingAttribute(FileAttributeId),
+    #[from(ignore)]
+    Compound(Box<CompoundError>),
+    /// A VERIFY or NVERIFY guarding a conditional operation failed.
+    #[from(ignore)]
+    PreconditionFailed,
+    #[from(ignore)]
+    Path(Box<PathError>),
+    /// Following symlinks while looking up a path went past `LookUpOptions::max_symlinks`.
+    #[from(ignore)]
+    TooManySymlinks,
+    /// The path went into a filesystem which is on another server. `Namespace` follows these.
+    #[from(ignore)]
+    Referral(Box<Referral>),
+    /// `Namespace` followed too many referrals looking up a path, likely because they loop.
+    #[from(ignore)]
+    TooManyReferrals,
 }
 
 const NFS: u32 = 100003;
@@ -175,6 +237,8 @@ compound_op_impl_no_ret! {
     FreeStateid
     DestroyClientId
     ReclaimComplete
+    LayoutError
+    LayoutStats
 }
 
 compound_op_impl_no_args! {
@@ -190,12 +254,15 @@ compound_op_impl_no_args_no_ret! {
     SaveFh
 }
 
-trait CompoundRequest {
+/// Something which can be sent as part of a compound: the arguments of an op, or several ops
+/// whose responses are returned together. See `Client::compound`.
+pub trait CompoundRequest {
     type Response;
     type Geometry;
 
     fn into_arg_array(self) -> (Vec<ArgOp>, Self::Geometry);
 
+    /// Takes this request's results off the front of `res_array`.
     fn process_reply(
         res_array: &mut VecDeque<ResOp>,
         geometry: Self::Geometry,
@@ -312,44 +379,71 @@ tuple_impls! {
 
 struct ClientWithoutSession<TransportT> {
     rpc_client: RpcClient<TransportT>,
+    minor_version: u32,
 }
 
 impl<TransportT: Transport> ClientWithoutSession<TransportT> {
-    fn new(rpc_client: RpcClient<TransportT>) -> Self {
-        Self { rpc_client }
+    fn new(rpc_client: RpcClient<TransportT>, minor_version: u32) -> Self {
+        Self {
+            rpc_client,
+            minor_version,
+        }
     }
 
     fn do_compound<Args>(&mut self, args: Args) -> Result<Args::Response>
+    where
+        Args: CompoundRequest,
+    {
+        let (_, geometry) = self.send_compound(args)?;
+        let (_, compound_reply) = self.receive_compound()?;
+        process_compound::<Args>(compound_reply, geometry)
+    }
+
+    /// Sends a compound without waiting for the reply.
+    fn send_compound<Args>(&mut self, args: Args) -> Result<(Xid, Args::Geometry)>
     where
         Args: CompoundRequest,
     {
         let (arg_array, geometry) = args.into_arg_array();
         let call_args = CompoundArgs {
             tag: "Test Client".into(),
-            minor_version: 1,
+            minor_version: self.minor_version,
             arg_array,
         };
 
-        self.rpc_client
+        let xid = self
+            .rpc_client
             .send_request(COMPOUND_PROCEDURE, call_args)?;
+        Ok((xid, geometry))
+    }
 
-        let compound_reply: CompoundRes = self.rpc_client.receive_reply()?;
-
-        if let StatusResult::Err(e) = compound_reply.status {
-            return Err(e.into());
-        }
+    /// Receives the reply to whichever outstanding compound the server answers next.
+    fn receive_compound(&mut self) -> Result<(Xid, CompoundRes)> {
+        Ok(self.rpc_client.receive_reply_with_xid()?)
+    }
+}
 
-        let mut res_array = compound_reply.res_array.into_iter().collect();
-        let reply = Args::process_reply(&mut res_array, geometry)?;
+fn process_compound<Args>(
+    compound_reply: CompoundRes,
+    geometry: Args::Geometry,
+) -> Result<Args::Response>
+where
+    Args: CompoundRequest,
+{
+    if let StatusResult::Err(e) = compound_reply.status {
+        return Err(e.into());
+    }
 
-        if !res_array.is_empty() {
-            return Err(Error::CompoundResponseMismatch(format!(
-                "trailing response: {res_array:?}"
-            )));
-        }
+    let mut res_array = compound_reply.res_array.into_iter().collect();
+    let reply = Args::process_reply(&mut res_array, geometry)?;
 
-        Ok(reply)
+    if !res_array.is_empty() {
+        return Err(Error::CompoundResponseMismatch(format!(
+            "trailing response: {res_array:?}"
+        )));
     }
+
+    Ok(reply)
 }
 
 fn random_client_owner() -> ClientOwner {
@@ -362,23 +456,83 @@ fn random_client_owner() -> ClientOwner {
 
 pub struct Client<TransportT> {
     raw_client: ClientWithoutSession<TransportT>,
+    /// Connections added with `add_connection`, bound to the same session.
+    trunked: Vec<ClientWithoutSession<TransportT>>,
+    next_connection: usize,
     session: CreateSessionRes,
-    sequence_id: SequenceId,
+    /// The next sequence id to use on each slot of the session.
+    slots: Vec<SequenceId>,
+    pipeline_config: PipelineConfig,
     client_id: ClientId,
     client_owner: ClientOwner,
+    exchange_id_flags: ExchangeIdFlags,
+    server_flags: ExchangeIdFlags,
+    server_owner: ServerOwner,
+    server_scope: ServerScope,
     max_read: u64,
     max_write: u64,
     supported_attrs: EnumSet<FileAttributeId>,
+    id_mapper: Box<dyn IdMapper>,
+    /// Set by `enable_cache`.
+    cache: Option<MetadataCache>,
+    /// Set by `enable_data_cache`.
+    data_cache: Option<DataCache>,
+    /// From the SEQUENCE of the last compound sent on its own.
+    status_flags: SequenceStatusFlags,
 }
 
 impl<TransportT: Transport> Client<TransportT> {
     pub fn new(transport: TransportT) -> Result<Self> {
-        let mut raw_client = ClientWithoutSession::new(RpcClient::new(transport, NFS));
+        Self::new_with_minor_version(transport, 1)
+    }
+
+    /// Like `new`, but sends compounds with the given minor version. Version 2 is needed for
+    /// operations like LAYOUTERROR and LAYOUTSTATS.
+    pub fn new_with_minor_version(transport: TransportT, minor_version: u32) -> Result<Self> {
+        let mut client = Self::new_session(transport, ExchangeIdFlags::empty(), minor_version)?;
+
+        let mut root_attrs = client
+            .do_compound(ReturnSecond(
+                (ReclaimCompleteArgs { one_fs: false }, PutRootFh),
+                GetAttrArgs {
+                    attr_request: [
+                        FileAttributeId::SupportedAttrs,
+                        FileAttributeId::MaxRead,
+                        FileAttributeId::MaxWrite,
+                    ]
+                    .into_iter()
+                    .collect(),
+                },
+            ))?
+            .object_attributes;
+
+        client.supported_attrs = root_attrs
+            .remove_as(FileAttributeId::SupportedAttrs)
+            .unwrap();
+        client.max_read = *root_attrs.get_as(FileAttributeId::MaxRead).unwrap();
+        client.max_write = *root_attrs.get_as(FileAttributeId::MaxWrite).unwrap();
+
+        Ok(client)
+    }
+
+    /// Connects to a pNFS data server. Only the session is set up, the client is meant to be used
+    /// for READ, WRITE and COMMIT with file handles from a layout.
+    pub fn new_data_server(transport: TransportT) -> Result<Self> {
+        Self::new_session(transport, ExchangeIdFlags::USE_PNFS_DS, 1)
+    }
+
+    fn new_session(
+        transport: TransportT,
+        flags: ExchangeIdFlags,
+        minor_version: u32,
+    ) -> Result<Self> {
+        let mut raw_client =
+            ClientWithoutSession::new(RpcClient::new(transport, NFS), minor_version);
 
         let client_owner = random_client_owner();
         let eid_res = raw_client.do_compound(ExchangeIdArgs {
             client_owner: client_owner.clone(),
-            flags: ExchangeIdFlags::empty(),
+            flags,
             state_protect: StateProtect::None,
             client_impl_id: None,
         })?;
@@ -410,206 +564,298 @@ impl<TransportT: Transport> Client<TransportT> {
             security_parameters: vec![],
         })?;
 
-        let mut client = Self {
+        let num_slots = session.fore_channel_attrs.max_requests.max(1);
+        Ok(Self {
             raw_client,
+            trunked: vec![],
+            next_connection: 0,
             session,
-            sequence_id: SequenceId(1),
+            slots: vec![SequenceId(1); num_slots as usize],
+            pipeline_config: PipelineConfig::default(),
             client_id,
             client_owner,
+            exchange_id_flags: flags,
+            server_flags: eid_res.flags,
+            server_owner: eid_res.server_owner,
+            server_scope: eid_res.server_scope,
             max_read: 0,
             max_write: 0,
             supported_attrs: Default::default(),
-        };
-
-        let mut root_attrs = client
-            .do_compound(ReturnSecond(
-                (ReclaimCompleteArgs { one_fs: false }, PutRootFh),
-                GetAttrArgs {
-                    attr_request: [
-                        FileAttributeId::SupportedAttrs,
-                        FileAttributeId::MaxRead,
-                        FileAttributeId::MaxWrite,
-                    ]
-                    .into_iter()
-                    .collect(),
-                },
-            ))?
-            .object_attributes;
-
-        client.supported_attrs = root_attrs
-            .remove_as(FileAttributeId::SupportedAttrs)
-            .unwrap();
-        client.max_read = *root_attrs.get_as(FileAttributeId::MaxRead).unwrap();
-        client.max_write = *root_attrs.get_as(FileAttributeId::MaxWrite).unwrap();
-
-        Ok(client)
+            id_mapper: Box::new(NumericIdMapper),
+            cache: None,
+            data_cache: None,
+            status_flags: SequenceStatusFlags::empty(),
+        })
     }
 
     fn do_compound<Args>(&mut self, args: Args) -> Result<Args::Response>
     where
         Args: CompoundRequest,
     {
-        let sequence = SequenceArgs {
+        let sequence = self.sequence_args(0, 0);
+        let connection = self.next_connection();
+        let (sequence, res) = self.connection(connection).do_compound((sequence, args))?;
+        self.status_flags = sequence.status_flags;
+        Ok(res)
+    }
+
+    /// The status flags the server sent with the last reply which wasn't pipelined, such as
+    /// `LEASE_MOVED`.
+    pub fn status_flags(&self) -> SequenceStatusFlags {
+        self.status_flags
+    }
+
+    /// The SEQUENCE arguments for the next request on the given slot.
+    fn sequence_args(&mut self, slot: usize, highest_slot: usize) -> SequenceArgs {
+        let sequence_id = self.slots[slot];
+        self.slots[slot].incr();
+        SequenceArgs {
             session_id: self.session.session_id,
-            sequence_id: self.sequence_id,
-            slot_id: SlotId(0),
-            highest_slot_id: SlotId(0),
+            sequence_id,
+            slot_id: SlotId(slot as u32),
+            highest_slot_id: SlotId(highest_slot as u32),
             cache_this: false,
-        };
+        }
+    }
 
-        self.sequence_id.incr();
+    /// How many compounds `read_all` and `write_all` keep in flight.
+    pub fn set_pipeline_config(&mut self, config: PipelineConfig) {
+        self.pipeline_config = config;
+    }
 
-        self.raw_client.do_compound(ReturnSecond(sequence, args))
+    pub fn minor_version(&self) -> u32 {
+        self.raw_client.minor_version
     }
 
+    pub fn set_id_mapper(&mut self, id_mapper: impl IdMapper + 'static) {
+        self.id_mapper = Box::new(id_mapper);
+    }
+
+    pub fn id_mapper(&self) -> &dyn IdMapper {
+        &*self.id_mapper
+    }
+
+    /// Decodes the `Owner` attribute to a local uid, if it is present.
+    pub fn owner_uid(&self, attrs: &FileAttributes) -> Option<u32> {
+        let owner: &String = attrs.get_as(FileAttributeId::Owner)?;
+        Some(self.id_mapper.decode_uid(owner))
+    }
+
+    /// Decodes the `OwnerGroup` attribute to a local gid, if it is present.
+    pub fn owner_gid(&self, attrs: &FileAttributes) -> Option<u32> {
+        let group: &String = attrs.get_as(FileAttributeId::OwnerGroup)?;
+        Some(self.id_mapper.decode_gid(group))
+    }
+
+    /// Encodes the given ids using the id mapper and sets them as the `Owner` and `OwnerGroup`.
+    pub fn set_owner(
+        &mut self,
+        handle: FileHandle,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        let mut attrs = FileAttributes::default();
+        if let Some(uid) = uid {
+            attrs.insert(FileAttribute::Owner(self.id_mapper.encode_uid(uid)));
+        }
+        if let Some(gid) = gid {
+            attrs.insert(FileAttribute::OwnerGroup(self.id_mapper.encode_gid(gid)));
+        }
+        self.set_attr(handle, attrs)
+    }
+
+    /// Fetches every supported attribute. `Owner` and `OwnerGroup` are the strings the server
+    /// sent, see `owner_uid` and `owner_gid` for mapping them.
     pub fn get_attr(&mut self, handle: FileHandle) -> Result<GetAttrRes> {
         let mut supported_attrs = self.supported_attrs.clone();
 
         supported_attrs.remove(FileAttributeId::TimeAccessSet);
         supported_attrs.remove(FileAttributeId::TimeModifySet);
 
-        self.do_compound(ReturnSecond(
-            PutFhArgs { object: handle },
-            GetAttrArgs {
-                attr_request: supported_attrs,
-            },
-        ))
+        Ok(GetAttrRes {
+            object_attributes: self.fetch_attributes(handle, supported_attrs)?,
+        })
+    }
+
+    /// Fetches the requested attributes, skipping any the server doesn't support. The owner and
+    /// group are the strings the server sent, without going through the id mapper.
+    pub fn get_attributes(
+        &mut self,
+        handle: FileHandle,
+        request: impl Into<EnumSet<FileAttributeId>>,
+    ) -> Result<Attributes> {
+        let attr_request = request
+            .into()
+            .into_iter()
+            .filter(|a| self.supported_attrs.contains(*a))
+            .collect();
+        Ok(self.fetch_attributes(handle, attr_request)?.into())
     }
 
-    pub fn look_up(&mut self, path: impl AsRef<Path>) -> Result<FileHandle> {
+    fn fetch_attributes_uncached(
+        &mut self,
+        handle: FileHandle,
+        attr_request: EnumSet<FileAttributeId>,
+    ) -> Result<FileAttributes> {
         Ok(self
             .do_compound(ReturnSecond(
-                (
-                    PutRootFh,
-                    Vec::from_iter(path.as_ref().components().filter_map(|c| match c {
-                        Component::Normal(p) => Some(LookUpArgs {
-                            object_name: p.to_str().unwrap().into(),
-                        }),
-                        _ => None,
-                    })),
-                ),
-                GetFh,
+                PutFhArgs { object: handle },
+                GetAttrArgs { attr_request },
             ))?
-            .object)
+            .object_attributes)
+    }
+
+    /// Sets the given attributes. The owner and group are sent as they are, use `set_owner` to
+    /// set them from ids.
+    pub fn set_attributes(&mut self, handle: FileHandle, attrs: Attributes) -> Result<()> {
+        self.set_attr(handle, attrs.into())
     }
 
     pub fn read(&mut self, handle: FileHandle, offset: u64, count: u32) -> Result<ReadRes> {
+        self.read_with_state(handle, StateId::anonymous(), offset, count)
+    }
+
+    fn read_with_state(
+        &mut self,
+        handle: FileHandle,
+        state_id: StateId,
+        offset: u64,
+        count: u32,
+    ) -> Result<ReadRes> {
+        if self.data_cache.is_some() {
+            return self.read_cached(&handle, state_id, offset, count);
+        }
         self.do_compound(ReturnSecond(
             PutFhArgs { object: handle },
             ReadArgs {
-                state_id: StateId::anonymous(),
+                state_id,
                 offset,
                 count,
             },
         ))
     }
 
-    pub fn read_all(&mut self, handle: FileHandle, mut sink: impl io::Write) -> Result<()> {
-        let mut offset = 0;
-        loop {
-            let read_res = self.read(handle.clone(), offset, self.max_read.try_into().unwrap())?;
-            offset += read_res.data.len() as u64;
-            sink.write_all(&read_res.data)?;
-            if read_res.eof {
-                break;
-            }
+    /// Reads the whole file into `sink`, keeping several READs in flight as `PipelineConfig` says.
+    pub fn read_all(&mut self, handle: FileHandle, sink: impl io::Write) -> Result<()> {
+        if self.data_cache.is_some() {
+            return self.read_all_cached(handle, sink);
         }
-        Ok(())
+        self.read_files([Ok((handle, u64::MAX, InOrder::new(sink)))], 1)
     }
 
     pub fn write(&mut self, handle: FileHandle, offset: u64, data: Vec<u8>) -> Result<WriteRes> {
+        self.write_with_state(
+            handle,
+            StateId::anonymous(),
+            offset,
+            StableHow::FileSync,
+            data,
+        )
+    }
+
+    /// Like `write`, but with a choice of how stable the data has to be before the reply. Data
+    /// written `Unstable` is only safe once `commit` returns the same verifier as the write did.
+    pub fn write_with_stability(
+        &mut self,
+        handle: FileHandle,
+        offset: u64,
+        stable: StableHow,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        self.write_with_state(handle, StateId::anonymous(), offset, stable, data)
+    }
+
+    fn write_with_state(
+        &mut self,
+        handle: FileHandle,
+        state_id: StateId,
+        offset: u64,
+        stable: StableHow,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        self.invalidate_attributes(&handle);
+        self.invalidate_data_range(&handle, offset, data.len() as u64);
         self.do_compound(ReturnSecond(
             PutFhArgs { object: handle },
             WriteArgs {
-                state_id: StateId::anonymous(),
+                state_id,
                 offset,
-                stable: StableHow::FileSync,
+                stable,
                 data,
             },
         ))
     }
 
-    pub fn write_all(&mut self, handle: FileHandle, mut source: impl io::Read) -> Result<()> {
-        let mut offset = 0;
-        loop {
-            let mut buf = vec![0; self.max_write as usize];
-            let amount_read = source.read(&mut buf[..])?;
-            if amount_read == 0 {
-                break;
-            }
-
-            buf.resize(amount_read, 0);
-
-            while !buf.is_empty() {
-                let write_res = self.write(handle.clone(), offset, buf.clone())?;
-                buf = buf[write_res.count as usize..].to_owned();
-            }
+    /// Asks the server to put all `Unstable` writes to the file on stable storage.
+    pub fn commit(&mut self, handle: FileHandle) -> Result<CommitRes> {
+        self.do_compound(ReturnSecond(
+            PutFhArgs { object: handle },
+            CommitArgs {
+                offset: 0,
+                count: 0,
+            },
+        ))
+    }
 
-            offset += amount_read as u64;
-        }
+    fn close(&mut self, handle: FileHandle, open_state_id: StateId) -> Result<()> {
+        self.do_compound(ReturnSecond(
+            PutFhArgs { object: handle },
+            CloseArgs {
+                sequence_id: SequenceId(0),
+                open_stateid: open_state_id,
+            },
+        ))?;
         Ok(())
     }
 
+    /// Writes everything from `source` UNSTABLE, keeping several WRITEs in flight as
+    /// `PipelineConfig` says, then commits it. Anything the server loses to a reboot before the
+    /// commit is sent again.
+    pub fn write_all(&mut self, handle: FileHandle, source: impl io::Read) -> Result<()> {
+        self.write_files([Ok((handle, source))], 1)
+    }
+
     pub fn create_file(&mut self, parent: FileHandle, name: &str) -> Result<FileHandle> {
-        Ok(self
-            .do_compound(ReturnSecond(
-                (
-                    PutFhArgs { object: parent },
-                    OpenArgs {
-                        sequence_id: SequenceId(0),
-                        share_access: ShareAccess::WRITE,
-                        share_deny: ShareDeny::NONE,
-                        owner: StateOwner {
-                            client_id: self.client_id,
-                            opaque: self.client_owner.owner_id.clone(),
-                        },
-                        open_how: OpenFlag::OpenCreate(CreateHow::Exclusive {
-                            create_verifier: Verifier(0),
-                        }),
-                        claim: OpenClaim::Null { file: name.into() },
-                    },
-                ),
-                GetFh,
-            ))?
-            .object)
+        Ok(self.create_file_open(parent, name)?.0)
     }
 
-    pub fn read_dir(
+    /// Like `create_file`, but also returns the state id of the open, for closing it with.
+    pub(crate) fn create_file_open(
         &mut self,
-        handle: FileHandle,
-        attr_request: EnumSet<FileAttributeId>,
-    ) -> Result<Vec<DirectoryEntry>> {
-        let mut entries = vec![];
-        let attr_request: EnumSet<_> = attr_request
-            .into_iter()
-            .filter(|a| self.supported_attrs.contains(*a))
-            .collect();
-
-        let mut cookie = Cookie::initial();
-        loop {
-            let res = self.do_compound(ReturnSecond(
-                PutFhArgs {
-                    object: handle.clone(),
-                },
-                ReadDirArgs {
-                    cookie,
-                    cookie_verifier: Verifier(0),
-                    directory_count: 1000,
-                    max_count: 1000,
-                    attr_request: attr_request.clone(),
+        parent: FileHandle,
+        name: &str,
+    ) -> Result<(FileHandle, StateId)> {
+        let ((), open_res, get_fh) = self.do_compound((
+            PutFhArgs {
+                object: parent.clone(),
+            },
+            OpenArgs {
+                sequence_id: SequenceId(0),
+                share_access: ShareAccess::WRITE,
+                share_deny: ShareDeny::NONE,
+                owner: StateOwner {
+                    client_id: self.client_id,
+                    opaque: self.client_owner.owner_id.clone(),
                 },
-            ))?;
-
-            entries.extend(res.reply.entries);
-
-            if res.reply.eof {
-                break Ok(entries);
-            }
-            cookie = entries.last().unwrap().cookie;
-        }
+                open_how: OpenFlag::OpenCreate(CreateHow::Exclusive {
+                    create_verifier: Verifier(0),
+                }),
+                claim: OpenClaim::Null { file: name.into() },
+            },
+            GetFh,
+        ))?;
+        let entry = (get_fh.object.clone(), Some(FileType::Regular));
+        self.entry_added(&parent, name, Some(entry), &open_res.change_info);
+        Ok((get_fh.object, open_res.state_id))
     }
 
+    /// Sets the given attributes, with `Owner` and `OwnerGroup` sent as they are. Use
+    /// `set_owner` to set them from ids.
     pub fn set_attr(&mut self, handle: FileHandle, attrs: FileAttributes) -> Result<()> {
+        self.invalidate_attributes(&handle);
+        if attrs.get(FileAttributeId::Size).is_some() {
+            self.invalidate_data(handle.clone());
+        }
         self.do_compound(ReturnSecond(
             PutFhArgs { object: handle },
             SetAttrArgs {
@@ -620,15 +866,63 @@ impl<TransportT: Transport> Client<TransportT> {
         Ok(())
     }
 
+    fn get_single_attr<T>(&mut self, handle: FileHandle, id: FileAttributeId) -> Result<T>
+    where
+        T: TryFrom<FileAttribute>,
+    {
+        self.fetch_attributes(handle, [id].into_iter().collect())?
+            .remove_as(id)
+            .ok_or(Error::MissingAttribute(id))
+    }
+
+    pub fn get_acl(&mut self, handle: FileHandle) -> Result<Acl> {
+        self.get_single_attr(handle, FileAttributeId::Acl)
+    }
+
+    pub fn set_acl(&mut self, handle: FileHandle, acl: Acl) -> Result<()> {
+        self.set_attr(handle, [FileAttribute::Acl(acl)].into_iter().collect())
+    }
+
+    /// Fetches the ACL, passes it through the given edit and writes the result back.
+    pub fn modify_acl(
+        &mut self,
+        handle: FileHandle,
+        edit: impl FnOnce(AclBuilder) -> AclBuilder,
+    ) -> Result<Acl> {
+        let acl = edit(self.get_acl(handle.clone())?.into()).build();
+        self.set_acl(handle, acl.clone())?;
+        Ok(acl)
+    }
+
+    pub fn get_dacl(&mut self, handle: FileHandle) -> Result<AclWithFlags> {
+        self.get_single_attr(handle, FileAttributeId::Dacl)
+    }
+
+    pub fn set_dacl(&mut self, handle: FileHandle, dacl: AclWithFlags) -> Result<()> {
+        self.set_attr(handle, [FileAttribute::Dacl(dacl)].into_iter().collect())
+    }
+
+    pub fn get_sacl(&mut self, handle: FileHandle) -> Result<AclWithFlags> {
+        self.get_single_attr(handle, FileAttributeId::Sacl)
+    }
+
+    pub fn set_sacl(&mut self, handle: FileHandle, sacl: AclWithFlags) -> Result<()> {
+        self.set_attr(handle, [FileAttribute::Sacl(sacl)].into_iter().collect())
+    }
+
     pub fn remove(&mut self, handle: FileHandle, entry_name: &str) -> Result<ChangeInfo> {
-        Ok(self
+        let change_info = self
             .do_compound(ReturnSecond(
-                PutFhArgs { object: handle },
+                PutFhArgs {
+                    object: handle.clone(),
+                },
                 RemoveArgs {
                     target: entry_name.into(),
                 },
             ))?
-            .change_info)
+            .change_info;
+        self.entry_removed(&handle, entry_name, &change_info);
+        Ok(change_info)
     }
 
     pub fn rename(
@@ -638,17 +932,58 @@ impl<TransportT: Transport> Client<TransportT> {
         src_entry: &str,
         target_entry: &str,
     ) -> Result<RenameRes> {
-        self.do_compound(ReturnSecond(
+        let res = self.do_compound(ReturnSecond(
             (
-                PutFhArgs { object: src_dir },
+                PutFhArgs {
+                    object: src_dir.clone(),
+                },
                 SaveFh,
-                PutFhArgs { object: target_dir },
+                PutFhArgs {
+                    object: target_dir.clone(),
+                },
             ),
             RenameArgs {
                 old_name: src_entry.to_owned(),
                 new_name: target_entry.to_owned(),
             },
-        ))
+        ))?;
+        self.entry_renamed(
+            (&src_dir, src_entry),
+            (&target_dir, target_entry),
+            (&res.source_change_info, &res.target_change_info),
+        );
+        Ok(res)
+    }
+
+    fn create_object(
+        &mut self,
+        parent_dir: FileHandle,
+        name: &str,
+        object_type: CreateType,
+        attrs: FileAttributes,
+    ) -> Result<FileHandle> {
+        let file_type = match &object_type {
+            CreateType::Link(_) => FileType::Link,
+            CreateType::Block(_) => FileType::Block,
+            CreateType::Character(_) => FileType::Character,
+            CreateType::Socket => FileType::Socket,
+            CreateType::Fifo => FileType::Fifo,
+            CreateType::Directory => FileType::Directory,
+        };
+        let ((), create_res, get_fh) = self.do_compound((
+            PutFhArgs {
+                object: parent_dir.clone(),
+            },
+            CreateArgs {
+                object_type,
+                object_name: name.to_owned(),
+                create_attrs: attrs,
+            },
+            GetFh,
+        ))?;
+        let entry = (get_fh.object.clone(), Some(file_type));
+        self.entry_added(&parent_dir, name, Some(entry), &create_res.change_info);
+        Ok(get_fh.object)
     }
 
     pub fn create_directory(
@@ -657,18 +992,78 @@ impl<TransportT: Transport> Client<TransportT> {
         name: &str,
         attrs: FileAttributes,
     ) -> Result<FileHandle> {
+        self.create_object(parent_dir, name, CreateType::Directory, attrs)
+    }
+
+    /// Creates a symlink pointing at `target`, which isn't checked by the server.
+    pub fn create_symlink(
+        &mut self,
+        parent_dir: FileHandle,
+        name: &str,
+        target: &str,
+        attrs: FileAttributes,
+    ) -> Result<FileHandle> {
+        self.create_object(parent_dir, name, CreateType::Link(target.into()), attrs)
+    }
+
+    /// The target of a symlink.
+    pub fn read_link(&mut self, handle: FileHandle) -> Result<String> {
         Ok(self
+            .do_compound(ReturnSecond(PutFhArgs { object: handle }, ReadLink))?
+            .link)
+    }
+
+    /// Adds another name for an existing file in the given directory.
+    pub fn hard_link(
+        &mut self,
+        handle: FileHandle,
+        dir: FileHandle,
+        name: &str,
+    ) -> Result<ChangeInfo> {
+        let change_info = self
             .do_compound(ReturnSecond(
                 (
-                    PutFhArgs { object: parent_dir },
-                    CreateArgs {
-                        object_type: CreateType::Directory,
-                        object_name: name.to_owned(),
-                        create_attrs: attrs,
+                    PutFhArgs {
+                        object: handle.clone(),
+                    },
+                    SaveFh,
+                    PutFhArgs {
+                        object: dir.clone(),
                     },
                 ),
-                GetFh,
+                LinkArgs {
+                    new_name: name.into(),
+                },
             ))?
-            .object)
+            .change_info;
+        self.invalidate_attributes(&handle);
+        self.entry_added(&dir, name, None, &change_info);
+        Ok(change_info)
     }
+
+    /// Creates a device, socket or FIFO.
+    pub fn mknod(
+        &mut self,
+        parent_dir: FileHandle,
+        name: &str,
+        special_file: SpecialFile,
+        attrs: FileAttributes,
+    ) -> Result<FileHandle> {
+        let object_type = match special_file {
+            SpecialFile::Block(device) => CreateType::Block(device),
+            SpecialFile::Character(device) => CreateType::Character(device),
+            SpecialFile::Socket => CreateType::Socket,
+            SpecialFile::Fifo => CreateType::Fifo,
+        };
+        self.create_object(parent_dir, name, object_type, attrs)
+    }
+}
+
+/// The kinds of file `Client::mknod` creates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpecialFile {
+    Block(DeviceData),
+    Character(DeviceData),
+    Socket,
+    Fifo,
 }
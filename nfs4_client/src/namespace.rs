@@ -0,0 +1,206 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Namespaces which span several servers. When a path goes into a filesystem the server says has
+//! moved, the `fs_locations` it gives are used to connect to a server which has it, with a session
+//! of its own, and the rest of the path is looked up there.
+
+use super::path_walk::{path_error, PathError};
+use super::{Client, Connector, Error, LookUpOptions, Result, NFS_PORT};
+use nfs4::{FileHandle, FsId, FsLocations, PathName};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs as _};
+use std::path::{Path, PathBuf};
+
+/// How many referrals to follow looking up one path.
+const MAX_REFERRALS: usize = 8;
+
+/// A point where looking up a path went from one filesystem into another.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountCrossing {
+    /// The path of the mount point, with any symlinks before it replaced by their targets.
+    pub path: PathBuf,
+    /// The filesystem on the other side.
+    pub fs_id: FsId,
+    /// For a referral, the server the rest of the path was looked up on.
+    pub server: Option<String>,
+}
+
+/// Where a path goes into a filesystem which is on another server.
+#[derive(Debug)]
+pub struct Referral {
+    /// What the server which referred elsewhere calls the filesystem.
+    pub fs_id: FsId,
+    pub locations: FsLocations,
+    /// What is left of the path being looked up, below the referral.
+    pub rest: PathBuf,
+    /// The mount points crossed before getting to the referral.
+    pub crossings: Vec<MountCrossing>,
+}
+
+/// A path resolved by `Namespace::look_up`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Located {
+    /// Which of the namespace's clients the handle is for, see `Namespace::client`.
+    pub server: usize,
+    pub handle: FileHandle,
+    pub crossings: Vec<MountCrossing>,
+}
+
+pub(crate) fn absolute_path(path_name: &PathName) -> PathBuf {
+    let mut path = PathBuf::from("/");
+    path.extend(path_name.0.iter().map(|c| &c.0));
+    path
+}
+
+/// Where a path on a server a referral led to is in the namespace, given the namespace path
+/// `mount` of the referral and the path `root` it went to on the server.
+fn namespace_path(path: &Path, mount: &Path, root: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(rest) if rest.as_os_str().is_empty() => mount.to_owned(),
+        Ok(rest) => mount.join(rest),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// The addresses of a server named in `fs_locations`. Besides a host name or IP address, which
+/// is connected to on the NFS port, `host:port` is accepted.
+pub(crate) fn server_addrs(server: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs = server
+        .to_socket_addrs()
+        .or_else(|_| (server, NFS_PORT).to_socket_addrs())?;
+    Ok(addrs.collect())
+}
+
+/// A namespace made of the filesystems of several servers. It starts with a client for one
+/// server, and connects to others with the given connector as referrals lead to them.
+pub struct Namespace<ConnectorT: Connector> {
+    connector: ConnectorT,
+    /// The server the namespace starts on, then the ones referrals have led to, by address.
+    servers: Vec<(Option<SocketAddr>, Client<ConnectorT::Transport>)>,
+}
+
+impl<ConnectorT: Connector> Namespace<ConnectorT> {
+    pub fn new(root: Client<ConnectorT::Transport>, connector: ConnectorT) -> Self {
+        Self {
+            connector,
+            servers: vec![(None, root)],
+        }
+    }
+
+    /// The client for a server of the namespace, 0 being the one it starts on.
+    ///
+    /// # Panics
+    ///
+    /// If there is no such server.
+    pub fn client(&mut self, server: usize) -> &mut Client<ConnectorT::Transport> {
+        &mut self.servers[server].1
+    }
+
+    /// Looks up an absolute path, following symlinks and referrals.
+    pub fn look_up(&mut self, path: impl AsRef<Path>) -> Result<Located> {
+        self.look_up_with(path, LookUpOptions::default())
+    }
+
+    /// Looks up an absolute path, following referrals. Paths in what is returned, including
+    /// errors, are in terms of the namespace rather than of the server they were found on.
+    pub fn look_up_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: LookUpOptions,
+    ) -> Result<Located> {
+        let mut server = 0;
+        let mut path = path.as_ref().to_owned();
+        let mut mount = PathBuf::from("/");
+        let mut root = PathBuf::from("/");
+        let mut crossings = vec![];
+        for _ in 0..=MAX_REFERRALS {
+            let (handle, found) = match self.servers[server].1.look_up_mounts(&path, options) {
+                Ok(found) => found,
+                Err(Error::Path(error)) => {
+                    let PathError { path: at, error } = *error;
+                    let at = namespace_path(&at, &mount, &root);
+                    let Error::Referral(referral) = error else {
+                        return Err(path_error(at, error));
+                    };
+                    let Referral {
+                        fs_id,
+                        locations,
+                        rest,
+                        crossings: found,
+                    } = *referral;
+                    crossings.extend(found.into_iter().map(|c| MountCrossing {
+                        path: namespace_path(&c.path, &mount, &root),
+                        ..c
+                    }));
+
+                    let (index, name, location_root) = self
+                        .connect(&locations)
+                        .map_err(|e| path_error(at.clone(), e))?;
+                    crossings.push(MountCrossing {
+                        path: at.clone(),
+                        fs_id,
+                        server: Some(name),
+                    });
+                    server = index;
+                    path = location_root.join(rest);
+                    mount = at;
+                    root = location_root;
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            crossings.extend(found.into_iter().map(|c| MountCrossing {
+                path: namespace_path(&c.path, &mount, &root),
+                ..c
+            }));
+            return Ok(Located {
+                server,
+                handle,
+                crossings,
+            });
+        }
+        Err(path_error(mount, Error::TooManyReferrals))
+    }
+
+    /// Gets a client for one of the locations, reusing one for a server already connected to.
+    /// Returns its index, the name of the server and the path of the filesystem on it.
+    fn connect(&mut self, locations: &FsLocations) -> Result<(usize, String, PathBuf)> {
+        let mut last_error = None;
+        for location in &locations.locations {
+            let root = absolute_path(&location.root_path);
+            for name in &location.server {
+                let addrs = match server_addrs(name) {
+                    Ok(addrs) => addrs,
+                    Err(error) => {
+                        last_error = Some(error.into());
+                        continue;
+                    }
+                };
+                for addr in addrs {
+                    if let Some(index) = self.servers.iter().position(|(a, _)| *a == Some(addr)) {
+                        return Ok((index, name.clone(), root));
+                    }
+                    let client = self
+                        .connector
+                        .connect(addr)
+                        .map_err(Error::from)
+                        .and_then(Client::new);
+                    match client {
+                        Ok(client) => {
+                            self.servers.push((Some(addr), client));
+                            return Ok((self.servers.len() - 1, name.clone(), root));
+                        }
+                        Err(error) => last_error = Some(error),
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no usable server in {locations:?}"),
+            )
+            .into()
+        }))
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Just enough of NFSv3 to do I/O against a flexible files data server.
+
+use super::*;
+use sun_rpc::{AuthSysParameters, Gid, OpaqueAuth, Uid};
+
+/// An NFSv3 connection to a data server. The status codes NFSv3 shares with NFSv4 have the same
+/// values, so errors are reported as the NFSv4 ones.
+pub(crate) struct Nfs3DataServer<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+}
+
+impl<TransportT: Transport> Nfs3DataServer<TransportT> {
+    pub(crate) fn new(transport: TransportT, uid: u32, gid: u32) -> Self {
+        let mut rpc_client =
+            RpcClient::new(transport, nfs3::NFS_PROGRAM).with_version(nfs3::NFS_VERSION);
+        rpc_client.set_credential(OpaqueAuth::auth_sys(AuthSysParameters {
+            stamp: 0,
+            machine_name: "nfs4-client".into(),
+            uid: Uid(uid),
+            gid: Gid(gid),
+            gids: vec![Gid(gid)],
+        }));
+        Self { rpc_client }
+    }
+
+    fn call<Args: serde::Serialize, Res: serde::de::DeserializeOwned + std::fmt::Debug, Fail>(
+        &mut self,
+        procedure: nfs3::Procedure,
+        args: Args,
+    ) -> Result<Res>
+    where
+        nfs3::StatusResult<Res, Fail>: serde::de::DeserializeOwned + std::fmt::Debug,
+    {
+        self.rpc_client.send_request(procedure.into(), args)?;
+        match self
+            .rpc_client
+            .receive_reply::<nfs3::StatusResult<Res, Fail>>()?
+        {
+            nfs3::StatusResult::Ok(res) => Ok(res),
+            nfs3::StatusResult::Err(e, _) => Err(Error::Protocol(
+                StatusError::try_from(e as u32).unwrap_or(StatusError::ServerFault),
+            )),
+        }
+    }
+
+    pub(crate) fn read(&mut self, file: FileHandle, offset: u64, count: u32) -> Result<ReadRes> {
+        let res: nfs3::ReadRes = self.call::<_, _, nfs3::AttributesFail>(
+            nfs3::Procedure::Read,
+            nfs3::ReadArgs {
+                file: nfs3::FileHandle(file.0),
+                offset,
+                count,
+            },
+        )?;
+        Ok(ReadRes {
+            eof: res.eof,
+            data: res.data,
+        })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        file: FileHandle,
+        offset: u64,
+        stable: StableHow,
+        data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        let stable = match stable {
+            StableHow::Unstable => nfs3::StableHow::Unstable,
+            StableHow::DataSync => nfs3::StableHow::DataSync,
+            StableHow::FileSync => nfs3::StableHow::FileSync,
+        };
+        let res: nfs3::WriteRes = self.call::<_, _, nfs3::WccFail>(
+            nfs3::Procedure::Write,
+            nfs3::WriteArgs {
+                file: nfs3::FileHandle(file.0),
+                offset,
+                count: data.len() as u32,
+                stable,
+                data,
+            },
+        )?;
+        Ok(WriteRes {
+            count: res.count,
+            committed: match res.committed {
+                nfs3::StableHow::Unstable => StableHow::Unstable,
+                nfs3::StableHow::DataSync => StableHow::DataSync,
+                nfs3::StableHow::FileSync => StableHow::FileSync,
+            },
+            write_veritifer: Verifier(res.verifier.0),
+        })
+    }
+
+    pub(crate) fn commit(&mut self, file: FileHandle) -> Result<CommitRes> {
+        let res: nfs3::CommitRes = self.call::<_, _, nfs3::WccFail>(
+            nfs3::Procedure::Commit,
+            nfs3::CommitArgs {
+                file: nfs3::FileHandle(file.0),
+                offset: 0,
+                count: 0,
+            },
+        )?;
+        Ok(CommitRes {
+            write_verifier: Verifier(res.verifier.0),
+        })
+    }
+}
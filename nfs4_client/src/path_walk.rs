@@ -0,0 +1,442 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Resolving paths the way a local filesystem would: `..` goes to the parent with LOOKUPP and
+//! symlinks are followed. Several components are looked up in each compound, with the type of
+//! each one fetched so symlinks can be spotted. With the cache enabled, components it knows
+//! about are skipped. Changes of `FsId` along the way are recorded as mount points, and a
+//! component the server says has `Moved` comes back as `Error::Referral` with where it went.
+
+use super::cache::{change, file_type};
+use super::namespace::{MountCrossing, Referral};
+use super::Client;
+use super::{
+    CompoundError, CompoundRequest, Error, GetFh, LookUpP, PutRootFh, Result, ReturnSecond,
+    TempResult,
+};
+use nfs4::{
+    ArgOp, AttrRequest, FileAttributeId, FileAttributes, FileHandle, FileType, FsId, FsLocations,
+    GetAttrArgs, LookUpArgs, PutFhArgs, ResOp, StatusError,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use sun_rpc_client::Transport;
+
+/// How `Client::look_up_with` and `Client::look_up_from` resolve a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LookUpOptions {
+    /// Follow a symlink in the final component. Otherwise the symlink itself is returned, like
+    /// `lstat` does.
+    pub follow_final_symlink: bool,
+    /// How many symlinks to follow before failing with `Error::TooManySymlinks`.
+    pub max_symlinks: usize,
+}
+
+impl Default for LookUpOptions {
+    fn default() -> Self {
+        Self {
+            follow_final_symlink: true,
+            max_symlinks: 40,
+        }
+    }
+}
+
+impl LookUpOptions {
+    /// Doesn't follow a symlink in the final component.
+    pub fn no_follow() -> Self {
+        Self {
+            follow_final_symlink: false,
+            ..Self::default()
+        }
+    }
+}
+
+/// Which part of a path couldn't be resolved.
+#[derive(Debug)]
+pub struct PathError {
+    /// The path up to and including the component which failed, with any symlinks before it
+    /// replaced by their targets.
+    pub path: PathBuf,
+    pub error: Error,
+}
+
+pub(crate) fn path_error(path: PathBuf, error: Error) -> Error {
+    Error::Path(Box::new(PathError { path, error }))
+}
+
+#[derive(Clone)]
+enum Step {
+    /// The directory to start from, or the root.
+    Start(Option<FileHandle>),
+    Name(String),
+    Parent,
+}
+
+/// Moves to the next component, returning its handle along with its type, `Change` and `FsId`.
+impl CompoundRequest for Step {
+    type Response = (FileHandle, FileAttributes);
+    type Geometry = ();
+
+    fn into_arg_array(self) -> (Vec<ArgOp>, Self::Geometry) {
+        let first = match self {
+            Self::Start(Some(object)) => PutFhArgs { object }.into(),
+            Self::Start(None) => PutRootFh.into(),
+            Self::Name(object_name) => LookUpArgs { object_name }.into(),
+            Self::Parent => LookUpP.into(),
+        };
+        let get_attr = GetAttrArgs {
+            attr_request: AttrRequest::new().type_().change().fs_id().into(),
+        };
+        (vec![first, GetFh.into(), get_attr.into()], ())
+    }
+
+    fn process_reply(res_array: &mut VecDeque<ResOp>, _geometry: ()) -> Result<Self::Response> {
+        match res_array.pop_front() {
+            Some(
+                ResOp::LookUp(res)
+                | ResOp::LookUpP(res)
+                | ResOp::PutFh(res)
+                | ResOp::PutRootFh(res),
+            ) => TempResult::from(res).0?,
+            op => {
+                return Err(Error::CompoundResponseMismatch(format!(
+                    "expected LookUp, LookUpP, PutFh or PutRootFh, got {op:?}"
+                )))
+            }
+        }
+        let handle = GetFh::process_reply(res_array, ())?.object;
+        let attrs = GetAttrArgs::process_reply(res_array, ())?.object_attributes;
+        Ok((handle, attrs))
+    }
+}
+
+/// The steps to resolve `path`, with the path of each one for errors. `..` in the root
+/// directory stays there. That is only known here when starting from the root, otherwise the walk
+/// finds out when LOOKUPP fails.
+fn path_steps(base: &Path, path: &Path, at_root: bool) -> Result<VecDeque<(Step, PathBuf)>> {
+    let mut steps = VecDeque::new();
+    let mut depth: usize = 0;
+    let mut display = base.to_owned();
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                if at_root && depth == 0 {
+                    continue;
+                }
+                depth = depth.saturating_sub(1);
+                display.push("..");
+                steps.push_back((Step::Parent, display.clone()));
+            }
+            Component::Normal(name) => {
+                depth += 1;
+                display.push(name);
+                let name = name.to_str().ok_or_else(|| {
+                    let error = io::Error::new(io::ErrorKind::InvalidFilename, "not UTF-8");
+                    path_error(display.clone(), error.into())
+                })?;
+                steps.push_back((Step::Name(name.into()), display.clone()));
+            }
+        }
+    }
+    Ok(steps)
+}
+
+fn fs_id(attrs: &FileAttributes) -> Option<FsId> {
+    attrs.get_as(FileAttributeId::FsId).copied()
+}
+
+/// Keeps track of the filesystem the walk is in, noting where it changes.
+#[derive(Default)]
+struct Crossings {
+    current: Option<FsId>,
+    crossings: Vec<MountCrossing>,
+}
+
+impl Crossings {
+    /// Moves to a component in the given filesystem. An unknown one is never a crossing, but the
+    /// next known one isn't compared against the one before it either.
+    fn step(&mut self, fs_id: Option<FsId>, path: &Path) {
+        if let (Some(current), Some(new)) = (&self.current, &fs_id) {
+            if current != new {
+                self.crossings.push(MountCrossing {
+                    path: path.to_owned(),
+                    fs_id: *new,
+                    server: None,
+                });
+            }
+        }
+        self.current = fs_id;
+    }
+}
+
+/// The rest of a path from the steps left in it.
+fn remaining_path(steps: &VecDeque<(Step, PathBuf)>) -> PathBuf {
+    steps
+        .iter()
+        .map(|(step, _)| match step {
+            Step::Name(name) => name.as_str(),
+            _ => "..",
+        })
+        .collect()
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Looks up an absolute path, following symlinks.
+    pub fn look_up(&mut self, path: impl AsRef<Path>) -> Result<FileHandle> {
+        self.look_up_with(path, LookUpOptions::default())
+    }
+
+    /// Looks up an absolute path.
+    pub fn look_up_with(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: LookUpOptions,
+    ) -> Result<FileHandle> {
+        self.walk_path(None, path.as_ref(), options)
+    }
+
+    /// Like `look_up_with`, but also returns the mount points the path went through, where the
+    /// `FsId` changed.
+    pub fn look_up_mounts(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: LookUpOptions,
+    ) -> Result<(FileHandle, Vec<MountCrossing>)> {
+        self.walk_path_crossings(None, path.as_ref(), options)
+    }
+
+    /// Looks up a path relative to the given directory. Absolute paths start from the root.
+    pub fn look_up_from(
+        &mut self,
+        dir: FileHandle,
+        path: impl AsRef<Path>,
+        options: LookUpOptions,
+    ) -> Result<FileHandle> {
+        let path = path.as_ref();
+        let dir = (!path.has_root()).then_some(dir);
+        self.walk_path(dir, path, options)
+    }
+
+    /// Does as many of `steps` as fit in a compound, starting from `dir` or the root. Returns
+    /// what the start and the ones which succeeded found, and the error for the next one if it
+    /// failed.
+    fn walk_steps(
+        &mut self,
+        dir: Option<FileHandle>,
+        steps: Vec<Step>,
+    ) -> Result<(Vec<<Step as CompoundRequest>::Response>, Option<Error>)> {
+        let mut steps: Vec<_> = [Step::Start(dir)].into_iter().chain(steps).collect();
+        let (found, error) = match self.compound(steps.clone()) {
+            Ok(found) => (found, None),
+            Err(Error::Compound(error)) => {
+                let CompoundError {
+                    index,
+                    status,
+                    results,
+                } = *error;
+                if index < 3 {
+                    return Err(status.into());
+                }
+                let mut results = results.into();
+                let found = (0..index / 3)
+                    .map(|_| Step::process_reply(&mut results, ()))
+                    .collect::<Result<_>>()?;
+                (found, Some(status.into()))
+            }
+            Err(error) => return Err(error),
+        };
+        steps.truncate(found.len());
+        self.cache_steps(&steps, &found);
+        Ok((found, error))
+    }
+
+    /// Records the names looked up and the attributes of what they refer to.
+    fn cache_steps(&mut self, steps: &[Step], found: &[<Step as CompoundRequest>::Response]) {
+        let Some(cache) = &mut self.cache else {
+            return;
+        };
+        let mut dir: Option<(&FileHandle, _)> = None;
+        for (step, (handle, attrs)) in steps.iter().zip(found) {
+            match (step, dir) {
+                (Step::Start(None), _) => cache.set_root(handle.clone()),
+                (Step::Name(name), Some((dir, Some(dir_change)))) => {
+                    let entry = (handle.clone(), file_type(attrs));
+                    cache.insert_entry(dir.clone(), dir_change, name.clone(), entry);
+                }
+                _ => {}
+            }
+            cache.insert_attributes(handle.clone(), attrs.clone());
+            dir = Some((handle, change(attrs)));
+        }
+    }
+
+    /// Skips the steps at the front which the cache knows the answer to. Symlinks are left for
+    /// the walk to deal with.
+    fn walk_cached(
+        &self,
+        dir: &mut Option<FileHandle>,
+        steps: &mut VecDeque<(Step, PathBuf)>,
+        crossings: &mut Crossings,
+    ) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let fs_id_request = AttrRequest::new().fs_id().into();
+        while let Some((Step::Name(name), _)) = steps.front() {
+            let Some(current) = dir.clone().or_else(|| cache.root()) else {
+                return;
+            };
+            match cache.look_up(&current, name) {
+                Some((handle, file_type)) if file_type != Some(FileType::Link) => {
+                    let attrs = cache.get_attributes(&handle, &fs_id_request);
+                    let (_, display) = steps.pop_front().unwrap();
+                    crossings.step(attrs.as_ref().and_then(fs_id), &display);
+                    *dir = Some(handle);
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Gets where the filesystem at `name` in `dir` or the root has moved to.
+    fn referral(&mut self, dir: Option<FileHandle>, name: String) -> Result<(FsId, FsLocations)> {
+        let look_up = LookUpArgs { object_name: name };
+        let get_attr = GetAttrArgs {
+            attr_request: AttrRequest::new().fs_id().fs_locations().into(),
+        };
+        let mut attrs = match dir {
+            Some(object) => {
+                self.do_compound(ReturnSecond((PutFhArgs { object }, look_up), get_attr))
+            }
+            None => self.do_compound(ReturnSecond((PutRootFh, look_up), get_attr)),
+        }?
+        .object_attributes;
+        let locations = attrs
+            .remove_as(FileAttributeId::FsLocations)
+            .ok_or(Error::MissingAttribute(FileAttributeId::FsLocations))?;
+        let fs_id = fs_id(&attrs).ok_or(Error::MissingAttribute(FileAttributeId::FsId))?;
+        Ok((fs_id, locations))
+    }
+
+    /// Whether `dir` is the root, where `..` leads back to itself.
+    fn is_root(&mut self, dir: Option<&FileHandle>) -> Result<bool> {
+        let Some(dir) = dir else {
+            return Ok(true);
+        };
+        let root = match self.cache.as_ref().and_then(|c| c.root()) {
+            Some(root) => root,
+            None => self.do_compound(ReturnSecond(PutRootFh, GetFh))?.object,
+        };
+        Ok(*dir == root)
+    }
+
+    fn walk_path(
+        &mut self,
+        dir: Option<FileHandle>,
+        path: &Path,
+        options: LookUpOptions,
+    ) -> Result<FileHandle> {
+        Ok(self.walk_path_crossings(dir, path, options)?.0)
+    }
+
+    fn walk_path_crossings(
+        &mut self,
+        mut dir: Option<FileHandle>,
+        path: &Path,
+        options: LookUpOptions,
+    ) -> Result<(FileHandle, Vec<MountCrossing>)> {
+        let ops_per_compound = self.session.fore_channel_attrs.max_operations as usize;
+        // Leaving room for the SEQUENCE and the ops finding the starting directory.
+        let steps_per_compound = (ops_per_compound.saturating_sub(4) / 3).max(1);
+
+        let base = if dir.is_none() {
+            PathBuf::from("/")
+        } else {
+            PathBuf::new()
+        };
+        let mut steps = path_steps(&base, path, dir.is_none())?;
+        let mut symlinks = 0;
+        let mut crossings = Crossings::default();
+        'walk: loop {
+            self.walk_cached(&mut dir, &mut steps, &mut crossings);
+            if steps.is_empty() {
+                break;
+            }
+            let batch = steps
+                .iter()
+                .take(steps_per_compound)
+                .map(|(step, _)| step.clone())
+                .collect();
+            let (found, error) = self.walk_steps(dir.clone(), batch)?;
+            let mut found = found.into_iter();
+            if let Some((_, start)) = found.next() {
+                crossings.current = fs_id(&start);
+            }
+
+            for (handle, attrs) in found {
+                let file_type = file_type(&attrs);
+                let (_, display) = steps.pop_front().unwrap();
+                crossings.step(fs_id(&attrs), &display);
+                let last = steps.is_empty();
+                if file_type != Some(FileType::Link) || (last && !options.follow_final_symlink) {
+                    dir = Some(handle);
+                    continue;
+                }
+
+                symlinks += 1;
+                if symlinks > options.max_symlinks {
+                    return Err(path_error(display, Error::TooManySymlinks));
+                }
+                let target = self
+                    .read_link(handle)
+                    .map_err(|e| path_error(display.clone(), e))?;
+                let target = Path::new(&target);
+                if target.has_root() {
+                    dir = None;
+                }
+                let base = if target.has_root() {
+                    PathBuf::from("/")
+                } else {
+                    display.parent().map(Path::to_owned).unwrap_or_default()
+                };
+                let mut target_steps = path_steps(&base, target, dir.is_none())?;
+                target_steps.extend(steps.drain(..));
+                steps = target_steps;
+                continue 'walk;
+            }
+
+            if let Some(error) = error {
+                let (step, display) = steps.pop_front().unwrap();
+                let no_parent = matches!(error, Error::Protocol(StatusError::NoEnt));
+                if matches!(step, Step::Parent) && no_parent && self.is_root(dir.as_ref())? {
+                    continue 'walk;
+                }
+                let moved = matches!(error, Error::Protocol(StatusError::Moved));
+                let error = match step {
+                    Step::Name(name)
+                        if moved && self.supported_attrs.contains(FileAttributeId::FsLocations) =>
+                    {
+                        let (fs_id, locations) = self
+                            .referral(dir, name)
+                            .map_err(|e| path_error(display.clone(), e))?;
+                        Error::Referral(Box::new(Referral {
+                            fs_id,
+                            locations,
+                            rest: remaining_path(&steps),
+                            crossings: crossings.crossings,
+                        }))
+                    }
+                    _ => error,
+                };
+                return Err(path_error(display, error));
+            }
+        }
+
+        let handle = match dir.or_else(|| self.cache.as_ref()?.root()) {
+            Some(handle) => handle,
+            None => self.do_compound(ReturnSecond(PutRootFh, GetFh))?.object,
+        };
+        Ok((handle, crossings.crossings))
+    }
+}
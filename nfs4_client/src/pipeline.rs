@@ -0,0 +1,188 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Keeps several compounds in flight, each on its own session slot, so a long round trip isn't
+//! paid once per READ or WRITE. With trunked connections they are spread across all of them.
+
+use super::{process_compound, Client, CompoundRequest, Result, ReturnSecond};
+use nfs4::SequenceArgs;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use sun_rpc::Xid;
+use sun_rpc_client::Transport;
+
+/// How many READ or WRITE compounds `Client::read_all` and `Client::write_all` keep in flight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PipelineConfig {
+    pub initial_window: usize,
+    pub max_window: usize,
+    /// Grow the window while round trips stay near the fastest seen, and halve it when they
+    /// start taking much longer, meaning requests are queueing somewhere.
+    pub adaptive: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            initial_window: 4,
+            max_window: 32,
+            adaptive: true,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Exactly `window` compounds in flight.
+    pub fn fixed(window: usize) -> Self {
+        Self {
+            initial_window: window,
+            max_window: window,
+            adaptive: false,
+        }
+    }
+}
+
+struct Window {
+    config: PipelineConfig,
+    size: usize,
+    min_rtt: Option<Duration>,
+}
+
+impl Window {
+    fn new(config: PipelineConfig, num_slots: usize) -> Self {
+        let config = PipelineConfig {
+            max_window: config.max_window.clamp(1, num_slots),
+            ..config
+        };
+        Self {
+            size: config.initial_window.clamp(1, config.max_window),
+            config,
+            min_rtt: None,
+        }
+    }
+
+    fn observe(&mut self, rtt: Duration) {
+        if !self.config.adaptive {
+            return;
+        }
+        let min_rtt = self.min_rtt.map_or(rtt, |m| m.min(rtt));
+        self.min_rtt = Some(min_rtt);
+        if rtt <= min_rtt * 2 {
+            self.size = (self.size + 1).min(self.config.max_window);
+        } else {
+            self.size = (self.size / 2).max(1);
+        }
+    }
+}
+
+type Geometry<Args> = <ReturnSecond<SequenceArgs, Args> as CompoundRequest>::Geometry;
+
+struct InFlight<Args: CompoundRequest, Info> {
+    connection: usize,
+    slot: usize,
+    sent: Instant,
+    geometry: Geometry<Args>,
+    info: Info,
+}
+
+/// Compounds of type `Args` which have been sent, each with some `Info` the caller needs to deal
+/// with the reply.
+pub(crate) struct Pipeline<Args: CompoundRequest, Info> {
+    window: Window,
+    free_slots: Vec<usize>,
+    /// How many compounds are in flight on each connection.
+    connections: Vec<usize>,
+    /// Keyed by connection as well, since each has its own xids.
+    in_flight: BTreeMap<(usize, Xid), InFlight<Args, Info>>,
+}
+
+impl<Args: CompoundRequest, Info> Pipeline<Args, Info> {
+    pub(crate) fn new<TransportT: Transport>(client: &Client<TransportT>) -> Self {
+        let num_slots = client.slots.len();
+        let window = Window::new(client.pipeline_config, num_slots);
+        Self {
+            free_slots: (0..window.config.max_window).rev().collect(),
+            window,
+            connections: vec![0; client.num_connections()],
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn can_send(&self) -> bool {
+        self.in_flight.len() < self.window.size && !self.free_slots.is_empty()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub(crate) fn send<TransportT: Transport>(
+        &mut self,
+        client: &mut Client<TransportT>,
+        args: Args,
+        info: Info,
+    ) -> Result<()> {
+        let slot = self.free_slots.pop().expect("no free slot");
+        let highest_slot = self
+            .in_flight
+            .values()
+            .map(|f| f.slot)
+            .fold(slot, usize::max);
+        let sequence = client.sequence_args(slot, highest_slot);
+        let connection = (0..self.connections.len())
+            .min_by_key(|&c| self.connections[c])
+            .unwrap();
+        let (xid, geometry) = client
+            .connection(connection)
+            .send_compound(ReturnSecond(sequence, args))?;
+        self.connections[connection] += 1;
+        let in_flight = InFlight {
+            connection,
+            slot,
+            sent: Instant::now(),
+            geometry,
+            info,
+        };
+        self.in_flight.insert((connection, xid), in_flight);
+        Ok(())
+    }
+
+    /// Waits for the next reply on the connection the oldest compound was sent on. The outer
+    /// error is for a broken connection, the inner one is the result of the compound.
+    pub(crate) fn receive<TransportT: Transport>(
+        &mut self,
+        client: &mut Client<TransportT>,
+    ) -> Result<(Info, Result<Args::Response>)> {
+        let connection = self
+            .in_flight
+            .values()
+            .min_by_key(|f| f.sent)
+            .expect("nothing in flight")
+            .connection;
+        let (xid, reply) = client.connection(connection).receive_compound()?;
+        let in_flight = self
+            .in_flight
+            .remove(&(connection, xid.clone()))
+            .ok_or_else(|| {
+                super::Error::CompoundResponseMismatch(format!("reply for unknown {xid:?}"))
+            })?;
+        self.connections[connection] -= 1;
+        self.free_slots.push(in_flight.slot);
+        self.window.observe(in_flight.sent.elapsed());
+        let res = process_compound::<ReturnSecond<SequenceArgs, Args>>(reply, in_flight.geometry);
+        Ok((in_flight.info, res))
+    }
+
+    /// Reads the replies still outstanding, so the connection can be used for other requests
+    /// after giving up part way through.
+    pub(crate) fn drain<TransportT: Transport>(&mut self, client: &mut Client<TransportT>) {
+        while !self.is_empty() {
+            if self.receive(client).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,548 @@
+// Copyright 2023 Remi Bernotavicius
+
+use super::flex_files::FlexFilesIo;
+use super::*;
+use std::collections::BTreeSet;
+use std::net::{SocketAddr, TcpStream};
+
+/// Opens connections to the data servers named in a device address.
+pub trait Connector {
+    type Transport: Transport;
+
+    fn connect(&mut self, addr: SocketAddr) -> io::Result<Self::Transport>;
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    type Transport = TcpStream;
+
+    fn connect(&mut self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        // Pipelined requests are small and would otherwise wait on the previous one's ACK.
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// A file opened with a pNFS layout. I/O within the layout goes directly to the data servers,
+/// anything outside of it goes through the metadata server.
+pub struct PnfsFile<DsTransportT> {
+    handle: FileHandle,
+    open_state_id: StateId,
+    layout_state_id: StateId,
+    io_mode: LayoutIoMode,
+    layout_offset: u64,
+    layout_length: u64,
+    layout: PnfsLayout<DsTransportT>,
+    size: u64,
+    last_write_offset: Option<u64>,
+}
+
+enum PnfsLayout<DsTransportT> {
+    Files(FilesLayoutIo<DsTransportT>),
+    FlexFiles(FlexFilesIo<DsTransportT>),
+}
+
+impl<DsTransportT> PnfsFile<DsTransportT> {
+    pub fn handle(&self) -> &FileHandle {
+        &self.handle
+    }
+
+    pub fn layout_type(&self) -> LayoutType {
+        match &self.layout {
+            PnfsLayout::Files(_) => LayoutType::NfsV41Files,
+            PnfsLayout::FlexFiles(_) => LayoutType::FlexFiles,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn in_layout(&self, offset: u64) -> bool {
+        offset >= self.layout_offset && offset - self.layout_offset < self.layout_length
+    }
+}
+
+struct FilesLayoutIo<DsTransportT> {
+    layout: FilesLayout,
+    stripe_indices: Vec<u32>,
+    data_servers: Vec<Client<DsTransportT>>,
+    /// (data server, stripe index) pairs with unstable writes
+    uncommitted: BTreeSet<(usize, usize)>,
+}
+
+impl<DsTransportT: Transport> FilesLayoutIo<DsTransportT> {
+    /// Returns the data server index and location for the given offset.
+    fn locate(&self, offset: u64) -> Option<(usize, StripeLocation)> {
+        let location = self.layout.locate(self.stripe_indices.len(), offset)?;
+        let data_server = self.stripe_indices[location.stripe_index] as usize;
+        Some((data_server, location))
+    }
+
+    fn read(
+        &mut self,
+        state_id: StateId,
+        offset: u64,
+        count: u32,
+        size: u64,
+    ) -> Result<Option<ReadRes>> {
+        let Some((data_server, location)) = self.locate(offset) else {
+            return Ok(None);
+        };
+        let data_server = &mut self.data_servers[data_server];
+        let count = count
+            .min(data_server.max_read.try_into().unwrap_or(u32::MAX))
+            .min(location.remaining.try_into().unwrap_or(u32::MAX));
+        let handle = self
+            .layout
+            .file_handle(location.stripe_index)
+            .unwrap()
+            .clone();
+        let res = data_server.read_with_state(handle, state_id, location.offset, count)?;
+        Ok(Some(fill_hole(res, offset, count, size)))
+    }
+
+    fn write(&mut self, state_id: StateId, offset: u64, data: &[u8]) -> Result<Option<WriteRes>> {
+        let Some((data_server, location)) = self.locate(offset) else {
+            return Ok(None);
+        };
+        let count = data
+            .len()
+            .min(location.remaining.try_into().unwrap_or(usize::MAX));
+        let handle = self
+            .layout
+            .file_handle(location.stripe_index)
+            .unwrap()
+            .clone();
+        let res = self.data_servers[data_server].write_with_state(
+            handle,
+            state_id,
+            location.offset,
+            StableHow::Unstable,
+            data[..count].to_vec(),
+        )?;
+        if res.committed == StableHow::Unstable {
+            self.uncommitted
+                .insert((data_server, location.stripe_index));
+        }
+        Ok(Some(res))
+    }
+
+    /// Commits to the data servers, returns true if the commit has to go through the metadata
+    /// server instead.
+    fn commit(&mut self) -> Result<bool> {
+        if self.uncommitted.is_empty() {
+            return Ok(false);
+        }
+        if self.layout.util.commit_through_mds() {
+            self.uncommitted.clear();
+            return Ok(true);
+        }
+        for (data_server, stripe_index) in std::mem::take(&mut self.uncommitted) {
+            let handle = self.layout.file_handle(stripe_index).unwrap().clone();
+            self.data_servers[data_server].commit(handle)?;
+        }
+        Ok(false)
+    }
+}
+
+/// A data server's copy can be shorter than the file. When the data server says its copy ended
+/// before the file does, the rest of what was asked for is a hole. A short read without eof is
+/// returned as it is, since servers are allowed to return less than asked for.
+pub(crate) fn fill_hole(mut res: ReadRes, offset: u64, count: u32, size: u64) -> ReadRes {
+    if !res.eof {
+        return res;
+    }
+    let wanted = size.saturating_sub(offset).min(count as u64) as usize;
+    if res.data.len() < wanted {
+        res.data.resize(wanted, 0);
+    }
+    res.eof = offset + res.data.len() as u64 >= size;
+    res
+}
+
+pub(crate) fn decode_body<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T> {
+    Ok(serde_xdr::from_bytes(body)?)
+}
+
+/// Connects to the first address in the list that works.
+pub(crate) fn connect_multipath<ConnectorT: Connector>(
+    connector: &mut ConnectorT,
+    addresses: &MultipathList,
+) -> Result<(NetAddr, ConnectorT::Transport)> {
+    let mut last_error = None;
+    for net_addr in &addresses.0 {
+        let Some(addr) = net_addr.to_socket_addr() else {
+            continue;
+        };
+        match connector.connect(addr) {
+            Ok(transport) => return Ok((net_addr.clone(), transport)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no usable data server address in {addresses:?}"),
+            )
+        })
+        .into())
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// True if the server said it can hand out pNFS layouts.
+    pub fn is_pnfs_mds(&self) -> bool {
+        self.server_flags.contains(ExchangeIdFlags::USE_PNFS_MDS)
+    }
+
+    /// Opens the file and gets a layout for all of it, connecting to its data servers with the
+    /// given connector. `NfsV41Files` layouts are preferred over `FlexFiles` if the file system
+    /// supports both.
+    pub fn pnfs_open<ConnectorT: Connector>(
+        &mut self,
+        handle: FileHandle,
+        io_mode: LayoutIoMode,
+        mut connector: ConnectorT,
+    ) -> Result<PnfsFile<ConnectorT::Transport>> {
+        let share_access = match io_mode {
+            LayoutIoMode::Read => ShareAccess::READ,
+            _ => ShareAccess::BOTH,
+        };
+        let attr_request = [FileAttributeId::Size, FileAttributeId::FsLayoutType]
+            .into_iter()
+            .filter(|a| self.supported_attrs.contains(*a))
+            .collect();
+        let ((), open_res, mut attrs) = self.do_compound((
+            PutFhArgs {
+                object: handle.clone(),
+            },
+            OpenArgs {
+                sequence_id: SequenceId(0),
+                share_access,
+                share_deny: ShareDeny::NONE,
+                owner: StateOwner {
+                    client_id: self.client_id,
+                    opaque: self.client_owner.owner_id.clone(),
+                },
+                open_how: OpenFlag::OpenNoCreate,
+                claim: OpenClaim::Fh,
+            },
+            GetAttrArgs { attr_request },
+        ))?;
+        let open_state_id = open_res.state_id;
+        let size = *attrs
+            .object_attributes
+            .get_as(FileAttributeId::Size)
+            .ok_or(Error::MissingAttribute(FileAttributeId::Size))?;
+        let layout_types: Vec<LayoutType> = attrs
+            .object_attributes
+            .remove_as(FileAttributeId::FsLayoutType)
+            .unwrap_or(vec![LayoutType::NfsV41Files]);
+        let layout_type = if layout_types.contains(&LayoutType::NfsV41Files) {
+            LayoutType::NfsV41Files
+        } else if layout_types.contains(&LayoutType::FlexFiles) {
+            LayoutType::FlexFiles
+        } else {
+            let _ = self.close(handle, open_state_id);
+            return Err(StatusError::UnknownLayoutType.into());
+        };
+
+        let res = self.pnfs_layout(
+            handle.clone(),
+            open_state_id,
+            io_mode,
+            layout_type,
+            size,
+            &mut connector,
+        );
+        if res.is_err() {
+            let _ = self.close(handle, open_state_id);
+        }
+        res
+    }
+
+    fn pnfs_layout<ConnectorT: Connector>(
+        &mut self,
+        handle: FileHandle,
+        open_state_id: StateId,
+        io_mode: LayoutIoMode,
+        layout_type: LayoutType,
+        size: u64,
+        connector: &mut ConnectorT,
+    ) -> Result<PnfsFile<ConnectorT::Transport>> {
+        let max_count = self.session.fore_channel_attrs.max_response_size;
+        let layout_res = self.do_compound(ReturnSecond(
+            PutFhArgs {
+                object: handle.clone(),
+            },
+            LayoutGetArgs {
+                signal_layout_available: false,
+                layout_type,
+                io_mode: io_mode.clone(),
+                offset: 0,
+                length: u64::MAX,
+                min_length: 0,
+                state_id: open_state_id,
+                max_count,
+            },
+        ))?;
+        let segment = layout_res
+            .layout
+            .into_iter()
+            .find(|l| l.content.type_ == layout_type)
+            .ok_or(StatusError::UnknownLayoutType)?;
+
+        let layout = match layout_type {
+            LayoutType::FlexFiles => PnfsLayout::FlexFiles(self.flex_files_io(
+                decode_body(&segment.content.body)?,
+                layout_res.state_id,
+                connector,
+            )?),
+            _ => PnfsLayout::Files(
+                self.files_layout_io(decode_body(&segment.content.body)?, connector)?,
+            ),
+        };
+
+        Ok(PnfsFile {
+            handle,
+            open_state_id,
+            layout_state_id: layout_res.state_id,
+            io_mode,
+            layout_offset: segment.offset,
+            layout_length: segment.length,
+            layout,
+            size,
+            last_write_offset: None,
+        })
+    }
+
+    fn files_layout_io<ConnectorT: Connector>(
+        &mut self,
+        layout: FilesLayout,
+        connector: &mut ConnectorT,
+    ) -> Result<FilesLayoutIo<ConnectorT::Transport>> {
+        let device_info = self.do_compound(GetDeviceInfoArgs {
+            device_id: layout.device_id,
+            layout_type: LayoutType::NfsV41Files,
+            max_count: self.session.fore_channel_attrs.max_response_size,
+            notify_types: Default::default(),
+        })?;
+        let device: FilesLayoutDsAddr = decode_body(&device_info.device_addr.body)?;
+
+        let stripe_count = device.stripe_indices.len();
+        let layout_ok = stripe_count > 0
+            && layout.util.stripe_unit() > 0
+            && (layout.fh_list.len() == 1 || layout.fh_list.len() == stripe_count)
+            && device
+                .stripe_indices
+                .iter()
+                .all(|i| (*i as usize) < device.multipath_ds_list.len());
+        if !layout_ok {
+            return Err(StatusError::BadLayout.into());
+        }
+
+        let mut data_servers = vec![];
+        for addresses in &device.multipath_ds_list {
+            let (_, transport) = connect_multipath(connector, addresses)?;
+            let mut data_server = Client::new_data_server(transport)?;
+            data_server.max_read = self.max_read;
+            data_server.max_write = self.max_write;
+            data_servers.push(data_server);
+        }
+
+        Ok(FilesLayoutIo {
+            layout,
+            stripe_indices: device.stripe_indices,
+            data_servers,
+            uncommitted: BTreeSet::new(),
+        })
+    }
+
+    /// Reads up to `count` bytes, stopping early at the end of a stripe unit.
+    pub fn pnfs_read<DsTransportT: Transport>(
+        &mut self,
+        file: &mut PnfsFile<DsTransportT>,
+        offset: u64,
+        count: u32,
+    ) -> Result<ReadRes> {
+        let count = count.min(self.max_read.try_into().unwrap_or(u32::MAX));
+        let res = if file.in_layout(offset) {
+            match &mut file.layout {
+                PnfsLayout::Files(io) => io.read(file.open_state_id, offset, count, file.size)?,
+                PnfsLayout::FlexFiles(io) => {
+                    let res = io.read(offset, count, file.size);
+                    self.report_flex_files_errors(&file.handle, io)?;
+                    res?
+                }
+            }
+        } else {
+            None
+        };
+        match res {
+            Some(res) => Ok(res),
+            None => self.read_with_state(file.handle.clone(), file.open_state_id, offset, count),
+        }
+    }
+
+    pub fn pnfs_read_all<DsTransportT: Transport>(
+        &mut self,
+        file: &mut PnfsFile<DsTransportT>,
+        mut sink: impl io::Write,
+    ) -> Result<()> {
+        let mut offset = 0;
+        while offset < file.size {
+            let count = self.max_read.try_into().unwrap_or(u32::MAX);
+            let read_res = self.pnfs_read(file, offset, count)?;
+            if read_res.data.is_empty() {
+                if read_res.eof {
+                    break;
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            offset += read_res.data.len() as u64;
+            sink.write_all(&read_res.data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes as much of `data` as fits in the stripe unit at `offset`.
+    pub fn pnfs_write<DsTransportT: Transport>(
+        &mut self,
+        file: &mut PnfsFile<DsTransportT>,
+        offset: u64,
+        mut data: Vec<u8>,
+    ) -> Result<WriteRes> {
+        data.truncate(self.max_write as usize);
+        let res = if file.in_layout(offset) {
+            match &mut file.layout {
+                PnfsLayout::Files(io) => io.write(file.open_state_id, offset, &data)?,
+                PnfsLayout::FlexFiles(io) => {
+                    let res = io.write(offset, &data);
+                    self.report_flex_files_errors(&file.handle, io)?;
+                    res?
+                }
+            }
+        } else {
+            None
+        };
+        let res = match res {
+            Some(res) => res,
+            None => self.write_with_state(
+                file.handle.clone(),
+                file.open_state_id,
+                offset,
+                StableHow::FileSync,
+                data,
+            )?,
+        };
+
+        if res.count > 0 {
+            let last = offset + res.count as u64 - 1;
+            file.last_write_offset = Some(file.last_write_offset.map_or(last, |l| l.max(last)));
+            file.size = file.size.max(last + 1);
+        }
+        Ok(res)
+    }
+
+    pub fn pnfs_write_all<DsTransportT: Transport>(
+        &mut self,
+        file: &mut PnfsFile<DsTransportT>,
+        mut source: impl io::Read,
+    ) -> Result<()> {
+        let mut offset = 0;
+        loop {
+            let mut buf = vec![0; self.max_write as usize];
+            let amount_read = source.read(&mut buf[..])?;
+            if amount_read == 0 {
+                break;
+            }
+
+            buf.truncate(amount_read);
+
+            while !buf.is_empty() {
+                let write_res = self.pnfs_write(file, offset, buf.clone())?;
+                let written = (write_res.count as usize).min(buf.len());
+                if written == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                buf.drain(..written);
+                offset += written as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits outstanding writes, then does LAYOUTCOMMIT, LAYOUTRETURN and CLOSE. For
+    /// `FlexFiles` layouts any data server errors and I/O statistics are sent along.
+    pub fn pnfs_close<DsTransportT: Transport>(
+        &mut self,
+        mut file: PnfsFile<DsTransportT>,
+    ) -> Result<()> {
+        let layout_type = file.layout_type();
+        let mut layout_commit = file.last_write_offset.is_some();
+        let return_body = match &mut file.layout {
+            PnfsLayout::Files(io) => {
+                if io.commit()? {
+                    self.commit(file.handle.clone())?;
+                }
+                vec![]
+            }
+            PnfsLayout::FlexFiles(io) => {
+                let res = io.commit();
+                self.report_flex_files_errors(&file.handle, io)?;
+                res?;
+                layout_commit &= !io.flags().contains(FlexFilesFlags::NO_LAYOUTCOMMIT);
+                self.send_flex_files_stats(
+                    &file.handle,
+                    io,
+                    file.layout_offset,
+                    file.layout_length,
+                )?;
+                io.return_body(file.layout_offset, file.layout_length)
+            }
+        };
+
+        if layout_commit {
+            self.do_compound(ReturnSecond(
+                PutFhArgs {
+                    object: file.handle.clone(),
+                },
+                LayoutCommitArgs {
+                    offset: file.layout_offset,
+                    length: file.layout_length,
+                    reclaim: false,
+                    state_id: file.layout_state_id,
+                    last_write_offset: file.last_write_offset,
+                    time_modify: None,
+                    layout_update: LayoutUpdate {
+                        type_: layout_type,
+                        body: vec![],
+                    },
+                },
+            ))?;
+        }
+
+        self.do_compound(ReturnSecond(
+            PutFhArgs {
+                object: file.handle.clone(),
+            },
+            LayoutReturnArgs {
+                reclaim: false,
+                layout_type,
+                io_mode: file.io_mode.clone(),
+                layout_return: LayoutReturn::File(LayoutReturnFile {
+                    offset: file.layout_offset,
+                    length: file.layout_length,
+                    state_id: file.layout_state_id,
+                    body: return_body,
+                }),
+            },
+        ))?;
+
+        self.close(file.handle, file.open_state_id)
+    }
+}
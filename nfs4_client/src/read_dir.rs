@@ -0,0 +1,160 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Listing a directory a page at a time, so only one READDIR reply is held in memory however big
+//! the directory is.
+
+use super::{Client, Error, Result, ReturnSecond};
+use nfs4::{
+    Cookie, DirectoryEntry, EnumSet, FileAttributeId, FileHandle, PutFhArgs, ReadDirArgs,
+    StatusError, Verifier,
+};
+use std::collections::{HashSet, VecDeque};
+use sun_rpc_client::Transport;
+
+/// Room left in a reply for everything but the entries.
+const REPLY_OVERHEAD: u32 = 512;
+
+/// How many times to start over when the server says the cookies have gone stale.
+pub(crate) const MAX_RESTARTS: usize = 5;
+
+/// How many returned names are remembered for skipping after starting over, so listing a huge
+/// directory doesn't hold on to every name in it.
+const MAX_REMEMBERED_NAMES: usize = 10_000;
+
+/// An iterator over the entries of a directory, returned by `Client::read_dir_iter`.
+///
+/// Each READDIR asks for as much as fits in the session's max response size. If the directory
+/// changes enough that the server rejects the cookie (`NotSame` or `BadCookie`), listing starts
+/// over from the beginning, skipping names already returned. Only the first
+/// `MAX_REMEMBERED_NAMES` names are remembered for this, so a restart in a directory bigger than
+/// that may return some entries twice.
+pub struct ReadDir<'a, TransportT: Transport> {
+    client: &'a mut Client<TransportT>,
+    handle: FileHandle,
+    attr_request: EnumSet<FileAttributeId>,
+    cookie: Cookie,
+    cookie_verifier: Verifier,
+    /// Entries from the last reply not returned yet.
+    pending: VecDeque<DirectoryEntry>,
+    eof: bool,
+    restarts: usize,
+    /// The names returned so far, up to `MAX_REMEMBERED_NAMES`, so starting over doesn't return
+    /// them again.
+    returned: HashSet<String>,
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Lists a directory lazily, fetching the requested attributes of each entry.
+    pub fn read_dir_iter(
+        &mut self,
+        handle: FileHandle,
+        attr_request: EnumSet<FileAttributeId>,
+    ) -> ReadDir<'_, TransportT> {
+        let attr_request = attr_request
+            .into_iter()
+            .filter(|a| self.supported_attrs.contains(*a))
+            .collect();
+        ReadDir {
+            client: self,
+            handle,
+            attr_request,
+            cookie: Cookie::initial(),
+            cookie_verifier: Verifier(0),
+            pending: VecDeque::new(),
+            eof: false,
+            restarts: 0,
+            returned: HashSet::new(),
+        }
+    }
+
+    /// A READDIR asking for as much as fits in the session's max response size.
+    pub(crate) fn read_dir_args(
+        &self,
+        cookie: Cookie,
+        cookie_verifier: Verifier,
+        attr_request: EnumSet<FileAttributeId>,
+    ) -> ReadDirArgs {
+        let max_response_size = self.session.fore_channel_attrs.max_response_size;
+        let max_count = max_response_size.saturating_sub(REPLY_OVERHEAD);
+        ReadDirArgs {
+            cookie,
+            cookie_verifier,
+            directory_count: max_count,
+            max_count,
+            attr_request,
+        }
+    }
+
+    pub fn read_dir(
+        &mut self,
+        handle: FileHandle,
+        attr_request: EnumSet<FileAttributeId>,
+    ) -> Result<Vec<DirectoryEntry>> {
+        self.read_dir_iter(handle, attr_request).collect()
+    }
+}
+
+impl<TransportT: Transport> ReadDir<'_, TransportT> {
+    /// The client the directory is being listed with.
+    pub fn client(&self) -> &Client<TransportT> {
+        self.client
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        let args = self.client.read_dir_args(
+            self.cookie,
+            self.cookie_verifier.clone(),
+            self.attr_request.clone(),
+        );
+        let res = self.client.do_compound(ReturnSecond(
+            PutFhArgs {
+                object: self.handle.clone(),
+            },
+            args,
+        ))?;
+        self.cookie_verifier = res.cookie_verifier;
+        if let Some(last) = res.reply.entries.last() {
+            self.cookie = last.cookie;
+        } else if !res.reply.eof {
+            return Err(Error::Protocol(StatusError::TooSmall));
+        }
+        self.eof = res.reply.eof;
+        self.pending = res.reply.entries.into();
+        Ok(())
+    }
+}
+
+impl<TransportT: Transport> Iterator for ReadDir<'_, TransportT> {
+    type Item = Result<DirectoryEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                if self.restarts > 0 && self.returned.contains(&entry.name) {
+                    continue;
+                }
+                if self.returned.len() < MAX_REMEMBERED_NAMES {
+                    self.returned.insert(entry.name.clone());
+                }
+                return Some(Ok(entry));
+            }
+            if self.eof {
+                return None;
+            }
+            match self.fetch() {
+                Ok(()) => {}
+                Err(Error::Protocol(StatusError::NotSame | StatusError::BadCookie))
+                    if self.restarts < MAX_RESTARTS =>
+                {
+                    self.restarts += 1;
+                    self.cookie = Cookie::initial();
+                    self.cookie_verifier = Verifier(0);
+                }
+                Err(error) => {
+                    self.eof = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,726 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! A small filesystem interface which application code can be written against, implemented by
+//! `Client` and by `MemoryFs` so that code can be tested without a server.
+
+use super::{Client, Error, GetFh, ReturnSecond};
+use nfs4::{
+    AttrRequest, Attributes, CloseArgs, CreateHow, FileAttribute, FileAttributeId, FileAttributes,
+    FileHandle, FileType, LookUpArgs, Mode, OpenArgs, OpenClaim, OpenFlag, PutFhArgs, SequenceId,
+    SetTime, ShareAccess, ShareDeny, StateId, StateOwner, StatusError, Time,
+};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path};
+use std::time::SystemTime;
+use sun_rpc_client::Transport;
+
+/// The parts of a file's attributes every implementation can provide.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub num_links: u32,
+    pub file_id: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub handle: FileHandle,
+    pub metadata: Metadata,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Create the file if it doesn't exist.
+    pub create: bool,
+    /// With `create`, fail with `AlreadyExists` if the file exists.
+    pub exclusive: bool,
+    /// Set the size to zero.
+    pub truncate: bool,
+}
+
+/// The attributes to change in `RemoteFs::setattr`; `None` fields are left alone.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SetAttributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+/// Filesystem operations on file handles. Errors are reported as `io::Error` with the
+/// `io::ErrorKind` matching the failure, e.g. `NotFound` or `DirectoryNotEmpty`.
+///
+/// `Client` has inherent methods also called `remove` and `rename`, so calling those through the
+/// trait on a `Client` needs the `RemoteFs::remove(&mut client, ..)` form.
+pub trait RemoteFs {
+    fn root(&mut self) -> io::Result<FileHandle>;
+
+    fn lookup(&mut self, dir: &FileHandle, name: &str) -> io::Result<FileHandle>;
+
+    fn stat(&mut self, handle: &FileHandle) -> io::Result<Metadata>;
+
+    /// Opens a regular file in `dir`, creating it if asked to, and returns its handle.
+    fn open(
+        &mut self,
+        dir: &FileHandle,
+        name: &str,
+        options: OpenOptions,
+    ) -> io::Result<FileHandle>;
+
+    /// Reads at most `buf.len()` bytes at `offset`, returning 0 at the end of the file.
+    fn read_at(&mut self, handle: &FileHandle, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes some prefix of `data` at `offset`, returning how much was written.
+    fn write_at(&mut self, handle: &FileHandle, offset: u64, data: &[u8]) -> io::Result<usize>;
+
+    fn readdir(&mut self, dir: &FileHandle) -> io::Result<Vec<DirEntry>>;
+
+    fn mkdir(&mut self, dir: &FileHandle, name: &str, mode: u32) -> io::Result<FileHandle>;
+
+    /// Removes a file, symlink or empty directory.
+    fn remove(&mut self, dir: &FileHandle, name: &str) -> io::Result<()>;
+
+    /// Moves an entry, replacing what is at the destination.
+    fn rename(
+        &mut self,
+        from_dir: &FileHandle,
+        from_name: &str,
+        to_dir: &FileHandle,
+        to_name: &str,
+    ) -> io::Result<()>;
+
+    fn setattr(&mut self, handle: &FileHandle, attrs: SetAttributes) -> io::Result<()>;
+
+    fn symlink(&mut self, dir: &FileHandle, name: &str, target: &str) -> io::Result<FileHandle>;
+
+    fn readlink(&mut self, handle: &FileHandle) -> io::Result<String>;
+
+    /// Looks up each component of `path` starting from the root. `..` goes back to the
+    /// directory the previous component was looked up in, and stays put at the root.
+    fn lookup_path(&mut self, path: &Path) -> io::Result<FileHandle> {
+        let mut handles = vec![self.root()?];
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    let name = name
+                        .to_str()
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidFilename))?;
+                    let handle = self.lookup(handles.last().unwrap(), name)?;
+                    handles.push(handle);
+                }
+                Component::ParentDir => {
+                    if handles.len() > 1 {
+                        handles.pop();
+                    }
+                }
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            }
+        }
+        Ok(handles.pop().unwrap())
+    }
+}
+
+fn error_kind(error: StatusError) -> io::ErrorKind {
+    use io::ErrorKind;
+    match error {
+        StatusError::Perm | StatusError::Access | StatusError::WrongSec => {
+            ErrorKind::PermissionDenied
+        }
+        StatusError::NoEnt => ErrorKind::NotFound,
+        StatusError::Exist => ErrorKind::AlreadyExists,
+        StatusError::XDev => ErrorKind::CrossesDevices,
+        StatusError::NotDir | StatusError::Symlink => ErrorKind::NotADirectory,
+        StatusError::Isdir => ErrorKind::IsADirectory,
+        StatusError::Inval | StatusError::BadType | StatusError::BadChar => ErrorKind::InvalidInput,
+        StatusError::NameTooLong | StatusError::BadName => ErrorKind::InvalidFilename,
+        StatusError::FBig => ErrorKind::FileTooLarge,
+        StatusError::NoSpc => ErrorKind::StorageFull,
+        StatusError::RoFs => ErrorKind::ReadOnlyFilesystem,
+        StatusError::MLink => ErrorKind::TooManyLinks,
+        StatusError::NotEmpty => ErrorKind::DirectoryNotEmpty,
+        StatusError::DQuot => ErrorKind::QuotaExceeded,
+        StatusError::Stale | StatusError::FhExpired => ErrorKind::StaleNetworkFileHandle,
+        StatusError::NotSupported | StatusError::AttrNotSupported => ErrorKind::Unsupported,
+        StatusError::Denied => ErrorKind::WouldBlock,
+        StatusError::Delay
+        | StatusError::Grace
+        | StatusError::Locked
+        | StatusError::ShareDenied
+        | StatusError::FileOpen => ErrorKind::ResourceBusy,
+        _ => ErrorKind::Other,
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match error {
+            Error::Io(e) => return e,
+            Error::Path(e) => {
+                let inner = io::Error::from(e.error);
+                return io::Error::new(inner.kind(), format!("{}: {inner}", e.path.display()));
+            }
+            Error::Protocol(ref e) => error_kind(e.clone()),
+            Error::Lock(ref e) => error_kind(e.error.clone()),
+            Error::Compound(ref e) => error_kind(e.status.clone()),
+            Error::SunRpc(_)
+            | Error::Deserialization(_)
+            | Error::CompoundResponseMismatch(_)
+            | Error::PreconditionFailed
+            | Error::TooManySymlinks
+            | Error::Referral(_)
+            | Error::TooManyReferrals => io::ErrorKind::Other,
+            Error::MissingAttribute(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, format!("{error:?}"))
+    }
+}
+
+fn set_attributes(id_mapper: &dyn super::IdMapper, attrs: SetAttributes) -> Attributes {
+    Attributes {
+        mode: attrs.mode.map(Mode),
+        owner: attrs.uid.map(|uid| id_mapper.encode_uid(uid)),
+        owner_group: attrs.gid.map(|gid| id_mapper.encode_gid(gid)),
+        size: attrs.size,
+        time_modify_set: attrs
+            .modified
+            .map(|t| SetTime::SetToClientTime(Time::from(t))),
+        ..Default::default()
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    fn metadata(&self, attrs: Attributes) -> io::Result<Metadata> {
+        let missing = |id| io::Error::from(Error::MissingAttribute(id));
+        Ok(Metadata {
+            file_type: attrs.type_.ok_or_else(|| missing(FileAttributeId::Type))?,
+            size: attrs.size.unwrap_or(0),
+            mode: attrs.mode.map(|m| m.0).unwrap_or(0),
+            uid: attrs
+                .owner
+                .map(|o| self.id_mapper.decode_uid(&o))
+                .unwrap_or(super::NOBODY_ID),
+            gid: attrs
+                .owner_group
+                .map(|g| self.id_mapper.decode_gid(&g))
+                .unwrap_or(super::NOBODY_ID),
+            num_links: attrs.num_links.unwrap_or(1),
+            file_id: attrs.file_id.map(|f| f.0).unwrap_or(0),
+            modified: attrs.time_modify.and_then(|t| t.to_system_time()),
+        })
+    }
+}
+
+fn remote_fs_attrs() -> AttrRequest {
+    AttrRequest::metadata().file_id()
+}
+
+impl<TransportT: Transport> RemoteFs for Client<TransportT> {
+    fn root(&mut self) -> io::Result<FileHandle> {
+        Ok(self.look_up("/")?)
+    }
+
+    fn lookup(&mut self, dir: &FileHandle, name: &str) -> io::Result<FileHandle> {
+        Ok(self
+            .do_compound(ReturnSecond(
+                (
+                    PutFhArgs {
+                        object: dir.clone(),
+                    },
+                    LookUpArgs {
+                        object_name: name.into(),
+                    },
+                ),
+                GetFh,
+            ))?
+            .object)
+    }
+
+    fn stat(&mut self, handle: &FileHandle) -> io::Result<Metadata> {
+        let attrs = self.get_attributes(handle.clone(), remote_fs_attrs())?;
+        self.metadata(attrs)
+    }
+
+    fn open(
+        &mut self,
+        dir: &FileHandle,
+        name: &str,
+        options: OpenOptions,
+    ) -> io::Result<FileHandle> {
+        let open_how = match options {
+            OpenOptions { create: false, .. } => OpenFlag::OpenNoCreate,
+            OpenOptions {
+                exclusive: true, ..
+            } => OpenFlag::OpenCreate(CreateHow::Guarded {
+                create_attrs: Default::default(),
+            }),
+            _ => OpenFlag::OpenCreate(CreateHow::Unchecked),
+        };
+        let ((), open_res, get_fh) = self.do_compound((
+            PutFhArgs {
+                object: dir.clone(),
+            },
+            OpenArgs {
+                sequence_id: SequenceId(0),
+                share_access: ShareAccess::BOTH,
+                share_deny: ShareDeny::NONE,
+                owner: StateOwner {
+                    client_id: self.client_id,
+                    opaque: self.client_owner.owner_id.clone(),
+                },
+                open_how,
+                claim: OpenClaim::Null { file: name.into() },
+            },
+            GetFh,
+        ))?;
+        let handle = get_fh.object;
+        let entry = (handle.clone(), Some(FileType::Regular));
+        self.entry_added(dir, name, Some(entry), &open_res.change_info);
+        self.do_compound(ReturnSecond(
+            PutFhArgs {
+                object: handle.clone(),
+            },
+            CloseArgs {
+                sequence_id: SequenceId(0),
+                open_stateid: open_res.state_id,
+            },
+        ))?;
+        if options.truncate {
+            self.set_attr(
+                handle.clone(),
+                [FileAttribute::Size(0)].into_iter().collect(),
+            )?;
+        }
+        Ok(handle)
+    }
+
+    fn read_at(&mut self, handle: &FileHandle, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.max_read as usize);
+        let res =
+            self.read_with_state(handle.clone(), StateId::anonymous(), offset, count as u32)?;
+        let len = res.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&res.data[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&mut self, handle: &FileHandle, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let count = data.len().min(self.max_write as usize);
+        let res = Client::write(self, handle.clone(), offset, data[..count].to_vec())?;
+        Ok(res.count as usize)
+    }
+
+    fn readdir(&mut self, dir: &FileHandle) -> io::Result<Vec<DirEntry>> {
+        let request = remote_fs_attrs().file_handle();
+        let entries = self.read_dir(dir.clone(), request.into())?;
+        entries
+            .into_iter()
+            .map(|entry| {
+                let mut attrs = Attributes::from(entry.attrs);
+                let handle = match attrs.file_handle.take() {
+                    Some(handle) => handle,
+                    None => self.lookup(dir, &entry.name)?,
+                };
+                Ok(DirEntry {
+                    name: entry.name,
+                    handle,
+                    metadata: self.metadata(attrs)?,
+                })
+            })
+            .collect()
+    }
+
+    fn mkdir(&mut self, dir: &FileHandle, name: &str, mode: u32) -> io::Result<FileHandle> {
+        Ok(self.create_directory(
+            dir.clone(),
+            name,
+            [FileAttribute::Mode(Mode(mode))].into_iter().collect(),
+        )?)
+    }
+
+    fn remove(&mut self, dir: &FileHandle, name: &str) -> io::Result<()> {
+        Client::remove(self, dir.clone(), name)?;
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        from_dir: &FileHandle,
+        from_name: &str,
+        to_dir: &FileHandle,
+        to_name: &str,
+    ) -> io::Result<()> {
+        Client::rename(self, from_dir.clone(), to_dir.clone(), from_name, to_name)?;
+        Ok(())
+    }
+
+    fn setattr(&mut self, handle: &FileHandle, attrs: SetAttributes) -> io::Result<()> {
+        let attrs = set_attributes(&*self.id_mapper, attrs);
+        Ok(self.set_attributes(handle.clone(), attrs)?)
+    }
+
+    fn symlink(&mut self, dir: &FileHandle, name: &str, target: &str) -> io::Result<FileHandle> {
+        let attrs = FileAttributes::default();
+        Ok(self.create_symlink(dir.clone(), name, target, attrs)?)
+    }
+
+    fn readlink(&mut self, handle: &FileHandle) -> io::Result<String> {
+        Ok(self.read_link(handle.clone())?)
+    }
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, u64>),
+    Symlink(String),
+}
+
+struct Node {
+    kind: NodeKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    modified: SystemTime,
+}
+
+impl Node {
+    fn new(kind: NodeKind, mode: u32) -> Self {
+        Self {
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            modified: SystemTime::now(),
+        }
+    }
+}
+
+const MEMORY_FS_ROOT: u64 = 1;
+
+/// A `RemoteFs` which keeps everything in memory, for testing code written against the trait.
+pub struct MemoryFs {
+    nodes: BTreeMap<u64, Node>,
+    next_id: u64,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        let root = Node::new(NodeKind::Directory(BTreeMap::new()), 0o755);
+        Self {
+            nodes: BTreeMap::from([(MEMORY_FS_ROOT, root)]),
+            next_id: MEMORY_FS_ROOT + 1,
+        }
+    }
+
+    fn id(handle: &FileHandle) -> io::Result<u64> {
+        let bytes = handle.0.as_slice().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::StaleNetworkFileHandle, "bad file handle")
+        })?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn handle(id: u64) -> FileHandle {
+        FileHandle(id.to_be_bytes().to_vec())
+    }
+
+    fn node(&self, handle: &FileHandle) -> io::Result<&Node> {
+        self.nodes
+            .get(&Self::id(handle)?)
+            .ok_or_else(|| io::ErrorKind::StaleNetworkFileHandle.into())
+    }
+
+    fn node_mut(&mut self, handle: &FileHandle) -> io::Result<&mut Node> {
+        self.nodes
+            .get_mut(&Self::id(handle)?)
+            .ok_or_else(|| io::ErrorKind::StaleNetworkFileHandle.into())
+    }
+
+    fn entries(&self, dir: &FileHandle) -> io::Result<&BTreeMap<String, u64>> {
+        match &self.node(dir)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(io::ErrorKind::NotADirectory.into()),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: &FileHandle) -> io::Result<&mut BTreeMap<String, u64>> {
+        match &mut self.node_mut(dir)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(io::ErrorKind::NotADirectory.into()),
+        }
+    }
+
+    fn data_mut(&mut self, handle: &FileHandle) -> io::Result<&mut Vec<u8>> {
+        match &mut self.node_mut(handle)?.kind {
+            NodeKind::File(data) => Ok(data),
+            NodeKind::Directory(_) => Err(io::ErrorKind::IsADirectory.into()),
+            NodeKind::Symlink(_) => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn insert(&mut self, dir: &FileHandle, name: &str, node: Node) -> io::Result<FileHandle> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(io::ErrorKind::InvalidFilename.into());
+        }
+        let id = self.next_id;
+        let entries = self.entries_mut(dir)?;
+        if entries.contains_key(name) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        entries.insert(name.into(), id);
+        self.node_mut(dir)?.modified = SystemTime::now();
+        self.nodes.insert(id, node);
+        self.next_id += 1;
+        Ok(Self::handle(id))
+    }
+
+    /// Whether `id` is `dir` or somewhere under it.
+    fn is_within(&self, id: u64, dir: u64) -> bool {
+        id == dir
+            || match &self.nodes[&dir].kind {
+                NodeKind::Directory(entries) => {
+                    entries.values().any(|&child| self.is_within(id, child))
+                }
+                _ => false,
+            }
+    }
+
+    /// Removes a node and everything under it.
+    fn drop_node(&mut self, id: u64) {
+        if let Some(Node {
+            kind: NodeKind::Directory(entries),
+            ..
+        }) = self.nodes.remove(&id)
+        {
+            for child in entries.into_values() {
+                self.drop_node(child);
+            }
+        }
+    }
+}
+
+impl RemoteFs for MemoryFs {
+    fn root(&mut self) -> io::Result<FileHandle> {
+        Ok(Self::handle(MEMORY_FS_ROOT))
+    }
+
+    fn lookup(&mut self, dir: &FileHandle, name: &str) -> io::Result<FileHandle> {
+        let id = self.entries(dir)?.get(name).ok_or_else(not_found)?;
+        Ok(Self::handle(*id))
+    }
+
+    fn stat(&mut self, handle: &FileHandle) -> io::Result<Metadata> {
+        let node = self.node(handle)?;
+        let (file_type, size, num_links) = match &node.kind {
+            NodeKind::File(data) => (FileType::Regular, data.len() as u64, 1),
+            NodeKind::Directory(entries) => {
+                let sub_dirs = entries
+                    .values()
+                    .filter(|id| matches!(self.nodes[id].kind, NodeKind::Directory(_)))
+                    .count();
+                (
+                    FileType::Directory,
+                    entries.len() as u64,
+                    2 + sub_dirs as u32,
+                )
+            }
+            NodeKind::Symlink(target) => (FileType::Link, target.len() as u64, 1),
+        };
+        Ok(Metadata {
+            file_type,
+            size,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid,
+            num_links,
+            file_id: Self::id(handle)?,
+            modified: Some(node.modified),
+        })
+    }
+
+    fn open(
+        &mut self,
+        dir: &FileHandle,
+        name: &str,
+        options: OpenOptions,
+    ) -> io::Result<FileHandle> {
+        let handle = match self.lookup(dir, name) {
+            Ok(_) if options.create && options.exclusive => {
+                return Err(io::ErrorKind::AlreadyExists.into())
+            }
+            Ok(handle) => handle,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && options.create => {
+                self.insert(dir, name, Node::new(NodeKind::File(vec![]), 0o644))?
+            }
+            Err(e) => return Err(e),
+        };
+        let data = self.data_mut(&handle)?;
+        if options.truncate {
+            data.clear();
+        }
+        Ok(handle)
+    }
+
+    fn read_at(&mut self, handle: &FileHandle, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data_mut(handle)?;
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&mut self, handle: &FileHandle, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let offset = usize::try_from(offset).map_err(|_| io::ErrorKind::FileTooLarge)?;
+        let end = offset
+            .checked_add(data.len())
+            .ok_or(io::ErrorKind::FileTooLarge)?;
+        let file = self.data_mut(handle)?;
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[offset..end].copy_from_slice(data);
+        self.node_mut(handle)?.modified = SystemTime::now();
+        Ok(data.len())
+    }
+
+    fn readdir(&mut self, dir: &FileHandle) -> io::Result<Vec<DirEntry>> {
+        let entries = self.entries(dir)?.clone();
+        entries
+            .into_iter()
+            .map(|(name, id)| {
+                let handle = Self::handle(id);
+                Ok(DirEntry {
+                    name,
+                    metadata: self.stat(&handle)?,
+                    handle,
+                })
+            })
+            .collect()
+    }
+
+    fn mkdir(&mut self, dir: &FileHandle, name: &str, mode: u32) -> io::Result<FileHandle> {
+        let node = Node::new(NodeKind::Directory(BTreeMap::new()), mode);
+        self.insert(dir, name, node)
+    }
+
+    fn remove(&mut self, dir: &FileHandle, name: &str) -> io::Result<()> {
+        let id = *self.entries(dir)?.get(name).ok_or_else(not_found)?;
+        if let NodeKind::Directory(entries) = &self.nodes[&id].kind {
+            if !entries.is_empty() {
+                return Err(io::ErrorKind::DirectoryNotEmpty.into());
+            }
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.node_mut(dir)?.modified = SystemTime::now();
+        self.nodes.remove(&id);
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        from_dir: &FileHandle,
+        from_name: &str,
+        to_dir: &FileHandle,
+        to_name: &str,
+    ) -> io::Result<()> {
+        let id = *self
+            .entries(from_dir)?
+            .get(from_name)
+            .ok_or_else(not_found)?;
+        let is_dir = |node: &Node| matches!(node.kind, NodeKind::Directory(_));
+        if is_dir(&self.nodes[&id]) && self.is_within(Self::id(to_dir)?, id) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        if let Some(&existing) = self.entries(to_dir)?.get(to_name) {
+            if existing == id {
+                return Ok(());
+            }
+            match (is_dir(&self.nodes[&id]), &self.nodes[&existing].kind) {
+                (_, NodeKind::Directory(entries)) if !entries.is_empty() => {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into())
+                }
+                (false, NodeKind::Directory(_)) => return Err(io::ErrorKind::IsADirectory.into()),
+                (true, NodeKind::File(_) | NodeKind::Symlink(_)) => {
+                    return Err(io::ErrorKind::NotADirectory.into())
+                }
+                _ => {}
+            }
+            self.drop_node(existing);
+        }
+        self.entries_mut(from_dir)?.remove(from_name);
+        self.entries_mut(to_dir)?.insert(to_name.into(), id);
+        let now = SystemTime::now();
+        self.node_mut(from_dir)?.modified = now;
+        self.node_mut(to_dir)?.modified = now;
+        Ok(())
+    }
+
+    fn setattr(&mut self, handle: &FileHandle, attrs: SetAttributes) -> io::Result<()> {
+        let mut resized = false;
+        if let Some(size) = attrs.size {
+            let size = usize::try_from(size).map_err(|_| io::ErrorKind::FileTooLarge)?;
+            let data = self.data_mut(handle)?;
+            resized = data.len() != size;
+            data.resize(size, 0);
+        }
+        let node = self.node_mut(handle)?;
+        if let Some(mode) = attrs.mode {
+            node.mode = mode & 0o7777;
+        }
+        if let Some(uid) = attrs.uid {
+            node.uid = uid;
+        }
+        if let Some(gid) = attrs.gid {
+            node.gid = gid;
+        }
+        if let Some(modified) = attrs.modified {
+            node.modified = modified;
+        } else if resized {
+            node.modified = SystemTime::now();
+        }
+        Ok(())
+    }
+
+    fn symlink(&mut self, dir: &FileHandle, name: &str, target: &str) -> io::Result<FileHandle> {
+        let node = Node::new(NodeKind::Symlink(target.into()), 0o777);
+        self.insert(dir, name, node)
+    }
+
+    fn readlink(&mut self, handle: &FileHandle) -> io::Result<String> {
+        match &self.node(handle)?.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+}
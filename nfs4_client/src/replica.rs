@@ -0,0 +1,307 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Carrying on with a filesystem which has replicas, or which moves, on other servers. The
+//! locations come from `fs_locations_info` when the filesystem is first used, since the server
+//! may not be around to ask later. When the server answers `Moved`, sets `LEASE_MOVED`, or the
+//! connection fails, the best of the other locations is connected to, and files are opened again
+//! there before carrying on.
+
+use super::namespace::{absolute_path, server_addrs};
+use super::{Client, Connector, Error, Result};
+use nfs4::{
+    AttrRequest, FileAttributeId, FileHandle, FsLocationsInfo, FsLocationsInfoByte,
+    FsLocationsServerFlags, ReadRes, SequenceStatusFlags, ShareAccess, StableHow, StateId,
+    StatusError, WriteRes,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+use sun_rpc_client::Error as RpcError;
+
+/// A server a filesystem can be found on, from `fs_locations_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaLocation {
+    pub server: String,
+    /// Where the filesystem is on the server.
+    pub root: PathBuf,
+    pub flags: FsLocationsServerFlags,
+    /// Lower ranks are used before higher ones, and within a rank lower orders go first.
+    pub read_rank: u8,
+    pub read_order: u8,
+    pub write_rank: u8,
+    pub write_order: u8,
+    /// Servers with the same non-zero handle class give out the same file handles.
+    pub handle_class: u8,
+}
+
+impl ReplicaLocation {
+    fn usable(&self, write: bool) -> bool {
+        let gone = FsLocationsServerFlags::ABSENT | FsLocationsServerFlags::GOING;
+        !self.flags.intersects(gone)
+            && (!write || self.flags.contains(FsLocationsServerFlags::WRITABLE))
+    }
+
+    fn preference(&self, write: bool) -> (u8, u8) {
+        if write {
+            (self.write_rank, self.write_order)
+        } else {
+            (self.read_rank, self.read_order)
+        }
+    }
+}
+
+fn replica_locations(info: &FsLocationsInfo) -> Vec<ReplicaLocation> {
+    info.items
+        .iter()
+        .flat_map(|item| {
+            let root = absolute_path(&item.root_path);
+            item.entries.iter().map(move |entry| ReplicaLocation {
+                server: entry.server.clone(),
+                root: root.clone(),
+                flags: entry.flags(),
+                read_rank: entry.info_byte(FsLocationsInfoByte::ReadRank),
+                read_order: entry.info_byte(FsLocationsInfoByte::ReadOrder),
+                write_rank: entry.info_byte(FsLocationsInfoByte::WriteRank),
+                write_order: entry.info_byte(FsLocationsInfoByte::WriteOrder),
+                handle_class: entry.info_byte(FsLocationsInfoByte::ClHandle),
+            })
+        })
+        .collect()
+}
+
+/// Whether the error means the filesystem should be looked for elsewhere.
+fn should_fail_over(error: &Error) -> bool {
+    match error {
+        Error::Protocol(StatusError::Moved | StatusError::LeaseMoved) => true,
+        Error::Io(_) => true,
+        // A connection which closes shows up as a reply which couldn't be read.
+        Error::SunRpc(error) => matches!(error, RpcError::Io(_) | RpcError::Deseralization(_)),
+        Error::Path(error) => should_fail_over(&error.error),
+        _ => false,
+    }
+}
+
+/// A file opened with `Replicated::open`, which follows the filesystem from server to server.
+#[derive(Debug)]
+pub struct ReplicatedFile {
+    /// The path from the root of the filesystem.
+    path: PathBuf,
+    share_access: ShareAccess,
+    handle: FileHandle,
+    state_id: StateId,
+    /// The failover the file was last opened after, if it was opened yet.
+    epoch: Option<usize>,
+    /// The handle class of the server it was opened on.
+    handle_class: u8,
+}
+
+impl ReplicatedFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The handle on the server it was last opened on.
+    pub fn handle(&self) -> &FileHandle {
+        &self.handle
+    }
+}
+
+/// A filesystem with several locations, used through whichever one is working.
+///
+/// Writes are sent `FileSync`, so nothing written can be lost to a failover. Files opened for
+/// writing only fail over to locations marked `WRITABLE`, which read-only replicas aren't.
+pub struct Replicated<ConnectorT: Connector> {
+    connector: ConnectorT,
+    client: Client<ConnectorT::Transport>,
+    locations: Vec<ReplicaLocation>,
+    /// Which of `locations` the client is connected to, if that is known.
+    current: Option<usize>,
+    /// Where the filesystem is on the server the client is connected to.
+    root: PathBuf,
+    /// How many times it failed over.
+    epoch: usize,
+}
+
+impl<ConnectorT: Connector> Replicated<ConnectorT> {
+    /// Gets the locations of the filesystem `path` is in from the server `client` is connected
+    /// to.
+    pub fn new(
+        mut client: Client<ConnectorT::Transport>,
+        path: impl AsRef<Path>,
+        connector: ConnectorT,
+    ) -> Result<Self> {
+        let handle = client.look_up(path)?;
+        let attrs = client.get_attributes(handle, AttrRequest::new().fs_locations_info())?;
+        let info = attrs
+            .fs_locations_info
+            .ok_or(Error::MissingAttribute(FileAttributeId::FsLocationsInfo))?;
+        let locations = replica_locations(&info);
+        let current = locations
+            .iter()
+            .position(|l| l.flags.contains(FsLocationsServerFlags::CUR_REQ));
+        Ok(Self {
+            connector,
+            client,
+            locations,
+            current,
+            root: absolute_path(&info.fs_root),
+            epoch: 0,
+        })
+    }
+
+    /// The client for the location in use.
+    pub fn client(&mut self) -> &mut Client<ConnectorT::Transport> {
+        &mut self.client
+    }
+
+    pub fn locations(&self) -> &[ReplicaLocation] {
+        &self.locations
+    }
+
+    /// The location in use, if it is known.
+    pub fn current(&self) -> Option<&ReplicaLocation> {
+        Some(&self.locations[self.current?])
+    }
+
+    /// Opens a file by its path from the root of the filesystem.
+    pub fn open(
+        &mut self,
+        path: impl AsRef<Path>,
+        share_access: ShareAccess,
+    ) -> Result<ReplicatedFile> {
+        let path = path.as_ref();
+        let mut file = ReplicatedFile {
+            path: path.strip_prefix("/").unwrap_or(path).to_owned(),
+            share_access,
+            handle: FileHandle(vec![]),
+            state_id: StateId::anonymous(),
+            epoch: None,
+            handle_class: 0,
+        };
+        self.run(&mut file, |_, _| Ok(()))?;
+        Ok(file)
+    }
+
+    pub fn read(&mut self, file: &mut ReplicatedFile, offset: u64, count: u32) -> Result<ReadRes> {
+        self.run(file, |client, file| {
+            client.read_with_state(file.handle.clone(), file.state_id, offset, count)
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        file: &mut ReplicatedFile,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<WriteRes> {
+        self.run(file, |client, file| {
+            let handle = file.handle.clone();
+            client.write_with_state(
+                handle,
+                file.state_id,
+                offset,
+                StableHow::FileSync,
+                data.to_vec(),
+            )
+        })
+    }
+
+    /// Closes the file on the server it is open on, if that is still the one in use.
+    pub fn close(&mut self, file: ReplicatedFile) -> Result<()> {
+        if file.epoch != Some(self.epoch) {
+            return Ok(());
+        }
+        self.client.close(file.handle, file.state_id)
+    }
+
+    /// Does `op` on the file, failing over and trying again as long as there are locations left
+    /// to try.
+    fn run<R>(
+        &mut self,
+        file: &mut ReplicatedFile,
+        mut op: impl FnMut(&mut Client<ConnectorT::Transport>, &ReplicatedFile) -> Result<R>,
+    ) -> Result<R> {
+        let write = file.share_access.contains(ShareAccess::WRITE);
+        let mut failed = vec![];
+        loop {
+            let res = self.reopen(file).and_then(|()| op(&mut self.client, file));
+            match res {
+                Err(error) if should_fail_over(&error) => {
+                    failed.extend(self.current);
+                    if self.fail_over(write, &mut failed).is_err() {
+                        return Err(error);
+                    }
+                }
+                res => {
+                    // The filesystem is going, so move before the next request fails.
+                    let moved = self
+                        .client
+                        .status_flags()
+                        .contains(SequenceStatusFlags::LEASE_MOVED);
+                    if res.is_ok() && moved {
+                        failed.extend(self.current);
+                        let _ = self.fail_over(write, &mut failed);
+                    }
+                    return res;
+                }
+            }
+        }
+    }
+
+    /// Opens the file on the server in use, if it isn't yet. It is looked up by its path unless
+    /// the handle it has is good there too.
+    fn reopen(&mut self, file: &mut ReplicatedFile) -> Result<()> {
+        if file.epoch == Some(self.epoch) {
+            return Ok(());
+        }
+        let handle_class = self.current().map_or(0, |l| l.handle_class);
+        if file.epoch.is_none() || handle_class == 0 || handle_class != file.handle_class {
+            file.handle = self.client.look_up(self.root.join(&file.path))?;
+        }
+        file.state_id = self
+            .client
+            .open_state(file.handle.clone(), file.share_access)?;
+        file.epoch = Some(self.epoch);
+        file.handle_class = handle_class;
+        Ok(())
+    }
+
+    /// Switches to the most preferred location which can be used and hasn't failed.
+    fn fail_over(&mut self, write: bool, failed: &mut Vec<usize>) -> Result<()> {
+        let mut candidates: Vec<_> = (0..self.locations.len())
+            .filter(|i| !failed.contains(i) && self.locations[*i].usable(write))
+            .collect();
+        candidates.sort_by_key(|&i| self.locations[i].preference(write));
+        let mut last_error = None;
+        for index in candidates {
+            match self.connect(&self.locations[index].server.clone()) {
+                Ok(client) => {
+                    self.client = client;
+                    self.current = Some(index);
+                    self.root = self.locations[index].root.clone();
+                    self.epoch += 1;
+                    return Ok(());
+                }
+                Err(error) => {
+                    failed.push(index);
+                    last_error = Some(error);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no location left to fail over to").into()
+        }))
+    }
+
+    fn connect(&mut self, server: &str) -> Result<Client<ConnectorT::Transport>> {
+        let mut last_error = None;
+        for addr in server_addrs(server)? {
+            match self.connector.connect(addr) {
+                Ok(transport) => return Client::new(transport),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for server"))
+            .into())
+    }
+}
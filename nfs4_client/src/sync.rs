@@ -0,0 +1,613 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Making one directory tree look like another, like `rsync -a`, in either direction between
+//! local disk and the server. Both trees are listed, what differs is worked out as a list of
+//! `SyncAction`s, and then unless it is a dry run they are carried out, with the contents of
+//! several files being copied at once.
+
+use super::transfer::ReadSink;
+use super::{Client, Result, WalkOptions};
+use nfs4::{AttrRequest, Attributes, FileAttributes, FileHandle, FileType, Mode, SetTime};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead as _};
+use std::os::unix::fs::{FileExt as _, MetadataExt as _, PermissionsExt as _};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use sun_rpc_client::Transport;
+
+/// How `Client::sync_to_remote` and `Client::sync_from_remote` decide what to copy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncOptions {
+    /// Compare the contents of files the same size, rather than trusting modification times.
+    pub checksum: bool,
+    /// Remove anything in the destination which isn't in the source.
+    pub delete: bool,
+    pub preserve_mode: bool,
+    /// Set the owner and group, which usually needs root.
+    pub preserve_owner: bool,
+    pub preserve_times: bool,
+    /// Work out what to do without doing any of it.
+    pub dry_run: bool,
+    /// How many files to copy at once.
+    pub parallel: usize,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            checksum: false,
+            delete: false,
+            preserve_mode: true,
+            preserve_owner: false,
+            preserve_times: true,
+            dry_run: false,
+            parallel: 4,
+        }
+    }
+}
+
+/// Something done to the destination to make it match the source. Paths are relative to the
+/// roots of the trees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncAction {
+    Remove(PathBuf),
+    CreateDirectory(PathBuf),
+    CreateSymlink(PathBuf, String),
+    /// Copying the contents of a file, and then its attributes.
+    Copy(PathBuf),
+    /// Copying just the attributes, of a file whose contents are already the same.
+    SetAttributes(PathBuf),
+}
+
+impl SyncAction {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Remove(path)
+            | Self::CreateDirectory(path)
+            | Self::CreateSymlink(path, _)
+            | Self::Copy(path)
+            | Self::SetAttributes(path) => path,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    File,
+    Directory,
+    Symlink(String),
+    /// Devices, sockets and FIFOs, which aren't copied.
+    Other,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    kind: Kind,
+    size: u64,
+    modified: Option<SystemTime>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// For entries on the server.
+    handle: Option<FileHandle>,
+}
+
+/// Everything below a directory, each directory coming before what is in it.
+type Tree = Vec<(PathBuf, Entry)>;
+
+/// Modification times are compared to the second, like rsync does.
+fn same_time(a: Option<SystemTime>, b: Option<SystemTime>) -> bool {
+    let seconds = |t: Option<SystemTime>| t?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+    seconds(a) == seconds(b)
+}
+
+fn utf8(name: &OsStr) -> Result<&str> {
+    name.to_str().ok_or_else(|| {
+        let message = format!("{name:?} isn't UTF-8");
+        io::Error::new(io::ErrorKind::InvalidData, message).into()
+    })
+}
+
+fn add_local_dir(root: &Path, dir: &Path, tree: &mut Tree) -> Result<()> {
+    let mut names = fs::read_dir(root.join(dir))?
+        .map(|e| Ok(e?.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    for name in names {
+        utf8(&name)?;
+        let path = dir.join(name);
+        let full_path = root.join(&path);
+        let metadata = fs::symlink_metadata(&full_path)?;
+        let kind = if metadata.is_dir() {
+            Kind::Directory
+        } else if metadata.is_file() {
+            Kind::File
+        } else if metadata.is_symlink() {
+            Kind::Symlink(utf8(fs::read_link(&full_path)?.as_os_str())?.into())
+        } else {
+            Kind::Other
+        };
+        let entry = Entry {
+            kind,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            handle: None,
+        };
+        tree.push((path.clone(), entry));
+        if metadata.is_dir() {
+            add_local_dir(root, &path, tree)?;
+        }
+    }
+    Ok(())
+}
+
+fn local_tree(root: &Path) -> Result<Tree> {
+    let mut tree = vec![];
+    add_local_dir(root, Path::new(""), &mut tree)?;
+    Ok(tree)
+}
+
+/// Checks what is written to it against what is read from a local file.
+struct Compare<ReadT> {
+    local: ReadT,
+    same: bool,
+}
+
+impl<ReadT: io::BufRead> io::Write for Compare<ReadT> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while self.same && !buf.is_empty() {
+            let local = self.local.fill_buf()?;
+            if local.is_empty() {
+                self.same = false;
+                break;
+            }
+            let amount = local.len().min(buf.len());
+            self.same = local[..amount] == buf[..amount];
+            self.local.consume(amount);
+            buf = &buf[amount..];
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file being read from the server, several of which are in flight at once.
+///
+/// The data goes to a temporary file next to the destination, which is renamed over it once
+/// complete, like rsync does. That way read-only files can be replaced, and a failed download
+/// doesn't leave a truncated file behind.
+struct Download {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: fs::File,
+}
+
+impl Download {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let (file, temp_path) = create_temp_file(&path)?;
+        Ok(Self {
+            path,
+            temp_path,
+            file,
+        })
+    }
+}
+
+impl ReadSink for Download {
+    fn write_at(&mut self, offset: u64, data: Vec<u8>) -> Result<()> {
+        self.file.write_all_at(&data, offset)?;
+        Ok(())
+    }
+
+    /// Moves the temporary file into place.
+    fn finish(self, len: u64) -> Result<()> {
+        // Less than expected if the file shrank while being read.
+        let res = self
+            .file
+            .set_len(len)
+            .and_then(|()| fs::rename(&self.temp_path, &self.path));
+        if res.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+        Ok(res?)
+    }
+
+    fn abort(self) {
+        let _ = fs::remove_file(self.temp_path);
+    }
+}
+
+/// Creates a file to download into in the same directory as `path`, so it can be renamed over it.
+fn create_temp_file(path: &Path) -> io::Result<(fs::File, PathBuf)> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut attempt = 0;
+    loop {
+        let temp_path = path.with_file_name(format!(".{name}.{}.{attempt}", std::process::id()));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((file, temp_path)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Makes the directory `remote` look like the local directory `local`, returning what was
+    /// done, or with `dry_run` what would be.
+    pub fn sync_to_remote(
+        &mut self,
+        local: impl AsRef<Path>,
+        remote: FileHandle,
+        options: &SyncOptions,
+    ) -> Result<Vec<SyncAction>> {
+        let local = local.as_ref();
+        let source = local_tree(local)?;
+        let dest = self.remote_tree(remote.clone(), options.parallel)?;
+        Syncer::new(self, options, true, local, remote, &dest).run(source, dest)
+    }
+
+    /// Makes the local directory `local` look like the directory `remote`, creating it if it
+    /// doesn't exist.
+    pub fn sync_from_remote(
+        &mut self,
+        remote: FileHandle,
+        local: impl AsRef<Path>,
+        options: &SyncOptions,
+    ) -> Result<Vec<SyncAction>> {
+        let local = local.as_ref();
+        let source = self.remote_tree(remote.clone(), options.parallel)?;
+        let dest = if local.exists() {
+            local_tree(local)?
+        } else {
+            if !options.dry_run {
+                fs::create_dir_all(local)?;
+            }
+            vec![]
+        };
+        Syncer::new(self, options, false, local, remote, &source).run(source, dest)
+    }
+
+    fn remote_tree(&mut self, root: FileHandle, parallel: usize) -> Result<Tree> {
+        let attr_request = AttrRequest::new()
+            .size()
+            .time_modify()
+            .mode()
+            .owner()
+            .owner_group();
+        let options = WalkOptions {
+            attr_request: attr_request.into(),
+            parallel,
+            ..Default::default()
+        };
+        let entries = self.walk(root, options)?.collect::<Result<Vec<_>>>()?;
+        let mut tree = vec![];
+        for entry in entries {
+            let attrs = entry.attrs;
+            let kind = match attrs.type_ {
+                Some(FileType::Regular) => Kind::File,
+                Some(FileType::Directory) => Kind::Directory,
+                Some(FileType::Link) => Kind::Symlink(self.read_link(entry.handle.clone())?),
+                _ => Kind::Other,
+            };
+            let entry_info = Entry {
+                kind,
+                size: attrs.size.unwrap_or_default(),
+                modified: attrs.modified(),
+                mode: attrs.mode.map(|m| m.0 & 0o7777),
+                uid: attrs.owner.map(|o| self.id_mapper.decode_uid(&o)),
+                gid: attrs.owner_group.map(|g| self.id_mapper.decode_gid(&g)),
+                handle: Some(entry.handle),
+            };
+            tree.push((entry.path, entry_info));
+        }
+        Ok(tree)
+    }
+
+    /// Whether the file on the server has the same contents as the local one.
+    fn same_contents(&mut self, local: &Path, remote: FileHandle) -> Result<bool> {
+        let mut compare = Compare {
+            local: io::BufReader::new(fs::File::open(local)?),
+            same: true,
+        };
+        self.read_all(remote, &mut compare)?;
+        Ok(compare.same && compare.local.fill_buf()?.is_empty())
+    }
+}
+
+/// A sync in progress.
+struct Syncer<'a, TransportT: Transport> {
+    client: &'a mut Client<TransportT>,
+    options: &'a SyncOptions,
+    /// Whether the destination is the server.
+    upload: bool,
+    local_root: PathBuf,
+    /// Handles of what is on the server, including the root as the empty path.
+    remote_handles: HashMap<PathBuf, FileHandle>,
+}
+
+impl<'a, TransportT: Transport> Syncer<'a, TransportT> {
+    fn new(
+        client: &'a mut Client<TransportT>,
+        options: &'a SyncOptions,
+        upload: bool,
+        local_root: &Path,
+        remote_root: FileHandle,
+        remote_tree: &Tree,
+    ) -> Self {
+        let mut remote_handles: HashMap<_, _> = remote_tree
+            .iter()
+            .filter_map(|(path, e)| Some((path.clone(), e.handle.clone()?)))
+            .collect();
+        remote_handles.insert(PathBuf::new(), remote_root);
+        Self {
+            client,
+            options,
+            upload,
+            local_root: local_root.into(),
+            remote_handles,
+        }
+    }
+
+    fn run(mut self, source: Tree, dest: Tree) -> Result<Vec<SyncAction>> {
+        let (actions, dest) = self.plan(&source, dest)?;
+        if !self.options.dry_run {
+            self.apply(&actions, &source, &dest)?;
+        }
+        Ok(actions)
+    }
+
+    /// Works out what to do, returning it along with what in the destination is being kept.
+    fn plan(
+        &mut self,
+        source: &Tree,
+        dest: Tree,
+    ) -> Result<(Vec<SyncAction>, HashMap<PathBuf, Entry>)> {
+        let source_kinds: HashMap<&Path, &Kind> =
+            source.iter().map(|(p, e)| (p.as_path(), &e.kind)).collect();
+        let mut removed = HashSet::new();
+        let mut actions = vec![];
+        let mut kept = HashMap::new();
+        for (path, entry) in dest {
+            let parent_removed = path.parent().is_some_and(|p| removed.contains(p));
+            let remove = match source_kinds.get(path.as_path()) {
+                _ if parent_removed => true,
+                None => self.options.delete,
+                Some(kind) => **kind != entry.kind,
+            };
+            if remove {
+                removed.insert(path.clone());
+                actions.push(SyncAction::Remove(path));
+            } else {
+                kept.insert(path, entry);
+            }
+        }
+        // What is in a directory has to go before it does.
+        actions.reverse();
+
+        for (path, entry) in source {
+            let action = match (kept.get(path), &entry.kind) {
+                (None, Kind::Directory) => Some(SyncAction::CreateDirectory(path.clone())),
+                (None, Kind::File) => Some(SyncAction::Copy(path.clone())),
+                (None, Kind::Symlink(target)) => {
+                    Some(SyncAction::CreateSymlink(path.clone(), target.clone()))
+                }
+                (Some(dest), Kind::File) if !self.same_contents(path, entry, dest)? => {
+                    Some(SyncAction::Copy(path.clone()))
+                }
+                (Some(dest), Kind::File | Kind::Directory)
+                    if self.attributes_differ(entry, dest) =>
+                {
+                    Some(SyncAction::SetAttributes(path.clone()))
+                }
+                _ => None,
+            };
+            actions.extend(action);
+        }
+        Ok((actions, kept))
+    }
+
+    fn same_contents(&mut self, path: &Path, source: &Entry, dest: &Entry) -> Result<bool> {
+        if source.size != dest.size {
+            return Ok(false);
+        }
+        if !self.options.checksum {
+            return Ok(same_time(source.modified, dest.modified));
+        }
+        let remote = if self.upload { dest } else { source };
+        let handle = remote.handle.clone().expect("remote entry without handle");
+        self.client
+            .same_contents(&self.local_root.join(path), handle)
+    }
+
+    fn attributes_differ(&self, source: &Entry, dest: &Entry) -> bool {
+        let options = self.options;
+        (options.preserve_mode && source.mode.is_some() && source.mode != dest.mode)
+            || (options.preserve_owner && (source.uid, source.gid) != (dest.uid, dest.gid))
+            || (options.preserve_times && !same_time(source.modified, dest.modified))
+    }
+
+    fn apply(
+        &mut self,
+        actions: &[SyncAction],
+        source: &Tree,
+        dest: &HashMap<PathBuf, Entry>,
+    ) -> Result<()> {
+        let source_entries: HashMap<&Path, &Entry> =
+            source.iter().map(|(p, e)| (p.as_path(), e)).collect();
+        let mut copies = vec![];
+        for action in actions {
+            match action {
+                SyncAction::Remove(path) => self.remove(path)?,
+                SyncAction::CreateDirectory(path) => self.create_directory(path)?,
+                SyncAction::CreateSymlink(path, target) => self.create_symlink(path, target)?,
+                SyncAction::Copy(path) => copies.push((
+                    path.as_path(),
+                    source_entries[path.as_path()],
+                    dest.get(path),
+                )),
+                SyncAction::SetAttributes(_) => {}
+            }
+        }
+        self.copy(copies)?;
+
+        // Attributes go last, since copying changes modification times, and deepest first,
+        // since changing what is in a directory changes its modification time too.
+        let changed: HashSet<&Path> = actions
+            .iter()
+            .filter(|a| !matches!(a, SyncAction::Remove(_) | SyncAction::CreateSymlink(..)))
+            .map(|a| a.path())
+            .collect();
+        let touched: HashSet<&Path> = actions
+            .iter()
+            .filter(|a| !matches!(a, SyncAction::SetAttributes(_)))
+            .filter_map(|a| a.path().parent())
+            .collect();
+        for (path, entry) in source.iter().rev() {
+            let is_dir = entry.kind == Kind::Directory;
+            if changed.contains(path.as_path()) || (is_dir && touched.contains(path.as_path())) {
+                self.set_attributes(path, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remote_parent<'p>(&self, path: &'p Path) -> (FileHandle, &'p str) {
+        let parent = self.remote_handles[path.parent().unwrap()].clone();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        (parent, name)
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        if self.upload {
+            let (parent, name) = self.remote_parent(path);
+            self.client.remove(parent, name)?;
+        } else {
+            let path = self.local_root.join(path);
+            if fs::symlink_metadata(&path)?.is_dir() {
+                fs::remove_dir(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_directory(&mut self, path: &Path) -> Result<()> {
+        if self.upload {
+            let (parent, name) = self.remote_parent(path);
+            let handle = self
+                .client
+                .create_directory(parent, name, FileAttributes::default())?;
+            self.remote_handles.insert(path.into(), handle);
+        } else {
+            fs::create_dir(self.local_root.join(path))?;
+        }
+        Ok(())
+    }
+
+    fn create_symlink(&mut self, path: &Path, target: &str) -> Result<()> {
+        if self.upload {
+            let (parent, name) = self.remote_parent(path);
+            self.client
+                .create_symlink(parent, name, target, FileAttributes::default())?;
+        } else {
+            std::os::unix::fs::symlink(target, self.local_root.join(path))?;
+        }
+        Ok(())
+    }
+
+    /// Copies the contents of files, given with the entry for each in the source and the
+    /// destination if it is already there.
+    fn copy(&mut self, files: Vec<(&Path, &Entry, Option<&Entry>)>) -> Result<()> {
+        let parallel = self.options.parallel;
+        if !self.upload {
+            let local_root = &self.local_root;
+            let files = files.into_iter().map(|(path, source, _)| {
+                let handle = source.handle.clone().expect("remote entry without handle");
+                let download = Download::create(local_root.join(path))?;
+                Ok((handle, source.size, download))
+            });
+            return self.client.read_files(files, parallel);
+        }
+
+        let mut uploads = vec![];
+        for (path, source, dest) in files {
+            let handle = match dest {
+                Some(dest) => {
+                    let handle = self.remote_handles[path].clone();
+                    if dest.size > source.size {
+                        let attrs = Attributes {
+                            size: Some(source.size),
+                            ..Default::default()
+                        };
+                        self.client.set_attributes(handle.clone(), attrs)?;
+                    }
+                    handle
+                }
+                None => {
+                    let (parent, name) = self.remote_parent(path);
+                    let (handle, state_id) = self.client.create_file_open(parent, name)?;
+                    // The upload writes with the anonymous state id, so the open isn't needed.
+                    self.client.close(handle.clone(), state_id)?;
+                    self.remote_handles.insert(path.into(), handle.clone());
+                    handle
+                }
+            };
+            uploads.push((self.local_root.join(path), handle));
+        }
+        let uploads = uploads
+            .into_iter()
+            .map(|(path, handle)| Ok((handle, fs::File::open(path)?)));
+        self.client.write_files(uploads, parallel)
+    }
+
+    fn set_attributes(&mut self, path: &Path, entry: &Entry) -> Result<()> {
+        let options = self.options;
+        if self.upload {
+            let mut attrs = Attributes::default();
+            if options.preserve_mode {
+                attrs.mode = entry.mode.map(Mode);
+            }
+            if options.preserve_owner {
+                let id_mapper = self.client.id_mapper();
+                attrs.owner = entry.uid.map(|uid| id_mapper.encode_uid(uid));
+                attrs.owner_group = entry.gid.map(|gid| id_mapper.encode_gid(gid));
+            }
+            if options.preserve_times {
+                let modified = entry.modified.map(Into::into);
+                attrs.time_modify_set = modified.map(SetTime::SetToClientTime);
+            }
+            if attrs != Attributes::default() {
+                let handle = self.remote_handles[path].clone();
+                self.client.set_attributes(handle, attrs)?;
+            }
+            return Ok(());
+        }
+
+        let path = self.local_root.join(path);
+        // Times before the mode, which might stop the file being opened.
+        if let (true, Some(modified)) = (options.preserve_times, entry.modified) {
+            fs::File::open(&path)?.set_modified(modified)?;
+        }
+        if options.preserve_owner {
+            std::os::unix::fs::chown(&path, entry.uid, entry.gid)?;
+        }
+        if let (true, Some(mode)) = (options.preserve_mode, entry.mode) {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Reading or writing the whole contents of files with the READs or WRITEs for several of them
+//! in flight together on one `Pipeline`. `Client::read_all` and `Client::write_all` use it for a
+//! single file, and sync for many.
+
+use super::pipeline::Pipeline;
+use super::unstable::{UncommittedWrites, MAX_UNCOMMITTED};
+use super::{Client, Result, ReturnSecond};
+use nfs4::{FileHandle, PutFhArgs, ReadArgs, StableHow, StateId, WriteArgs};
+use std::collections::BTreeMap;
+use std::io;
+use std::iter::Peekable;
+use sun_rpc_client::Transport;
+
+/// Where the data read from a file goes.
+pub(crate) trait ReadSink: Sized {
+    /// Takes data read from `offset`. Chunks can arrive in any order.
+    fn write_at(&mut self, offset: u64, data: Vec<u8>) -> Result<()>;
+
+    /// Called once all of the file has been read, with where the data read ends.
+    fn finish(self, len: u64) -> Result<()>;
+
+    /// Called instead of `finish` when the transfer fails.
+    fn abort(self) {}
+}
+
+/// Passes data on to an `io::Write` in order, holding on to chunks which arrive before the ones
+/// in front of them.
+pub(crate) struct InOrder<WriteT> {
+    sink: WriteT,
+    received: BTreeMap<u64, Vec<u8>>,
+    written: u64,
+}
+
+impl<WriteT> InOrder<WriteT> {
+    pub(crate) fn new(sink: WriteT) -> Self {
+        Self {
+            sink,
+            received: BTreeMap::new(),
+            written: 0,
+        }
+    }
+}
+
+impl<WriteT: io::Write> ReadSink for InOrder<WriteT> {
+    fn write_at(&mut self, offset: u64, data: Vec<u8>) -> Result<()> {
+        self.received.insert(offset, data);
+        while let Some(data) = self.received.remove(&self.written) {
+            self.sink.write_all(&data)?;
+            self.written += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn finish(self, _len: u64) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file being read, several of which are in flight at once.
+struct FileRead<SinkT> {
+    handle: FileHandle,
+    /// How much to read, unless eof comes first.
+    size: u64,
+    sink: SinkT,
+    next_offset: u64,
+    /// The rest of chunks the server returned less of than asked for.
+    remainders: Vec<(u64, u32)>,
+    in_flight: usize,
+    /// Where the data received so far ends.
+    len: u64,
+    eof: bool,
+}
+
+impl<SinkT> FileRead<SinkT> {
+    fn next_chunk(&mut self, max_read: u64) -> Option<(u64, u32)> {
+        if let Some(remainder) = self.remainders.pop() {
+            return Some(remainder);
+        }
+        if self.eof || self.next_offset >= self.size {
+            return None;
+        }
+        let count = max_read
+            .min(u64::from(u32::MAX))
+            .min(self.size - self.next_offset);
+        self.next_offset += count;
+        Some((self.next_offset - count, count as u32))
+    }
+
+    fn is_done(&self) -> bool {
+        self.in_flight == 0
+            && self.remainders.is_empty()
+            && (self.eof || self.next_offset >= self.size)
+    }
+}
+
+/// A file being written, several of which are in flight at once.
+struct FileWrite<SourceT> {
+    handle: FileHandle,
+    source: SourceT,
+    next_offset: u64,
+    done_reading: bool,
+    /// The rest of chunks the server wrote less of than it was sent.
+    remainders: Vec<(u64, Vec<u8>)>,
+    in_flight: usize,
+    uncommitted: UncommittedWrites,
+}
+
+impl<SourceT: io::Read> FileWrite<SourceT> {
+    fn next_chunk(&mut self, max_write: usize) -> io::Result<Option<(u64, Vec<u8>)>> {
+        if let Some(remainder) = self.remainders.pop() {
+            return Ok(Some(remainder));
+        }
+        if self.done_reading {
+            return Ok(None);
+        }
+        let mut buf = vec![0; max_write];
+        let amount_read = self.source.read(&mut buf)?;
+        if amount_read == 0 {
+            self.done_reading = true;
+            return Ok(None);
+        }
+        buf.truncate(amount_read);
+        self.next_offset += amount_read as u64;
+        Ok(Some((self.next_offset - amount_read as u64, buf)))
+    }
+
+    fn is_done(&self) -> bool {
+        self.done_reading && self.remainders.is_empty() && self.in_flight == 0
+    }
+}
+
+type ReadPipeline = Pipeline<ReturnSecond<PutFhArgs, ReadArgs>, (usize, u64, u32)>;
+type WritePipeline = Pipeline<ReturnSecond<PutFhArgs, WriteArgs>, (usize, u64, Vec<u8>)>;
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Reads each file, up to the given size or eof, into its sink, with the reads for up to
+    /// `parallel` files in flight together. The files are only opened as they are started.
+    pub(crate) fn read_files<SinkT: ReadSink>(
+        &mut self,
+        files: impl IntoIterator<Item = Result<(FileHandle, u64, SinkT)>>,
+        parallel: usize,
+    ) -> Result<()> {
+        let mut pipeline = Pipeline::new(self);
+        let mut active = BTreeMap::new();
+        let mut files = files.into_iter().peekable();
+        let res = self.read_pipelined(&mut files, parallel, &mut pipeline, &mut active);
+        if res.is_err() {
+            pipeline.drain(self);
+            for file in active.into_values() {
+                file.sink.abort();
+            }
+        }
+        res
+    }
+
+    fn read_pipelined<SinkT: ReadSink>(
+        &mut self,
+        files: &mut Peekable<impl Iterator<Item = Result<(FileHandle, u64, SinkT)>>>,
+        parallel: usize,
+        pipeline: &mut ReadPipeline,
+        active: &mut BTreeMap<usize, FileRead<SinkT>>,
+    ) -> Result<()> {
+        let mut next_id = 0;
+        loop {
+            while active.len() < parallel.max(1) {
+                let Some(file) = files.next() else {
+                    break;
+                };
+                let (handle, size, sink) = file?;
+                let read = FileRead {
+                    handle,
+                    size,
+                    sink,
+                    next_offset: 0,
+                    remainders: vec![],
+                    in_flight: 0,
+                    len: 0,
+                    eof: false,
+                };
+                active.insert(next_id, read);
+                next_id += 1;
+            }
+
+            'send: while pipeline.can_send() {
+                for (&id, read) in active.iter_mut() {
+                    if let Some((offset, count)) = read.next_chunk(self.max_read) {
+                        let args = ReturnSecond(
+                            PutFhArgs {
+                                object: read.handle.clone(),
+                            },
+                            ReadArgs {
+                                state_id: StateId::anonymous(),
+                                offset,
+                                count,
+                            },
+                        );
+                        read.in_flight += 1;
+                        pipeline.send(self, args, (id, offset, count))?;
+                        continue 'send;
+                    }
+                }
+                break;
+            }
+
+            let done: Vec<_> = active
+                .iter()
+                .filter(|(_, r)| r.is_done())
+                .map(|(&id, _)| id)
+                .collect();
+            for id in done {
+                let read = active.remove(&id).unwrap();
+                read.sink.finish(read.len)?;
+            }
+
+            if pipeline.is_empty() {
+                if active.is_empty() && files.peek().is_none() {
+                    break Ok(());
+                }
+                continue;
+            }
+
+            let ((id, offset, count), res) = pipeline.receive(self)?;
+            let res = res?;
+            let read = active.get_mut(&id).expect("read for unknown file");
+            read.in_flight -= 1;
+            let len = res.data.len() as u32;
+            if res.eof || len == 0 {
+                read.eof = true;
+            } else if len < count {
+                read.remainders.push((offset + u64::from(len), count - len));
+            }
+            if len > 0 {
+                read.len = read.len.max(offset + u64::from(len));
+                read.sink.write_at(offset, res.data)?;
+            }
+        }
+    }
+
+    /// Writes everything from each source to its file UNSTABLE, with the writes for up to
+    /// `parallel` files in flight together, committing them in batches.
+    pub(crate) fn write_files<SourceT: io::Read>(
+        &mut self,
+        files: impl IntoIterator<Item = Result<(FileHandle, SourceT)>>,
+        parallel: usize,
+    ) -> Result<()> {
+        let mut pipeline = Pipeline::new(self);
+        let mut files = files.into_iter().peekable();
+        let res = self.write_pipelined(&mut files, parallel, &mut pipeline);
+        if res.is_err() {
+            pipeline.drain(self);
+        }
+        res
+    }
+
+    fn write_pipelined<SourceT: io::Read>(
+        &mut self,
+        files: &mut Peekable<impl Iterator<Item = Result<(FileHandle, SourceT)>>>,
+        parallel: usize,
+        pipeline: &mut WritePipeline,
+    ) -> Result<()> {
+        let mut next_id = 0;
+        let mut active = BTreeMap::new();
+        // Files all written, waiting for the pipeline to empty so they can be committed.
+        let mut finished: Vec<FileWrite<SourceT>> = vec![];
+        loop {
+            while active.len() < parallel.max(1) {
+                let Some(file) = files.next() else {
+                    break;
+                };
+                let (handle, source) = file?;
+                self.invalidate_attributes(&handle);
+                self.invalidate_data(handle.clone());
+                let write = FileWrite {
+                    handle,
+                    source,
+                    next_offset: 0,
+                    done_reading: false,
+                    remainders: vec![],
+                    in_flight: 0,
+                    uncommitted: UncommittedWrites::default(),
+                };
+                active.insert(next_id, write);
+                next_id += 1;
+            }
+
+            let uncommitted: usize = active
+                .values()
+                .chain(&finished)
+                .map(|w| w.uncommitted.len())
+                .sum();
+            // Hold off sending more while waiting to commit, since that needs the connection.
+            let commit_due = uncommitted >= MAX_UNCOMMITTED;
+            'send: while !commit_due && pipeline.can_send() {
+                for (&id, write) in &mut active {
+                    if let Some((offset, data)) = write.next_chunk(self.max_write as usize)? {
+                        let args = ReturnSecond(
+                            PutFhArgs {
+                                object: write.handle.clone(),
+                            },
+                            WriteArgs {
+                                state_id: StateId::anonymous(),
+                                offset,
+                                stable: StableHow::Unstable,
+                                data: data.clone(),
+                            },
+                        );
+                        write.in_flight += 1;
+                        pipeline.send(self, args, (id, offset, data))?;
+                        continue 'send;
+                    }
+                }
+                break;
+            }
+
+            let done: Vec<_> = active
+                .iter()
+                .filter(|(_, w)| w.is_done())
+                .map(|(&id, _)| id)
+                .collect();
+            finished.extend(done.into_iter().filter_map(|id| active.remove(&id)));
+
+            if pipeline.is_empty() {
+                for mut write in finished.drain(..) {
+                    self.commit_writes(
+                        &write.handle,
+                        StateId::anonymous(),
+                        &mut write.uncommitted,
+                    )?;
+                }
+                if commit_due {
+                    for write in active.values_mut() {
+                        self.commit_writes(
+                            &write.handle,
+                            StateId::anonymous(),
+                            &mut write.uncommitted,
+                        )?;
+                    }
+                }
+                if active.is_empty() && files.peek().is_none() {
+                    break Ok(());
+                }
+                continue;
+            }
+
+            let ((id, offset, mut data), res) = pipeline.receive(self)?;
+            let res = res?;
+            let write = active.get_mut(&id).expect("write for unknown file");
+            write.in_flight -= 1;
+            let written = (res.count as usize).min(data.len());
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            if written < data.len() {
+                write
+                    .remainders
+                    .push((offset + written as u64, data.split_off(written)));
+            }
+            if res.committed == StableHow::Unstable {
+                write.uncommitted.record(offset, data, res.write_veritifer);
+            }
+        }
+    }
+}
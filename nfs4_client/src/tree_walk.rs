@@ -0,0 +1,532 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Walking a whole tree, like `find`. Directories are listed whole, several at a time with their
+//! READDIRs pipelined, ahead of when their entries are needed.
+
+use super::namespace::Referral;
+use super::path_walk::path_error;
+use super::pipeline::Pipeline;
+use super::read_dir::MAX_RESTARTS;
+use super::{Client, Error, Result, ReturnSecond};
+use nfs4::{
+    Attributes, Cookie, EnumSet, FileAttributeId, FileHandle, FileId, FileType, FsId, PutFhArgs,
+    ReadDirArgs, ReadDirRes, StatusError, Verifier,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use sun_rpc_client::Transport;
+
+/// The order `Client::walk` returns entries in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Each directory is followed by what is in it, like `find`.
+    #[default]
+    DepthFirst,
+    /// Everything at one depth comes before anything deeper.
+    BreadthFirst,
+}
+
+/// Which entries `Client::walk` returns. Everything set has to match.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalkFilter {
+    /// A shell pattern for the name, with `*`, `?` and `[...]`.
+    pub name: Option<String>,
+    pub file_type: Option<FileType>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+}
+
+impl WalkFilter {
+    fn attr_request(&self) -> EnumSet<FileAttributeId> {
+        let mut request = EnumSet::default();
+        if self.min_size.is_some() || self.max_size.is_some() {
+            request.insert(FileAttributeId::Size);
+        }
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            request.insert(FileAttributeId::TimeModify);
+        }
+        request
+    }
+
+    pub fn matches(&self, name: &str, attrs: &Attributes) -> bool {
+        let size = attrs.size.unwrap_or(0);
+        let modified = attrs.modified();
+        self.name.as_ref().is_none_or(|p| glob_matches(p, name))
+            && self
+                .file_type
+                .as_ref()
+                .is_none_or(|t| attrs.type_.as_ref() == Some(t))
+            && self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self
+                .modified_after
+                .is_none_or(|after| modified.is_some_and(|m| m > after))
+            && self
+                .modified_before
+                .is_none_or(|before| modified.is_some_and(|m| m < before))
+    }
+}
+
+/// Matches one character against a `[...]` class, returning whether it matched and the length
+/// of the class, or `None` if it isn't closed.
+fn class_matches(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.get(1), Some('!' | '^'));
+    let mut i = if negated { 2 } else { 1 };
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        if class[i] == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|&e| e != ']') {
+            matched |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Whether `name` matches the shell pattern.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to go back to if what follows the last `*` stops matching.
+    let mut star = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match class_matches(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None => (name[n] == '[').then_some(1),
+            },
+            Some(&c) => (c == name[n]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// How `Client::walk` goes through a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkOptions {
+    pub order: WalkOrder,
+    /// Attributes to fetch for each entry, besides the ones the walk needs itself.
+    pub attr_request: EnumSet<FileAttributeId>,
+    /// Only matching entries are returned, but directories are descended into either way.
+    pub filter: WalkFilter,
+    /// Don't descend further than this many directories below the root.
+    pub max_depth: Option<usize>,
+    /// Don't descend into directories on other filesystems.
+    pub same_file_system: bool,
+    /// How many directories to fetch at once.
+    pub parallel: usize,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            order: WalkOrder::default(),
+            attr_request: EnumSet::default(),
+            filter: WalkFilter::default(),
+            max_depth: None,
+            same_file_system: false,
+            parallel: 4,
+        }
+    }
+}
+
+/// Something found by `Client::walk`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkEntry {
+    /// The path relative to the root of the walk.
+    pub path: PathBuf,
+    pub handle: FileHandle,
+    pub attrs: Attributes,
+    /// How many directories below the root it is, 1 for what is in the root.
+    pub depth: usize,
+    /// It is the root of a different filesystem than the directory it is in, going by `FsId`.
+    pub mount_point: bool,
+}
+
+/// An entry, with the listing of what is in it if it is a directory to descend into.
+type Item = (Result<WalkEntry>, Option<usize>);
+
+enum Work {
+    Entry(Box<Item>),
+    /// Everything in a directory, which may not have been fetched yet.
+    Listing(usize),
+}
+
+enum ListingState {
+    Waiting,
+    InFlight,
+    Done(Result<Vec<Item>>),
+}
+
+struct Listing {
+    handle: FileHandle,
+    path: PathBuf,
+    depth: usize,
+    fs_id: Option<FsId>,
+    cookie: Cookie,
+    cookie_verifier: Verifier,
+    restarts: usize,
+    entries: Vec<Result<WalkEntry>>,
+    state: ListingState,
+}
+
+impl Listing {
+    fn new(handle: FileHandle, path: PathBuf, depth: usize, fs_id: Option<FsId>) -> Self {
+        Self {
+            handle,
+            path,
+            depth,
+            fs_id,
+            cookie: Cookie::initial(),
+            cookie_verifier: Verifier(0),
+            restarts: 0,
+            entries: vec![],
+            state: ListingState::Waiting,
+        }
+    }
+}
+
+/// An iterator over everything below a directory, returned by `Client::walk`.
+///
+/// A directory which can't be listed comes out as an `Error::Path` for it, and the walk carries
+/// on with the rest. So does a referral to a filesystem on another server, which comes out as an
+/// `Error::Referral` for it. Symlinks aren't followed, and a directory already seen, going by its
+/// `FsId` and `FileId`, isn't descended into again.
+pub struct Walk<'a, TransportT: Transport> {
+    client: &'a mut Client<TransportT>,
+    options: WalkOptions,
+    attr_request: EnumSet<FileAttributeId>,
+    root_fs_id: Option<FsId>,
+    pipeline: Pipeline<ReturnSecond<PutFhArgs, ReadDirArgs>, usize>,
+    listings: HashMap<usize, Listing>,
+    next_listing: usize,
+    /// Listings to fetch, in the order they will be needed.
+    to_fetch: VecDeque<usize>,
+    /// How many listings are fetched but not walked through yet.
+    fetched_ahead: usize,
+    work: VecDeque<Work>,
+    visited: HashSet<(FsId, FileId)>,
+    broken: bool,
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Walks through everything below the given directory, which isn't itself returned.
+    pub fn walk(&mut self, root: FileHandle, options: WalkOptions) -> Result<Walk<'_, TransportT>> {
+        let root_attrs = self.get_attributes(
+            root.clone(),
+            [FileAttributeId::FsId, FileAttributeId::FileId]
+                .into_iter()
+                .collect::<EnumSet<_>>(),
+        )?;
+        let mut visited = HashSet::new();
+        if let (Some(fs_id), Some(file_id)) = (root_attrs.fs_id, root_attrs.file_id) {
+            visited.insert((fs_id, file_id));
+        }
+
+        let mut attr_request = options.attr_request.clone();
+        for id in options.filter.attr_request().into_iter().chain([
+            FileAttributeId::Type,
+            FileAttributeId::FileHandle,
+            FileAttributeId::FsId,
+            FileAttributeId::FileId,
+            FileAttributeId::FsLocations,
+        ]) {
+            attr_request.insert(id);
+        }
+        let attr_request = attr_request
+            .into_iter()
+            .filter(|a| self.supported_attrs.contains(*a))
+            .collect();
+
+        let pipeline = Pipeline::new(self);
+        let mut walk = Walk {
+            client: self,
+            options,
+            attr_request,
+            root_fs_id: root_attrs.fs_id,
+            pipeline,
+            listings: HashMap::new(),
+            next_listing: 0,
+            to_fetch: VecDeque::new(),
+            fetched_ahead: 0,
+            work: VecDeque::new(),
+            visited,
+            broken: false,
+        };
+        let id = walk.add_listing(root, PathBuf::new(), 0, root_attrs.fs_id);
+        walk.to_fetch.push_back(id);
+        walk.work.push_back(Work::Listing(id));
+        Ok(walk)
+    }
+}
+
+impl<TransportT: Transport> Walk<'_, TransportT> {
+    /// The client the walk is using.
+    pub fn client(&self) -> &Client<TransportT> {
+        self.client
+    }
+
+    fn add_listing(
+        &mut self,
+        handle: FileHandle,
+        path: PathBuf,
+        depth: usize,
+        fs_id: Option<FsId>,
+    ) -> usize {
+        let id = self.next_listing;
+        self.next_listing += 1;
+        self.listings
+            .insert(id, Listing::new(handle, path, depth, fs_id));
+        id
+    }
+
+    fn send(&mut self, id: usize) -> Result<()> {
+        let listing = self.listings.get_mut(&id).unwrap();
+        listing.state = ListingState::InFlight;
+        let put_fh = PutFhArgs {
+            object: listing.handle.clone(),
+        };
+        let args = self.client.read_dir_args(
+            listing.cookie,
+            listing.cookie_verifier.clone(),
+            self.attr_request.clone(),
+        );
+        self.pipeline
+            .send(self.client, ReturnSecond(put_fh, args), id)
+    }
+
+    /// Sends READDIRs for the listings needed next, up to `parallel` of them. The listing
+    /// `needed` is sent whatever the limit, since the walk is waiting for it.
+    fn fill(&mut self, needed: usize) -> Result<()> {
+        if matches!(self.listings[&needed].state, ListingState::Waiting) {
+            self.to_fetch.retain(|&id| id != needed);
+            self.to_fetch.push_front(needed);
+        }
+        let parallel = self.options.parallel.max(1);
+        while self.pipeline.can_send() {
+            let Some(&id) = self.to_fetch.front() else {
+                break;
+            };
+            let started = self.listings[&id].cookie != Cookie::initial();
+            let busy = self.pipeline.len() + self.fetched_ahead >= parallel;
+            if id != needed && !started && busy {
+                break;
+            }
+            self.to_fetch.pop_front();
+            self.send(id)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<()> {
+        let (id, res) = self.pipeline.receive(self.client)?;
+        let listing = self.listings.get_mut(&id).unwrap();
+        match res {
+            Ok(res) => {
+                if let Err(error) = self.add_entries(id, res) {
+                    self.finish(id, Err(error));
+                }
+            }
+            Err(Error::Protocol(StatusError::NotSame | StatusError::BadCookie))
+                if listing.restarts < MAX_RESTARTS =>
+            {
+                listing.restarts += 1;
+                listing.cookie = Cookie::initial();
+                listing.cookie_verifier = Verifier(0);
+                listing.entries.clear();
+                listing.state = ListingState::Waiting;
+                self.to_fetch.push_front(id);
+            }
+            Err(error) => self.finish(id, Err(error)),
+        }
+        Ok(())
+    }
+
+    fn add_entries(&mut self, id: usize, res: ReadDirRes) -> Result<()> {
+        let listing = self.listings.get_mut(&id).unwrap();
+        listing.cookie_verifier = res.cookie_verifier;
+        match res.reply.entries.last() {
+            Some(last) => listing.cookie = last.cookie,
+            None if !res.reply.eof => return Err(Error::Protocol(StatusError::TooSmall)),
+            None => {}
+        }
+        for entry in res.reply.entries {
+            let mut attrs = Attributes::from(entry.attrs);
+            let path = listing.path.join(&entry.name);
+            let Some(handle) = attrs.file_handle.take() else {
+                // A referral has no handle here, only where it went.
+                let (Some(fs_id), Some(locations)) = (attrs.fs_id, attrs.fs_locations) else {
+                    return Err(Error::MissingAttribute(FileAttributeId::FileHandle));
+                };
+                let referral = Referral {
+                    fs_id,
+                    locations,
+                    rest: PathBuf::new(),
+                    crossings: vec![],
+                };
+                let error = path_error(path, Error::Referral(Box::new(referral)));
+                listing.entries.push(Err(error));
+                continue;
+            };
+            listing.entries.push(Ok(WalkEntry {
+                path,
+                handle,
+                mount_point: attrs.fs_id.is_some() && attrs.fs_id != listing.fs_id,
+                attrs,
+                depth: listing.depth + 1,
+            }));
+        }
+        if !res.reply.eof {
+            listing.state = ListingState::Waiting;
+            self.to_fetch.push_front(id);
+            return Ok(());
+        }
+
+        let entries = std::mem::take(&mut listing.entries);
+        let mut children = vec![];
+        let mut items = vec![];
+        for entry in entries {
+            let child = entry
+                .as_ref()
+                .ok()
+                .filter(|e| self.should_descend(e))
+                .map(|e| {
+                    let child =
+                        self.add_listing(e.handle.clone(), e.path.clone(), e.depth, e.attrs.fs_id);
+                    children.push(child);
+                    child
+                });
+            items.push((entry, child));
+        }
+        match self.options.order {
+            WalkOrder::DepthFirst => {
+                for &child in children.iter().rev() {
+                    self.to_fetch.push_front(child);
+                }
+            }
+            WalkOrder::BreadthFirst => self.to_fetch.extend(children),
+        }
+        self.finish(id, Ok(items));
+        Ok(())
+    }
+
+    fn should_descend(&mut self, entry: &WalkEntry) -> bool {
+        let attrs = &entry.attrs;
+        if !attrs.is_dir() || self.options.max_depth.is_some_and(|max| entry.depth >= max) {
+            return false;
+        }
+        if self.options.same_file_system && attrs.fs_id != self.root_fs_id {
+            return false;
+        }
+        match (attrs.fs_id, attrs.file_id) {
+            (Some(fs_id), Some(file_id)) => self.visited.insert((fs_id, file_id)),
+            _ => true,
+        }
+    }
+
+    fn finish(&mut self, id: usize, res: Result<Vec<Item>>) {
+        self.listings.get_mut(&id).unwrap().state = ListingState::Done(res);
+        self.fetched_ahead += 1;
+    }
+
+    /// Waits for the listing to be fetched, and takes it.
+    fn take_listing(&mut self, id: usize) -> Result<Listing> {
+        while !matches!(self.listings[&id].state, ListingState::Done(_)) {
+            self.fill(id)?;
+            self.receive()?;
+        }
+        self.fetched_ahead -= 1;
+        Ok(self.listings.remove(&id).unwrap())
+    }
+}
+
+impl<TransportT: Transport> Iterator for Walk<'_, TransportT> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.broken {
+            match self.work.pop_front()? {
+                Work::Entry(item) => {
+                    let (entry, child) = *item;
+                    if let Some(child) = child {
+                        match self.options.order {
+                            WalkOrder::DepthFirst => self.work.push_front(Work::Listing(child)),
+                            WalkOrder::BreadthFirst => self.work.push_back(Work::Listing(child)),
+                        }
+                    }
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(error) => return Some(Err(error)),
+                    };
+                    let name = entry.path.file_name().unwrap_or_default();
+                    if self
+                        .options
+                        .filter
+                        .matches(&name.to_string_lossy(), &entry.attrs)
+                    {
+                        return Some(Ok(entry));
+                    }
+                }
+                Work::Listing(id) => {
+                    let listing = match self.take_listing(id) {
+                        Ok(listing) => listing,
+                        Err(error) => {
+                            // The connection is broken, so there is no carrying on.
+                            self.broken = true;
+                            return Some(Err(error));
+                        }
+                    };
+                    let ListingState::Done(res) = listing.state else {
+                        unreachable!()
+                    };
+                    match res {
+                        Ok(items) => {
+                            for item in items.into_iter().rev() {
+                                self.work.push_front(Work::Entry(Box::new(item)));
+                            }
+                        }
+                        Err(error) => return Some(Err(path_error(listing.path, error))),
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<TransportT: Transport> Drop for Walk<'_, TransportT> {
+    fn drop(&mut self) {
+        self.pipeline.drain(self.client);
+    }
+}
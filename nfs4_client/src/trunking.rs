@@ -0,0 +1,93 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Extra connections bound to the client's session, to the same address or any other the server
+//! answers on, with compounds spread across them. This is what Linux calls `nconnect`.
+
+use super::{Client, ClientWithoutSession, Result, NFS};
+use nfs4::{
+    BindConnToSessionArgs, ChannelDirectionFromServer, DestroyClientIdArgs, ExchangeIdArgs,
+    ExchangeIdRes, StateProtect,
+};
+use sun_rpc_client::{RpcClient, Transport};
+
+/// How a server reached over a new connection relates to the one the client is using, as
+/// worked out from EXCHANGE_ID in RFC 5661 section 2.10.5.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trunking {
+    /// A different server, or one which didn't recognise the client.
+    None,
+    /// The same server, which knows the client but can't use its session on the connection.
+    ClientId,
+    /// The same server, the connection can be bound to the session.
+    Session,
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    fn trunking_with(&self, eid_res: &ExchangeIdRes) -> Trunking {
+        if eid_res.server_scope != self.server_scope
+            || eid_res.server_owner.major_id != self.server_owner.major_id
+            || eid_res.client_id != self.client_id
+        {
+            Trunking::None
+        } else if eid_res.server_owner.minor_id != self.server_owner.minor_id {
+            Trunking::ClientId
+        } else {
+            Trunking::Session
+        }
+    }
+
+    /// Does an EXCHANGE_ID over the given connection to find out whether it reaches the same
+    /// server. If session trunking is possible the connection is bound to the session with
+    /// BIND_CONN_TO_SESSION and used from then on, otherwise it is dropped.
+    pub fn add_connection(&mut self, transport: TransportT) -> Result<Trunking> {
+        let mut raw_client =
+            ClientWithoutSession::new(RpcClient::new(transport, NFS), self.minor_version());
+        let eid_res = raw_client.do_compound(ExchangeIdArgs {
+            client_owner: self.client_owner.clone(),
+            flags: self.exchange_id_flags,
+            state_protect: StateProtect::None,
+            client_impl_id: None,
+        })?;
+
+        let trunking = self.trunking_with(&eid_res);
+        match trunking {
+            Trunking::Session => {
+                raw_client.do_compound(BindConnToSessionArgs {
+                    session_id: self.session.session_id,
+                    direction: ChannelDirectionFromServer::Fore,
+                    use_connection_in_rdma_mode: false,
+                })?;
+                self.trunked.push(raw_client);
+            }
+            Trunking::ClientId => {}
+            Trunking::None => {
+                // The EXCHANGE_ID made a client record there which nothing is going to use.
+                if eid_res.client_id != self.client_id {
+                    let _ = raw_client.do_compound(DestroyClientIdArgs {
+                        client_id: eid_res.client_id,
+                    });
+                }
+            }
+        }
+        Ok(trunking)
+    }
+
+    /// How many connections compounds are spread across.
+    pub fn num_connections(&self) -> usize {
+        self.trunked.len() + 1
+    }
+
+    pub(crate) fn connection(&mut self, index: usize) -> &mut ClientWithoutSession<TransportT> {
+        match index {
+            0 => &mut self.raw_client,
+            i => &mut self.trunked[i - 1],
+        }
+    }
+
+    /// The connection to send the next compound on, going round all of them in turn.
+    pub(crate) fn next_connection(&mut self) -> usize {
+        let index = self.next_connection % self.num_connections();
+        self.next_connection = index + 1;
+        index
+    }
+}
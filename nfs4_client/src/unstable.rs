@@ -0,0 +1,100 @@
+// Copyright 2023 Remi Bernotavicius
+
+use super::{Client, Result};
+use nfs4::{FileHandle, StableHow, StateId, Verifier};
+use std::io;
+use sun_rpc_client::Transport;
+
+/// How much UNSTABLE data to send before committing it, bounding what has to be kept around.
+pub(crate) const MAX_UNCOMMITTED: usize = 16 * 1024 * 1024;
+
+/// Data written UNSTABLE, which the server loses if it reboots before a COMMIT. It is kept until
+/// committed, so it can be sent again when the write verifier shows the server rebooted.
+#[derive(Default)]
+pub(crate) struct UncommittedWrites {
+    verifier: Option<Verifier>,
+    /// Set when writes came back with different verifiers, so some may have been lost already.
+    verifier_changed: bool,
+    ranges: Vec<(u64, Vec<u8>)>,
+    len: usize,
+}
+
+impl UncommittedWrites {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn record(&mut self, offset: u64, data: Vec<u8>, verifier: Verifier) {
+        match &self.verifier {
+            Some(v) if *v != verifier => self.verifier_changed = true,
+            _ => {}
+        }
+        self.verifier = Some(verifier);
+        self.len += data.len();
+        self.ranges.push((offset, data));
+    }
+
+    fn take(&mut self) -> Vec<(u64, Vec<u8>)> {
+        self.verifier = None;
+        self.verifier_changed = false;
+        self.len = 0;
+        std::mem::take(&mut self.ranges)
+    }
+}
+
+impl<TransportT: Transport> Client<TransportT> {
+    /// Writes all of `data` UNSTABLE, keeping what isn't yet on stable storage in `uncommitted`.
+    pub(crate) fn write_unstable(
+        &mut self,
+        handle: &FileHandle,
+        state_id: StateId,
+        offset: u64,
+        data: &[u8],
+        uncommitted: &mut UncommittedWrites,
+    ) -> Result<()> {
+        let mut sent = 0;
+        while sent < data.len() {
+            let count = (data.len() - sent).min(self.max_write as usize);
+            let res = self.write_with_state(
+                handle.clone(),
+                state_id,
+                offset + sent as u64,
+                StableHow::Unstable,
+                data[sent..sent + count].to_vec(),
+            )?;
+            let written = (res.count as usize).min(count);
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            if res.committed == StableHow::Unstable {
+                let range = data[sent..sent + written].to_vec();
+                uncommitted.record(offset + sent as u64, range, res.write_veritifer);
+            }
+            sent += written;
+        }
+        Ok(())
+    }
+
+    /// COMMITs the data in `uncommitted`. If the verifier shows the server lost any of it, all of
+    /// it is written again and the COMMIT retried.
+    pub(crate) fn commit_writes(
+        &mut self,
+        handle: &FileHandle,
+        state_id: StateId,
+        uncommitted: &mut UncommittedWrites,
+    ) -> Result<()> {
+        while !uncommitted.ranges.is_empty() {
+            let res = self.commit(handle.clone())?;
+            if !uncommitted.verifier_changed
+                && uncommitted.verifier.as_ref() == Some(&res.write_verifier)
+            {
+                uncommitted.take();
+                break;
+            }
+            for (offset, data) in uncommitted.take() {
+                self.write_unstable(handle, state_id, offset, &data, uncommitted)?;
+            }
+        }
+        Ok(())
+    }
+}
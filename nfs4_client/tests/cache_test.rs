@@ -0,0 +1,131 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, StatusError};
+use nfs4_client::{CacheConfig, Client, Error};
+use nfs4_test_server::TestServer;
+use std::time::Duration;
+
+fn cached_client(server: &TestServer, timeout: Duration) -> Client<std::net::TcpStream> {
+    let mut client = Client::new(server.connect()).unwrap();
+    client.enable_cache(CacheConfig::fixed(timeout));
+    client
+}
+
+fn size(client: &mut Client<std::net::TcpStream>, path: &str) -> u64 {
+    let handle = client.look_up(path).unwrap();
+    client
+        .get_attributes(handle, AttrRequest::new().size())
+        .unwrap()
+        .size
+        .unwrap()
+}
+
+fn is_no_ent(error: Error) -> bool {
+    matches!(error, Error::Path(e) if matches!(e.error, Error::Protocol(StatusError::NoEnt)))
+}
+
+#[test]
+fn look_ups_skip_cached_directories() {
+    let server = TestServer::new();
+    let mut path = String::new();
+    for i in 0..10 {
+        path.push_str(&format!("/d{i}"));
+        server.create_dir(&path);
+    }
+    for i in 0..5 {
+        server.write_file(&format!("{path}/f{i}"), b"");
+    }
+    let mut client = cached_client(&server, Duration::from_secs(60));
+
+    let f0 = client.look_up(format!("{path}/f0")).unwrap();
+    let before = server.total_compounds();
+    for i in 1..5 {
+        client.look_up(format!("{path}/f{i}")).unwrap();
+    }
+    assert_eq!(server.total_compounds() - before, 4);
+
+    let before = server.total_compounds();
+    assert_eq!(client.look_up(format!("{path}/f0")).unwrap(), f0);
+    client.look_up(&path).unwrap();
+    client.look_up("/").unwrap();
+    assert_eq!(server.total_compounds(), before);
+}
+
+#[test]
+fn attributes_time_out() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"abc");
+    let mut client = cached_client(&server, Duration::from_millis(200));
+    let mut other = Client::new(server.connect()).unwrap();
+
+    assert_eq!(size(&mut client, "/a_file"), 3);
+    let handle = other.look_up("/a_file").unwrap();
+    other.write(handle, 3, b"def".to_vec()).unwrap();
+    assert_eq!(size(&mut client, "/a_file"), 3);
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(size(&mut client, "/a_file"), 6);
+
+    // Writes from this client drop what it had cached.
+    let handle = client.look_up("/a_file").unwrap();
+    client.write(handle, 6, b"g".to_vec()).unwrap();
+    assert_eq!(size(&mut client, "/a_file"), 7);
+}
+
+#[test]
+fn own_changes_keep_directory_cached() {
+    let server = TestServer::new();
+    server.create_dir("/a");
+    server.write_file("/a/old", b"");
+    let mut client = cached_client(&server, Duration::from_secs(60));
+    let a = client.look_up("/a").unwrap();
+    client.look_up("/a/old").unwrap();
+
+    let created = client.create_file(a.clone(), "new").unwrap();
+    let before = server.total_compounds();
+    assert_eq!(client.look_up("/a/new").unwrap(), created);
+    client.look_up("/a/old").unwrap();
+    assert_eq!(server.total_compounds(), before);
+
+    client.remove(a.clone(), "old").unwrap();
+    assert!(is_no_ent(client.look_up("/a/old").unwrap_err()));
+
+    client
+        .rename(a.clone(), a.clone(), "new", "renamed")
+        .unwrap();
+    let before = server.total_compounds();
+    assert_eq!(client.look_up("/a/renamed").unwrap(), created);
+    assert_eq!(server.total_compounds(), before);
+    assert!(is_no_ent(client.look_up("/a/new").unwrap_err()));
+}
+
+#[test]
+fn changes_from_other_clients() {
+    let server = TestServer::new();
+    server.create_dir("/a");
+    server.write_file("/a/file", b"");
+    let mut client = cached_client(&server, Duration::from_millis(200));
+    let mut other = Client::new(server.connect()).unwrap();
+    let a = client.look_up("/a").unwrap();
+    let file = client.look_up("/a/file").unwrap();
+
+    other.remove(a.clone(), "file").unwrap();
+    assert_eq!(client.look_up("/a/file").unwrap(), file);
+    client.invalidate(a.clone());
+    assert!(is_no_ent(client.look_up("/a/file").unwrap_err()));
+
+    // Names which aren't found aren't cached.
+    let recreated = other.create_file(a.clone(), "file").unwrap();
+    assert_eq!(client.look_up("/a/file").unwrap(), recreated);
+
+    other.remove(a.clone(), "file").unwrap();
+    assert_eq!(client.look_up("/a/file").unwrap(), recreated);
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(is_no_ent(client.look_up("/a/file").unwrap_err()));
+
+    other.create_file(a.clone(), "another").unwrap();
+    client.look_up("/a/another").unwrap();
+    other.remove(a, "another").unwrap();
+    client.invalidate_all();
+    assert!(is_no_ent(client.look_up("/a/another").unwrap_err()));
+}
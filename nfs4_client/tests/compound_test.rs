@@ -0,0 +1,96 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{
+    ArgOp, AttrRequest, Attributes, GetAttrArgs, LookUpArgs, PutFhArgs, ResOp, StatusError,
+    StatusResult,
+};
+use nfs4_client::{Client, Error, GetFh, PutRootFh};
+use nfs4_test_server::TestServer;
+
+fn look_up_args(name: &str) -> LookUpArgs {
+    LookUpArgs {
+        object_name: name.into(),
+    }
+}
+
+#[test]
+fn typed_results() {
+    let server = TestServer::new();
+    server.create_dir("/a_dir");
+    server.write_file("/a_dir/a_file", b"hello");
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let ((), (), (), fh, attrs) = client
+        .compound((
+            PutRootFh,
+            look_up_args("a_dir"),
+            look_up_args("a_file"),
+            GetFh,
+            GetAttrArgs {
+                attr_request: AttrRequest::new().size().into(),
+            },
+        ))
+        .unwrap();
+    assert_eq!(fh.object, client.look_up("/a_dir/a_file").unwrap());
+    assert_eq!(Attributes::from(attrs.object_attributes).size, Some(5));
+
+    let dir = client.look_up("/a_dir").unwrap();
+    let results = client
+        .compound(vec![
+            (
+                PutFhArgs {
+                    object: dir.clone(),
+                },
+                GetFh,
+            ),
+            (PutFhArgs { object: fh.object }, GetFh),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].1.object, dir);
+}
+
+#[test]
+fn failed_op_index() {
+    let server = TestServer::new();
+    server.create_dir("/a_dir");
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let Err(Error::Compound(error)) = client.compound((
+        PutRootFh,
+        look_up_args("a_dir"),
+        look_up_args("missing"),
+        GetFh,
+    )) else {
+        panic!("expected the compound to fail");
+    };
+    assert_eq!(error.index, 2);
+    assert_eq!(error.status, StatusError::NoEnt);
+    assert_eq!(
+        error.results,
+        vec![
+            ResOp::PutRootFh(StatusResult::Ok(())),
+            ResOp::LookUp(StatusResult::Ok(())),
+            ResOp::LookUp(StatusResult::Err(StatusError::NoEnt)),
+        ]
+    );
+}
+
+#[test]
+fn untyped_ops() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let results = client
+        .compound(vec![
+            ArgOp::PutRootFh,
+            ArgOp::LookUp(look_up_args("a_file")),
+            ArgOp::GetFh,
+        ])
+        .unwrap();
+    let [_, _, ResOp::GetFh(StatusResult::Ok(fh))] = &results[..] else {
+        panic!("unexpected results {results:?}");
+    };
+    assert_eq!(fh.object, client.look_up("/a_file").unwrap());
+}
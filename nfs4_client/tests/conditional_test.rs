@@ -0,0 +1,84 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, Attributes, Change};
+use nfs4_client::{Client, Error, Precondition};
+use nfs4_test_server::TestServer;
+
+fn change(client: &mut Client<std::net::TcpStream>, path: &str) -> Change {
+    let handle = client.look_up(path).unwrap();
+    client
+        .get_attributes(handle, AttrRequest::new().change())
+        .unwrap()
+        .change
+        .unwrap()
+}
+
+#[test]
+fn write_if_unchanged() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"aaaa");
+    let mut client = Client::new(server.connect()).unwrap();
+    let mut other = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let seen = change(&mut client, "/a_file");
+    client
+        .write_if_unchanged(handle.clone(), seen, 0, b"b".to_vec())
+        .unwrap();
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"baaa");
+
+    // The write changed the file, so the old change attribute no longer matches.
+    assert!(matches!(
+        client.write_if_unchanged(handle.clone(), seen, 1, b"c".to_vec()),
+        Err(Error::PreconditionFailed)
+    ));
+
+    let seen = change(&mut client, "/a_file");
+    other.write(handle.clone(), 3, b"z".to_vec()).unwrap();
+    assert!(matches!(
+        client.write_if_unchanged(handle, seen, 1, b"c".to_vec()),
+        Err(Error::PreconditionFailed)
+    ));
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"baaz");
+}
+
+#[test]
+fn write_if_size_differs() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let not_empty = Precondition::Differs(Attributes::default().with_size(0));
+    assert!(matches!(
+        client.write_if(handle.clone(), not_empty.clone(), 0, b"x".to_vec()),
+        Err(Error::PreconditionFailed)
+    ));
+    client.write(handle.clone(), 0, b"abc".to_vec()).unwrap();
+    client
+        .write_if(handle, not_empty, 3, b"d".to_vec())
+        .unwrap();
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"abcd");
+}
+
+#[test]
+fn remove_if_attrs_match() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"hello");
+    let mut client = Client::new(server.connect()).unwrap();
+    let root = client.look_up("/").unwrap();
+
+    let wrong_size = Precondition::Matches(Attributes::default().with_size(4));
+    assert!(matches!(
+        client.remove_if_attrs_match(root.clone(), "a_file", wrong_size),
+        Err(Error::PreconditionFailed)
+    ));
+    assert!(server.file_contents("/a_file").is_some());
+
+    let seen = change(&mut client, "/a_file");
+    let precondition = Precondition::Matches(Attributes::default().with_size(5).with_change(seen));
+    client
+        .remove_if_attrs_match(root, "a_file", precondition)
+        .unwrap();
+    assert!(server.file_contents("/a_file").is_none());
+}
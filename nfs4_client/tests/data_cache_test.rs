@@ -0,0 +1,154 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::ShareAccess;
+use nfs4_client::{Client, DataCacheConfig};
+use nfs4_test_server::TestServer;
+use std::io::{Read as _, Seek as _, SeekFrom, Write as _};
+
+const BLOCK_SIZE: u32 = 16 * 1024;
+
+fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn cached_client(server: &TestServer, config: DataCacheConfig) -> Client<std::net::TcpStream> {
+    let mut client = Client::new(server.connect()).unwrap();
+    client.enable_data_cache(config).unwrap();
+    client
+}
+
+fn config() -> DataCacheConfig {
+    DataCacheConfig {
+        block_size: BLOCK_SIZE,
+        ..Default::default()
+    }
+}
+
+fn read_all(client: &mut Client<std::net::TcpStream>, path: &str) -> Vec<u8> {
+    let handle = client.look_up(path).unwrap();
+    let mut data = vec![];
+    client.read_all(handle, &mut data).unwrap();
+    data
+}
+
+#[test]
+fn repeated_reads_come_from_cache() {
+    let server = TestServer::new();
+    let expected = contents(100_000);
+    server.write_file("/a_file", &expected);
+    let mut client = cached_client(&server, config());
+
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+    let handle = client.look_up("/a_file").unwrap();
+    let before = server.total_compounds();
+    let mut data = vec![];
+    client.read_all(handle.clone(), &mut data).unwrap();
+    assert_eq!(data, expected);
+    // Just the GETATTR checking the file hasn't changed.
+    assert_eq!(server.total_compounds() - before, 1);
+
+    let before = server.total_compounds();
+    let res = client.read(handle, 20_000, 30_000).unwrap();
+    assert_eq!(res.data, &expected[20_000..50_000]);
+    assert!(!res.eof);
+    assert_eq!(server.total_compounds(), before);
+}
+
+#[test]
+fn close_to_open() {
+    let server = TestServer::new();
+    server.write_file("/a_file", &contents(50_000));
+    let mut client = cached_client(&server, config());
+    let mut other = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let mut file = client.open_file(handle.clone(), ShareAccess::READ).unwrap();
+    let mut data = vec![];
+    file.read_to_end(&mut data).unwrap();
+    assert_eq!(data, contents(50_000));
+
+    other.write(handle.clone(), 0, b"changed".to_vec()).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut start = [0; 7];
+    file.read_exact(&mut start).unwrap();
+    assert_eq!(&start, &contents(7)[..]);
+    file.close().unwrap();
+
+    let mut file = client.open_file(handle, ShareAccess::READ).unwrap();
+    file.read_exact(&mut start).unwrap();
+    assert_eq!(&start, b"changed");
+}
+
+#[test]
+fn own_writes_update_cache() {
+    let server = TestServer::new();
+    server.write_file("/a_file", &contents(40_000));
+    let mut client = cached_client(&server, config());
+    let handle = client.look_up("/a_file").unwrap();
+    let mut expected = contents(40_000);
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+
+    let mut file = client.open_file(handle.clone(), ShareAccess::BOTH).unwrap();
+    file.seek(SeekFrom::Start(30_000)).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.close().unwrap();
+    expected[30_000..30_005].copy_from_slice(b"hello");
+
+    client
+        .write(handle.clone(), 40_000, b"more".to_vec())
+        .unwrap();
+    expected.extend_from_slice(b"more");
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+}
+
+#[test]
+fn bounded_memory() {
+    let server = TestServer::new();
+    let expected = contents(200_000);
+    server.write_file("/a_file", &expected);
+    let config = DataCacheConfig {
+        max_memory: 2 * BLOCK_SIZE as usize,
+        ..config()
+    };
+    let mut client = cached_client(&server, config);
+
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+    let before = server.total_compounds();
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+    assert!(server.total_compounds() - before > 10);
+}
+
+#[test]
+fn persists_on_disk() {
+    let directory =
+        std::env::temp_dir().join(format!("nfs4_data_cache_test_{}", std::process::id()));
+    let server = TestServer::new();
+    let expected = contents(100_000);
+    server.write_file("/a_file", &expected);
+    let config = DataCacheConfig {
+        directory: Some(directory.clone()),
+        ..config()
+    };
+
+    let mut client = cached_client(&server, config.clone());
+    assert_eq!(read_all(&mut client, "/a_file"), expected);
+    drop(client);
+
+    let mut client = cached_client(&server, config.clone());
+    let handle = client.look_up("/a_file").unwrap();
+    let before = server.total_compounds();
+    let mut data = vec![];
+    client.read_all(handle.clone(), &mut data).unwrap();
+    assert_eq!(data, expected);
+    assert_eq!(server.total_compounds() - before, 1);
+
+    // A change from elsewhere throws away what is on disk.
+    let mut other = Client::new(server.connect()).unwrap();
+    other.write(handle.clone(), 0, b"changed".to_vec()).unwrap();
+    let mut client = cached_client(&server, config);
+    let data = read_all(&mut client, "/a_file");
+    assert_eq!(&data[..7], b"changed");
+    assert_eq!(data[7..], expected[7..]);
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
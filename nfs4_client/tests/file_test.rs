@@ -0,0 +1,138 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::ShareAccess;
+use nfs4_client::Client;
+use nfs4_test_server::{IoCounts, TestServer, MAX_READ, MAX_WRITE};
+use std::io::{BufRead as _, ErrorKind, Read as _, Seek as _, SeekFrom, Write as _};
+
+fn connect(server: &TestServer) -> Client<std::net::TcpStream> {
+    Client::new(server.connect()).unwrap()
+}
+
+#[test]
+fn small_writes_are_buffered() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = connect(&server);
+    let handle = client.look_up("/a_file").unwrap();
+
+    let mut file = client.open_file(handle, ShareAccess::WRITE).unwrap();
+    for i in 0..1000 {
+        writeln!(file, "line {i}").unwrap();
+    }
+    assert_eq!(server.io_counts().writes, 0);
+    file.close().unwrap();
+
+    let expected: String = (0..1000).map(|i| format!("line {i}\n")).collect();
+    assert_eq!(
+        server.file_contents("/a_file").unwrap(),
+        expected.as_bytes()
+    );
+    assert_eq!(
+        server.io_counts(),
+        IoCounts {
+            reads: 0,
+            writes: 1,
+            commits: 1,
+        }
+    );
+}
+
+#[test]
+fn large_writes_are_split() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = connect(&server);
+    let handle = client.look_up("/a_file").unwrap();
+
+    let data: Vec<u8> = (0..MAX_WRITE as usize * 2 + 10)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut file = client.open_file(handle, ShareAccess::WRITE).unwrap();
+    file.write_all(&data).unwrap();
+    assert_eq!(server.io_counts().writes, 2);
+    file.sync_all().unwrap();
+    assert_eq!(server.io_counts().commits, 1);
+    drop(file);
+
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+    assert_eq!(server.io_counts().writes, 3);
+}
+
+#[test]
+fn buffered_reads() {
+    let server = TestServer::new();
+    let contents: String = (0..20000).map(|i| format!("{i}\n")).collect();
+    assert!(contents.len() as u64 > MAX_READ);
+    server.write_file("/a_file", contents.as_bytes());
+    let mut client = connect(&server);
+    let handle = client.look_up("/a_file").unwrap();
+
+    let file = client.open_file(handle, ShareAccess::READ).unwrap();
+    let lines: Vec<String> = file.lines().map(|l| l.unwrap()).collect();
+    assert_eq!(lines.len(), 20000);
+    assert_eq!(lines[12345], "12345");
+
+    let expected_reads = contents.len().div_ceil(MAX_READ as usize) + 1;
+    assert_eq!(server.io_counts().reads, expected_reads);
+}
+
+#[test]
+fn seek_and_overwrite() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"0123456789");
+    let mut client = connect(&server);
+    let handle = client.look_up("/a_file").unwrap();
+
+    let mut file = client.open_file(handle, ShareAccess::BOTH).unwrap();
+    let mut buf = [0; 3];
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"012");
+
+    assert_eq!(file.seek(SeekFrom::End(-2)).unwrap(), 8);
+    file.read_exact(&mut buf[..2]).unwrap();
+    assert_eq!(&buf[..2], b"89");
+
+    file.seek(SeekFrom::Start(4)).unwrap();
+    file.write_all(b"ab").unwrap();
+    assert_eq!(file.stream_position().unwrap(), 6);
+    file.seek(SeekFrom::Current(-3)).unwrap();
+    file.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"3ab");
+
+    file.seek(SeekFrom::End(2)).unwrap();
+    file.write_all(b"!").unwrap();
+    assert_eq!(
+        file.seek(SeekFrom::Current(-100)).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    let mut rest = String::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "0123ab6789\0\0!");
+    file.close().unwrap();
+}
+
+#[test]
+fn rewrite_after_crash() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = connect(&server);
+    let handle = client.look_up("/a_file").unwrap();
+
+    let data: Vec<u8> = (0..MAX_WRITE as usize + 100)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut file = client.open_file(handle, ShareAccess::WRITE).unwrap();
+    file.write_all(&data).unwrap();
+    assert_eq!(server.io_counts().writes, 1);
+
+    server.crash();
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"");
+    file.close().unwrap();
+
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+    assert_eq!(server.io_counts().writes, 4);
+    assert_eq!(server.io_counts().commits, 2);
+}
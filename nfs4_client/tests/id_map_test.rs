@@ -0,0 +1,93 @@
+// Copyright Remi Bernotavicius
+
+use nfs4_client::{
+    DomainIdMapper, IdMapper as _, NumericIdMapper, PasswdIdMapper, StaticIdMapper, NOBODY_ID,
+};
+
+const PASSWD: &str = "\
+root:x:0:0:root:/root:/bin/bash
+# a comment
+alice:x:1000:1000:Alice:/home/alice:/bin/bash
+bob:x:1001:100::/home/bob:/bin/sh
+";
+
+const GROUP: &str = "\
+root:x:0:
+users:x:100:bob
+alice:x:1000:
+";
+
+#[test]
+fn numeric_mapping() {
+    assert_eq!(NumericIdMapper.owner_to_uid("1000"), Some(1000));
+    assert_eq!(NumericIdMapper.owner_to_uid("alice@example.com"), None);
+    assert_eq!(NumericIdMapper.decode_uid("alice@example.com"), NOBODY_ID);
+    assert_eq!(NumericIdMapper.encode_gid(100), "100");
+    assert_eq!(NumericIdMapper.display_owner("1000"), "1000");
+    assert_eq!(
+        NumericIdMapper.display_owner("alice@example.com"),
+        "alice@example.com"
+    );
+}
+
+#[test]
+fn static_mapping() {
+    let mapper = StaticIdMapper::new().user("alice", 1000).group("staff", 50);
+    assert_eq!(mapper.owner_to_uid("alice"), Some(1000));
+    assert_eq!(mapper.group_to_gid("staff"), Some(50));
+    assert_eq!(mapper.uid_to_owner(1000).as_deref(), Some("alice"));
+    assert_eq!(mapper.encode_uid(7), "7");
+}
+
+#[test]
+fn passwd_mapping() {
+    let mapper = PasswdIdMapper::parse(PASSWD, GROUP);
+    assert_eq!(mapper.owner_to_uid("bob"), Some(1001));
+    assert_eq!(mapper.owner_to_uid("1234"), Some(1234));
+    assert_eq!(mapper.owner_to_uid("carol"), None);
+    assert_eq!(mapper.group_to_gid("users"), Some(100));
+    assert_eq!(mapper.uid_to_owner(0).as_deref(), Some("root"));
+    assert_eq!(mapper.gid_to_group(1000).as_deref(), Some("alice"));
+}
+
+#[test]
+fn domain_mapping() {
+    let mapper = DomainIdMapper::new("example.com", PasswdIdMapper::parse(PASSWD, GROUP))
+        .with_local_domain("corp.example.com");
+
+    assert_eq!(mapper.owner_to_uid("alice@example.com"), Some(1000));
+    assert_eq!(mapper.owner_to_uid("alice@EXAMPLE.COM"), Some(1000));
+    assert_eq!(mapper.owner_to_uid("bob@corp.example.com"), Some(1001));
+    assert_eq!(mapper.owner_to_uid("alice@elsewhere.org"), None);
+    assert_eq!(mapper.decode_uid("alice@elsewhere.org"), NOBODY_ID);
+    assert_eq!(mapper.display_owner("alice@example.com"), "1000");
+    assert_eq!(
+        mapper.display_owner("alice@elsewhere.org"),
+        "alice@elsewhere.org"
+    );
+    assert_eq!(mapper.owner_to_uid("0"), Some(0));
+
+    assert_eq!(mapper.encode_uid(1001), "bob@example.com");
+    assert_eq!(mapper.encode_gid(100), "users@example.com");
+    assert_eq!(mapper.encode_uid(4242), "4242");
+}
+
+#[test]
+fn idmapd_conf() {
+    let conf = "\
+[General]
+Verbosity = 0
+# Domain = wrong.example.com
+Domain = example.com
+Local-Realms = EXAMPLE.COM, other.example.com
+
+[Mapping]
+Domain = ignored.example.com
+";
+    let mapper =
+        DomainIdMapper::from_idmapd_conf(conf, StaticIdMapper::new().user("alice", 5)).unwrap();
+    assert_eq!(mapper.owner_to_uid("alice@other.example.com"), Some(5));
+    assert_eq!(mapper.encode_uid(5), "alice@example.com");
+
+    assert!(DomainIdMapper::from_idmapd_conf("[Mapping]\n", NumericIdMapper).is_none());
+}
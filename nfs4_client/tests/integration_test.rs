@@ -1,11 +1,14 @@
 // Copyright Remi Bernotavicius
 
-use nfs4::{FileAttribute, FileAttributeId, FileHandle};
+use nfs4::{
+    AceMask, Acl, AttrRequest, Attributes, FileAttribute, FileAttributeId, FileHandle, Mode,
+};
 use nfs4_client::Client;
 use nfs4_client::NFS_PORT;
 use std::collections::BTreeSet;
 use std::net::TcpStream;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 
 macro_rules! test {
     ($test_name:ident) => {
@@ -33,6 +36,7 @@ impl<'machine> Fixture<'machine> {
 
     fn run(&mut self) {
         let tests = [
+            test!(acl_test),
             test!(create_directory_test),
             test!(create_file_test),
             test!(read_dir_test),
@@ -40,6 +44,8 @@ impl<'machine> Fixture<'machine> {
             test!(remove_test),
             test!(rename_test),
             test!(set_attr_test),
+            test!(set_owner_test),
+            test!(get_attributes_test),
         ];
 
         for (test, test_name) in tests {
@@ -123,6 +129,43 @@ impl<'machine> Fixture<'machine> {
         );
     }
 
+    fn set_owner_test(&mut self) {
+        let handle = self.create_file("/files/a_file");
+
+        self.client
+            .set_owner(handle.clone(), Some(1000), Some(100))
+            .unwrap();
+
+        let reply = self.client.get_attr(handle).unwrap();
+        assert_eq!(self.client.owner_uid(&reply.object_attributes), Some(1000));
+        assert_eq!(self.client.owner_gid(&reply.object_attributes), Some(100));
+    }
+
+    fn get_attributes_test(&mut self) {
+        let handle = self.create_file("/files/a_file");
+        self.client
+            .set_attributes(
+                handle.clone(),
+                Attributes::default()
+                    .with_size(10)
+                    .with_mode(Mode(0o600))
+                    .with_modify_time(UNIX_EPOCH + Duration::from_secs(1000)),
+            )
+            .unwrap();
+
+        let attrs = self
+            .client
+            .get_attributes(handle, AttrRequest::metadata())
+            .unwrap();
+        assert!(attrs.is_file());
+        assert_eq!(attrs.size, Some(10));
+        assert_eq!(attrs.mode, Some(Mode(0o600)));
+        assert_eq!(
+            attrs.modified(),
+            Some(UNIX_EPOCH + Duration::from_secs(1000))
+        );
+    }
+
     fn read_dir_test(&mut self) {
         let parent = self.client.look_up("/files").unwrap();
 
@@ -161,6 +204,35 @@ impl<'machine> Fixture<'machine> {
         self.client.look_up("/files/b_file").unwrap();
     }
 
+    fn acl_test(&mut self) {
+        let handle = self.create_file("/files/a_file");
+
+        self.client
+            .set_acl(handle.clone(), Acl::from_mode(Mode(0o640), false))
+            .unwrap();
+        assert_eq!(
+            self.client.get_acl(handle.clone()).unwrap().to_mode(),
+            Mode(0o640)
+        );
+
+        let acl = self
+            .client
+            .modify_acl(handle.clone(), |b| {
+                b.allow(nfs4::Identity::everyone(), AceMask::READ_DATA)
+            })
+            .unwrap();
+        assert_eq!(acl.to_mode(), Mode(0o644));
+
+        let reply = self.client.get_attr(handle).unwrap();
+        assert_eq!(
+            *reply
+                .object_attributes
+                .get_as::<Mode>(FileAttributeId::Mode)
+                .unwrap(),
+            Mode(0o644)
+        );
+    }
+
     fn create_directory_test(&mut self) {
         let parent = self.client.look_up("/files").unwrap();
         let new_dir = self
@@ -0,0 +1,93 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, DeviceData, FileAttributes, FileType, StatusError};
+use nfs4_client::{Client, Error, SpecialFile};
+use nfs4_test_server::TestServer;
+
+#[test]
+fn symlinks() {
+    let server = TestServer::new();
+    server.create_dir("/a");
+    server.write_file("/a/target", b"hello");
+    let mut client = Client::new(server.connect()).unwrap();
+    let a = client.look_up("/a").unwrap();
+
+    let link = client
+        .create_symlink(a, "link", "target", FileAttributes::default())
+        .unwrap();
+    assert_eq!(client.read_link(link).unwrap(), "target");
+    assert_eq!(
+        client.look_up("/a/link").unwrap(),
+        client.look_up("/a/target").unwrap()
+    );
+
+    let target = client.look_up("/a/target").unwrap();
+    assert!(matches!(
+        client.read_link(target),
+        Err(Error::Protocol(StatusError::Inval))
+    ));
+}
+
+#[test]
+fn hard_links() {
+    let server = TestServer::new();
+    server.create_dir("/a");
+    server.write_file("/file", b"hello");
+    let mut client = Client::new(server.connect()).unwrap();
+    let file = client.look_up("/file").unwrap();
+    let a = client.look_up("/a").unwrap();
+
+    client.hard_link(file.clone(), a.clone(), "other").unwrap();
+    assert_eq!(client.look_up("/a/other").unwrap(), file);
+    let attrs = client
+        .get_attributes(file.clone(), AttrRequest::new().num_links())
+        .unwrap();
+    assert_eq!(attrs.num_links, Some(2));
+
+    assert!(matches!(
+        client.hard_link(file.clone(), a.clone(), "other"),
+        Err(Error::Protocol(StatusError::Exist))
+    ));
+
+    let root = client.look_up("/").unwrap();
+    client.remove(root, "file").unwrap();
+    assert_eq!(server.file_contents("/a/other").unwrap(), b"hello");
+    let attrs = client
+        .get_attributes(file, AttrRequest::new().num_links())
+        .unwrap();
+    assert_eq!(attrs.num_links, Some(1));
+}
+
+#[test]
+fn special_files() {
+    let server = TestServer::new();
+    let mut client = Client::new(server.connect()).unwrap();
+    let root = client.look_up("/").unwrap();
+
+    let device = DeviceData { major: 8, minor: 1 };
+    let cases = [
+        ("block", SpecialFile::Block(device.clone()), FileType::Block),
+        (
+            "char",
+            SpecialFile::Character(device.clone()),
+            FileType::Character,
+        ),
+        ("fifo", SpecialFile::Fifo, FileType::Fifo),
+        ("socket", SpecialFile::Socket, FileType::Socket),
+    ];
+    for (name, special_file, file_type) in cases {
+        let handle = client
+            .mknod(root.clone(), name, special_file, FileAttributes::default())
+            .unwrap();
+        let attrs = client
+            .get_attributes(handle, AttrRequest::new().type_().raw_dev())
+            .unwrap();
+        assert_eq!(attrs.type_, Some(file_type), "{name}");
+    }
+
+    let handle = client.look_up("/block").unwrap();
+    let attrs = client
+        .get_attributes(handle, AttrRequest::new().raw_dev())
+        .unwrap();
+    assert_eq!(attrs.raw_dev, Some(device));
+}
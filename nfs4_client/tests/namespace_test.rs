@@ -0,0 +1,165 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, FsId};
+use nfs4_client::{
+    Client, Error, LookUpOptions, MountCrossing, Namespace, TcpConnector, WalkOptions,
+};
+use nfs4_test_server::TestServer;
+
+fn fs_id(major: u64) -> FsId {
+    FsId { major, minor: 0 }
+}
+
+fn crossing(path: &str, major: u64, server: Option<&TestServer>) -> MountCrossing {
+    MountCrossing {
+        path: path.into(),
+        fs_id: fs_id(major),
+        server: server.map(|s| s.addr().to_string()),
+    }
+}
+
+/// A server whose `/exports/remote` is a referral to `/shared` on a second server.
+fn federation() -> (TestServer, TestServer) {
+    let other = TestServer::new();
+    other.create_dir("/shared");
+    other.create_dir("/shared/dir");
+    other.write_file("/shared/dir/file", b"on the other server");
+    let origin = TestServer::new();
+    origin.create_dir("/exports");
+    origin.write_file("/exports/local", b"here");
+    origin.create_referral("/exports/remote", other.addr(), "/shared");
+    (origin, other)
+}
+
+#[test]
+fn mount_points() {
+    let server = TestServer::new();
+    server.create_dir("/data");
+    server.create_mount("/data/disk");
+    server.create_dir("/data/disk/dir");
+    server.create_symlink("/link", "data/disk");
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let (_, crossings) = client
+        .look_up_mounts("/link/dir", LookUpOptions::default())
+        .unwrap();
+    assert_eq!(crossings, [crossing("/data/disk", 2, None)]);
+    let (_, crossings) = client
+        .look_up_mounts("/data/disk/dir/..", LookUpOptions::default())
+        .unwrap();
+    assert_eq!(crossings, [crossing("/data/disk", 2, None)]);
+    let (_, crossings) = client
+        .look_up_mounts("/data", LookUpOptions::default())
+        .unwrap();
+    assert_eq!(crossings, []);
+
+    let root = client.look_up("/data").unwrap();
+    let entries: Vec<_> = client
+        .walk(root.clone(), WalkOptions::default())
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let mount_points: Vec<_> = entries
+        .iter()
+        .map(|e| (e.path.to_str().unwrap(), e.mount_point))
+        .collect();
+    assert_eq!(mount_points, [("disk", true), ("disk/dir", false)]);
+
+    let options = WalkOptions {
+        same_file_system: true,
+        ..Default::default()
+    };
+    let entries = client.walk(root, options).unwrap().count();
+    assert_eq!(entries, 1);
+}
+
+#[test]
+fn referral_without_namespace() {
+    let (origin, _other) = federation();
+    let mut client = Client::new(origin.connect()).unwrap();
+
+    let Err(Error::Path(error)) = client.look_up("/exports/remote/dir/file") else {
+        panic!("expected a path error");
+    };
+    assert_eq!(error.path.to_str(), Some("/exports/remote"));
+    let Error::Referral(referral) = error.error else {
+        panic!("expected a referral, got {:?}", error.error);
+    };
+    assert_eq!(referral.rest.to_str(), Some("dir/file"));
+    assert_eq!(referral.fs_id, fs_id(2));
+    assert_eq!(referral.locations.locations.len(), 1);
+
+    // Walking the directory reports the referral and carries on.
+    let exports = client.look_up("/exports").unwrap();
+    let results: Vec<_> = client
+        .walk(exports, WalkOptions::default())
+        .unwrap()
+        .collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].as_ref().unwrap().path.to_str(), Some("local"));
+    let Err(Error::Path(error)) = &results[1] else {
+        panic!("expected a path error");
+    };
+    assert_eq!(error.path.to_str(), Some("remote"));
+    assert!(matches!(error.error, Error::Referral(_)));
+}
+
+#[test]
+fn follows_referrals() {
+    let (origin, other) = federation();
+    other.create_mount("/shared/mnt");
+    other.write_file("/shared/mnt/deep", b"deeper");
+    let client = Client::new(origin.connect()).unwrap();
+    let mut namespace = Namespace::new(client, TcpConnector);
+
+    let located = namespace.look_up("/exports/remote/dir/file").unwrap();
+    assert_eq!(located.server, 1);
+    assert_eq!(
+        located.crossings,
+        [crossing("/exports/remote", 2, Some(&other))]
+    );
+    let attrs = namespace
+        .client(located.server)
+        .get_attributes(located.handle, AttrRequest::new().size())
+        .unwrap();
+    assert_eq!(attrs.size, Some(19));
+
+    // Mount points on the other server are given in terms of the namespace.
+    let located = namespace.look_up("/exports/remote/mnt/deep").unwrap();
+    assert_eq!(located.server, 1);
+    assert_eq!(
+        located.crossings,
+        [
+            crossing("/exports/remote", 2, Some(&other)),
+            crossing("/exports/remote/mnt", 2, None),
+        ]
+    );
+
+    // The referral itself is the root of the other server's filesystem.
+    let located = namespace.look_up("/exports/remote").unwrap();
+    assert_eq!(located.server, 1);
+    let located = namespace.look_up("/exports/local").unwrap();
+    assert_eq!(located.server, 0);
+    assert!(located.crossings.is_empty());
+
+    // The session with the other server was made once and reused.
+    assert_eq!(other.connection_compounds().len(), 1);
+
+    let Err(Error::Path(error)) = namespace.look_up("/exports/remote/missing") else {
+        panic!("expected a path error");
+    };
+    assert_eq!(error.path.to_str(), Some("/exports/remote/missing"));
+}
+
+#[test]
+fn referral_loop() {
+    let server = TestServer::new();
+    server.create_referral("/loop", server.addr(), "/loop");
+    let client = Client::new(server.connect()).unwrap();
+    let mut namespace = Namespace::new(client, TcpConnector);
+
+    let Err(Error::Path(error)) = namespace.look_up("/loop/file") else {
+        panic!("expected a path error");
+    };
+    assert!(matches!(error.error, Error::TooManyReferrals));
+}
@@ -0,0 +1,171 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, FileType, StatusError};
+use nfs4_client::{Client, Error, LookUpOptions, PathError};
+use nfs4_test_server::TestServer;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::Path;
+
+fn setup() -> (TestServer, Client<std::net::TcpStream>) {
+    let server = TestServer::new();
+    server.create_dir("/a");
+    server.create_dir("/a/b");
+    server.write_file("/a/c", b"c");
+    let client = Client::new(server.connect()).unwrap();
+    (server, client)
+}
+
+fn path_error(error: Error) -> PathError {
+    match error {
+        Error::Path(e) => *e,
+        e => panic!("unexpected error {e:?}"),
+    }
+}
+
+#[test]
+fn parent_directories() {
+    let (_server, mut client) = setup();
+    let c = client.look_up("/a/c").unwrap();
+    assert_eq!(client.look_up("/a/b/../c").unwrap(), c);
+    assert_eq!(client.look_up("/../a/./c").unwrap(), c);
+    assert_eq!(client.look_up("/a/../../a/c").unwrap(), c);
+    assert_eq!(
+        client.look_up("/a/b/..").unwrap(),
+        client.look_up("/a").unwrap()
+    );
+}
+
+#[test]
+fn relative_to_directory() {
+    let (_server, mut client) = setup();
+    let a = client.look_up("/a").unwrap();
+    let b = client.look_up("/a/b").unwrap();
+    let c = client.look_up("/a/c").unwrap();
+    let options = LookUpOptions::default();
+    assert_eq!(client.look_up_from(b.clone(), "../c", options).unwrap(), c);
+    assert_eq!(client.look_up_from(b.clone(), "..", options).unwrap(), a);
+    assert_eq!(client.look_up_from(b.clone(), "", options).unwrap(), b);
+    assert_eq!(client.look_up_from(b, "/a/c", options).unwrap(), c);
+}
+
+#[test]
+fn follows_symlinks() {
+    let (server, mut client) = setup();
+    server.create_symlink("/relative", "a/c");
+    server.create_symlink("/absolute", "/a/c");
+    server.create_symlink("/dir", "a");
+    server.create_symlink("/a/b/up", "../c");
+    server.create_symlink("/chain", "dir/b/up");
+
+    let c = client.look_up("/a/c").unwrap();
+    for path in ["/relative", "/absolute", "/dir/c", "/a/b/up", "/chain"] {
+        assert_eq!(client.look_up(path).unwrap(), c, "{path}");
+    }
+    let b = client.look_up("/a/b").unwrap();
+    let options = LookUpOptions::default();
+    assert_eq!(client.look_up_from(b, "up", options).unwrap(), c);
+}
+
+#[test]
+fn symlinks_climbing_above_root() {
+    let (server, mut client) = setup();
+    server.create_symlink("/a/b/up", "../../../a/c");
+    server.create_symlink("/a/top", "../../a");
+
+    let a = client.look_up("/a").unwrap();
+    let b = client.look_up("/a/b").unwrap();
+    let c = client.look_up("/a/c").unwrap();
+    assert_eq!(client.look_up("/a/b/up").unwrap(), c);
+    assert_eq!(client.look_up("/a/top/c").unwrap(), c);
+    let options = LookUpOptions::default();
+    assert_eq!(client.look_up_from(b.clone(), "up", options).unwrap(), c);
+    assert_eq!(client.look_up_from(b, "../../../a/c", options).unwrap(), c);
+    assert_eq!(client.look_up_from(a.clone(), "top", options).unwrap(), a);
+}
+
+#[test]
+fn no_follow_final_symlink() {
+    let (server, mut client) = setup();
+    server.create_symlink("/link", "a/c");
+    server.create_symlink("/dir", "a");
+
+    let link = client
+        .look_up_with("/link", LookUpOptions::no_follow())
+        .unwrap();
+    let attrs = client
+        .get_attributes(link, AttrRequest::new().type_())
+        .unwrap();
+    assert_eq!(attrs.type_, Some(FileType::Link));
+
+    // Symlinks before the final component are still followed.
+    assert_eq!(
+        client
+            .look_up_with("/dir/c", LookUpOptions::no_follow())
+            .unwrap(),
+        client.look_up("/a/c").unwrap()
+    );
+}
+
+#[test]
+fn symlink_loop() {
+    let (server, mut client) = setup();
+    server.create_symlink("/loop1", "loop2");
+    server.create_symlink("/loop2", "/loop1");
+
+    let error = path_error(client.look_up("/loop1/c").unwrap_err());
+    assert!(matches!(error.error, Error::TooManySymlinks), "{error:?}");
+
+    let options = LookUpOptions {
+        max_symlinks: 0,
+        ..Default::default()
+    };
+    server.create_symlink("/link", "a");
+    let error = path_error(client.look_up_with("/link/c", options).unwrap_err());
+    assert_eq!(error.path, Path::new("/link"));
+    assert!(matches!(error.error, Error::TooManySymlinks));
+}
+
+#[test]
+fn errors_name_the_component() {
+    let (server, mut client) = setup();
+    server.create_symlink("/link", "a/missing");
+
+    let error = path_error(client.look_up("/a/missing/c").unwrap_err());
+    assert_eq!(error.path, Path::new("/a/missing"));
+    assert!(matches!(error.error, Error::Protocol(StatusError::NoEnt)));
+
+    let error = path_error(client.look_up("/a/c/d").unwrap_err());
+    assert_eq!(error.path, Path::new("/a/c/d"));
+    assert!(matches!(error.error, Error::Protocol(StatusError::NotDir)));
+
+    let error = path_error(client.look_up("/link/d").unwrap_err());
+    assert_eq!(error.path, Path::new("/a/missing"));
+
+    let name = OsStr::from_bytes(b"bad\xff");
+    let error = path_error(client.look_up(Path::new("/a").join(name)).unwrap_err());
+    assert_eq!(error.path, Path::new("/a").join(name));
+    assert!(
+        matches!(error.error, Error::Io(ref e) if e.kind() == std::io::ErrorKind::InvalidFilename)
+    );
+}
+
+#[test]
+fn long_paths() {
+    let server = TestServer::new();
+    let mut path = String::new();
+    for i in 0..20 {
+        path.push_str(&format!("/d{i}"));
+        server.create_dir(&path);
+    }
+    server.write_file(&format!("{path}/f"), b"deep");
+
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up(format!("{path}/f")).unwrap();
+    let mut data = vec![];
+    client.read_all(handle, &mut data).unwrap();
+    assert_eq!(data, b"deep");
+
+    let error = path_error(client.look_up(format!("{path}/missing")).unwrap_err());
+    assert_eq!(error.path, Path::new(&format!("{path}/missing")));
+}
@@ -0,0 +1,107 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::StatusError;
+use nfs4_client::{Client, Error, PipelineConfig};
+use nfs4_test_server::{TestServer, MAX_READ, MAX_WRITE};
+use std::time::{Duration, Instant};
+
+const LATENCY: Duration = Duration::from_millis(20);
+const CHUNKS: usize = 16;
+
+fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 249) as u8).collect()
+}
+
+fn timed_read(server: &TestServer, config: PipelineConfig) -> (Vec<u8>, Duration) {
+    let mut client = Client::new(server.connect()).unwrap();
+    client.set_pipeline_config(config);
+    let handle = client.look_up("/a_file").unwrap();
+    let mut data = vec![];
+    let start = Instant::now();
+    client.read_all(handle, &mut data).unwrap();
+    (data, start.elapsed())
+}
+
+#[test]
+fn read_all_overlaps_requests() {
+    let server = TestServer::new();
+    let data = test_data(MAX_READ as usize * CHUNKS + 123);
+    server.write_file("/a_file", &data);
+    server.set_latency(LATENCY);
+
+    let (serial_data, serial) = timed_read(&server, PipelineConfig::fixed(1));
+    assert_eq!(serial_data, data);
+    assert!(serial >= LATENCY * CHUNKS as u32);
+
+    let (pipelined_data, pipelined) = timed_read(&server, PipelineConfig::default());
+    assert_eq!(pipelined_data, data);
+    assert!(pipelined < serial / 2, "{pipelined:?} vs {serial:?}");
+}
+
+#[test]
+fn write_all_overlaps_requests() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    server.set_latency(LATENCY);
+    let data = test_data(MAX_WRITE as usize * CHUNKS + 77);
+
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    client.set_pipeline_config(PipelineConfig::fixed(1));
+    let start = Instant::now();
+    client.write_all(handle.clone(), &data[..]).unwrap();
+    let serial = start.elapsed();
+    assert!(serial >= LATENCY * CHUNKS as u32);
+
+    client.set_pipeline_config(PipelineConfig::fixed(8));
+    let start = Instant::now();
+    client.write_all(handle, &data[..]).unwrap();
+    let pipelined = start.elapsed();
+    assert!(pipelined < serial / 2, "{pipelined:?} vs {serial:?}");
+
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+    assert_eq!(server.io_counts().writes, (CHUNKS + 1) * 2);
+}
+
+#[test]
+fn read_all_exact_multiple_of_max_read() {
+    let server = TestServer::new();
+    let data = test_data(MAX_READ as usize * 4);
+    server.write_file("/a_file", &data);
+    server.write_file("/empty", b"");
+
+    let mut client = Client::new(server.connect()).unwrap();
+    client.set_pipeline_config(PipelineConfig::fixed(3));
+    let mut read = vec![];
+    let handle = client.look_up("/a_file").unwrap();
+    client.read_all(handle, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    let mut read = vec![];
+    let handle = client.look_up("/empty").unwrap();
+    client.read_all(handle, &mut read).unwrap();
+    assert!(read.is_empty());
+}
+
+#[test]
+fn connection_usable_after_failure() {
+    let server = TestServer::new();
+    let data = test_data(MAX_READ as usize * 8);
+    server.write_file("/a_file", &data);
+
+    let mut client = Client::new(server.connect()).unwrap();
+    client.set_pipeline_config(PipelineConfig::fixed(8));
+    let handle = client.look_up("/a_file").unwrap();
+
+    server.set_io_error(Some(StatusError::Io));
+    assert!(matches!(
+        client.read_all(handle.clone(), &mut vec![]),
+        Err(Error::Protocol(StatusError::Io))
+    ));
+
+    server.set_io_error(None);
+    let mut read = vec![];
+    client.read_all(handle, &mut read).unwrap();
+    assert_eq!(read, data);
+}
@@ -0,0 +1,286 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{
+    AttrRequest, DeviceError, FlexFilesFlags, FlexFilesLayoutReturn, LayoutIoMode, LayoutReturn,
+    LayoutType, OperationId, StatusError, StatusResult,
+};
+use nfs4_client::{Client, TcpConnector};
+use nfs4_test_server::{
+    flex_files_device_id, FilesLayoutConfig, FlexFilesConfig, ServerConfig, TestServer,
+};
+
+const STRIPE_UNIT: u32 = 4096;
+
+struct PnfsServers {
+    mds: TestServer,
+    data_servers: [TestServer; 2],
+}
+
+fn start_servers(dense: bool, commit_through_mds: bool) -> PnfsServers {
+    let data_servers = [TestServer::data_server(), TestServer::data_server()];
+    let mds = TestServer::start(ServerConfig {
+        files_layout: Some(FilesLayoutConfig {
+            data_servers: data_servers.iter().map(|s| s.addr()).collect(),
+            stripe_unit: STRIPE_UNIT,
+            dense,
+            commit_through_mds,
+        }),
+        ..Default::default()
+    });
+    mds.write_file("/a_file", b"");
+    PnfsServers { mds, data_servers }
+}
+
+fn test_data() -> Vec<u8> {
+    (0..STRIPE_UNIT as usize * 3 + 100)
+        .map(|i| (i / STRIPE_UNIT as usize) as u8 + 1)
+        .collect()
+}
+
+fn write_and_read_back(servers: &PnfsServers) -> Vec<u8> {
+    let mut client = Client::new(servers.mds.connect()).unwrap();
+    assert!(client.is_pnfs_mds());
+
+    let handle = client.look_up("/a_file").unwrap();
+    let data = test_data();
+
+    let mut file = client
+        .pnfs_open(handle.clone(), LayoutIoMode::ReadWrite, TcpConnector)
+        .unwrap();
+    client.pnfs_write_all(&mut file, &data[..]).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    let attrs = client
+        .get_attributes(handle.clone(), AttrRequest::new().size())
+        .unwrap();
+    assert_eq!(attrs.size, Some(data.len() as u64));
+
+    let mut file = client
+        .pnfs_open(handle, LayoutIoMode::Read, TcpConnector)
+        .unwrap();
+    let mut read_data = vec![];
+    client.pnfs_read_all(&mut file, &mut read_data).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    read_data
+}
+
+#[test]
+fn pnfs_dense_striping() {
+    let servers = start_servers(true, false);
+    assert_eq!(write_and_read_back(&servers), test_data());
+
+    let unit = STRIPE_UNIT as usize;
+    let handle = Client::new(servers.mds.connect())
+        .unwrap()
+        .look_up("/a_file")
+        .unwrap();
+    let first = servers.data_servers[0].handle_contents(&handle).unwrap();
+    let second = servers.data_servers[1].handle_contents(&handle).unwrap();
+
+    let data = test_data();
+    assert_eq!(first, [&data[..unit], &data[unit * 2..unit * 3]].concat());
+    assert_eq!(second, [&data[unit..unit * 2], &data[unit * 3..]].concat());
+}
+
+#[test]
+fn pnfs_sparse_striping() {
+    let servers = start_servers(false, true);
+    assert_eq!(write_and_read_back(&servers), test_data());
+
+    let unit = STRIPE_UNIT as usize;
+    let handle = Client::new(servers.mds.connect())
+        .unwrap()
+        .look_up("/a_file")
+        .unwrap();
+    let first = servers.data_servers[0].handle_contents(&handle).unwrap();
+    let second = servers.data_servers[1].handle_contents(&handle).unwrap();
+
+    // With a sparse layout each data server holds its stripes at their file offsets.
+    let data = test_data();
+    assert_eq!(first.len(), unit * 3);
+    assert_eq!(&first[..unit], &data[..unit]);
+    assert!(first[unit..unit * 2].iter().all(|b| *b == 0));
+    assert_eq!(&first[unit * 2..], &data[unit * 2..unit * 3]);
+    assert_eq!(&second[unit..unit * 2], &data[unit..unit * 2]);
+    assert_eq!(&second[unit * 3..], &data[unit * 3..]);
+}
+
+#[test]
+fn pnfs_short_data_server_reads() {
+    let servers = start_servers(true, false);
+    for data_server in &servers.data_servers {
+        data_server.set_read_limit(Some(100));
+    }
+    // Short reads without eof are picked up from where they stopped, not taken for holes.
+    assert_eq!(write_and_read_back(&servers), test_data());
+}
+
+fn start_flex_files_servers(flags: FlexFilesFlags) -> (TestServer, [TestServer; 2]) {
+    start_flex_files_servers_with(flags, false)
+}
+
+fn start_flex_files_servers_with(
+    flags: FlexFilesFlags,
+    nfs3: bool,
+) -> (TestServer, [TestServer; 2]) {
+    let data_servers = [TestServer::data_server(), TestServer::data_server()];
+    let mds = TestServer::start(ServerConfig {
+        flex_files_layout: Some(FlexFilesConfig {
+            mirrors: data_servers.iter().map(|s| vec![s.addr()]).collect(),
+            stripe_unit: 0,
+            flags,
+            nfs3,
+        }),
+        ..Default::default()
+    });
+    mds.write_file("/a_file", b"");
+    (mds, data_servers)
+}
+
+#[test]
+fn flex_files_mirrored_writes() {
+    let (mds, data_servers) = start_flex_files_servers(FlexFilesFlags::empty());
+    let mut client = Client::new_with_minor_version(mds.connect(), 2).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+    let data = test_data();
+
+    let mut file = client
+        .pnfs_open(handle.clone(), LayoutIoMode::ReadWrite, TcpConnector)
+        .unwrap();
+    assert_eq!(file.layout_type(), LayoutType::FlexFiles);
+    client.pnfs_write_all(&mut file, &data[..]).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    for data_server in &data_servers {
+        assert_eq!(data_server.handle_contents(&handle).unwrap(), data);
+    }
+
+    let mut file = client
+        .pnfs_open(handle, LayoutIoMode::Read, TcpConnector)
+        .unwrap();
+    let mut read_data = vec![];
+    client.pnfs_read_all(&mut file, &mut read_data).unwrap();
+    client.pnfs_close(file).unwrap();
+    assert_eq!(read_data, data);
+
+    let stats = mds.layout_stats();
+    let written: u64 = stats.iter().map(|s| s.write.bytes).sum();
+    assert_eq!(written, data.len() as u64 * 2);
+    assert!(stats
+        .iter()
+        .all(|s| s.layout_update.type_ == LayoutType::FlexFiles));
+    assert!(mds.layout_errors().is_empty());
+}
+
+fn flex_files_short_mirror_reads(nfs3: bool) {
+    let (mds, data_servers) = start_flex_files_servers_with(FlexFilesFlags::empty(), nfs3);
+    let data = test_data();
+    let mut client = Client::new_with_minor_version(mds.connect(), 2).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let mut file = client
+        .pnfs_open(handle.clone(), LayoutIoMode::ReadWrite, TcpConnector)
+        .unwrap();
+    client.pnfs_write_all(&mut file, &data[..]).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    for data_server in &data_servers {
+        data_server.set_read_limit(Some(100));
+    }
+    let mut file = client
+        .pnfs_open(handle, LayoutIoMode::Read, TcpConnector)
+        .unwrap();
+    let mut read_data = vec![];
+    client.pnfs_read_all(&mut file, &mut read_data).unwrap();
+    client.pnfs_close(file).unwrap();
+    assert_eq!(read_data, data);
+}
+
+#[test]
+fn flex_files_v41_short_mirror_reads() {
+    flex_files_short_mirror_reads(false);
+}
+
+#[test]
+fn flex_files_v3_short_mirror_reads() {
+    flex_files_short_mirror_reads(true);
+}
+
+#[test]
+fn flex_files_read_fails_over_to_mirror() {
+    let (mds, data_servers) = start_flex_files_servers(FlexFilesFlags::empty());
+    let data = test_data();
+    let mut client = Client::new_with_minor_version(mds.connect(), 2).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let mut file = client
+        .pnfs_open(handle.clone(), LayoutIoMode::ReadWrite, TcpConnector)
+        .unwrap();
+    client.pnfs_write_all(&mut file, &data[..]).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    data_servers[0].set_io_error(Some(StatusError::Io));
+
+    let mut file = client
+        .pnfs_open(handle, LayoutIoMode::Read, TcpConnector)
+        .unwrap();
+    let mut read_data = vec![];
+    client.pnfs_read_all(&mut file, &mut read_data).unwrap();
+    client.pnfs_close(file).unwrap();
+    assert_eq!(read_data, data);
+
+    let layout_errors = mds.layout_errors();
+    assert!(!layout_errors.is_empty());
+    for layout_error in &layout_errors {
+        assert_eq!(
+            layout_error.errors,
+            [DeviceError {
+                device_id: flex_files_device_id(0, 0),
+                status: StatusResult::Err(StatusError::Io),
+                opnum: OperationId::Read,
+            }]
+        );
+    }
+
+    // The errors are repeated in the LAYOUTRETURN body.
+    let layout_return = mds.layout_returns().pop().unwrap();
+    let LayoutReturn::File(returned) = layout_return.layout_return else {
+        panic!("unexpected layout return {layout_return:?}");
+    };
+    let body: FlexFilesLayoutReturn = serde_xdr::from_bytes(&returned.body[..]).unwrap();
+    assert_eq!(body.io_errors.len(), layout_errors.len());
+}
+
+#[test]
+fn flex_files_v41_reports_errors_on_return() {
+    let (mds, data_servers) = start_flex_files_servers(FlexFilesFlags::empty());
+    let mut client = Client::new(mds.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    data_servers[1].set_io_error(Some(StatusError::NoSpc));
+    let mut file = client
+        .pnfs_open(handle.clone(), LayoutIoMode::ReadWrite, TcpConnector)
+        .unwrap();
+    client.pnfs_write_all(&mut file, &test_data()[..]).unwrap();
+    client.pnfs_close(file).unwrap();
+
+    // The writes fell back to the metadata server.
+    assert_eq!(mds.handle_contents(&handle).unwrap(), test_data());
+
+    // LAYOUTERROR and LAYOUTSTATS are NFSv4.2 only.
+    assert!(mds.layout_errors().is_empty());
+    assert!(mds.layout_stats().is_empty());
+
+    let layout_return = mds.layout_returns().pop().unwrap();
+    let LayoutReturn::File(returned) = layout_return.layout_return else {
+        panic!("unexpected layout return {layout_return:?}");
+    };
+    let body: FlexFilesLayoutReturn = serde_xdr::from_bytes(&returned.body[..]).unwrap();
+    assert!(!body.io_errors.is_empty());
+    assert!(body
+        .io_errors
+        .iter()
+        .all(|e| e.errors[0].opnum == OperationId::Write
+            && e.errors[0].device_id == flex_files_device_id(1, 0)));
+}
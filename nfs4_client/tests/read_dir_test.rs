@@ -0,0 +1,78 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, StatusError};
+use nfs4_client::{Client, Error};
+use nfs4_test_server::{ServerConfig, TestServer};
+
+fn small_replies(max_response_size: u32) -> TestServer {
+    TestServer::start(ServerConfig {
+        max_response_size: Some(max_response_size),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn pages_through_large_directory() {
+    let server = small_replies(2048);
+    server.create_dir("/dir");
+    let mut expected: Vec<_> = (0..200).map(|i| format!("file_{i:03}")).collect();
+    for name in &expected {
+        server.write_file(&format!("/dir/{name}"), b"abc");
+    }
+    let mut client = Client::new(server.connect()).unwrap();
+    let dir = client.look_up("/dir").unwrap();
+
+    let before = server.total_compounds();
+    let entries = client
+        .read_dir(dir, AttrRequest::new().size().into())
+        .unwrap();
+    assert!(server.total_compounds() - before > 5);
+
+    let mut names: Vec<_> = entries.iter().map(|e| e.name.clone()).collect();
+    names.sort();
+    expected.sort();
+    assert_eq!(names, expected);
+    let sizes: Vec<_> = entries
+        .into_iter()
+        .map(|e| nfs4::Attributes::from(e.attrs).size)
+        .collect();
+    assert!(sizes.iter().all(|s| *s == Some(3)));
+}
+
+#[test]
+fn restarts_when_directory_changes() {
+    let server = small_replies(1024);
+    server.create_dir("/dir");
+    for i in 0..100 {
+        server.write_file(&format!("/dir/f{i:03}"), b"");
+    }
+    let mut client = Client::new(server.connect()).unwrap();
+    let mut other = Client::new(server.connect()).unwrap();
+    let dir = client.look_up("/dir").unwrap();
+
+    let mut entries = client.read_dir_iter(dir.clone(), Default::default());
+    let mut names: Vec<_> = entries.by_ref().take(30).map(|e| e.unwrap().name).collect();
+    other.create_file(dir.clone(), "a_new_file").unwrap();
+    names.extend(entries.map(|e| e.unwrap().name));
+
+    assert!(names.contains(&"a_new_file".to_owned()));
+    names.retain(|n| n != "a_new_file");
+    let expected: Vec<_> = (0..100).map(|i| format!("f{i:03}")).collect();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn entry_too_big_for_reply() {
+    let server = small_replies(600);
+    server.create_dir("/dir");
+    server.write_file(&format!("/dir/{}", "x".repeat(200)), b"");
+    let mut client = Client::new(server.connect()).unwrap();
+    let dir = client.look_up("/dir").unwrap();
+
+    let mut entries = client.read_dir_iter(dir, Default::default());
+    assert!(matches!(
+        entries.next(),
+        Some(Err(Error::Protocol(StatusError::TooSmall)))
+    ));
+    assert!(entries.next().is_none());
+}
@@ -0,0 +1,216 @@
+// Copyright Remi Bernotavicius
+
+use nfs4_client::{Client, MemoryFs, OpenOptions, RemoteFs, SetAttributes};
+use nfs4_test_server::TestServer;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+const CREATE: OpenOptions = OpenOptions {
+    create: true,
+    exclusive: false,
+    truncate: false,
+};
+
+/// Goes through every operation, so both implementations are held to the same behaviour.
+fn exercise(fs: &mut dyn RemoteFs) {
+    let root = fs.root().unwrap();
+    let dir = fs.mkdir(&root, "dir", 0o750).unwrap();
+    assert!(fs.stat(&dir).unwrap().is_dir());
+    assert_eq!(fs.stat(&dir).unwrap().mode, 0o750);
+    assert_eq!(
+        fs.mkdir(&root, "dir", 0o755).unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+
+    let file = fs.open(&dir, "file", CREATE).unwrap();
+    assert_eq!(fs.write_at(&file, 0, b"hello world").unwrap(), 11);
+    assert_eq!(fs.write_at(&file, 6, b"there").unwrap(), 5);
+    let mut buf = [0; 32];
+    let n = fs.read_at(&file, 0, &mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello there");
+    assert_eq!(fs.read_at(&file, 11, &mut buf).unwrap(), 0);
+
+    let metadata = fs.stat(&file).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.size, 11);
+    assert_eq!(fs.lookup_path(Path::new("/dir/file")).unwrap(), file);
+    assert_eq!(
+        fs.lookup_path(Path::new("/dir/../dir/./file")).unwrap(),
+        file
+    );
+    assert_eq!(fs.lookup_path(Path::new("/../..")).unwrap(), root);
+
+    let exclusive = OpenOptions {
+        exclusive: true,
+        ..CREATE
+    };
+    assert_eq!(
+        fs.open(&dir, "file", exclusive).unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        fs.open(&dir, "missing", OpenOptions::default())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::NotFound
+    );
+
+    let link = fs.symlink(&dir, "link", "file").unwrap();
+    assert!(fs.stat(&link).unwrap().is_symlink());
+    assert_eq!(fs.readlink(&link).unwrap(), "file");
+
+    let names: Vec<_> = fs
+        .readdir(&dir)
+        .unwrap()
+        .into_iter()
+        .map(|e| (e.name, e.handle, e.metadata.file_type))
+        .collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&("file".into(), file.clone(), nfs4::FileType::Regular)));
+    assert!(names.contains(&("link".into(), link, nfs4::FileType::Link)));
+
+    fs.setattr(
+        &file,
+        SetAttributes {
+            size: Some(5),
+            mode: Some(0o600),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let metadata = fs.stat(&file).unwrap();
+    assert_eq!((metadata.size, metadata.mode), (5, 0o600));
+
+    let truncate = OpenOptions {
+        truncate: true,
+        ..Default::default()
+    };
+    fs.open(&dir, "file", truncate).unwrap();
+    assert_eq!(fs.stat(&file).unwrap().size, 0);
+
+    fs.rename(&dir, "file", &root, "moved").unwrap();
+    assert_eq!(
+        fs.lookup(&dir, "file").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(fs.lookup(&root, "moved").unwrap(), file);
+
+    assert_eq!(
+        fs.remove(&root, "dir").unwrap_err().kind(),
+        ErrorKind::DirectoryNotEmpty
+    );
+    fs.remove(&dir, "link").unwrap();
+    fs.remove(&root, "dir").unwrap();
+    fs.remove(&root, "moved").unwrap();
+    assert!(fs.readdir(&root).unwrap().is_empty());
+}
+
+#[test]
+fn memory_fs() {
+    exercise(&mut MemoryFs::new());
+}
+
+#[test]
+fn nfs4_client() {
+    let server = TestServer::new();
+    let mut client = Client::new(server.connect()).unwrap();
+    exercise(&mut client);
+}
+
+#[test]
+fn memory_fs_rename_over_existing() {
+    let mut fs = MemoryFs::new();
+    let root = fs.root().unwrap();
+    let a = fs.open(&root, "a", CREATE).unwrap();
+    fs.write_at(&a, 0, b"a").unwrap();
+    fs.open(&root, "b", CREATE).unwrap();
+    fs.mkdir(&root, "d", 0o755).unwrap();
+
+    fs.rename(&root, "a", &root, "b").unwrap();
+    assert_eq!(fs.lookup(&root, "b").unwrap(), a);
+    assert_eq!(
+        fs.rename(&root, "b", &root, "d").unwrap_err().kind(),
+        ErrorKind::IsADirectory
+    );
+    assert_eq!(
+        fs.rename(&root, "d", &root, "b").unwrap_err().kind(),
+        ErrorKind::NotADirectory
+    );
+    assert_eq!(
+        fs.read_at(&root, 0, &mut [0; 4]).unwrap_err().kind(),
+        ErrorKind::IsADirectory
+    );
+}
+
+#[test]
+fn memory_fs_rename_into_itself() {
+    let mut fs = MemoryFs::new();
+    let root = fs.root().unwrap();
+    let a = fs.mkdir(&root, "a", 0o755).unwrap();
+    let b = fs.mkdir(&a, "b", 0o755).unwrap();
+
+    assert_eq!(
+        fs.rename(&root, "a", &b, "a").unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        fs.rename(&root, "a", &a, "c").unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    fs.rename(&a, "b", &root, "b").unwrap();
+    assert_eq!(fs.lookup(&root, "b").unwrap(), b);
+}
+
+#[test]
+fn memory_fs_setattr_modified() {
+    let mut fs = MemoryFs::new();
+    let root = fs.root().unwrap();
+    let file = fs.open(&root, "file", CREATE).unwrap();
+    fs.write_at(&file, 0, b"abc").unwrap();
+    let then = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fs.setattr(
+        &file,
+        SetAttributes {
+            modified: Some(then),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // Neither a mode change nor truncating to the same size touches the modification time...
+    fs.setattr(
+        &file,
+        SetAttributes {
+            mode: Some(0o600),
+            size: Some(3),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(fs.stat(&file).unwrap().modified, Some(then));
+
+    // ...but changing the size does.
+    fs.setattr(
+        &file,
+        SetAttributes {
+            size: Some(1),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_ne!(fs.stat(&file).unwrap().modified, Some(then));
+}
+
+#[test]
+fn memory_fs_write_past_end_of_address_space() {
+    let mut fs = MemoryFs::new();
+    let root = fs.root().unwrap();
+    let file = fs.open(&root, "file", CREATE).unwrap();
+    let offset = usize::MAX as u64 - 1;
+    assert_eq!(
+        fs.write_at(&file, offset, b"abc").unwrap_err().kind(),
+        ErrorKind::FileTooLarge
+    );
+    assert_eq!(fs.stat(&file).unwrap().size, 0);
+}
@@ -0,0 +1,178 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{
+    Component, FsLocationsInfo, FsLocationsInfoByte, FsLocationsInfoFlags, FsLocationsItem,
+    FsLocationsServer, FsLocationsServerFlags, PathName, ShareAccess,
+};
+use nfs4_client::{Client, Replicated, TcpConnector};
+use nfs4_test_server::TestServer;
+use std::net::TcpStream;
+
+struct Location<'a> {
+    server: &'a TestServer,
+    root: &'a str,
+    flags: FsLocationsServerFlags,
+    read: (u8, u8),
+    write: (u8, u8),
+}
+
+/// A server with `data/file` and `data/other` in the filesystem at `root`.
+fn server_with_fs(root: &str) -> TestServer {
+    let server = TestServer::new();
+    server.create_dir(root);
+    server.create_dir(&format!("{root}/data"));
+    server.write_file(&format!("{root}/data/file"), b"v1");
+    server.write_file(&format!("{root}/data/other"), b"other");
+    server
+}
+
+/// Gives every server the same `fs_locations_info`, apart from `CUR_REQ`.
+fn set_locations(locations: &[Location]) {
+    let path_name = |path: &str| {
+        PathName(
+            path.split('/')
+                .filter(|c| !c.is_empty())
+                .map(|c| Component(c.into()))
+                .collect(),
+        )
+    };
+    for current in locations {
+        let items = locations
+            .iter()
+            .map(|l| {
+                let mut entry = FsLocationsServer {
+                    currency: 0,
+                    info: vec![],
+                    server: l.server.addr().to_string(),
+                };
+                let mut flags = l.flags;
+                flags.set(
+                    FsLocationsServerFlags::CUR_REQ,
+                    l.server.addr() == current.server.addr(),
+                );
+                entry.set_info_byte(FsLocationsInfoByte::GFlags, flags.bits());
+                entry.set_info_byte(FsLocationsInfoByte::ReadRank, l.read.0);
+                entry.set_info_byte(FsLocationsInfoByte::ReadOrder, l.read.1);
+                entry.set_info_byte(FsLocationsInfoByte::WriteRank, l.write.0);
+                entry.set_info_byte(FsLocationsInfoByte::WriteOrder, l.write.1);
+                FsLocationsItem {
+                    entries: vec![entry],
+                    root_path: path_name(l.root),
+                }
+            })
+            .collect();
+        current.server.set_fs_locations_info(FsLocationsInfo {
+            flags: FsLocationsInfoFlags::empty(),
+            valid_for: 0,
+            fs_root: path_name(current.root),
+            items,
+        });
+    }
+}
+
+/// A writable primary, a read-only replica, and a writable standby which is only for when the
+/// primary is gone.
+fn cluster() -> (TestServer, TestServer, TestServer) {
+    let primary = server_with_fs("/export");
+    let replica = server_with_fs("/ro");
+    let standby = server_with_fs("/standby");
+    set_locations(&[
+        Location {
+            server: &primary,
+            root: "/export",
+            flags: FsLocationsServerFlags::WRITABLE,
+            read: (0, 0),
+            write: (0, 0),
+        },
+        Location {
+            server: &replica,
+            root: "/ro",
+            flags: FsLocationsServerFlags::empty(),
+            read: (0, 1),
+            write: (0, 0),
+        },
+        Location {
+            server: &standby,
+            root: "/standby",
+            flags: FsLocationsServerFlags::WRITABLE,
+            read: (1, 0),
+            write: (1, 0),
+        },
+    ]);
+    (primary, replica, standby)
+}
+
+fn replicated(primary: &TestServer) -> Replicated<TcpConnector> {
+    let client: Client<TcpStream> = Client::new(primary.connect()).unwrap();
+    Replicated::new(client, "/export/data", TcpConnector).unwrap()
+}
+
+fn current(replicated: &Replicated<TcpConnector>) -> String {
+    replicated.current().unwrap().server.clone()
+}
+
+#[test]
+fn locations() {
+    let (primary, replica, _standby) = cluster();
+    let replicated = replicated(&primary);
+    let locations = replicated.locations();
+    assert_eq!(locations.len(), 3);
+    assert_eq!(current(&replicated), primary.addr().to_string());
+    assert_eq!(locations[1].server, replica.addr().to_string());
+    assert_eq!(locations[1].root.to_str(), Some("/ro"));
+    assert_eq!((locations[2].read_rank, locations[2].read_order), (1, 0));
+}
+
+#[test]
+fn reads_fail_over_to_replica() {
+    let (primary, replica, _standby) = cluster();
+    let mut replicated = replicated(&primary);
+    let mut file = replicated.open("data/file", ShareAccess::READ).unwrap();
+    assert_eq!(replicated.read(&mut file, 0, 10).unwrap().data, b"v1");
+
+    primary.shut_down();
+    assert_eq!(replicated.read(&mut file, 0, 10).unwrap().data, b"v1");
+    assert_eq!(current(&replicated), replica.addr().to_string());
+    replicated.close(file).unwrap();
+}
+
+#[test]
+fn writes_fail_over_to_writable_location() {
+    let (primary, replica, standby) = cluster();
+    let mut replicated = replicated(&primary);
+    let mut file = replicated.open("/data/file", ShareAccess::BOTH).unwrap();
+    replicated.write(&mut file, 0, b"v2").unwrap();
+    assert_eq!(primary.file_contents("/export/data/file").unwrap(), b"v2");
+
+    primary.shut_down();
+    replicated.write(&mut file, 0, b"v3").unwrap();
+    assert_eq!(current(&replicated), standby.addr().to_string());
+    assert_eq!(standby.file_contents("/standby/data/file").unwrap(), b"v3");
+    assert_eq!(replica.file_contents("/ro/data/file").unwrap(), b"v1");
+
+    // With the standby gone too, there is nowhere left to write.
+    standby.shut_down();
+    assert!(replicated.write(&mut file, 0, b"v4").is_err());
+}
+
+#[test]
+fn migration() {
+    let (primary, replica, _standby) = cluster();
+    let mut replicated = replicated(&primary);
+    let mut file = replicated.open("data/file", ShareAccess::READ).unwrap();
+
+    // The OPEN works, but says the lease moved, so the next request goes elsewhere.
+    primary.migrate();
+    let mut other = replicated.open("data/other", ShareAccess::READ).unwrap();
+    assert_eq!(current(&replicated), replica.addr().to_string());
+    assert_eq!(replicated.read(&mut other, 0, 10).unwrap().data, b"other");
+    assert_eq!(replicated.read(&mut file, 0, 10).unwrap().data, b"v1");
+
+    // A READ answered with `Moved` moves on as well.
+    let (primary, replica, _standby) = cluster();
+    let mut replicated = self::replicated(&primary);
+    let mut file = replicated.open("data/file", ShareAccess::READ).unwrap();
+    primary.set_io_error(Some(nfs4::StatusError::Moved));
+    assert_eq!(replicated.read(&mut file, 0, 10).unwrap().data, b"v1");
+    assert_eq!(current(&replicated), replica.addr().to_string());
+}
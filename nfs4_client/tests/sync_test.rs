@@ -0,0 +1,359 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::{AttrRequest, Attributes, SetTime, StatusError};
+use nfs4_client::{Client, SyncAction, SyncOptions};
+use nfs4_test_server::TestServer;
+use std::fs;
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nfs4_sync_test_{}_{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn local_tree(root: &Path) {
+    fs::create_dir_all(root.join("a/b")).unwrap();
+    fs::write(root.join("a/b/deep.txt"), b"deep").unwrap();
+    fs::write(root.join("a/one.txt"), b"one").unwrap();
+    fs::write(root.join("big"), contents(300_000)).unwrap();
+    fs::set_permissions(root.join("a/one.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    std::os::unix::fs::symlink("a/one.txt", root.join("link")).unwrap();
+}
+
+fn contents(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn paths(actions: &[SyncAction]) -> Vec<String> {
+    actions
+        .iter()
+        .map(|a| a.path().to_str().unwrap().to_owned())
+        .collect()
+}
+
+fn remote_attrs(client: &mut Client<TcpStream>, path: &str) -> Attributes {
+    let handle = client.look_up(path).unwrap();
+    let request = AttrRequest::new().mode().time_modify();
+    client.get_attributes(handle, request).unwrap()
+}
+
+#[test]
+fn to_remote() {
+    let local = temp_dir("to_remote");
+    local_tree(&local);
+    let server = TestServer::new();
+    server.create_dir("/dest");
+    let mut client = Client::new(server.connect()).unwrap();
+    let dest = client.look_up("/dest").unwrap();
+
+    let actions = client
+        .sync_to_remote(&local, dest.clone(), &SyncOptions::default())
+        .unwrap();
+    assert_eq!(
+        actions,
+        [
+            SyncAction::CreateDirectory("a".into()),
+            SyncAction::CreateDirectory("a/b".into()),
+            SyncAction::Copy("a/b/deep.txt".into()),
+            SyncAction::Copy("a/one.txt".into()),
+            SyncAction::Copy("big".into()),
+            SyncAction::CreateSymlink("link".into(), "a/one.txt".into()),
+        ]
+    );
+    assert_eq!(server.file_contents("/dest/a/b/deep.txt").unwrap(), b"deep");
+    assert_eq!(server.open_files(), 0);
+    assert_eq!(
+        server.file_contents("/dest/big").unwrap(),
+        contents(300_000)
+    );
+    let link = client
+        .look_up_with("/dest/link", nfs4_client::LookUpOptions::no_follow())
+        .unwrap();
+    assert_eq!(client.read_link(link).unwrap(), "a/one.txt");
+
+    let attrs = remote_attrs(&mut client, "/dest/a/one.txt");
+    assert_eq!(attrs.mode.unwrap().0, 0o600);
+    let local_modified = fs::metadata(local.join("a/one.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(attrs.modified(), Some(local_modified));
+
+    // Nothing left to do the second time.
+    let actions = client
+        .sync_to_remote(&local, dest, &SyncOptions::default())
+        .unwrap();
+    assert_eq!(actions, []);
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn from_remote() {
+    let server = TestServer::new();
+    server.create_dir("/src");
+    server.create_dir("/src/dir");
+    server.write_file("/src/dir/file", &contents(200_000));
+    server.write_file("/src/empty", b"");
+    server.create_symlink("/src/link", "dir/file");
+    let mut client = Client::new(server.connect()).unwrap();
+    let src = client.look_up("/src").unwrap();
+    let local = temp_dir("from_remote").join("dest");
+
+    let actions = client
+        .sync_from_remote(src.clone(), &local, &SyncOptions::default())
+        .unwrap();
+    assert_eq!(paths(&actions), ["dir", "dir/file", "empty", "link"]);
+    assert_eq!(fs::read(local.join("dir/file")).unwrap(), contents(200_000));
+    assert_eq!(fs::read(local.join("empty")).unwrap(), b"");
+    assert_eq!(
+        fs::read_link(local.join("link")).unwrap(),
+        Path::new("dir/file")
+    );
+    let metadata = fs::metadata(local.join("dir/file")).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o644);
+    let attrs = remote_attrs(&mut client, "/src/dir/file");
+    assert_eq!(attrs.modified(), Some(metadata.modified().unwrap()));
+
+    let actions = client
+        .sync_from_remote(src, &local, &SyncOptions::default())
+        .unwrap();
+    assert_eq!(actions, []);
+    fs::remove_dir_all(local.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn from_remote_short_reads() {
+    let server = TestServer::new();
+    server.create_dir("/src");
+    for i in 0..4 {
+        server.write_file(&format!("/src/file_{i}"), &contents(100_000 + i));
+    }
+    let mut client = Client::new(server.connect()).unwrap();
+    let src = client.look_up("/src").unwrap();
+    let local = temp_dir("from_remote_short_reads");
+
+    // The rest of each short read is asked for again, with several files in flight.
+    server.set_read_limit(Some(1000));
+    let options = SyncOptions {
+        parallel: 3,
+        ..Default::default()
+    };
+    client.sync_from_remote(src, &local, &options).unwrap();
+    for i in 0..4 {
+        let data = fs::read(local.join(format!("file_{i}"))).unwrap();
+        assert_eq!(data, contents(100_000 + i));
+    }
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn from_remote_replaces_files() {
+    let server = TestServer::new();
+    server.create_dir("/src");
+    server.write_file("/src/file", b"new contents");
+    let mut client = Client::new(server.connect()).unwrap();
+    let src = client.look_up("/src").unwrap();
+    let local = temp_dir("from_remote_replaces_files");
+    fs::write(local.join("file"), b"old").unwrap();
+    fs::set_permissions(local.join("file"), fs::Permissions::from_mode(0o444)).unwrap();
+
+    // A failed transfer leaves the file as it was, without anything else behind.
+    server.set_io_error(Some(StatusError::Io));
+    client
+        .sync_from_remote(src.clone(), &local, &SyncOptions::default())
+        .unwrap_err();
+    assert_eq!(fs::read(local.join("file")).unwrap(), b"old");
+    let names: Vec<_> = fs::read_dir(&local)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["file"]);
+
+    server.set_io_error(None);
+    client
+        .sync_from_remote(src, &local, &SyncOptions::default())
+        .unwrap();
+    assert_eq!(fs::read(local.join("file")).unwrap(), b"new contents");
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn dry_run_and_delete() {
+    let server = TestServer::new();
+    server.create_dir("/src");
+    server.write_file("/src/keep", b"keep");
+    server.write_file("/src/new", b"new");
+    let mut client = Client::new(server.connect()).unwrap();
+    let src = client.look_up("/src").unwrap();
+    let local = temp_dir("dry_run_and_delete");
+    fs::create_dir_all(local.join("extra/inner")).unwrap();
+    fs::write(local.join("extra/inner/file"), b"x").unwrap();
+    fs::write(local.join("keep"), b"older").unwrap();
+    // A directory where the source has a file.
+    fs::create_dir(local.join("new")).unwrap();
+
+    let options = SyncOptions {
+        delete: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    let actions = client
+        .sync_from_remote(src.clone(), &local, &options)
+        .unwrap();
+    assert_eq!(
+        actions,
+        [
+            SyncAction::Remove("new".into()),
+            SyncAction::Remove("extra/inner/file".into()),
+            SyncAction::Remove("extra/inner".into()),
+            SyncAction::Remove("extra".into()),
+            SyncAction::Copy("keep".into()),
+            SyncAction::Copy("new".into()),
+        ]
+    );
+    assert_eq!(fs::read(local.join("keep")).unwrap(), b"older");
+    assert!(local.join("extra/inner/file").exists());
+
+    // Without delete, extra things are left alone, but what is in the way still goes.
+    let options = SyncOptions::default();
+    let actions = client
+        .sync_from_remote(src.clone(), &local, &options)
+        .unwrap();
+    assert_eq!(paths(&actions), ["new", "keep", "new"]);
+    assert_eq!(fs::read(local.join("new")).unwrap(), b"new");
+    assert!(local.join("extra/inner/file").exists());
+
+    let options = SyncOptions {
+        delete: true,
+        ..Default::default()
+    };
+    let actions = client.sync_from_remote(src, &local, &options).unwrap();
+    assert_eq!(
+        paths(&actions),
+        ["extra/inner/file", "extra/inner", "extra"]
+    );
+    let mut names: Vec<_> = fs::read_dir(&local)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["keep", "new"]);
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn checksum() {
+    let local = temp_dir("checksum");
+    fs::write(local.join("file"), b"original").unwrap();
+    let server = TestServer::new();
+    server.create_dir("/dest");
+    let mut client = Client::new(server.connect()).unwrap();
+    let dest = client.look_up("/dest").unwrap();
+    client
+        .sync_to_remote(&local, dest.clone(), &SyncOptions::default())
+        .unwrap();
+
+    // Changed, but with the same size and modification time.
+    server.write_file("/dest/file", b"changed!");
+    let handle = client.look_up("/dest/file").unwrap();
+    let modified = fs::metadata(local.join("file"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let attrs = Attributes {
+        time_modify_set: Some(SetTime::SetToClientTime(modified.into())),
+        ..Default::default()
+    };
+    client.set_attributes(handle, attrs).unwrap();
+
+    let actions = client
+        .sync_to_remote(&local, dest.clone(), &SyncOptions::default())
+        .unwrap();
+    assert_eq!(actions, []);
+    let options = SyncOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let actions = client
+        .sync_to_remote(&local, dest.clone(), &options)
+        .unwrap();
+    assert_eq!(actions, [SyncAction::Copy("file".into())]);
+    assert_eq!(server.file_contents("/dest/file").unwrap(), b"original");
+    let actions = client.sync_to_remote(&local, dest, &options).unwrap();
+    assert_eq!(actions, []);
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn updates_in_place() {
+    let local = temp_dir("updates_in_place");
+    fs::write(local.join("file"), contents(100_000)).unwrap();
+    let server = TestServer::new();
+    server.create_dir("/dest");
+    let mut client = Client::new(server.connect()).unwrap();
+    let dest = client.look_up("/dest").unwrap();
+    client
+        .sync_to_remote(&local, dest.clone(), &SyncOptions::default())
+        .unwrap();
+
+    let earlier = SystemTime::now() - Duration::from_secs(3600);
+    fs::write(local.join("file"), b"shorter").unwrap();
+    fs::File::open(local.join("file"))
+        .unwrap()
+        .set_modified(earlier)
+        .unwrap();
+    let actions = client
+        .sync_to_remote(&local, dest, &SyncOptions::default())
+        .unwrap();
+    assert_eq!(actions, [SyncAction::Copy("file".into())]);
+    assert_eq!(server.file_contents("/dest/file").unwrap(), b"shorter");
+    let attrs = remote_attrs(&mut client, "/dest/file");
+    assert_eq!(attrs.modified(), Some(earlier));
+    fs::remove_dir_all(local).unwrap();
+}
+
+#[test]
+fn copies_files_in_parallel() {
+    let local = temp_dir("copies_files_in_parallel");
+    for i in 0..20 {
+        fs::write(local.join(format!("file_{i:02}")), contents(1000 + i)).unwrap();
+    }
+    let server = TestServer::new();
+    server.create_dir("/dest");
+    let mut client = Client::new(server.connect()).unwrap();
+    let dest = client.look_up("/dest").unwrap();
+    server.set_latency(Duration::from_millis(10));
+
+    let mut timed_sync = |parallel| {
+        let out = local.join(format!("out_{parallel}"));
+        let start = Instant::now();
+        let options = SyncOptions {
+            parallel,
+            ..Default::default()
+        };
+        let actions = client
+            .sync_from_remote(dest.clone(), &out, &options)
+            .unwrap();
+        assert_eq!(actions.len(), 20);
+        start.elapsed()
+    };
+    let mut client_up = Client::new(server.connect()).unwrap();
+    let actions = client_up
+        .sync_to_remote(&local, dest.clone(), &SyncOptions::default())
+        .unwrap();
+    assert_eq!(actions.len(), 20);
+
+    let serial = timed_sync(1);
+    let parallel = timed_sync(8);
+    assert!(parallel * 2 < serial, "{parallel:?} vs {serial:?}");
+    for i in 0..20 {
+        let name = format!("out_8/file_{i:02}");
+        assert_eq!(fs::read(local.join(name)).unwrap(), contents(1000 + i));
+    }
+    fs::remove_dir_all(local).unwrap();
+}
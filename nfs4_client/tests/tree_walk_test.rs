@@ -0,0 +1,187 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::FileType;
+use nfs4_client::{glob_matches, Client, WalkEntry, WalkFilter, WalkOptions, WalkOrder};
+use nfs4_test_server::TestServer;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+fn tree() -> TestServer {
+    let server = TestServer::new();
+    server.create_dir("/root");
+    server.create_dir("/root/a");
+    server.create_dir("/root/a/b");
+    server.write_file("/root/a/b/deep.txt", &[0; 100]);
+    server.write_file("/root/a/one.txt", b"1");
+    server.create_dir("/root/c");
+    server.write_file("/root/c/two.rs", &[0; 2000]);
+    server.write_file("/root/top.txt", b"top");
+    server.create_symlink("/root/link", "a");
+    server
+}
+
+fn walk(client: &mut Client<TcpStream>, options: WalkOptions) -> Vec<WalkEntry> {
+    let root = client.look_up("/root").unwrap();
+    client
+        .walk(root, options)
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+fn paths(entries: &[WalkEntry]) -> Vec<String> {
+    entries
+        .iter()
+        .map(|e| e.path.to_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn depth_first_and_breadth_first() {
+    let server = tree();
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let entries = walk(&mut client, WalkOptions::default());
+    assert_eq!(
+        paths(&entries),
+        [
+            "a",
+            "a/b",
+            "a/b/deep.txt",
+            "a/one.txt",
+            "c",
+            "c/two.rs",
+            "link",
+            "top.txt"
+        ]
+    );
+    let depths: Vec<_> = entries.iter().map(|e| e.depth).collect();
+    assert_eq!(depths, [1, 2, 3, 2, 1, 2, 1, 1]);
+    assert_eq!(entries[6].attrs.type_, Some(FileType::Link));
+
+    let options = WalkOptions {
+        order: WalkOrder::BreadthFirst,
+        ..Default::default()
+    };
+    assert_eq!(
+        paths(&walk(&mut client, options)),
+        [
+            "a",
+            "c",
+            "link",
+            "top.txt",
+            "a/b",
+            "a/one.txt",
+            "c/two.rs",
+            "a/b/deep.txt"
+        ]
+    );
+}
+
+#[test]
+fn filters() {
+    let server = tree();
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let filtered = |client: &mut Client<TcpStream>, filter| {
+        let options = WalkOptions {
+            filter,
+            ..Default::default()
+        };
+        paths(&walk(client, options))
+    };
+    let by_name = WalkFilter {
+        name: Some("*.txt".into()),
+        ..Default::default()
+    };
+    assert_eq!(
+        filtered(&mut client, by_name),
+        ["a/b/deep.txt", "a/one.txt", "top.txt"]
+    );
+    let by_type = WalkFilter {
+        file_type: Some(FileType::Directory),
+        ..Default::default()
+    };
+    assert_eq!(filtered(&mut client, by_type), ["a", "a/b", "c"]);
+    let by_size = WalkFilter {
+        file_type: Some(FileType::Regular),
+        min_size: Some(3),
+        max_size: Some(1000),
+        ..Default::default()
+    };
+    assert_eq!(filtered(&mut client, by_size), ["a/b/deep.txt", "top.txt"]);
+    let by_mtime = WalkFilter {
+        modified_before: Some(std::time::UNIX_EPOCH),
+        ..Default::default()
+    };
+    assert!(filtered(&mut client, by_mtime).is_empty());
+}
+
+#[test]
+fn patterns() {
+    assert!(glob_matches("*.rs", "main.rs"));
+    assert!(!glob_matches("*.rs", "main.rs.bak"));
+    assert!(glob_matches("a*b*c", "axxbyybc"));
+    assert!(glob_matches("file_?[0-9]", "file_a7"));
+    assert!(!glob_matches("file_?[!0-9]", "file_a7"));
+    assert!(glob_matches("[a-c]*", "banana"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("?", ""));
+}
+
+#[test]
+fn fetches_directories_in_parallel() {
+    let server = TestServer::new();
+    server.create_dir("/root");
+    for i in 0..20 {
+        server.create_dir(&format!("/root/dir_{i:02}"));
+        for j in 0..3 {
+            server.write_file(&format!("/root/dir_{i:02}/file_{j}"), b"");
+        }
+    }
+    let mut client = Client::new(server.connect()).unwrap();
+    server.set_latency(Duration::from_millis(20));
+
+    let mut timed_walk = |parallel| {
+        let before = server.total_compounds();
+        let start = Instant::now();
+        let options = WalkOptions {
+            parallel,
+            ..Default::default()
+        };
+        let entries = walk(&mut client, options);
+        assert_eq!(entries.len(), 80);
+        assert_eq!(entries[0].path.to_str(), Some("dir_00"));
+        assert_eq!(entries[1].path.to_str(), Some("dir_00/file_0"));
+        // A LOOKUP and GETATTR, and a READDIR for each directory, whether or not they overlap.
+        assert_eq!(server.total_compounds() - before, 23);
+        start.elapsed()
+    };
+    let serial = timed_walk(1);
+    let parallel = timed_walk(8);
+    assert!(parallel * 2 < serial, "{parallel:?} vs {serial:?}");
+}
+
+#[test]
+fn cycles_and_max_depth() {
+    let server = tree();
+    server.hard_link("/root", "/root/a/b/back_to_root");
+    server.hard_link("/root/c", "/root/a/also_c");
+    let mut client = Client::new(server.connect()).unwrap();
+
+    let entries = walk(&mut client, WalkOptions::default());
+    let paths = paths(&entries);
+    // The loops are returned, but not descended into.
+    assert!(paths.contains(&"a/b/back_to_root".to_owned()));
+    assert!(paths.contains(&"a/also_c".to_owned()));
+    assert_eq!(paths.iter().filter(|p| p.ends_with("two.rs")).count(), 1);
+    assert_eq!(entries.len(), 10);
+
+    let options = WalkOptions {
+        max_depth: Some(1),
+        ..Default::default()
+    };
+    let entries = walk(&mut client, options);
+    assert_eq!(entries.len(), 4);
+    assert!(entries.iter().all(|e| e.depth == 1));
+}
@@ -0,0 +1,90 @@
+// Copyright Remi Bernotavicius
+
+use nfs4_client::{Client, PipelineConfig, Trunking};
+use nfs4_test_server::{connect, TestServer, MAX_READ};
+
+#[test]
+fn session_trunking_spreads_compounds() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"hello");
+    let mut client = Client::new(server.connect()).unwrap();
+    for _ in 0..3 {
+        assert_eq!(
+            client.add_connection(server.connect()).unwrap(),
+            Trunking::Session
+        );
+    }
+    assert_eq!(client.num_connections(), 4);
+
+    let before = server.connection_compounds();
+    for _ in 0..8 {
+        client.look_up("/a_file").unwrap();
+    }
+    let after = server.connection_compounds();
+    assert_eq!(after.len(), 4);
+    for (i, count) in after.iter().enumerate() {
+        assert!(*count > before.get(i).copied().unwrap_or(0), "{after:?}");
+    }
+}
+
+#[test]
+fn pipelined_io_over_trunked_connections() {
+    let server = TestServer::new();
+    let data: Vec<u8> = (0..MAX_READ as usize * 10 + 5)
+        .map(|i| (i % 241) as u8)
+        .collect();
+    server.write_file("/a_file", b"");
+
+    let mut client = Client::new(server.connect()).unwrap();
+    client.add_connection(server.connect()).unwrap();
+    client.add_connection(server.connect()).unwrap();
+    client.set_pipeline_config(PipelineConfig::fixed(6));
+
+    let handle = client.look_up("/a_file").unwrap();
+    client.write_all(handle.clone(), &data[..]).unwrap();
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+
+    let mut read = vec![];
+    client.read_all(handle, &mut read).unwrap();
+    assert_eq!(read, data);
+    assert_eq!(server.connection_compounds().len(), 3);
+}
+
+#[test]
+fn session_trunking_other_address() {
+    let server = TestServer::new();
+    let addr = server.listen_on_new_port(0);
+    let mut client = Client::new(server.connect()).unwrap();
+    assert_eq!(
+        client.add_connection(connect(addr)).unwrap(),
+        Trunking::Session
+    );
+    assert_eq!(client.num_connections(), 2);
+    client.look_up("/").unwrap();
+    client.look_up("/").unwrap();
+}
+
+#[test]
+fn client_id_trunking_other_address() {
+    let server = TestServer::new();
+    let addr = server.listen_on_new_port(1);
+    let mut client = Client::new(server.connect()).unwrap();
+    assert_eq!(
+        client.add_connection(connect(addr)).unwrap(),
+        Trunking::ClientId
+    );
+    assert_eq!(client.num_connections(), 1);
+}
+
+#[test]
+fn no_trunking_with_other_server() {
+    let server = TestServer::new();
+    let other = TestServer::new();
+    let mut client = Client::new(server.connect()).unwrap();
+    assert_eq!(
+        client.add_connection(other.connect()).unwrap(),
+        Trunking::None
+    );
+    assert_eq!(client.num_connections(), 1);
+    client.look_up("/").unwrap();
+}
@@ -0,0 +1,88 @@
+// Copyright Remi Bernotavicius
+
+use nfs4::StableHow;
+use nfs4_client::Client;
+use nfs4_test_server::{TestServer, MAX_WRITE};
+use std::io;
+
+/// Reads from `data`, crashing the server once it has written the first chunk.
+struct CrashingReader<'a> {
+    data: &'a [u8],
+    server: &'a TestServer,
+    reads: usize,
+}
+
+impl io::Read for CrashingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reads == 1 {
+            // The first chunk is still in flight while the second is read.
+            while self.server.io_counts().writes == 0 {
+                std::thread::yield_now();
+            }
+            self.server.crash();
+        }
+        self.reads += 1;
+        let len = buf.len().min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+fn test_data() -> Vec<u8> {
+    (0..MAX_WRITE as usize * 3 + 7)
+        .map(|i| (i % 253) as u8)
+        .collect()
+}
+
+#[test]
+fn write_all_commits_once() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let data = test_data();
+    client.write_all(handle, &data[..]).unwrap();
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+    assert_eq!(server.io_counts().writes, 4);
+    assert_eq!(server.io_counts().commits, 1);
+}
+
+#[test]
+fn write_all_resends_after_crash() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"");
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let data = test_data();
+    let source = CrashingReader {
+        data: &data,
+        server: &server,
+        reads: 0,
+    };
+    client.write_all(handle, source).unwrap();
+    assert_eq!(server.file_contents("/a_file").unwrap(), data);
+    assert_eq!(server.io_counts().writes, 8);
+    assert_eq!(server.io_counts().commits, 2);
+}
+
+#[test]
+fn commit_verifier_changes_on_crash() {
+    let server = TestServer::new();
+    server.write_file("/a_file", b"stable");
+    let mut client = Client::new(server.connect()).unwrap();
+    let handle = client.look_up("/a_file").unwrap();
+
+    let res = client
+        .write_with_stability(handle.clone(), 0, StableHow::Unstable, b"UNSTABLE".to_vec())
+        .unwrap();
+    assert_eq!(res.committed, StableHow::Unstable);
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"UNSTABLE");
+
+    server.crash();
+    assert_eq!(server.file_contents("/a_file").unwrap(), b"stable");
+    let commit = client.commit(handle).unwrap();
+    assert_ne!(commit.write_verifier, res.write_veritifer);
+}
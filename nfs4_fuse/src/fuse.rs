@@ -0,0 +1,354 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! The `fuser::Filesystem` for an `InodeFs`.
+
+use super::{Entry, InodeFs};
+use fuser::{
+    FileAttr, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+};
+use nfs4_client::{Metadata, RemoteFs, SetAttributes};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn errno(error: &io::Error) -> i32 {
+    use io::ErrorKind;
+    if let Some(errno) = error.raw_os_error() {
+        return errno;
+    }
+    match error.kind() {
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::PermissionDenied => libc::EACCES,
+        ErrorKind::AlreadyExists => libc::EEXIST,
+        ErrorKind::NotADirectory => libc::ENOTDIR,
+        ErrorKind::IsADirectory => libc::EISDIR,
+        ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        ErrorKind::InvalidInput | ErrorKind::InvalidFilename => libc::EINVAL,
+        ErrorKind::FileTooLarge => libc::EFBIG,
+        ErrorKind::StorageFull => libc::ENOSPC,
+        ErrorKind::QuotaExceeded => libc::EDQUOT,
+        ErrorKind::ReadOnlyFilesystem => libc::EROFS,
+        ErrorKind::CrossesDevices => libc::EXDEV,
+        ErrorKind::TooManyLinks => libc::EMLINK,
+        ErrorKind::StaleNetworkFileHandle => libc::ESTALE,
+        ErrorKind::ResourceBusy => libc::EBUSY,
+        ErrorKind::WouldBlock => libc::EAGAIN,
+        ErrorKind::Unsupported => libc::ENOTSUP,
+        _ => libc::EIO,
+    }
+}
+
+fn file_type(file_type: &nfs4::FileType) -> fuser::FileType {
+    use nfs4::FileType::*;
+    match file_type {
+        Regular => fuser::FileType::RegularFile,
+        Directory | AttrDir => fuser::FileType::Directory,
+        Block => fuser::FileType::BlockDevice,
+        Character => fuser::FileType::CharDevice,
+        Link => fuser::FileType::Symlink,
+        Socket => fuser::FileType::Socket,
+        Fifo => fuser::FileType::NamedPipe,
+    }
+}
+
+fn file_attr(inode: u64, metadata: &Metadata) -> FileAttr {
+    let modified = metadata.modified.unwrap_or(UNIX_EPOCH);
+    FileAttr {
+        ino: inode,
+        size: metadata.size,
+        blocks: metadata.size.div_ceil(512),
+        atime: modified,
+        mtime: modified,
+        ctime: modified,
+        crtime: modified,
+        kind: file_type(&metadata.file_type),
+        perm: (metadata.mode & 0o7777) as u16,
+        nlink: metadata.num_links,
+        uid: metadata.uid,
+        gid: metadata.gid,
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+fn name(name: &OsStr) -> io::Result<&str> {
+    name.to_str()
+        .ok_or_else(|| io::ErrorKind::InvalidFilename.into())
+}
+
+fn time(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+/// Serves an `InodeFs` to the kernel. The attribute timeout is also how long the kernel is told
+/// it may cache entries and attributes for.
+pub struct Fuse<FsT> {
+    fs: InodeFs<FsT>,
+    /// Directory listings taken at `opendir`, so that `readdir` can go through them by offset.
+    listings: HashMap<u64, Vec<Entry>>,
+    next_listing: u64,
+}
+
+impl<FsT: RemoteFs> Fuse<FsT> {
+    pub fn new(fs: InodeFs<FsT>) -> Self {
+        Self {
+            fs,
+            listings: HashMap::new(),
+            next_listing: 1,
+        }
+    }
+
+    fn reply_entry(&self, res: io::Result<(u64, Metadata)>, reply: ReplyEntry) {
+        match res {
+            Ok((inode, metadata)) => {
+                reply.entry(&self.fs.attr_timeout(), &file_attr(inode, &metadata), 0)
+            }
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+}
+
+impl<FsT: RemoteFs> Filesystem for Fuse<FsT> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let res = self::name(name).and_then(|name| self.fs.lookup(parent, name));
+        self.reply_entry(res, reply);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.fs.getattr(ino) {
+            Ok(metadata) => reply.attr(&self.fs.attr_timeout(), &file_attr(ino, &metadata)),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let attrs = SetAttributes {
+            mode: mode.map(|m| m & 0o7777),
+            uid,
+            gid,
+            size,
+            modified: mtime.map(time),
+        };
+        match self.fs.setattr(ino, attrs) {
+            Ok(metadata) => reply.attr(&self.fs.attr_timeout(), &file_attr(ino, &metadata)),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.fs.readlink(ino) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let mode = mode & !umask & 0o7777;
+        let res = self::name(name).and_then(|name| self.fs.mkdir(parent, name, mode));
+        self.reply_entry(res, reply);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self::name(name).and_then(|name| self.fs.remove(parent, name, false)) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self::name(name).and_then(|name| self.fs.remove(parent, name, true)) {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let res = self::name(link_name).and_then(|name| {
+            let target = self::name(target.as_os_str())?;
+            self.fs.symlink(parent, name, target)
+        });
+        self.reply_entry(res, reply);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // `RENAME_NOREPLACE` and `RENAME_EXCHANGE` have nothing to map to in NFS.
+        if flags != 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let res = self::name(name).and_then(|name| {
+            let new_name = self::name(newname)?;
+            self.fs.rename(parent, name, newparent, new_name)
+        });
+        match res {
+            Ok(()) => reply.ok(),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.fs.read(ino, offset as u64, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.fs.write(ino, offset as u64, data) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.fs.readdir(ino) {
+            Ok(entries) => {
+                let fh = self.next_listing;
+                self.next_listing += 1;
+                self.listings.insert(fh, entries);
+                reply.opened(fh, 0);
+            }
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+
+    /// Goes through the listing taken at `opendir`, with `.` and `..` first. Both are given the
+    /// directory's own inode, since parents aren't kept track of.
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(entries) = self.listings.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        let dots = [
+            (ino, ".", fuser::FileType::Directory),
+            (ino, "..", fuser::FileType::Directory),
+        ];
+        let entries = entries
+            .iter()
+            .map(|e| (e.inode, e.name.as_str(), file_type(&e.metadata.file_type)));
+        for (i, (inode, name, kind)) in dots
+            .into_iter()
+            .chain(entries)
+            .enumerate()
+            .skip(offset as usize)
+        {
+            if reply.add(inode, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.listings.remove(&fh);
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mode = mode & !umask & 0o7777;
+        let exclusive = flags & libc::O_EXCL != 0;
+        let res = self::name(name).and_then(|name| self.fs.create(parent, name, mode, exclusive));
+        match res {
+            Ok((inode, metadata)) => reply.created(
+                &self.fs.attr_timeout(),
+                &file_attr(inode, &metadata),
+                0,
+                0,
+                0,
+            ),
+            Err(error) => reply.error(errno(&error)),
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! Mounting an NFSv4.1 export with FUSE. `InodeFs` puts the inode numbers FUSE works in terms of,
+//! and a cache of attributes, in front of any `RemoteFs`. The `fuse` module, behind the feature of
+//! the same name, hooks it up to the kernel with `fuser`.
+
+use nfs4::FileHandle;
+use nfs4_client::{Metadata, OpenOptions, RemoteFs, SetAttributes};
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "fuse")]
+pub mod fuse;
+
+/// The inode number FUSE gives the root of the filesystem.
+pub const ROOT_INODE: u64 = 1;
+
+/// A directory entry, as listed by `InodeFs::readdir`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub inode: u64,
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// A `RemoteFs` addressed by inode number.
+///
+/// Inode numbers are the server's file ids, except that the root's is swapped with `ROOT_INODE`.
+/// The handle of every inode seen is kept for as long as the filesystem is. Attributes are used
+/// for `attr_timeout` after they were fetched, unless something done here changed them first.
+pub struct InodeFs<FsT> {
+    fs: FsT,
+    attr_timeout: Duration,
+    root_file_id: u64,
+    handles: HashMap<u64, FileHandle>,
+    attrs: HashMap<u64, (Metadata, Instant)>,
+}
+
+impl<FsT: RemoteFs> InodeFs<FsT> {
+    /// Uses the directory `root` as the root of the filesystem.
+    pub fn new(mut fs: FsT, root: FileHandle, attr_timeout: Duration) -> io::Result<Self> {
+        let metadata = fs.stat(&root)?;
+        if !metadata.is_dir() {
+            return Err(io::ErrorKind::NotADirectory.into());
+        }
+        let mut inode_fs = Self {
+            fs,
+            attr_timeout,
+            root_file_id: metadata.file_id,
+            handles: HashMap::new(),
+            attrs: HashMap::new(),
+        };
+        inode_fs.remember(root, metadata);
+        Ok(inode_fs)
+    }
+
+    pub fn fs(&mut self) -> &mut FsT {
+        &mut self.fs
+    }
+
+    pub fn attr_timeout(&self) -> Duration {
+        self.attr_timeout
+    }
+
+    fn inode(&self, file_id: u64) -> u64 {
+        if file_id == self.root_file_id {
+            ROOT_INODE
+        } else if file_id == ROOT_INODE {
+            self.root_file_id
+        } else {
+            file_id
+        }
+    }
+
+    fn handle(&self, inode: u64) -> io::Result<FileHandle> {
+        self.handles
+            .get(&inode)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::StaleNetworkFileHandle.into())
+    }
+
+    /// Keeps the handle and attributes of a file, returning its inode number.
+    fn remember(&mut self, handle: FileHandle, metadata: Metadata) -> u64 {
+        let inode = self.inode(metadata.file_id);
+        self.handles.insert(inode, handle);
+        self.attrs.insert(inode, (metadata, Instant::now()));
+        inode
+    }
+
+    fn stat_handle(&mut self, handle: FileHandle) -> io::Result<(u64, Metadata)> {
+        let metadata = self.fs.stat(&handle)?;
+        let inode = self.remember(handle, metadata.clone());
+        Ok((inode, metadata))
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: &str) -> io::Result<(u64, Metadata)> {
+        let dir = self.handle(parent)?;
+        let handle = self.fs.lookup(&dir, name)?;
+        self.stat_handle(handle)
+    }
+
+    pub fn getattr(&mut self, inode: u64) -> io::Result<Metadata> {
+        if let Some((metadata, fetched)) = self.attrs.get(&inode) {
+            if fetched.elapsed() < self.attr_timeout {
+                return Ok(metadata.clone());
+            }
+        }
+        let handle = self.handle(inode)?;
+        Ok(self.stat_handle(handle)?.1)
+    }
+
+    /// Lists a directory, without `.` and `..`. The attributes which come with the entries are
+    /// cached like any others.
+    pub fn readdir(&mut self, inode: u64) -> io::Result<Vec<Entry>> {
+        let dir = self.handle(inode)?;
+        let entries = self.fs.readdir(&dir)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| Entry {
+                inode: self.remember(entry.handle, entry.metadata.clone()),
+                name: entry.name,
+                metadata: entry.metadata,
+            })
+            .collect())
+    }
+
+    /// Reads up to `size` bytes at `offset`, only returning less at the end of the file.
+    pub fn read(&mut self, inode: u64, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let handle = self.handle(inode)?;
+        let mut data = vec![0; size];
+        let mut read = 0;
+        while read < size {
+            let n = self
+                .fs
+                .read_at(&handle, offset + read as u64, &mut data[read..])?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+
+    pub fn write(&mut self, inode: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        let handle = self.handle(inode)?;
+        let mut written = 0;
+        while written < data.len() {
+            let n = self
+                .fs
+                .write_at(&handle, offset + written as u64, &data[written..])?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            written += n;
+        }
+        self.attrs.remove(&inode);
+        Ok(())
+    }
+
+    /// Creates a regular file with the given mode. Unless `exclusive`, a file which is already
+    /// there is opened instead and keeps its mode.
+    pub fn create(
+        &mut self,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        exclusive: bool,
+    ) -> io::Result<(u64, Metadata)> {
+        let dir = self.handle(parent)?;
+        let options = OpenOptions {
+            create: true,
+            exclusive: true,
+            truncate: false,
+        };
+        let handle = match self.fs.open(&dir, name, options) {
+            Ok(handle) => {
+                let attrs = SetAttributes {
+                    mode: Some(mode),
+                    ..Default::default()
+                };
+                self.fs.setattr(&handle, attrs)?;
+                handle
+            }
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists && !exclusive => {
+                self.fs.open(&dir, name, OpenOptions::default())?
+            }
+            Err(error) => return Err(error),
+        };
+        self.attrs.remove(&parent);
+        self.stat_handle(handle)
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &str, mode: u32) -> io::Result<(u64, Metadata)> {
+        let dir = self.handle(parent)?;
+        let handle = self.fs.mkdir(&dir, name, mode)?;
+        self.attrs.remove(&parent);
+        self.stat_handle(handle)
+    }
+
+    pub fn symlink(
+        &mut self,
+        parent: u64,
+        name: &str,
+        target: &str,
+    ) -> io::Result<(u64, Metadata)> {
+        let dir = self.handle(parent)?;
+        let handle = self.fs.symlink(&dir, name, target)?;
+        self.attrs.remove(&parent);
+        self.stat_handle(handle)
+    }
+
+    pub fn readlink(&mut self, inode: u64) -> io::Result<String> {
+        let handle = self.handle(inode)?;
+        self.fs.readlink(&handle)
+    }
+
+    /// Removes a directory if `directory`, otherwise anything else, failing with `IsADirectory`
+    /// or `NotADirectory` when it is the other kind.
+    ///
+    /// All cached attributes are dropped, since the link counts of files other than the one named
+    /// can change.
+    pub fn remove(&mut self, parent: u64, name: &str, directory: bool) -> io::Result<()> {
+        let (_, metadata) = self.lookup(parent, name)?;
+        match (directory, metadata.is_dir()) {
+            (true, false) => return Err(io::ErrorKind::NotADirectory.into()),
+            (false, true) => return Err(io::ErrorKind::IsADirectory.into()),
+            _ => {}
+        }
+        let dir = self.handle(parent)?;
+        self.fs.remove(&dir, name)?;
+        self.attrs.clear();
+        Ok(())
+    }
+
+    /// Moves an entry, replacing what is at the destination. Like `remove`, this drops all cached
+    /// attributes.
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> io::Result<()> {
+        let from_dir = self.handle(parent)?;
+        let to_dir = self.handle(new_parent)?;
+        self.fs.rename(&from_dir, name, &to_dir, new_name)?;
+        self.attrs.clear();
+        Ok(())
+    }
+
+    pub fn setattr(&mut self, inode: u64, attrs: SetAttributes) -> io::Result<Metadata> {
+        let handle = self.handle(inode)?;
+        self.fs.setattr(&handle, attrs)?;
+        self.attrs.remove(&inode);
+        Ok(self.stat_handle(handle)?.1)
+    }
+}
@@ -0,0 +1,59 @@
+// Copyright 2023 Remi Bernotavicius
+
+use clap::Parser;
+use fuser::MountOption;
+use nfs4_fuse::fuse::Fuse;
+use nfs4_fuse::InodeFs;
+use std::io;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Mount an NFSv4.1 export with FUSE, without needing the kernel's NFS client or root.
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Options {
+    host: String,
+    mount_point: PathBuf,
+    #[clap(long, default_value_t = nfs4_client::NFS_PORT)]
+    port: u16,
+    /// The directory on the server to mount
+    #[clap(long, default_value = "/")]
+    path: PathBuf,
+    /// How many seconds attributes and looked up names are cached for
+    #[clap(long, default_value_t = 1.0)]
+    attr_timeout: f64,
+    #[clap(long)]
+    read_only: bool,
+    /// Let other users access the mount, which `/etc/fuse.conf` has to allow
+    #[clap(long)]
+    allow_other: bool,
+}
+
+fn main() -> io::Result<()> {
+    let opts = Options::parse();
+
+    let transport = TcpStream::connect((opts.host.as_str(), opts.port))?;
+    transport.set_nodelay(true)?;
+    let attr_timeout = Duration::from_secs_f64(opts.attr_timeout);
+    let mut client = nfs4_client::Client::new(transport)?;
+    // What the client caches is under `InodeFs`'s own cache, so it can't be kept any longer.
+    client.enable_cache(nfs4_client::CacheConfig::fixed(attr_timeout));
+    let root = client.look_up(&opts.path)?;
+    let fs = InodeFs::new(client, root, attr_timeout)?;
+
+    let mut mount_options = vec![
+        MountOption::FSName(format!("{}:{}", opts.host, opts.path.display())),
+        MountOption::Subtype("nfs4".into()),
+        MountOption::DefaultPermissions,
+        MountOption::NoDev,
+        MountOption::NoSuid,
+    ];
+    if opts.read_only {
+        mount_options.push(MountOption::RO);
+    }
+    if opts.allow_other {
+        mount_options.push(MountOption::AllowOther);
+    }
+    fuser::mount2(Fuse::new(fs), &opts.mount_point, &mount_options)
+}
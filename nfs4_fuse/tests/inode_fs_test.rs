@@ -0,0 +1,157 @@
+// Copyright Remi Bernotavicius
+
+use nfs4_client::{Client, MemoryFs, RemoteFs, SetAttributes};
+use nfs4_fuse::{InodeFs, ROOT_INODE};
+use nfs4_test_server::TestServer;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+fn inode_fs<FsT: RemoteFs>(mut fs: FsT, attr_timeout: Duration) -> InodeFs<FsT> {
+    let root = fs.root().unwrap();
+    InodeFs::new(fs, root, attr_timeout).unwrap()
+}
+
+/// Does what a session of FUSE requests would, so that both filesystems are held to it.
+fn exercise<FsT: RemoteFs>(fs: &mut InodeFs<FsT>) {
+    assert!(fs.getattr(ROOT_INODE).unwrap().is_dir());
+
+    let (dir, metadata) = fs.mkdir(ROOT_INODE, "dir", 0o750).unwrap();
+    assert_eq!(metadata.mode, 0o750);
+    let (file, metadata) = fs.create(dir, "file", 0o640, true).unwrap();
+    assert_eq!(metadata.mode, 0o640);
+    assert_eq!(
+        fs.create(dir, "file", 0o600, true).unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+    // Without `O_EXCL` the file which is there is used as it is.
+    assert_eq!(fs.create(dir, "file", 0o600, false).unwrap().0, file);
+    assert_eq!(fs.getattr(file).unwrap().mode, 0o640);
+
+    fs.write(file, 0, b"hello world").unwrap();
+    fs.write(file, 6, b"there").unwrap();
+    assert_eq!(fs.read(file, 0, 100).unwrap(), b"hello there");
+    assert_eq!(fs.read(file, 6, 3).unwrap(), b"the");
+    assert_eq!(fs.read(file, 100, 10).unwrap(), b"");
+    assert_eq!(fs.getattr(file).unwrap().size, 11);
+
+    let metadata = fs
+        .setattr(
+            file,
+            SetAttributes {
+                size: Some(5),
+                mode: Some(0o600),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!((metadata.size, metadata.mode), (5, 0o600));
+    assert_eq!(fs.read(file, 0, 100).unwrap(), b"hello");
+
+    let (link, metadata) = fs.symlink(dir, "link", "file").unwrap();
+    assert!(metadata.is_symlink());
+    assert_eq!(fs.readlink(link).unwrap(), "file");
+
+    assert_eq!(fs.lookup(ROOT_INODE, "dir").unwrap().0, dir);
+    assert_eq!(fs.lookup(dir, "file").unwrap().0, file);
+    assert_eq!(
+        fs.lookup(dir, "missing").unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    let mut entries: Vec<_> = fs
+        .readdir(dir)
+        .unwrap()
+        .into_iter()
+        .map(|e| (e.name, e.inode))
+        .collect();
+    entries.sort();
+    assert_eq!(entries, [("file".into(), file), ("link".into(), link)]);
+
+    fs.rename(dir, "file", ROOT_INODE, "moved").unwrap();
+    assert_eq!(fs.lookup(ROOT_INODE, "moved").unwrap().0, file);
+    assert_eq!(fs.read(file, 0, 100).unwrap(), b"hello");
+
+    assert_eq!(
+        fs.remove(ROOT_INODE, "dir", false).unwrap_err().kind(),
+        ErrorKind::IsADirectory
+    );
+    assert_eq!(
+        fs.remove(ROOT_INODE, "moved", true).unwrap_err().kind(),
+        ErrorKind::NotADirectory
+    );
+    assert_eq!(
+        fs.remove(ROOT_INODE, "dir", true).unwrap_err().kind(),
+        ErrorKind::DirectoryNotEmpty
+    );
+    fs.remove(dir, "link", false).unwrap();
+    fs.remove(ROOT_INODE, "dir", true).unwrap();
+    fs.remove(ROOT_INODE, "moved", false).unwrap();
+    assert!(fs.readdir(ROOT_INODE).unwrap().is_empty());
+}
+
+#[test]
+fn memory_fs() {
+    exercise(&mut inode_fs(MemoryFs::new(), Duration::ZERO));
+}
+
+#[test]
+fn client() {
+    let server = TestServer::new();
+    let client: Client<TcpStream> = Client::new(server.connect()).unwrap();
+    exercise(&mut inode_fs(client, Duration::from_secs(60)));
+}
+
+#[test]
+fn attribute_cache() {
+    let server = TestServer::new();
+    server.write_file("/file", b"abc");
+    let client: Client<TcpStream> = Client::new(server.connect()).unwrap();
+    let mut fs = inode_fs(client, Duration::from_secs(60));
+
+    let (file, _) = fs.lookup(ROOT_INODE, "file").unwrap();
+    let before = server.total_compounds();
+    assert_eq!(fs.getattr(file).unwrap().size, 3);
+    assert_eq!(server.total_compounds(), before);
+
+    // Changes made elsewhere aren't seen until the attributes time out...
+    server.write_file("/file", b"abcdef");
+    assert_eq!(fs.getattr(file).unwrap().size, 3);
+
+    // ...but ones made through the filesystem are.
+    fs.write(file, 6, b"g").unwrap();
+    assert_eq!(fs.getattr(file).unwrap().size, 7);
+
+    let client: Client<TcpStream> = Client::new(server.connect()).unwrap();
+    let mut fs = inode_fs(client, Duration::ZERO);
+    let (file, _) = fs.lookup(ROOT_INODE, "file").unwrap();
+    server.write_file("/file", b"a");
+    assert_eq!(fs.getattr(file).unwrap().size, 1);
+}
+
+#[test]
+fn subdirectory_root() {
+    let server = TestServer::new();
+    server.create_dir("/export");
+    server.write_file("/export/file", b"abc");
+    let mut client: Client<TcpStream> = Client::new(server.connect()).unwrap();
+    let root = client.lookup_path(Path::new("/export")).unwrap();
+    let mut fs = InodeFs::new(client, root, Duration::ZERO).unwrap();
+
+    let (file, _) = fs.lookup(ROOT_INODE, "file").unwrap();
+    assert_ne!(file, ROOT_INODE);
+    assert_eq!(fs.read(file, 0, 10).unwrap(), b"abc");
+    let entries = fs.readdir(ROOT_INODE).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].inode, file);
+
+    let mut client: Client<TcpStream> = Client::new(server.connect()).unwrap();
+    let file = client.lookup_path(Path::new("/export/file")).unwrap();
+    assert_eq!(
+        InodeFs::new(client, file, Duration::ZERO)
+            .err()
+            .unwrap()
+            .kind(),
+        ErrorKind::NotADirectory
+    );
+}
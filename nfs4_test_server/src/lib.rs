@@ -0,0 +1,1651 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! A small in-memory NFSv4.1 (and just enough of 4.2) server listening on localhost. It is for tests which need server
+//! behaviour the VM's Linux server doesn't provide, like pNFS file layouts or more than one
+//! server. Only the operations the client uses are implemented, anything else fails the compound
+//! with `NotSupported`. It also speaks NFSv3, MOUNT and NLM, and acts as its own portmapper.
+
+mod lock_server;
+mod nfs3_server;
+
+use nfs4::*;
+use std::collections::BTreeMap;
+use std::io::{self, Write as _};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use sun_rpc::{AcceptedReplyBody, Message, MessageBody, Xid};
+
+const ROOT_ID: u64 = 1;
+const NULL_PROCEDURE: u32 = 0;
+const COMPOUND_PROCEDURE: u32 = 1;
+const LAYOUT_DEVICE_ID: DeviceId = DeviceId([1; 16]);
+
+pub const MAX_READ: u64 = 64 * 1024;
+pub const MAX_WRITE: u64 = 64 * 1024;
+
+/// How many of each I/O operation the server has handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoCounts {
+    pub reads: usize,
+    pub writes: usize,
+    pub commits: usize,
+}
+
+/// Makes the server hand out `NfsV41Files` layouts striping files across the given data servers.
+#[derive(Clone, Debug)]
+pub struct FilesLayoutConfig {
+    pub data_servers: Vec<SocketAddr>,
+    pub stripe_unit: u32,
+    pub dense: bool,
+    pub commit_through_mds: bool,
+}
+
+/// Makes the server hand out `FlexFiles` layouts. Each mirror is a list of data servers the file
+/// is striped across.
+#[derive(Clone, Debug)]
+pub struct FlexFilesConfig {
+    pub mirrors: Vec<Vec<SocketAddr>>,
+    pub stripe_unit: u64,
+    pub flags: FlexFilesFlags,
+    /// Tell clients to use NFSv3 with the data servers rather than NFSv4.1.
+    pub nfs3: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Act as a pNFS data server. Unknown file handles are treated as empty files.
+    pub data_server: bool,
+    pub files_layout: Option<FilesLayoutConfig>,
+    pub flex_files_layout: Option<FlexFilesConfig>,
+    /// Caps the max response size CREATE_SESSION agrees to, so replies like READDIR have to be
+    /// split up.
+    pub max_response_size: Option<u32>,
+}
+
+/// The device id for data server `index` of `mirror` in a `FlexFiles` layout.
+pub fn flex_files_device_id(mirror: usize, index: usize) -> DeviceId {
+    let mut id = [0; 16];
+    id[0] = 2;
+    id[1..9].copy_from_slice(&(mirror as u64).to_be_bytes());
+    id[9..].copy_from_slice(&(index as u64).to_be_bytes()[1..]);
+    DeviceId(id)
+}
+
+enum NodeKind {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, u64>),
+    Symlink(String),
+    /// A device, socket or FIFO.
+    Special(FileType, DeviceData),
+    /// Where a filesystem which lives on another server is mounted.
+    Referral(FsLocations),
+}
+
+struct Node {
+    kind: NodeKind,
+    parent: u64,
+    mode: u32,
+    /// How many directory entries refer to the node, for anything but a directory.
+    links: u32,
+    change: u64,
+    modified: Time,
+    /// Size reported through LAYOUTCOMMIT, for files whose data lives on data servers.
+    layout_size: u64,
+    /// The major number of the filesystem the node is in.
+    fs: u64,
+}
+
+impl Node {
+    fn new(kind: NodeKind, parent: u64, mode: u32) -> Self {
+        Self {
+            kind,
+            parent,
+            mode,
+            links: 1,
+            change: 1,
+            modified: SystemTime::now().into(),
+            layout_size: 0,
+            fs: 1,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match &self.kind {
+            NodeKind::File(_) => FileType::Regular,
+            NodeKind::Directory(_) | NodeKind::Referral(_) => FileType::Directory,
+            NodeKind::Symlink(_) => FileType::Link,
+            NodeKind::Special(file_type, _) => file_type.clone(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match &self.kind {
+            NodeKind::File(data) => (data.len() as u64).max(self.layout_size),
+            NodeKind::Directory(entries) => entries.len() as u64,
+            NodeKind::Symlink(target) => target.len() as u64,
+            NodeKind::Special(..) | NodeKind::Referral(_) => 0,
+        }
+    }
+
+    fn touch(&mut self) {
+        self.change += 1;
+        self.modified = SystemTime::now().into();
+    }
+}
+
+fn handle_for(id: u64) -> FileHandle {
+    FileHandle(id.to_be_bytes().to_vec())
+}
+
+fn id_for(handle: &FileHandle) -> Result<u64, StatusError> {
+    Ok(u64::from_be_bytes(
+        handle.0[..]
+            .try_into()
+            .map_err(|_| StatusError::BadHandle)?,
+    ))
+}
+
+fn splice(data: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    let end = offset + bytes.len();
+    if data.len() < end {
+        data.resize(end, 0);
+    }
+    data[offset..end].copy_from_slice(bytes);
+}
+
+fn no_device() -> DeviceData {
+    DeviceData { major: 0, minor: 0 }
+}
+
+fn change_info(before: u64, after: u64) -> ChangeInfo {
+    ChangeInfo {
+        atomic: true,
+        before: ChangeId(before),
+        after: ChangeId(after),
+    }
+}
+
+fn supported_attrs() -> EnumSet<FileAttributeId> {
+    [
+        FileAttributeId::SupportedAttrs,
+        FileAttributeId::Type,
+        FileAttributeId::Change,
+        FileAttributeId::Size,
+        FileAttributeId::FsId,
+        FileAttributeId::FileHandle,
+        FileAttributeId::FileId,
+        FileAttributeId::LeaseTime,
+        FileAttributeId::MaxRead,
+        FileAttributeId::MaxWrite,
+        FileAttributeId::Mode,
+        FileAttributeId::NumLinks,
+        FileAttributeId::Owner,
+        FileAttributeId::OwnerGroup,
+        FileAttributeId::RawDev,
+        FileAttributeId::TimeModify,
+        FileAttributeId::TimeModifySet,
+        FileAttributeId::FsLocations,
+        FileAttributeId::MountedOnFileid,
+        FileAttributeId::FsLayoutType,
+        FileAttributeId::FsLocationsInfo,
+    ]
+    .into_iter()
+    .collect()
+}
+
+#[derive(Default)]
+struct Connection {
+    id: u64,
+    /// The `ServerOwner` minor id of the address the connection came in on.
+    minor_id: u64,
+    current: Option<u64>,
+    saved: Option<u64>,
+}
+
+impl Connection {
+    fn current(&self) -> Result<u64, StatusError> {
+        self.current.ok_or(StatusError::NoFileHandle)
+    }
+}
+
+struct ServerState {
+    config: ServerConfig,
+    owner: Vec<u8>,
+    port: u16,
+    nodes: BTreeMap<u64, Node>,
+    next_id: u64,
+    next_client_id: u64,
+    next_state_id: u32,
+    next_connection_id: u64,
+    /// Client ids by client owner, so an EXCHANGE_ID from a known owner gets its existing id.
+    clients: BTreeMap<Vec<u8>, ClientId>,
+    sessions: Vec<SessionId>,
+    /// The connections bound to each session, which are the only ones it can be used on.
+    bound_connections: Vec<(SessionId, u64)>,
+    /// How many SEQUENCE compounds came in on each connection.
+    connection_compounds: BTreeMap<u64, usize>,
+    write_verifier: Verifier,
+    io_error: Option<StatusError>,
+    layout_errors: Vec<LayoutErrorArgs>,
+    layout_stats: Vec<LayoutStatsArgs>,
+    layout_returns: Vec<LayoutReturnArgs>,
+    io_counts: IoCounts,
+    /// How many OPENs haven't been closed yet.
+    open_files: usize,
+    /// The most a READ returns, for `set_read_limit`.
+    read_limit: Option<u32>,
+    /// What files looked like before their uncommitted UNSTABLE writes, for `crash`.
+    uncommitted: BTreeMap<u64, Vec<u8>>,
+    latency: Duration,
+    lock_manager: lock_server::LockManager,
+    /// Reported for every node, so clients know where replicas are.
+    fs_locations_info: Option<FsLocationsInfo>,
+    /// Set by `migrate`, to have SEQUENCE say so.
+    lease_moved: bool,
+    /// Set by `shut_down`, to close connections as they are used.
+    down: bool,
+}
+
+fn wrap<T>(
+    res: Result<T, StatusError>,
+    op: impl FnOnce(StatusResult<T>) -> ResOp,
+) -> (ResOp, Option<StatusError>) {
+    match res {
+        Ok(v) => (op(StatusResult::Ok(v)), None),
+        Err(e) => (op(StatusResult::Err(e.clone())), Some(e)),
+    }
+}
+
+impl ServerState {
+    fn new(config: ServerConfig, owner: Vec<u8>, port: u16) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            ROOT_ID,
+            Node::new(NodeKind::Directory(BTreeMap::new()), ROOT_ID, 0o755),
+        );
+        Self {
+            config,
+            owner,
+            port,
+            nodes,
+            next_id: ROOT_ID + 1,
+            next_client_id: 1,
+            next_state_id: 1,
+            next_connection_id: 1,
+            clients: BTreeMap::new(),
+            sessions: vec![],
+            bound_connections: vec![],
+            connection_compounds: BTreeMap::new(),
+            write_verifier: Verifier(0x5e5e),
+            io_error: None,
+            layout_errors: vec![],
+            layout_stats: vec![],
+            layout_returns: vec![],
+            io_counts: IoCounts::default(),
+            open_files: 0,
+            read_limit: None,
+            uncommitted: BTreeMap::new(),
+            latency: Duration::ZERO,
+            lock_manager: lock_server::LockManager::new(),
+            fs_locations_info: None,
+            lease_moved: false,
+            down: false,
+        }
+    }
+
+    fn node(&self, id: u64) -> Result<&Node, StatusError> {
+        self.nodes.get(&id).ok_or(StatusError::Stale)
+    }
+
+    fn node_mut(&mut self, id: u64) -> Result<&mut Node, StatusError> {
+        self.nodes.get_mut(&id).ok_or(StatusError::Stale)
+    }
+
+    fn resolve(&self, path: &str) -> Result<u64, StatusError> {
+        let mut id = ROOT_ID;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            id = *self.entries(id)?.get(name).ok_or(StatusError::NoEnt)?;
+        }
+        Ok(id)
+    }
+
+    fn file_data_mut(&mut self, id: u64) -> Result<&mut Vec<u8>, StatusError> {
+        if self.config.data_server && !self.nodes.contains_key(&id) {
+            self.nodes
+                .insert(id, Node::new(NodeKind::File(vec![]), ROOT_ID, 0o644));
+        }
+        match &mut self.node_mut(id)?.kind {
+            NodeKind::File(data) => Ok(data),
+            NodeKind::Directory(_) => Err(StatusError::Isdir),
+            NodeKind::Symlink(_) | NodeKind::Special(..) | NodeKind::Referral(_) => {
+                Err(StatusError::Inval)
+            }
+        }
+    }
+
+    /// Writes to a file. UNSTABLE writes are lost by `crash` until they are committed.
+    fn write_data(
+        &mut self,
+        id: u64,
+        offset: u64,
+        bytes: &[u8],
+        unstable: bool,
+    ) -> Result<(), StatusError> {
+        if unstable && !self.uncommitted.contains_key(&id) {
+            let before = self.file_data_mut(id)?.clone();
+            self.uncommitted.insert(id, before);
+        }
+        splice(self.file_data_mut(id)?, offset as usize, bytes);
+        if !unstable {
+            if let Some(before) = self.uncommitted.get_mut(&id) {
+                splice(before, offset as usize, bytes);
+            }
+        }
+        self.node_mut(id)?.touch();
+        Ok(())
+    }
+
+    fn commit_data(&mut self, id: u64) {
+        self.uncommitted.remove(&id);
+    }
+
+    /// Throws away uncommitted writes and changes the write verifier, like a reboot would.
+    fn crash(&mut self) {
+        for (id, before) in std::mem::take(&mut self.uncommitted) {
+            if let Ok(data) = self.file_data_mut(id) {
+                *data = before;
+            }
+        }
+        self.write_verifier.0 += 1;
+    }
+
+    fn entries(&self, id: u64) -> Result<&BTreeMap<String, u64>, StatusError> {
+        match &self.node(id)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            NodeKind::Referral(_) => Err(StatusError::Moved),
+            _ => Err(StatusError::NotDir),
+        }
+    }
+
+    /// Like `entries`, but a symlink is `Symlink` rather than `NotDir`, as LOOKUP and LOOKUPP
+    /// report it so clients know to follow it.
+    fn lookup_entries(&self, id: u64) -> Result<&BTreeMap<String, u64>, StatusError> {
+        match &self.node(id)?.kind {
+            NodeKind::Symlink(_) => Err(StatusError::Symlink),
+            _ => self.entries(id),
+        }
+    }
+
+    fn entries_mut(&mut self, id: u64) -> Result<&mut BTreeMap<String, u64>, StatusError> {
+        match &mut self.node_mut(id)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(StatusError::NotDir),
+        }
+    }
+
+    fn new_state_id(&mut self) -> StateId {
+        let mut other = [0; 12];
+        other[..4].copy_from_slice(&self.next_state_id.to_be_bytes());
+        self.next_state_id += 1;
+        StateId {
+            sequence_id: 1,
+            other,
+        }
+    }
+
+    /// Adds a new node to the given directory, returning its id and the directory's change info.
+    fn insert_node(
+        &mut self,
+        parent: u64,
+        name: &str,
+        kind: NodeKind,
+        mode: u32,
+    ) -> Result<(u64, ChangeInfo), StatusError> {
+        if self.entries(parent)?.contains_key(name) {
+            return Err(StatusError::Exist);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut node = Node::new(kind, parent, mode);
+        node.fs = self.node(parent)?.fs;
+        self.nodes.insert(id, node);
+        self.entries_mut(parent)?.insert(name.into(), id);
+
+        let dir = self.node_mut(parent)?;
+        let before = dir.change;
+        dir.touch();
+        Ok((id, change_info(before, dir.change)))
+    }
+
+    fn attributes(
+        &self,
+        id: u64,
+        request: &EnumSet<FileAttributeId>,
+    ) -> Result<FileAttributes, StatusError> {
+        let node = self.node(id)?;
+        if let NodeKind::Referral(locations) = &node.kind {
+            // The filesystem isn't here, so there is only enough to say where it went.
+            return Ok([
+                FileAttribute::Type(FileType::Directory),
+                FileAttribute::FsId(FsId {
+                    major: node.fs,
+                    minor: 0,
+                }),
+                FileAttribute::MountedOnFileid(FileId(id)),
+                FileAttribute::FsLocations(locations.clone()),
+            ]
+            .into_iter()
+            .filter(|a| request.contains(a.to_id()))
+            .collect());
+        }
+        let num_links = match &node.kind {
+            NodeKind::Directory(entries) => {
+                2 + entries
+                    .values()
+                    .filter(|c| matches!(self.nodes[c].kind, NodeKind::Directory(_)))
+                    .count() as u32
+            }
+            _ => node.links,
+        };
+        let raw_dev = match &node.kind {
+            NodeKind::Special(_, device) => device.clone(),
+            _ => no_device(),
+        };
+        let mut layout_types = vec![];
+        if self.config.files_layout.is_some() {
+            layout_types.push(LayoutType::NfsV41Files);
+        }
+        if self.config.flex_files_layout.is_some() {
+            layout_types.push(LayoutType::FlexFiles);
+        }
+        Ok([
+            FileAttribute::SupportedAttrs(supported_attrs()),
+            FileAttribute::Type(node.file_type()),
+            FileAttribute::Change(Change(node.change)),
+            FileAttribute::Size(node.size()),
+            FileAttribute::FsId(FsId {
+                major: node.fs,
+                minor: 0,
+            }),
+            FileAttribute::FileHandle(handle_for(id)),
+            FileAttribute::FileId(FileId(id)),
+            FileAttribute::LeaseTime(Lease(90)),
+            FileAttribute::MaxRead(MAX_READ),
+            FileAttribute::MaxWrite(MAX_WRITE),
+            FileAttribute::Mode(Mode(node.mode)),
+            FileAttribute::NumLinks(num_links),
+            FileAttribute::Owner("0".into()),
+            FileAttribute::OwnerGroup("0".into()),
+            FileAttribute::RawDev(raw_dev),
+            FileAttribute::TimeModify(node.modified),
+            FileAttribute::MountedOnFileid(FileId(id)),
+            FileAttribute::FsLayoutType(layout_types),
+        ]
+        .into_iter()
+        .chain(
+            self.fs_locations_info
+                .clone()
+                .map(FileAttribute::FsLocationsInfo),
+        )
+        .filter(|a| request.contains(a.to_id()))
+        .collect())
+    }
+
+    fn compound(&mut self, conn: &mut Connection, args: CompoundArgs) -> CompoundRes {
+        let mut res_array = vec![];
+        let mut status = StatusResult::Ok(());
+        if !(1..=2).contains(&args.minor_version) {
+            status = StatusResult::Err(StatusError::MinorVersMismatch);
+        }
+        for op in args.arg_array {
+            if let StatusResult::Err(_) = status {
+                break;
+            }
+            match self.execute(conn, op) {
+                Some((res, error)) => {
+                    res_array.push(res);
+                    if let Some(error) = error {
+                        status = StatusResult::Err(error);
+                    }
+                }
+                None => status = StatusResult::Err(StatusError::NotSupported),
+            }
+        }
+        CompoundRes {
+            status,
+            tag: args.tag,
+            res_array,
+        }
+    }
+
+    fn execute(
+        &mut self,
+        conn: &mut Connection,
+        op: ArgOp,
+    ) -> Option<(ResOp, Option<StatusError>)> {
+        Some(match op {
+            ArgOp::ExchangeId(args) => wrap(self.exchange_id(conn, args), ResOp::ExchangeId),
+            ArgOp::CreateSession(args) => {
+                wrap(self.create_session(conn, args), ResOp::CreateSession)
+            }
+            ArgOp::DestroySession(args) => wrap(self.destroy_session(args), ResOp::DestroySession),
+            ArgOp::DestroyClientId(args) => {
+                wrap(self.destroy_client_id(args), ResOp::DestroyClientId)
+            }
+            ArgOp::BindConnToSession(args) => wrap(
+                self.bind_conn_to_session(conn, args),
+                ResOp::BindConnToSession,
+            ),
+            ArgOp::Sequence(args) => wrap(self.sequence(conn, args), ResOp::Sequence),
+            ArgOp::ReclaimComplete(_) => wrap(Ok(()), ResOp::ReclaimComplete),
+            ArgOp::PutRootFh => {
+                conn.current = Some(ROOT_ID);
+                wrap(Ok(()), ResOp::PutRootFh)
+            }
+            ArgOp::PutFh(args) => wrap(self.put_fh(conn, args), ResOp::PutFh),
+            ArgOp::GetFh => wrap(
+                conn.current().and_then(|id| {
+                    if let NodeKind::Referral(_) = self.node(id)?.kind {
+                        return Err(StatusError::Moved);
+                    }
+                    Ok(GetFhRes {
+                        object: handle_for(id),
+                    })
+                }),
+                ResOp::GetFh,
+            ),
+            ArgOp::SaveFh => {
+                let res = conn.current().map(|id| conn.saved = Some(id));
+                wrap(res, ResOp::SaveFh)
+            }
+            ArgOp::RestoreFh => {
+                let res = conn
+                    .saved
+                    .ok_or(StatusError::RestoreFh)
+                    .map(|id| conn.current = Some(id));
+                wrap(res, ResOp::RestoreFh)
+            }
+            ArgOp::LookUp(args) => wrap(self.look_up(conn, args), ResOp::LookUp),
+            ArgOp::LookUpP => wrap(self.look_up_parent(conn), ResOp::LookUpP),
+            ArgOp::GetAttr(args) => wrap(
+                conn.current().and_then(|id| {
+                    Ok(GetAttrRes {
+                        object_attributes: self.attributes(id, &args.attr_request)?,
+                    })
+                }),
+                ResOp::GetAttr,
+            ),
+            ArgOp::Verify(args) => wrap(
+                self.verify(conn, args.object_attributes, true),
+                ResOp::Verify,
+            ),
+            ArgOp::NVerify(args) => wrap(
+                self.verify(conn, args.object_attributes, false),
+                ResOp::NVerify,
+            ),
+            ArgOp::SetAttr(args) => {
+                let (status, res) = match self.set_attr(conn, args) {
+                    Ok(attr_set) => (StatusResult::Ok(()), SetAttrRes { attr_set }),
+                    Err(e) => (
+                        StatusResult::Err(e),
+                        SetAttrRes {
+                            attr_set: Default::default(),
+                        },
+                    ),
+                };
+                let error = match &status {
+                    StatusResult::Err(e) => Some(e.clone()),
+                    StatusResult::Ok(()) => None,
+                };
+                (ResOp::SetAttr(SetAttrStatusResult { status, res }), error)
+            }
+            ArgOp::Open(args) => {
+                let res = self.open(conn, args);
+                if res.is_ok() {
+                    self.open_files += 1;
+                }
+                wrap(res, ResOp::Open)
+            }
+            ArgOp::Close(args) => {
+                self.open_files = self.open_files.saturating_sub(1);
+                wrap(
+                    Ok(CloseRes {
+                        open_state_id: args.open_stateid,
+                    }),
+                    ResOp::Close,
+                )
+            }
+            ArgOp::Read(args) => {
+                self.io_counts.reads += 1;
+                wrap(self.read(conn, args), ResOp::Read)
+            }
+            ArgOp::Write(args) => {
+                self.io_counts.writes += 1;
+                wrap(self.write(conn, args), ResOp::Write)
+            }
+            ArgOp::Commit(_) => {
+                self.io_counts.commits += 1;
+                wrap(
+                    self.check_io().and_then(|()| {
+                        conn.current().map(|id| {
+                            self.commit_data(id);
+                            CommitRes {
+                                write_verifier: self.write_verifier.clone(),
+                            }
+                        })
+                    }),
+                    ResOp::Commit,
+                )
+            }
+            ArgOp::Create(args) => wrap(self.create(conn, args), ResOp::Create),
+            ArgOp::Link(args) => match self.link(conn, args) {
+                Ok(res) => (ResOp::Link(LockStatusResult::Ok(res)), None),
+                Err(error) => {
+                    let res = LockStatusResult::Err(LockStatusError {
+                        error: error.clone(),
+                        denied: None,
+                    });
+                    (ResOp::Link(res), Some(error))
+                }
+            },
+            ArgOp::Remove(args) => wrap(self.remove(conn, args), ResOp::Remove),
+            ArgOp::Rename(args) => wrap(self.rename(conn, args), ResOp::Rename),
+            ArgOp::ReadDir(args) => wrap(self.read_dir(conn, args), ResOp::ReadDir),
+            ArgOp::ReadLink => wrap(
+                conn.current().and_then(|id| match &self.node(id)?.kind {
+                    NodeKind::Symlink(link) => Ok(ReadLinkRes { link: link.clone() }),
+                    _ => Err(StatusError::Inval),
+                }),
+                ResOp::ReadLink,
+            ),
+            ArgOp::LayoutGet(args) => wrap(self.layout_get(conn, args), ResOp::LayoutGet),
+            ArgOp::GetDeviceInfo(args) => wrap(self.get_device_info(args), ResOp::GetDeviceInfo),
+            ArgOp::LayoutCommit(args) => wrap(self.layout_commit(conn, args), ResOp::LayoutCommit),
+            ArgOp::LayoutReturn(args) => {
+                self.layout_returns.push(args);
+                wrap(Ok(LayoutReturnRes { state_id: None }), ResOp::LayoutReturn)
+            }
+            ArgOp::LayoutError(args) => {
+                self.layout_errors.push(args);
+                wrap(Ok(()), ResOp::LayoutError)
+            }
+            ArgOp::LayoutStats(args) => {
+                self.layout_stats.push(args);
+                wrap(Ok(()), ResOp::LayoutStats)
+            }
+            _ => return None,
+        })
+    }
+
+    fn exchange_id(
+        &mut self,
+        conn: &Connection,
+        args: ExchangeIdArgs,
+    ) -> Result<ExchangeIdRes, StatusError> {
+        let owner_id = args.client_owner.owner_id;
+        let client_id = match self.clients.get(&owner_id) {
+            Some(client_id) => *client_id,
+            None => {
+                let client_id = ClientId(self.next_client_id);
+                self.next_client_id += 1;
+                self.clients.insert(owner_id, client_id);
+                client_id
+            }
+        };
+
+        let pnfs_flag = if self.config.data_server {
+            ExchangeIdFlags::USE_PNFS_DS
+        } else if self.config.files_layout.is_some() || self.config.flex_files_layout.is_some() {
+            ExchangeIdFlags::USE_PNFS_MDS
+        } else {
+            ExchangeIdFlags::USE_NON_PNFS
+        };
+        Ok(ExchangeIdRes {
+            client_id,
+            sequence_id: SequenceId(1),
+            flags: pnfs_flag,
+            state_protect: StateProtect::None,
+            server_owner: ServerOwner {
+                minor_id: conn.minor_id,
+                major_id: self.owner.clone(),
+            },
+            server_scope: ServerScope(self.owner.clone()),
+            server_impl_id: None,
+        })
+    }
+
+    fn create_session(
+        &mut self,
+        conn: &Connection,
+        args: CreateSessionArgs,
+    ) -> Result<CreateSessionRes, StatusError> {
+        let mut session_id = [0; 16];
+        session_id[..8].copy_from_slice(&args.client_id.0.to_be_bytes());
+        session_id[8..12].copy_from_slice(&(self.sessions.len() as u32).to_be_bytes());
+        let session_id = SessionId(session_id);
+        self.sessions.push(session_id);
+        self.bound_connections.push((session_id, conn.id));
+
+        let mut fore_channel_attrs = args.fore_channel_attrs;
+        if let Some(max) = self.config.max_response_size {
+            fore_channel_attrs.max_response_size = fore_channel_attrs.max_response_size.min(max);
+        }
+        Ok(CreateSessionRes {
+            session_id,
+            sequence_id: args.sequence_id,
+            flags: CreateSessionFlags::empty(),
+            fore_channel_attrs,
+            back_channel_attrs: args.back_channel_attrs,
+        })
+    }
+
+    fn destroy_session(&mut self, args: DestroySessionArgs) -> Result<(), StatusError> {
+        let len = self.sessions.len();
+        self.sessions.retain(|s| *s != args.session_id);
+        if self.sessions.len() == len {
+            return Err(StatusError::BadSession);
+        }
+        Ok(())
+    }
+
+    fn destroy_client_id(&mut self, args: DestroyClientIdArgs) -> Result<(), StatusError> {
+        let len = self.clients.len();
+        self.clients.retain(|_, id| *id != args.client_id);
+        if self.clients.len() == len {
+            return Err(StatusError::StaleClientId);
+        }
+        Ok(())
+    }
+
+    /// Connections have to be bound to a session before SEQUENCE works on them. A real server
+    /// only insists on that with state protection, this one always does to check clients bind.
+    fn bind_conn_to_session(
+        &mut self,
+        conn: &Connection,
+        args: BindConnToSessionArgs,
+    ) -> Result<BindConnToSessionRes, StatusError> {
+        if !self.sessions.contains(&args.session_id) {
+            return Err(StatusError::BadSession);
+        }
+        let binding = (args.session_id, conn.id);
+        if !self.bound_connections.contains(&binding) {
+            self.bound_connections.push(binding);
+        }
+        Ok(BindConnToSessionRes {
+            session_id: args.session_id,
+            direction: args.direction,
+            use_connection_in_rdma_mode: false,
+        })
+    }
+
+    fn sequence(
+        &mut self,
+        conn: &Connection,
+        args: SequenceArgs,
+    ) -> Result<SequenceRes, StatusError> {
+        if !self.sessions.contains(&args.session_id) {
+            return Err(StatusError::BadSession);
+        }
+        if !self.bound_connections.contains(&(args.session_id, conn.id)) {
+            return Err(StatusError::ConnNotBoundToSession);
+        }
+        *self.connection_compounds.entry(conn.id).or_default() += 1;
+        Ok(SequenceRes {
+            session_id: args.session_id,
+            sequence_id: args.sequence_id,
+            slot_id: args.slot_id,
+            highest_slot_id: args.highest_slot_id,
+            target_highest_slot_id: args.highest_slot_id,
+            status_flags: if self.lease_moved {
+                SequenceStatusFlags::LEASE_MOVED
+            } else {
+                SequenceStatusFlags::empty()
+            },
+        })
+    }
+
+    /// VERIFY succeeds when all the given attributes match, NVERIFY when any of them differs.
+    fn verify(
+        &self,
+        conn: &Connection,
+        expected: FileAttributes,
+        want_same: bool,
+    ) -> Result<(), StatusError> {
+        let ids = Attributes::from(expected.clone()).ids();
+        let same = self.attributes(conn.current()?, &ids)? == expected;
+        match (same, want_same) {
+            (true, false) => Err(StatusError::Same),
+            (false, true) => Err(StatusError::NotSame),
+            _ => Ok(()),
+        }
+    }
+
+    fn put_fh(&mut self, conn: &mut Connection, args: PutFhArgs) -> Result<(), StatusError> {
+        let id = id_for(&args.object)?;
+        if !self.config.data_server {
+            self.node(id)?;
+        }
+        conn.current = Some(id);
+        Ok(())
+    }
+
+    fn look_up(&mut self, conn: &mut Connection, args: LookUpArgs) -> Result<(), StatusError> {
+        let id = *self
+            .lookup_entries(conn.current()?)?
+            .get(&args.object_name)
+            .ok_or(StatusError::NoEnt)?;
+        conn.current = Some(id);
+        Ok(())
+    }
+
+    fn look_up_parent(&mut self, conn: &mut Connection) -> Result<(), StatusError> {
+        let current = conn.current()?;
+        self.lookup_entries(current)?;
+        if current == ROOT_ID {
+            return Err(StatusError::NoEnt);
+        }
+        conn.current = Some(self.node(current)?.parent);
+        Ok(())
+    }
+
+    fn set_attr(
+        &mut self,
+        conn: &mut Connection,
+        args: SetAttrArgs,
+    ) -> Result<EnumSet<FileAttributeId>, StatusError> {
+        let id = conn.current()?;
+        let mut attr_set = EnumSet::default();
+        let mut modified = None;
+        for attr in args.object_attributes.into_iter() {
+            let attr_id = attr.to_id();
+            match attr {
+                FileAttribute::Size(size) => self.file_data_mut(id)?.resize(size as usize, 0),
+                FileAttribute::Mode(mode) => self.node_mut(id)?.mode = mode.0 & 0o7777,
+                FileAttribute::Owner(_) | FileAttribute::OwnerGroup(_) => {}
+                FileAttribute::TimeModifySet(SetTime::SetToClientTime(time)) => {
+                    modified = Some(time)
+                }
+                FileAttribute::TimeModifySet(SetTime::SetToServerTime) => {}
+                _ => return Err(StatusError::AttrNotSupported),
+            }
+            attr_set.insert(attr_id);
+        }
+        let node = self.node_mut(id)?;
+        node.touch();
+        if let Some(modified) = modified {
+            node.modified = modified;
+        }
+        Ok(attr_set)
+    }
+
+    fn open(&mut self, conn: &mut Connection, args: OpenArgs) -> Result<OpenRes, StatusError> {
+        let current = conn.current()?;
+        let (id, change_info) = match args.claim {
+            OpenClaim::Null { file } => {
+                let dir_change = self.node(current)?.change;
+                match (self.entries(current)?.get(&file), args.open_how) {
+                    (Some(_), OpenFlag::OpenCreate(CreateHow::Guarded { .. })) => {
+                        return Err(StatusError::Exist)
+                    }
+                    (Some(id), _) => (*id, change_info(dir_change, dir_change)),
+                    (None, OpenFlag::OpenNoCreate) => return Err(StatusError::NoEnt),
+                    (None, OpenFlag::OpenCreate(_)) => {
+                        self.insert_node(current, &file, NodeKind::File(vec![]), 0o644)?
+                    }
+                }
+            }
+            OpenClaim::Fh => {
+                let change = self.node(current)?.change;
+                (current, change_info(change, change))
+            }
+            _ => return Err(StatusError::NotSupported),
+        };
+        if let NodeKind::Directory(_) = self.node(id)?.kind {
+            return Err(StatusError::Isdir);
+        }
+        conn.current = Some(id);
+        Ok(OpenRes {
+            state_id: self.new_state_id(),
+            change_info,
+            result_flags: OpenResult::empty(),
+            attribute_set: Default::default(),
+            delegation: OpenDelegation::None,
+        })
+    }
+
+    fn check_io(&self) -> Result<(), StatusError> {
+        match &self.io_error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn read(&mut self, conn: &mut Connection, args: ReadArgs) -> Result<ReadRes, StatusError> {
+        self.check_io()?;
+        let count = args.count.min(self.read_limit.unwrap_or(u32::MAX));
+        let data = self.file_data_mut(conn.current()?)?;
+        let start = (args.offset as usize).min(data.len());
+        let end = (start + count as usize).min(data.len());
+        Ok(ReadRes {
+            eof: end == data.len(),
+            data: data[start..end].to_vec(),
+        })
+    }
+
+    fn write(&mut self, conn: &mut Connection, args: WriteArgs) -> Result<WriteRes, StatusError> {
+        self.check_io()?;
+        let id = conn.current()?;
+        let unstable = args.stable == StableHow::Unstable;
+        self.write_data(id, args.offset, &args.data, unstable)?;
+        Ok(WriteRes {
+            count: args.data.len() as u32,
+            committed: args.stable,
+            write_veritifer: self.write_verifier.clone(),
+        })
+    }
+
+    fn create(
+        &mut self,
+        conn: &mut Connection,
+        args: CreateArgs,
+    ) -> Result<CreateRes, StatusError> {
+        let kind = match args.object_type {
+            CreateType::Directory => NodeKind::Directory(BTreeMap::new()),
+            CreateType::Link(target) => NodeKind::Symlink(target),
+            CreateType::Block(device) => NodeKind::Special(FileType::Block, device),
+            CreateType::Character(device) => NodeKind::Special(FileType::Character, device),
+            CreateType::Socket => NodeKind::Special(FileType::Socket, no_device()),
+            CreateType::Fifo => NodeKind::Special(FileType::Fifo, no_device()),
+        };
+        let mode = match args.create_attrs.get_as(FileAttributeId::Mode) {
+            Some(Mode(mode)) => *mode,
+            None => 0o755,
+        };
+        let (id, change_info) = self.insert_node(conn.current()?, &args.object_name, kind, mode)?;
+        conn.current = Some(id);
+        Ok(CreateRes {
+            change_info,
+            attribute_set: Default::default(),
+        })
+    }
+
+    /// Adds an entry for the saved file handle to the current directory.
+    fn link(&mut self, conn: &mut Connection, args: LinkArgs) -> Result<LinkRes, StatusError> {
+        let id = conn.saved.ok_or(StatusError::NoFileHandle)?;
+        let dir = conn.current()?;
+        if matches!(self.node(id)?.kind, NodeKind::Directory(_)) {
+            return Err(StatusError::Isdir);
+        }
+        let entries = self.entries_mut(dir)?;
+        if entries.contains_key(&args.new_name) {
+            return Err(StatusError::Exist);
+        }
+        entries.insert(args.new_name, id);
+        let node = self.node_mut(id)?;
+        node.links += 1;
+        node.change += 1;
+
+        let dir = self.node_mut(dir)?;
+        let before = dir.change;
+        dir.touch();
+        Ok(LinkRes {
+            change_info: change_info(before, dir.change),
+        })
+    }
+
+    fn remove(
+        &mut self,
+        conn: &mut Connection,
+        args: RemoveArgs,
+    ) -> Result<RemoveRes, StatusError> {
+        let dir = conn.current()?;
+        let id = *self
+            .entries(dir)?
+            .get(&args.target)
+            .ok_or(StatusError::NoEnt)?;
+        if let NodeKind::Directory(entries) = &self.node(id)?.kind {
+            if !entries.is_empty() {
+                return Err(StatusError::NotEmpty);
+            }
+        }
+        self.entries_mut(dir)?.remove(&args.target);
+        let node = self.node_mut(id)?;
+        node.links -= 1;
+        if node.links == 0 || matches!(node.kind, NodeKind::Directory(_)) {
+            self.nodes.remove(&id);
+        }
+
+        let dir = self.node_mut(dir)?;
+        let before = dir.change;
+        dir.touch();
+        Ok(RemoveRes {
+            change_info: change_info(before, dir.change),
+        })
+    }
+
+    fn rename(
+        &mut self,
+        conn: &mut Connection,
+        args: RenameArgs,
+    ) -> Result<RenameRes, StatusError> {
+        let source = conn.saved.ok_or(StatusError::NoFileHandle)?;
+        let target = conn.current()?;
+        let id = *self
+            .entries(source)?
+            .get(&args.old_name)
+            .ok_or(StatusError::NoEnt)?;
+        self.entries(target)?;
+
+        let source_before = self.node(source)?.change;
+        let target_before = self.node(target)?.change;
+        if let Some(replaced) = self.entries_mut(target)?.insert(args.new_name, id) {
+            if replaced != id {
+                self.nodes.remove(&replaced);
+            }
+        }
+        self.entries_mut(source)?.remove(&args.old_name);
+        self.node_mut(id)?.parent = target;
+        self.node_mut(source)?.touch();
+        if target != source {
+            self.node_mut(target)?.touch();
+        }
+        Ok(RenameRes {
+            source_change_info: change_info(source_before, self.node(source)?.change),
+            target_change_info: change_info(target_before, self.node(target)?.change),
+        })
+    }
+
+    fn read_dir(
+        &mut self,
+        conn: &mut Connection,
+        args: ReadDirArgs,
+    ) -> Result<ReadDirRes, StatusError> {
+        // Cookies 1 and 2 are reserved, so entry `n` gets cookie `n + 3`. They are positions in
+        // the directory, so any change to it makes them stale.
+        let dir = conn.current()?;
+        let cookie_verifier = Verifier(self.node(dir)?.change);
+        if args.cookie != Cookie::initial() && args.cookie_verifier != cookie_verifier {
+            return Err(StatusError::NotSame);
+        }
+        let skip = args.cookie.0.saturating_sub(2) as usize;
+        let all_entries = self.entries(dir)?;
+        if skip > all_entries.len() {
+            return Err(StatusError::BadCookie);
+        }
+
+        // Leaving room for the rest of the reply.
+        let mut size = 16;
+        let mut entries = vec![];
+        let mut eof = true;
+        for (i, (name, id)) in all_entries.iter().enumerate().skip(skip) {
+            let entry = DirectoryEntry {
+                cookie: Cookie(i as u64 + 3),
+                name: name.clone(),
+                attrs: self.attributes(*id, &args.attr_request)?,
+            };
+            size += serde_xdr::to_bytes(&entry).unwrap().len() + 4;
+            if size > args.max_count as usize {
+                eof = false;
+                break;
+            }
+            entries.push(entry);
+        }
+        if entries.is_empty() && !eof {
+            return Err(StatusError::TooSmall);
+        }
+        Ok(ReadDirRes {
+            cookie_verifier,
+            reply: DirectoryList { entries, eof },
+        })
+    }
+
+    fn layout_get(
+        &mut self,
+        conn: &mut Connection,
+        args: LayoutGetArgs,
+    ) -> Result<LayoutGetRes, StatusError> {
+        let id = conn.current()?;
+        self.file_data_mut(id)?;
+        let body = match args.layout_type {
+            LayoutType::NfsV41Files => self.files_layout(id)?,
+            LayoutType::FlexFiles => self.flex_files_layout(id)?,
+            _ => return Err(StatusError::UnknownLayoutType),
+        };
+        Ok(LayoutGetRes {
+            return_on_close: true,
+            state_id: self.new_state_id(),
+            layout: vec![Layout {
+                offset: 0,
+                length: u64::MAX,
+                io_mode: args.io_mode,
+                content: LayoutContent {
+                    type_: args.layout_type,
+                    body,
+                },
+            }],
+        })
+    }
+
+    fn files_layout(&self, id: u64) -> Result<Vec<u8>, StatusError> {
+        let config = self
+            .config
+            .files_layout
+            .as_ref()
+            .ok_or(StatusError::LayoutUnavailable)?;
+
+        let mut util = config.stripe_unit & Util::STRIPE_UNIT_SIZE_MASK;
+        if config.dense {
+            util |= Util::DENSE;
+        }
+        if config.commit_through_mds {
+            util |= Util::COMMIT_THRU_MDS;
+        }
+        let layout = FilesLayout {
+            device_id: LAYOUT_DEVICE_ID,
+            util: Util(util),
+            first_stripe_index: 0,
+            pattern_offset: 0,
+            fh_list: vec![handle_for(id)],
+        };
+        Ok(serde_xdr::to_bytes(&layout).unwrap())
+    }
+
+    fn flex_files_layout(&self, id: u64) -> Result<Vec<u8>, StatusError> {
+        let config = self
+            .config
+            .flex_files_layout
+            .as_ref()
+            .ok_or(StatusError::LayoutUnavailable)?;
+        let layout = FlexFilesLayout {
+            stripe_unit: config.stripe_unit,
+            mirrors: config
+                .mirrors
+                .iter()
+                .enumerate()
+                .map(|(m, data_servers)| FlexFilesMirror {
+                    data_servers: (0..data_servers.len())
+                        .map(|i| FlexFilesDataServer {
+                            device_id: flex_files_device_id(m, i),
+                            efficiency: 0,
+                            state_id: StateId::anonymous(),
+                            fh_versions: vec![handle_for(id)],
+                            user: "0".into(),
+                            group: "0".into(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+            flags: config.flags,
+            stats_collect_hint: 0,
+        };
+        Ok(serde_xdr::to_bytes(&layout).unwrap())
+    }
+
+    fn get_device_info(
+        &mut self,
+        args: GetDeviceInfoArgs,
+    ) -> Result<GetDeviceInfoRes, StatusError> {
+        let body = match args.layout_type {
+            LayoutType::NfsV41Files => self.files_device(args.device_id)?,
+            LayoutType::FlexFiles => self.flex_files_device(args.device_id)?,
+            _ => return Err(StatusError::UnknownLayoutType),
+        };
+        Ok(GetDeviceInfoRes {
+            device_addr: DeviceAddr {
+                layout_type: args.layout_type,
+                body,
+            },
+            notification: Default::default(),
+        })
+    }
+
+    fn flex_files_device(&self, device_id: DeviceId) -> Result<Vec<u8>, StatusError> {
+        let config = self
+            .config
+            .flex_files_layout
+            .as_ref()
+            .ok_or(StatusError::LayoutUnavailable)?;
+        let addr = config
+            .mirrors
+            .iter()
+            .enumerate()
+            .flat_map(|(m, ds)| ds.iter().enumerate().map(move |(i, a)| (m, i, a)))
+            .find(|(m, i, _)| flex_files_device_id(*m, *i) == device_id)
+            .map(|(_, _, a)| *a)
+            .ok_or(StatusError::NoEnt)?;
+        let device = FlexFilesDeviceAddr {
+            netaddrs: MultipathList(vec![NetAddr::from(addr)]),
+            versions: vec![FlexFilesDeviceVersion {
+                version: if config.nfs3 { 3 } else { 4 },
+                minor_version: if config.nfs3 { 0 } else { 1 },
+                rsize: MAX_READ as u32,
+                wsize: MAX_WRITE as u32,
+                tightly_coupled: false,
+            }],
+        };
+        Ok(serde_xdr::to_bytes(&device).unwrap())
+    }
+
+    fn files_device(&self, device_id: DeviceId) -> Result<Vec<u8>, StatusError> {
+        let config = self
+            .config
+            .files_layout
+            .as_ref()
+            .ok_or(StatusError::LayoutUnavailable)?;
+        if device_id != LAYOUT_DEVICE_ID {
+            return Err(StatusError::NoEnt);
+        }
+        let addr = FilesLayoutDsAddr {
+            stripe_indices: (0..config.data_servers.len() as u32).collect(),
+            multipath_ds_list: config
+                .data_servers
+                .iter()
+                .map(|a| MultipathList(vec![NetAddr::from(*a)]))
+                .collect(),
+        };
+        Ok(serde_xdr::to_bytes(&addr).unwrap())
+    }
+
+    fn layout_commit(
+        &mut self,
+        conn: &mut Connection,
+        args: LayoutCommitArgs,
+    ) -> Result<LayoutCommitRes, StatusError> {
+        let node = self.node_mut(conn.current()?)?;
+        let Some(last_write_offset) = args.last_write_offset else {
+            return Ok(LayoutCommitRes { new_size: None });
+        };
+        node.layout_size = node.layout_size.max(last_write_offset + 1);
+        node.touch();
+        Ok(LayoutCommitRes {
+            new_size: Some(node.size()),
+        })
+    }
+}
+
+fn reply<T: serde::Serialize>(xid: Xid, body: AcceptedReplyBody<T>) -> Vec<u8> {
+    sun_rpc_client::encode_reply(xid, body).unwrap()
+}
+
+fn handle_record(state: &Mutex<ServerState>, conn: &mut Connection, record: &[u8]) -> Vec<u8> {
+    let Some(header) = record.get(..24) else {
+        return reply(Xid(0), AcceptedReplyBody::<()>::GarbageArguments);
+    };
+    let word = |i: usize| u32::from_be_bytes(header[i * 4..][..4].try_into().unwrap());
+    let (xid, program, version, procedure) = (Xid(word(0)), word(3), word(4), word(5));
+    match (program, version, procedure) {
+        (_, _, NULL_PROCEDURE) => reply(xid, AcceptedReplyBody::Success(())),
+        (nfs3::NFS_PROGRAM, 4, COMPOUND_PROCEDURE) => {
+            match serde_xdr::from_bytes::<_, Message<CompoundArgs>>(record) {
+                Ok(Message {
+                    body: MessageBody::Call(call),
+                    ..
+                }) => {
+                    let res = state.lock().unwrap().compound(conn, call.call_args);
+                    reply(xid, AcceptedReplyBody::Success(res))
+                }
+                _ => reply(xid, AcceptedReplyBody::<()>::GarbageArguments),
+            }
+        }
+        (nfs3::NFS_PROGRAM, nfs3::NFS_VERSION, _) => {
+            state.lock().unwrap().handle_nfs3(xid, procedure, record)
+        }
+        (nfs3::mount::MOUNT_PROGRAM, nfs3::mount::MOUNT_VERSION, _) => {
+            state.lock().unwrap().handle_mount(xid, procedure, record)
+        }
+        (nfs3::nlm::NLM_PROGRAM, nfs3::nlm::NLM_VERSION, _) => state
+            .lock()
+            .unwrap()
+            .lock_manager
+            .handle_nlm(xid, procedure, record),
+        (nfs3::nsm::NSM_PROGRAM, nfs3::nsm::NSM_VERSION, _) => state
+            .lock()
+            .unwrap()
+            .lock_manager
+            .handle_nsm(xid, procedure, record),
+        (nfs3_server::PORT_MAPPER_PROGRAM, nfs3_server::PORT_MAPPER_VERSION, _) => state
+            .lock()
+            .unwrap()
+            .handle_port_mapper(xid, procedure, record),
+        (nfs3::NFS_PROGRAM, _, _) => reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+        _ => reply(xid, AcceptedReplyBody::<()>::ProgramUnavailable),
+    }
+}
+
+fn serve_connection(
+    state: Arc<Mutex<ServerState>>,
+    mut stream: TcpStream,
+    minor_id: u64,
+) -> io::Result<()> {
+    // Replies go out on their own thread, so with latency configured requests still overlap the
+    // way they would on a long network link.
+    let (sender, receiver) = mpsc::channel::<(Instant, Vec<u8>)>();
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    std::thread::spawn(move || {
+        for (due, reply) in receiver {
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            if writer.write_all(&reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut conn = {
+        let mut state = state.lock().unwrap();
+        let id = state.next_connection_id;
+        state.next_connection_id += 1;
+        Connection {
+            id,
+            minor_id,
+            ..Default::default()
+        }
+    };
+    while let Some(record) = sun_rpc_client::read_record(&mut stream)? {
+        if state.lock().unwrap().down {
+            stream.shutdown(std::net::Shutdown::Both)?;
+            break;
+        }
+        let due = Instant::now() + state.lock().unwrap().latency;
+        let reply = handle_record(&state, &mut conn, &record);
+        if sender.send((due, reply)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn accept_connections(listener: TcpListener, state: Arc<Mutex<ServerState>>, minor_id: u64) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if state.lock().unwrap().down {
+                continue;
+            }
+            let state = state.clone();
+            std::thread::spawn(move || serve_connection(state, stream, minor_id));
+        }
+    });
+}
+
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl TestServer {
+    /// Starts a server on a free localhost port. It keeps running until the process exits.
+    pub fn start(config: ServerConfig) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let owner = format!("nfs4_test_server-{}", addr.port()).into_bytes();
+        let state = Arc::new(Mutex::new(ServerState::new(config, owner, addr.port())));
+
+        accept_connections(listener, state.clone(), 0);
+        Self { addr, state }
+    }
+
+    pub fn new() -> Self {
+        Self::start(ServerConfig::default())
+    }
+
+    pub fn data_server() -> Self {
+        Self::start(ServerConfig {
+            data_server: true,
+            ..Default::default()
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        connect(self.addr)
+    }
+
+    /// Starts listening on another port as well, reporting the given `ServerOwner` minor id to
+    /// clients connecting there. The address on which the server was started has minor id 0, so
+    /// using that allows session trunking and anything else only client id trunking.
+    pub fn listen_on_new_port(&self, minor_id: u64) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        accept_connections(listener, self.state.clone(), minor_id);
+        addr
+    }
+
+    /// How many compounds each connection carried so far, in the order the connections were
+    /// made.
+    pub fn connection_compounds(&self) -> Vec<usize> {
+        let state = self.state.lock().unwrap();
+        state.connection_compounds.values().copied().collect()
+    }
+
+    /// How many compounds all connections carried so far.
+    pub fn total_compounds(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.connection_compounds.values().sum()
+    }
+
+    fn resolve(&self, path: &str) -> Option<u64> {
+        self.state.lock().unwrap().resolve(path).ok()
+    }
+
+    /// The contents of the regular file at the given path, if there is one.
+    pub fn file_contents(&self, path: &str) -> Option<Vec<u8>> {
+        let id = self.resolve(path)?;
+        self.handle_contents(&handle_for(id))
+    }
+
+    /// The data stored for the given handle. For a data server, this is the part of the file
+    /// striped to it.
+    pub fn handle_contents(&self, handle: &FileHandle) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        match &state.node(id_for(handle).ok()?).ok()?.kind {
+            NodeKind::File(data) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Creates or replaces a regular file at the given path. The parent directory must exist.
+    pub fn write_file(&self, path: &str, contents: &[u8]) {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self
+            .resolve(parent)
+            .expect("parent directory doesn't exist");
+        let mut state = self.state.lock().unwrap();
+        let id = match state.entries(parent).unwrap().get(name) {
+            Some(id) => *id,
+            None => {
+                state
+                    .insert_node(parent, name, NodeKind::File(vec![]), 0o644)
+                    .unwrap()
+                    .0
+            }
+        };
+        *state.file_data_mut(id).unwrap() = contents.to_vec();
+        state.node_mut(id).unwrap().touch();
+    }
+
+    /// Creates a directory at the given path. The parent directory must exist.
+    pub fn create_dir(&self, path: &str) {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self
+            .resolve(parent)
+            .expect("parent directory doesn't exist");
+        self.state
+            .lock()
+            .unwrap()
+            .insert_node(parent, name, NodeKind::Directory(BTreeMap::new()), 0o755)
+            .unwrap();
+    }
+
+    pub fn create_symlink(&self, path: &str, target: &str) {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self
+            .resolve(parent)
+            .expect("parent directory doesn't exist");
+        self.state
+            .lock()
+            .unwrap()
+            .insert_node(parent, name, NodeKind::Symlink(target.into()), 0o777)
+            .unwrap();
+    }
+
+    /// Adds another name for an existing file or directory. Unlike LINK, this allows linking
+    /// directories, so loops in the tree can be made.
+    pub fn hard_link(&self, existing: &str, path: &str) {
+        let id = self.resolve(existing).expect("file doesn't exist");
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self
+            .resolve(parent)
+            .expect("parent directory doesn't exist");
+        let mut state = self.state.lock().unwrap();
+        state.entries_mut(parent).unwrap().insert(name.into(), id);
+        state.node_mut(id).unwrap().links += 1;
+        state.node_mut(parent).unwrap().touch();
+    }
+
+    /// Creates a directory at the given path which is the root of a filesystem of its own, with
+    /// a different `FsId`. The parent directory must exist.
+    pub fn create_mount(&self, path: &str) {
+        self.insert_other_fs(path, NodeKind::Directory(BTreeMap::new()));
+    }
+
+    /// Makes the given path a referral to `root` on the server at `server`, whose address is
+    /// reported as `host:port`. The parent directory must exist.
+    pub fn create_referral(&self, path: &str, server: SocketAddr, root: &str) {
+        let path_name = |path: &str| {
+            PathName(
+                path.split('/')
+                    .filter(|c| !c.is_empty())
+                    .map(|c| Component(c.into()))
+                    .collect(),
+            )
+        };
+        let locations = FsLocations {
+            fs_root: path_name(path),
+            locations: vec![FsLocation {
+                server: vec![server.to_string()],
+                root_path: path_name(root),
+            }],
+        };
+        self.insert_other_fs(path, NodeKind::Referral(locations));
+    }
+
+    fn insert_other_fs(&self, path: &str, kind: NodeKind) {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self
+            .resolve(parent)
+            .expect("parent directory doesn't exist");
+        let mut state = self.state.lock().unwrap();
+        let fs = state.nodes.values().map(|n| n.fs).max().unwrap_or(1) + 1;
+        let (id, _) = state.insert_node(parent, name, kind, 0o755).unwrap();
+        state.node_mut(id).unwrap().fs = fs;
+    }
+
+    /// Makes READ, WRITE and COMMIT fail with the given error, or work again with `None`.
+    pub fn set_io_error(&self, error: Option<StatusError>) {
+        self.state.lock().unwrap().io_error = error;
+    }
+
+    /// Sets the `fs_locations_info` reported for everything on the server.
+    pub fn set_fs_locations_info(&self, info: FsLocationsInfo) {
+        self.state.lock().unwrap().fs_locations_info = Some(info);
+    }
+
+    /// Acts as though the filesystem moved elsewhere: READ, WRITE and COMMIT fail with `Moved`
+    /// and SEQUENCE replies have `LEASE_MOVED` set.
+    pub fn migrate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.io_error = Some(StatusError::Moved);
+        state.lease_moved = true;
+    }
+
+    /// Acts as though the server went down. Connections are closed when they are next used,
+    /// without a reply, and new ones right away.
+    pub fn shut_down(&self) {
+        self.state.lock().unwrap().down = true;
+    }
+
+    /// The LAYOUTERROR requests received so far.
+    pub fn layout_errors(&self) -> Vec<LayoutErrorArgs> {
+        self.state.lock().unwrap().layout_errors.clone()
+    }
+
+    /// The LAYOUTSTATS requests received so far.
+    pub fn layout_stats(&self) -> Vec<LayoutStatsArgs> {
+        self.state.lock().unwrap().layout_stats.clone()
+    }
+
+    /// Acts like the lock manager restarted: all locks are dropped, monitors registered with
+    /// statd are called back and only reclaims are allowed until `end_grace_period`.
+    pub fn reboot_lock_manager(&self) {
+        self.state.lock().unwrap().lock_manager.reboot();
+    }
+
+    pub fn end_grace_period(&self) {
+        self.state.lock().unwrap().lock_manager.end_grace_period();
+    }
+
+    /// Grants blocked locks with GRANTED_MSG rather than GRANTED, like Linux does, expecting the
+    /// client to send a GRANTED_RES back.
+    pub fn set_granted_msg(&self, granted_msg: bool) {
+        self.state.lock().unwrap().lock_manager.granted_msg = granted_msg;
+    }
+
+    /// The GRANTED_RES calls received so far.
+    pub fn granted_results(&self) -> Vec<nfs3::nlm::Res> {
+        self.state
+            .lock()
+            .unwrap()
+            .lock_manager
+            .granted_results
+            .clone()
+    }
+
+    /// Loses the writes which haven't been committed and changes the write verifier, as if the
+    /// server rebooted.
+    pub fn crash(&self) {
+        self.state.lock().unwrap().crash();
+    }
+
+    /// Delays every reply by the given amount, without holding up the requests behind it.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    pub fn io_counts(&self) -> IoCounts {
+        self.state.lock().unwrap().io_counts
+    }
+
+    /// Makes READ return at most `limit` bytes, without eof unless that is where the file ends,
+    /// as servers are allowed to. `None` goes back to returning all that was asked for.
+    pub fn set_read_limit(&self, limit: Option<u32>) {
+        self.state.lock().unwrap().read_limit = limit;
+    }
+
+    /// How many files have been opened and not closed again.
+    pub fn open_files(&self) -> usize {
+        self.state.lock().unwrap().open_files
+    }
+
+    /// The LAYOUTRETURN requests received so far.
+    pub fn layout_returns(&self) -> Vec<LayoutReturnArgs> {
+        self.state.lock().unwrap().layout_returns.clone()
+    }
+}
+
+/// Connects to a test server address, with Nagle's algorithm off.
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream
+}
+
+impl Default for TestServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
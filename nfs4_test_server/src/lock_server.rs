@@ -0,0 +1,274 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! NLM v4 and NSM, so NFSv3 clients can lock files and find out about server reboots. The same
+//! server plays the part of the client's statd, calling back monitors when `reboot` is called.
+
+use super::nfs3_server::call;
+use super::reply;
+use nfs3::nlm::{self, NetObj, NLM_PROGRAM};
+use nfs3::nsm;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use sun_rpc::{AcceptedReplyBody, Xid};
+use sun_rpc_client::RpcClient;
+
+#[derive(Clone)]
+struct LockEntry {
+    lock: nlm::Lock,
+    exclusive: bool,
+}
+
+fn end(lock: &nlm::Lock) -> u64 {
+    if lock.length == 0 {
+        u64::MAX
+    } else {
+        lock.offset.saturating_add(lock.length)
+    }
+}
+
+fn same_owner(a: &nlm::Lock, b: &nlm::Lock) -> bool {
+    a.caller_name == b.caller_name && a.owner == b.owner && a.svid == b.svid
+}
+
+fn overlaps(a: &nlm::Lock, b: &nlm::Lock) -> bool {
+    a.handle == b.handle && a.offset < end(b) && b.offset < end(a)
+}
+
+impl LockEntry {
+    fn conflicts_with(&self, other: &LockEntry) -> bool {
+        !same_owner(&self.lock, &other.lock)
+            && overlaps(&self.lock, &other.lock)
+            && (self.exclusive || other.exclusive)
+    }
+}
+
+/// Makes an RPC call on another thread, so callbacks don't hold up the server.
+fn call_back<Args: Serialize + Send + 'static>(
+    addr: SocketAddr,
+    program: u32,
+    version: u32,
+    procedure: u32,
+    args: Args,
+) {
+    std::thread::spawn(move || {
+        let Ok(stream) = TcpStream::connect(addr) else {
+            return;
+        };
+        let mut client = RpcClient::new(stream, program).with_version(version);
+        if client.send_request(procedure, args).is_ok() {
+            let _ = client.receive_reply::<()>();
+        }
+    });
+}
+
+pub(crate) struct LockManager {
+    locks: Vec<LockEntry>,
+    blocked: Vec<LockEntry>,
+    grace_period: bool,
+    state: i32,
+    monitors: Vec<nsm::Mon>,
+    /// The programs clients registered with the portmapper, as (program, version) to port. Every
+    /// client host is taken to be this one, so these are where callbacks go, on the loopback
+    /// address.
+    pub(crate) registrations: BTreeMap<(u32, u32), u16>,
+    /// Whether to call GRANTED_MSG rather than GRANTED.
+    pub(crate) granted_msg: bool,
+    pub(crate) granted_results: Vec<nlm::Res>,
+}
+
+impl LockManager {
+    pub(crate) fn new() -> Self {
+        Self {
+            locks: vec![],
+            blocked: vec![],
+            grace_period: false,
+            state: 1,
+            monitors: vec![],
+            registrations: BTreeMap::new(),
+            granted_msg: false,
+            granted_results: vec![],
+        }
+    }
+
+    /// Where a client registered the given program, as its portmapper would say.
+    fn registered_addr(&self, program: u32, version: u32) -> Option<SocketAddr> {
+        let port = *self.registrations.get(&(program, version))?;
+        Some(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+
+    fn conflict(&self, entry: &LockEntry) -> Option<&LockEntry> {
+        self.locks.iter().find(|l| entry.conflicts_with(l))
+    }
+
+    fn test(&self, args: nlm::TestArgs) -> nlm::TestRes {
+        let entry = LockEntry {
+            lock: args.lock,
+            exclusive: args.exclusive,
+        };
+        let reply = if self.grace_period {
+            nlm::TestReply::DeniedGracePeriod
+        } else if let Some(holder) = self.conflict(&entry) {
+            nlm::TestReply::Denied(nlm::Holder {
+                exclusive: holder.exclusive,
+                svid: holder.lock.svid,
+                owner: holder.lock.owner.clone(),
+                offset: holder.lock.offset,
+                length: holder.lock.length,
+            })
+        } else {
+            nlm::TestReply::Granted
+        };
+        nlm::TestRes {
+            cookie: args.cookie,
+            reply,
+        }
+    }
+
+    fn lock(&mut self, args: nlm::LockArgs) -> nlm::Status {
+        if self.grace_period && !args.reclaim {
+            return nlm::Status::DeniedGracePeriod;
+        }
+        let entry = LockEntry {
+            lock: args.lock,
+            exclusive: args.exclusive,
+        };
+        if self.conflict(&entry).is_none() {
+            self.locks.push(entry);
+            nlm::Status::Granted
+        } else if args.block {
+            self.blocked.push(entry);
+            nlm::Status::Blocked
+        } else {
+            nlm::Status::Denied
+        }
+    }
+
+    fn cancel(&mut self, args: nlm::CancelArgs) -> nlm::Status {
+        let len = self.blocked.len();
+        self.blocked.retain(|b| b.lock != args.lock);
+        if self.blocked.len() == len {
+            return nlm::Status::Denied;
+        }
+        nlm::Status::Granted
+    }
+
+    fn unlock(&mut self, args: nlm::UnlockArgs) -> nlm::Status {
+        self.locks
+            .retain(|l| !(same_owner(&l.lock, &args.lock) && overlaps(&l.lock, &args.lock)));
+        self.grant_blocked();
+        nlm::Status::Granted
+    }
+
+    /// Grants the blocked requests which no longer conflict, calling GRANTED or GRANTED_MSG on
+    /// their owners.
+    fn grant_blocked(&mut self) {
+        for entry in std::mem::take(&mut self.blocked) {
+            if self.conflict(&entry).is_some() {
+                self.blocked.push(entry);
+                continue;
+            }
+            if let Some(addr) = self.registered_addr(NLM_PROGRAM, nlm::NLM_VERSION) {
+                let args = nlm::TestArgs {
+                    cookie: NetObj::default(),
+                    exclusive: entry.exclusive,
+                    lock: entry.lock.clone(),
+                };
+                let procedure = if self.granted_msg {
+                    nlm::Procedure::GrantedMsg
+                } else {
+                    nlm::Procedure::Granted
+                };
+                call_back(addr, NLM_PROGRAM, nlm::NLM_VERSION, procedure.into(), args);
+            }
+            self.locks.push(entry);
+        }
+    }
+
+    fn free_all(&mut self, args: nlm::NotifyArgs) {
+        self.locks.retain(|l| l.lock.caller_name != args.name);
+        self.blocked.retain(|l| l.lock.caller_name != args.name);
+        self.grant_blocked();
+    }
+
+    /// Forgets all locks and starts a grace period, then notifies the monitors.
+    pub(crate) fn reboot(&mut self) {
+        self.state += 2;
+        self.locks.clear();
+        self.blocked.clear();
+        self.grace_period = true;
+        for mon in &self.monitors {
+            let my_id = &mon.mon_id.my_id;
+            let Some(addr) = self.registered_addr(my_id.my_program, my_id.my_version) else {
+                continue;
+            };
+            let status = nsm::Status {
+                mon_name: mon.mon_id.mon_name.clone(),
+                state: self.state,
+                private: mon.private,
+            };
+            call_back(
+                addr,
+                my_id.my_program,
+                my_id.my_version,
+                my_id.my_procedure,
+                status,
+            );
+        }
+    }
+
+    pub(crate) fn end_grace_period(&mut self) {
+        self.grace_period = false;
+    }
+
+    pub(crate) fn handle_nlm(&mut self, xid: Xid, procedure: u32, record: &[u8]) -> Vec<u8> {
+        let res = |cookie, status| nlm::Res { cookie, status };
+        match nlm::Procedure::try_from(procedure) {
+            Ok(nlm::Procedure::Null) => reply(xid, AcceptedReplyBody::Success(())),
+            Ok(nlm::Procedure::Test) => call(xid, record, |args| self.test(args)),
+            Ok(nlm::Procedure::Lock) => call(xid, record, |args: nlm::LockArgs| {
+                res(args.cookie.clone(), self.lock(args))
+            }),
+            Ok(nlm::Procedure::Cancel) => call(xid, record, |args: nlm::CancelArgs| {
+                res(args.cookie.clone(), self.cancel(args))
+            }),
+            Ok(nlm::Procedure::Unlock) => call(xid, record, |args: nlm::UnlockArgs| {
+                res(args.cookie.clone(), self.unlock(args))
+            }),
+            Ok(nlm::Procedure::FreeAll) => call(xid, record, |args| self.free_all(args)),
+            Ok(nlm::Procedure::GrantedRes) => call(xid, record, |args: nlm::Res| {
+                self.granted_results.push(args);
+            }),
+            _ => reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+        }
+    }
+
+    pub(crate) fn handle_nsm(&mut self, xid: Xid, procedure: u32, record: &[u8]) -> Vec<u8> {
+        let stat_res = |state| nsm::StatRes {
+            status: nsm::StatStatus::Success,
+            state,
+        };
+        match nsm::Procedure::try_from(procedure) {
+            Ok(nsm::Procedure::Null) => reply(xid, AcceptedReplyBody::Success(())),
+            Ok(nsm::Procedure::Stat) => call(xid, record, |_: nsm::Name| stat_res(self.state)),
+            Ok(nsm::Procedure::Monitor) => call(xid, record, |args: nsm::Mon| {
+                self.monitors.push(args);
+                stat_res(self.state)
+            }),
+            Ok(nsm::Procedure::Unmonitor) => call(xid, record, |args: nsm::MonId| {
+                self.monitors.retain(|m| m.mon_id != args);
+                nsm::State { state: self.state }
+            }),
+            Ok(nsm::Procedure::UnmonitorAll) => call(xid, record, |args: nsm::MyId| {
+                self.monitors.retain(|m| m.mon_id.my_id != args);
+                nsm::State { state: self.state }
+            }),
+            Ok(nsm::Procedure::SimulateCrash) => {
+                self.reboot();
+                reply(xid, AcceptedReplyBody::Success(()))
+            }
+            Ok(nsm::Procedure::Notify) => call(xid, record, |_: nsm::StatChange| ()),
+            _ => reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+        }
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2023 Remi Bernotavicius
+
+//! NFSv3, MOUNT v3 and a portmapper serving the same file tree as the NFSv4 server.
+
+use super::{handle_for, reply, Connection, NodeKind, ServerState, MAX_READ, MAX_WRITE};
+use nfs3::mount::{self, ExportEntry, ExportRes, MountArgs, MountRes, MOUNT_PROGRAM};
+use nfs3::nlm::NLM_PROGRAM;
+use nfs3::nsm::NSM_PROGRAM;
+use nfs3::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use sun_rpc::{AcceptedReplyBody, Mapping, Message, MessageBody, Xid, IPPROTO_TCP};
+
+pub(crate) const PORT_MAPPER_PROGRAM: u32 = 100000;
+pub(crate) const PORT_MAPPER_VERSION: u32 = 2;
+const SET_PROCEDURE: u32 = 1;
+const UNSET_PROCEDURE: u32 = 2;
+const GET_PORT_PROCEDURE: u32 = 3;
+
+type V4Error = nfs4::StatusError;
+
+pub(crate) fn call<Args: DeserializeOwned, Res: Serialize>(
+    xid: Xid,
+    record: &[u8],
+    body: impl FnOnce(Args) -> Res,
+) -> Vec<u8> {
+    match serde_xdr::from_bytes::<_, Message<Args>>(record) {
+        Ok(Message {
+            body: MessageBody::Call(call),
+            ..
+        }) => reply(xid, AcceptedReplyBody::Success(body(call.call_args))),
+        _ => reply(xid, AcceptedReplyBody::<()>::GarbageArguments),
+    }
+}
+
+/// The NFSv3 status codes are mostly the same numbers as the NFSv4 ones.
+fn status<T, F: Default>(res: Result<T, V4Error>) -> StatusResult<T, F> {
+    match res {
+        Ok(v) => StatusResult::Ok(v),
+        Err(e) => StatusResult::Err(
+            StatusError::try_from(e as u32).unwrap_or(StatusError::ServerFault),
+            F::default(),
+        ),
+    }
+}
+
+fn v3_handle(id: u64) -> FileHandle {
+    FileHandle(handle_for(id).0)
+}
+
+fn v3_time(time: nfs4::Time) -> Time {
+    Time {
+        seconds: time.seconds as u32,
+        nseconds: time.nseconds,
+    }
+}
+
+fn wcc(after: PostOpAttributes) -> WccData {
+    WccData {
+        before: None,
+        after,
+    }
+}
+
+impl ServerState {
+    /// A connection with the current file handle set to the given one, for reusing the NFSv4
+    /// operations.
+    fn v3_connection(&mut self, handle: FileHandle) -> Result<Connection, V4Error> {
+        let mut conn = Connection::default();
+        self.put_fh(
+            &mut conn,
+            nfs4::PutFhArgs {
+                object: nfs4::FileHandle(handle.0),
+            },
+        )?;
+        Ok(conn)
+    }
+
+    fn v3_attributes(&self, id: u64) -> Result<FileAttributes, V4Error> {
+        let node = self.node(id)?;
+        let (type_, num_links) = match &node.kind {
+            NodeKind::File(_) => (FileType::Regular, node.links),
+            NodeKind::Directory(_) | NodeKind::Referral(_) => (FileType::Directory, 2),
+            NodeKind::Symlink(_) => (FileType::Symlink, node.links),
+            NodeKind::Special(file_type, _) => {
+                let type_ = match file_type {
+                    nfs4::FileType::Block => FileType::Block,
+                    nfs4::FileType::Character => FileType::Character,
+                    nfs4::FileType::Socket => FileType::Socket,
+                    _ => FileType::Fifo,
+                };
+                (type_, node.links)
+            }
+        };
+        let time = v3_time(node.modified);
+        Ok(FileAttributes {
+            type_,
+            mode: node.mode,
+            num_links,
+            uid: 0,
+            gid: 0,
+            size: node.size(),
+            used: node.size(),
+            raw_device: SpecData::default(),
+            fsid: 1,
+            file_id: id,
+            access_time: time,
+            modify_time: time,
+            change_time: time,
+        })
+    }
+
+    fn post_op(&self, id: u64) -> PostOpAttributes {
+        self.v3_attributes(id).ok()
+    }
+
+    fn v3_get_attr(&mut self, args: GetAttrArgs) -> Result<GetAttrRes, V4Error> {
+        let id = self.v3_connection(args.object)?.current()?;
+        Ok(GetAttrRes {
+            object_attributes: self.v3_attributes(id)?,
+        })
+    }
+
+    fn v3_set_attr(&mut self, args: SetAttrArgs) -> Result<SetAttrRes, V4Error> {
+        let id = self.v3_connection(args.object)?.current()?;
+        if let Some(size) = args.new_attributes.size {
+            self.file_data_mut(id)?.resize(size as usize, 0);
+        }
+        if let Some(mode) = args.new_attributes.mode {
+            self.node_mut(id)?.mode = mode & 0o7777;
+        }
+        self.node_mut(id)?.touch();
+        Ok(SetAttrRes {
+            object_wcc: wcc(self.post_op(id)),
+        })
+    }
+
+    fn v3_look_up(&mut self, args: DirOpArgs) -> Result<LookUpRes, V4Error> {
+        let mut conn = self.v3_connection(args.dir)?;
+        let dir = conn.current()?;
+        match args.name.as_str() {
+            "." => {}
+            // NFSv3 has no LOOKUPP, `..` is looked up like any other name.
+            ".." => self.look_up_parent(&mut conn)?,
+            _ => self.look_up(
+                &mut conn,
+                nfs4::LookUpArgs {
+                    object_name: args.name,
+                },
+            )?,
+        }
+        let id = conn.current()?;
+        Ok(LookUpRes {
+            object: v3_handle(id),
+            object_attributes: self.post_op(id),
+            dir_attributes: self.post_op(dir),
+        })
+    }
+
+    fn v3_access(&mut self, args: AccessArgs) -> Result<AccessRes, V4Error> {
+        let id = self.v3_connection(args.object)?.current()?;
+        Ok(AccessRes {
+            object_attributes: self.post_op(id),
+            access: args.access,
+        })
+    }
+
+    fn v3_read_link(&mut self, args: ReadLinkArgs) -> Result<ReadLinkRes, V4Error> {
+        let id = self.v3_connection(args.symlink)?.current()?;
+        match &self.node(id)?.kind {
+            NodeKind::Symlink(target) => Ok(ReadLinkRes {
+                symlink_attributes: self.post_op(id),
+                data: target.clone(),
+            }),
+            _ => Err(V4Error::Inval),
+        }
+    }
+
+    fn v3_read(&mut self, args: ReadArgs) -> Result<ReadRes, V4Error> {
+        self.check_io()?;
+        let id = self.v3_connection(args.file)?.current()?;
+        let count = args.count.min(self.read_limit.unwrap_or(u32::MAX));
+        let data = self.file_data_mut(id)?;
+        let start = (args.offset as usize).min(data.len());
+        let end = (start + count as usize).min(data.len());
+        let eof = end == data.len();
+        let data = data[start..end].to_vec();
+        Ok(ReadRes {
+            file_attributes: self.post_op(id),
+            count: data.len() as u32,
+            eof,
+            data,
+        })
+    }
+
+    fn v3_write(&mut self, args: WriteArgs) -> Result<WriteRes, V4Error> {
+        self.check_io()?;
+        let id = self.v3_connection(args.file)?.current()?;
+        let unstable = args.stable == StableHow::Unstable;
+        self.write_data(id, args.offset, &args.data, unstable)?;
+        Ok(WriteRes {
+            file_wcc: wcc(self.post_op(id)),
+            count: args.data.len() as u32,
+            committed: args.stable,
+            verifier: Verifier(self.write_verifier.0),
+        })
+    }
+
+    /// Adds a node to the directory named in `where_`, for the procedures creating things.
+    fn v3_insert(
+        &mut self,
+        where_: DirOpArgs,
+        kind: NodeKind,
+        mode: Option<u32>,
+    ) -> Result<CreateRes, V4Error> {
+        let dir = self.v3_connection(where_.dir)?.current()?;
+        let (id, _) = self.insert_node(dir, &where_.name, kind, mode.unwrap_or(0o755))?;
+        Ok(CreateRes {
+            object: Some(v3_handle(id)),
+            object_attributes: self.post_op(id),
+            dir_wcc: wcc(self.post_op(dir)),
+        })
+    }
+
+    fn v3_create(&mut self, args: CreateArgs) -> Result<CreateRes, V4Error> {
+        let dir = self.v3_connection(args.where_.dir.clone())?.current()?;
+        let existing = self.entries(dir)?.get(&args.where_.name).copied();
+        match (existing, args.how) {
+            (Some(id), CreateHow::Unchecked(attrs)) => {
+                if let Some(size) = attrs.size {
+                    self.file_data_mut(id)?.resize(size as usize, 0);
+                }
+                Ok(CreateRes {
+                    object: Some(v3_handle(id)),
+                    object_attributes: self.post_op(id),
+                    dir_wcc: wcc(self.post_op(dir)),
+                })
+            }
+            (Some(_), _) => Err(V4Error::Exist),
+            (None, CreateHow::Unchecked(attrs) | CreateHow::Guarded(attrs)) => self.v3_insert(
+                args.where_,
+                NodeKind::File(vec![]),
+                Some(attrs.mode.unwrap_or(0o644)),
+            ),
+            (None, CreateHow::Exclusive(_)) => {
+                self.v3_insert(args.where_, NodeKind::File(vec![]), Some(0o644))
+            }
+        }
+    }
+
+    fn v3_remove(&mut self, args: DirOpArgs, directory: bool) -> Result<RemoveRes, V4Error> {
+        let mut conn = self.v3_connection(args.dir)?;
+        let dir = conn.current()?;
+        let id = *self.entries(dir)?.get(&args.name).ok_or(V4Error::NoEnt)?;
+        match (&self.node(id)?.kind, directory) {
+            (NodeKind::Directory(_), false) => return Err(V4Error::Isdir),
+            (NodeKind::File(_) | NodeKind::Symlink(_), true) => return Err(V4Error::NotDir),
+            _ => {}
+        }
+        self.remove(&mut conn, nfs4::RemoveArgs { target: args.name })?;
+        Ok(RemoveRes {
+            dir_wcc: wcc(self.post_op(dir)),
+        })
+    }
+
+    fn v3_rename(&mut self, args: RenameArgs) -> Result<RenameRes, V4Error> {
+        let mut conn = self.v3_connection(args.to.dir)?;
+        conn.saved = Some(self.v3_connection(args.from.dir)?.current()?);
+        self.rename(
+            &mut conn,
+            nfs4::RenameArgs {
+                old_name: args.from.name,
+                new_name: args.to.name,
+            },
+        )?;
+        Ok(RenameRes {
+            from_dir_wcc: wcc(self.post_op(conn.saved.unwrap())),
+            to_dir_wcc: wcc(self.post_op(conn.current()?)),
+        })
+    }
+
+    fn v3_read_dir_plus(&mut self, args: ReadDirPlusArgs) -> Result<ReadDirPlusRes, V4Error> {
+        // Same cookies as READDIR in NFSv4, entry `n` gets cookie `n + 3`.
+        let dir = self.v3_connection(args.dir)?.current()?;
+        let skip = args.cookie.0.saturating_sub(2) as usize;
+        let entries = self.entries(dir)?;
+
+        // Roughly what each entry takes up in the reply, so the client has to come back for more
+        // when the directory doesn't fit in `max_count`.
+        let mut space = args.max_count as usize;
+        let mut reply = DirectoryListPlus {
+            entries: vec![],
+            eof: true,
+        };
+        for (i, (name, id)) in entries.iter().enumerate().skip(skip) {
+            let size = 140 + name.len();
+            if size > space {
+                if reply.entries.is_empty() {
+                    return Err(V4Error::TooSmall);
+                }
+                reply.eof = false;
+                break;
+            }
+            space -= size;
+            reply.entries.push(DirectoryEntryPlus {
+                file_id: *id,
+                name: name.clone(),
+                cookie: Cookie(i as u64 + 3),
+                attributes: self.post_op(*id),
+                handle: Some(v3_handle(*id)),
+            });
+        }
+        Ok(ReadDirPlusRes {
+            dir_attributes: self.post_op(dir),
+            cookie_verifier: Verifier(0),
+            reply,
+        })
+    }
+
+    fn v3_fs_info(&mut self, args: FsInfoArgs) -> Result<FsInfoRes, V4Error> {
+        let id = self.v3_connection(args.fs_root)?.current()?;
+        Ok(FsInfoRes {
+            object_attributes: self.post_op(id),
+            read_max: MAX_READ as u32,
+            read_preferred: MAX_READ as u32,
+            read_multiple: 4096,
+            write_max: MAX_WRITE as u32,
+            write_preferred: MAX_WRITE as u32,
+            write_multiple: 4096,
+            dir_preferred: 8192,
+            max_file_size: u64::MAX,
+            time_delta: Time {
+                seconds: 0,
+                nseconds: 1,
+            },
+            properties: FsProperties::SYMLINK
+                | FsProperties::HOMOGENEOUS
+                | FsProperties::CAN_SET_TIME,
+        })
+    }
+
+    fn v3_commit(&mut self, args: CommitArgs) -> Result<CommitRes, V4Error> {
+        self.check_io()?;
+        let id = self.v3_connection(args.file)?.current()?;
+        self.commit_data(id);
+        Ok(CommitRes {
+            file_wcc: wcc(self.post_op(id)),
+            verifier: Verifier(self.write_verifier.0),
+        })
+    }
+
+    pub(crate) fn handle_nfs3(&mut self, xid: Xid, procedure: u32, record: &[u8]) -> Vec<u8> {
+        let Ok(procedure) = Procedure::try_from(procedure) else {
+            return reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable);
+        };
+        match procedure {
+            Procedure::Null => reply(xid, AcceptedReplyBody::Success(())),
+            Procedure::GetAttr => call(xid, record, |args| status::<_, ()>(self.v3_get_attr(args))),
+            Procedure::SetAttr => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_set_attr(args))
+            }),
+            Procedure::LookUp => call(xid, record, |args| {
+                status::<_, LookUpFail>(self.v3_look_up(args))
+            }),
+            Procedure::Access => call(xid, record, |args| {
+                status::<_, AttributesFail>(self.v3_access(args))
+            }),
+            Procedure::ReadLink => call(xid, record, |args| {
+                status::<_, AttributesFail>(self.v3_read_link(args))
+            }),
+            Procedure::Read => call(xid, record, |args| {
+                status::<_, AttributesFail>(self.v3_read(args))
+            }),
+            Procedure::Write => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_write(args))
+            }),
+            Procedure::Create => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_create(args))
+            }),
+            Procedure::MkDir => call(xid, record, |args: MkDirArgs| {
+                status::<_, WccFail>(self.v3_insert(
+                    args.where_,
+                    NodeKind::Directory(BTreeMap::new()),
+                    args.attributes.mode,
+                ))
+            }),
+            Procedure::Symlink => call(xid, record, |args: SymlinkArgs| {
+                status::<_, WccFail>(self.v3_insert(
+                    args.where_,
+                    NodeKind::Symlink(args.symlink.data),
+                    args.symlink.attributes.mode,
+                ))
+            }),
+            Procedure::Remove => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_remove(args, false))
+            }),
+            Procedure::RmDir => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_remove(args, true))
+            }),
+            Procedure::Rename => call(xid, record, |args| {
+                status::<_, RenameRes>(self.v3_rename(args))
+            }),
+            Procedure::ReadDirPlus => call(xid, record, |args| {
+                status::<_, AttributesFail>(self.v3_read_dir_plus(args))
+            }),
+            Procedure::FsInfo => call(xid, record, |args| {
+                status::<_, AttributesFail>(self.v3_fs_info(args))
+            }),
+            Procedure::Commit => call(xid, record, |args| {
+                status::<_, WccFail>(self.v3_commit(args))
+            }),
+            _ => reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+        }
+    }
+
+    pub(crate) fn handle_mount(&mut self, xid: Xid, procedure: u32, record: &[u8]) -> Vec<u8> {
+        let Ok(procedure) = mount::Procedure::try_from(procedure) else {
+            return reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable);
+        };
+        match procedure {
+            mount::Procedure::Mount => call(xid, record, |args: MountArgs| {
+                status::<_, ()>(self.resolve(&args.dir_path).map(|id| MountRes {
+                    handle: v3_handle(id),
+                    auth_flavors: vec![sun_rpc::AuthFlavor::Sys as u32],
+                }))
+            }),
+            mount::Procedure::Export => reply(
+                xid,
+                AcceptedReplyBody::Success(ExportRes {
+                    exports: vec![ExportEntry {
+                        dir_path: "/".into(),
+                        groups: vec![],
+                    }],
+                }),
+            ),
+            mount::Procedure::Dump => reply(
+                xid,
+                AcceptedReplyBody::Success(mount::DumpRes { mounts: vec![] }),
+            ),
+            mount::Procedure::Null | mount::Procedure::Unmount | mount::Procedure::UnmountAll => {
+                reply(xid, AcceptedReplyBody::Success(()))
+            }
+        }
+    }
+
+    /// The portmapper of both the server and its clients, since they all run on this host. What
+    /// the server serves itself always wins, so a client registering NLM doesn't hide the
+    /// server's.
+    pub(crate) fn handle_port_mapper(
+        &mut self,
+        xid: Xid,
+        procedure: u32,
+        record: &[u8],
+    ) -> Vec<u8> {
+        let registrations = &mut self.lock_manager.registrations;
+        match procedure {
+            SET_PROCEDURE => call(xid, record, |args: Mapping| {
+                let key = (args.program, args.version);
+                let Ok(port) = u16::try_from(args.port) else {
+                    return false;
+                };
+                if args.protocol != IPPROTO_TCP || registrations.contains_key(&key) {
+                    return false;
+                }
+                registrations.insert(key, port);
+                true
+            }),
+            UNSET_PROCEDURE => call(xid, record, |args: Mapping| {
+                registrations
+                    .remove(&(args.program, args.version))
+                    .is_some()
+            }),
+            GET_PORT_PROCEDURE => call(xid, record, |args: Mapping| {
+                let served = [
+                    (NFS_PROGRAM, 3),
+                    (NFS_PROGRAM, 4),
+                    (MOUNT_PROGRAM, 3),
+                    (NLM_PROGRAM, 4),
+                    (NSM_PROGRAM, 1),
+                ];
+                let key = (args.program, args.version);
+                if args.protocol != IPPROTO_TCP {
+                    0
+                } else if served.contains(&key) {
+                    self.port as u32
+                } else {
+                    registrations.get(&key).map_or(0, |&port| port.into())
+                }
+            }),
+            _ => reply(xid, AcceptedReplyBody::<()>::ProcedureUnavailable),
+        }
+    }
+}
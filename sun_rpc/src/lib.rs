@@ -181,3 +181,15 @@ pub enum MessageBody<Args> {
     Call(CallBody<Args>) = 0,
     Reply(ReplyBody<Args>) = 1,
 }
+
+pub const IPPROTO_TCP: u32 = 6;
+pub const IPPROTO_UDP: u32 = 17;
+
+/// An entry in the portmapper's table, also the argument to GETPORT.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Mapping {
+    pub program: u32,
+    pub version: u32,
+    pub protocol: u32,
+    pub port: u32,
+}
@@ -4,8 +4,8 @@ use derive_more::From;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{fmt, io};
 use sun_rpc::{
-    AcceptedReplyBody, AuthSysParameters, CallBody, Gid, Message, MessageBody, OpaqueAuth,
-    ReplyBody, Uid, Xid,
+    AcceptedReply, AcceptedReplyBody, AuthSysParameters, CallBody, Gid, Mapping, Message,
+    MessageBody, OpaqueAuth, ReplyBody, Uid, Xid, IPPROTO_TCP,
 };
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -30,11 +30,17 @@ impl<T> Transport for T where T: io::Read + io::Write {}
 
 pub const PORT_MAPPER: u32 = 100000;
 pub const PORT_MAPPER_PORT: u16 = 111;
+pub const PORT_MAPPER_VERSION: u32 = 2;
 pub const NULL_PROCEDURE: u32 = 0;
+const SET_PROCEDURE: u32 = 1;
+const UNSET_PROCEDURE: u32 = 2;
+const GET_PORT_PROCEDURE: u32 = 3;
 
 pub struct RpcClient<TransportT> {
     xid: Xid,
     program: u32,
+    version: u32,
+    credential: OpaqueAuth,
     transport: TransportT,
 }
 
@@ -43,25 +49,39 @@ impl<TransportT: Transport> RpcClient<TransportT> {
         Self {
             xid: Xid(1),
             program,
+            version: 4,
+            credential: OpaqueAuth::auth_sys(AuthSysParameters {
+                stamp: 0,
+                machine_name: "test-machine".into(),
+                uid: Uid(0),
+                gid: Gid(0),
+                gids: vec![Gid(0)],
+            }),
             transport,
         }
     }
 
-    pub fn send_request<T: Serialize>(&mut self, procedure: u32, call_args: T) -> Result<()> {
+    /// Use the given program version instead of 4.
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn set_credential(&mut self, credential: OpaqueAuth) {
+        self.credential = credential;
+    }
+
+    /// Sends a call without waiting for the reply, returning the xid the reply will have.
+    pub fn send_request<T: Serialize>(&mut self, procedure: u32, call_args: T) -> Result<Xid> {
+        let xid = self.xid.clone();
         let message = Message {
             xid: self.xid.clone(),
             body: MessageBody::Call(CallBody {
                 rpc_version: 2,
                 program: self.program,
-                version: 4,
+                version: self.version,
                 procedure,
-                credential: OpaqueAuth::auth_sys(AuthSysParameters {
-                    stamp: 0,
-                    machine_name: "test-machine".into(),
-                    uid: Uid(0),
-                    gid: Gid(0),
-                    gids: vec![Gid(0)],
-                }),
+                credential: self.credential.clone(),
                 verifier: OpaqueAuth::none(),
                 call_args,
             }),
@@ -76,22 +96,31 @@ impl<TransportT: Transport> RpcClient<TransportT> {
 
         self.xid = Xid(self.xid.0 + 1);
 
-        Ok(())
+        Ok(xid)
     }
 
     pub fn receive_reply<T: DeserializeOwned + fmt::Debug>(&mut self) -> Result<T> {
+        Ok(self.receive_reply_with_xid()?.1)
+    }
+
+    /// Receives the next reply along with its xid, for when several calls are outstanding and
+    /// the replies may come back in any order.
+    pub fn receive_reply_with_xid<T: DeserializeOwned + fmt::Debug>(&mut self) -> Result<(Xid, T)> {
         let fragment_header: u32 = serde_xdr::from_reader(&mut self.transport)?;
         let length = fragment_header & !(0x1 << 31);
-        let reply: Message<T> =
-            serde_xdr::from_reader(&mut io::Read::take(&mut self.transport, length as u64))?;
+        let mut record = io::Read::take(&mut self.transport, length as u64);
+        let reply: Message<T> = serde_xdr::from_reader(&mut record)?;
+
+        // Skip anything the reply type didn't consume, like the body of an error result.
+        io::copy(&mut record, &mut io::sink())?;
 
         if let Message {
+            xid,
             body: MessageBody::Reply(ReplyBody::Accepted(accepted_reply)),
-            ..
         } = reply
         {
             match accepted_reply.body {
-                AcceptedReplyBody::Success(b) => Ok(b),
+                AcceptedReplyBody::Success(b) => Ok((xid, b)),
                 AcceptedReplyBody::ProgramUnavailable => Err(Error::ProgramUnavailable),
                 AcceptedReplyBody::ProgramMismatch { .. } => Err(Error::ProgramMismatch),
                 AcceptedReplyBody::ProcedureUnavailable => Err(Error::ProcedureUnavailable),
@@ -104,6 +133,105 @@ impl<TransportT: Transport> RpcClient<TransportT> {
     }
 }
 
+/// Reads one record-marked message, for serving calls. Returns `None` at the end of the stream.
+pub fn read_record(transport: &mut impl io::Read) -> io::Result<Option<Vec<u8>>> {
+    let mut record = vec![];
+    loop {
+        let mut header = [0; 4];
+        match transport.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && record.is_empty() => {
+                return Ok(None)
+            }
+            res => res?,
+        }
+        let header = u32::from_be_bytes(header);
+        let start = record.len();
+        record.resize(start + (header & !(1 << 31)) as usize, 0);
+        transport.read_exact(&mut record[start..])?;
+        if header & (1 << 31) != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// Serializes a reply to the call with the given xid, including its record mark.
+pub fn encode_reply<T: Serialize>(xid: Xid, body: AcceptedReplyBody<T>) -> Result<Vec<u8>> {
+    let message = Message {
+        xid,
+        body: MessageBody::Reply(ReplyBody::Accepted(AcceptedReply {
+            verifier: OpaqueAuth::none(),
+            body,
+        })),
+    };
+    let mut serialized = vec![0; 4];
+    serde_xdr::to_writer(&mut serialized, &message)?;
+    let header = (serialized.len() - 4) as u32 | 1 << 31;
+    serialized[..4].copy_from_slice(&header.to_be_bytes());
+    Ok(serialized)
+}
+
+/// Finds the ports RPC programs are listening on by asking the portmapper (rpcbind).
+pub struct PortMapperClient<TransportT> {
+    rpc_client: RpcClient<TransportT>,
+}
+
+impl<TransportT: Transport> PortMapperClient<TransportT> {
+    pub fn new(transport: TransportT) -> Self {
+        Self {
+            rpc_client: RpcClient::new(transport, PORT_MAPPER).with_version(PORT_MAPPER_VERSION),
+        }
+    }
+
+    /// The TCP port the given program version is registered on, `None` if it isn't registered.
+    pub fn get_port(&mut self, program: u32, version: u32) -> Result<Option<u16>> {
+        self.rpc_client.send_request(
+            GET_PORT_PROCEDURE,
+            Mapping {
+                program,
+                version,
+                protocol: IPPROTO_TCP,
+                port: 0,
+            },
+        )?;
+        let port: u32 = self.rpc_client.receive_reply()?;
+        if port == 0 {
+            return Ok(None);
+        }
+        Ok(Some(port.try_into().map_err(|_| {
+            Error::UnexpectedReply(format!("bad port number {port}"))
+        })?))
+    }
+
+    /// Registers the given program version on a TCP port. Returns false if the portmapper
+    /// refused, which it does when the program version is already registered.
+    pub fn set(&mut self, program: u32, version: u32, port: u16) -> Result<bool> {
+        self.rpc_client.send_request(
+            SET_PROCEDURE,
+            Mapping {
+                program,
+                version,
+                protocol: IPPROTO_TCP,
+                port: port.into(),
+            },
+        )?;
+        self.rpc_client.receive_reply()
+    }
+
+    /// Removes the registrations of the given program version. Returns false if there were none.
+    pub fn unset(&mut self, program: u32, version: u32) -> Result<bool> {
+        self.rpc_client.send_request(
+            UNSET_PROCEDURE,
+            Mapping {
+                program,
+                version,
+                protocol: IPPROTO_TCP,
+                port: 0,
+            },
+        )?;
+        self.rpc_client.receive_reply()
+    }
+}
+
 #[test]
 fn ping() {
     vm_test_fixture::fixture(&[PORT_MAPPER_PORT], |m| {